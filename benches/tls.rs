@@ -0,0 +1,188 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use boring::ssl::{SslAcceptor, SslConnector};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tokio::runtime::Runtime;
+use tokio::time::Instant;
+
+use ztunnel::identity::Identity;
+use ztunnel::tls::{self, CsrOptions, EcCurve, KeyType, SanChecker};
+
+fn test_certs(id: &Identity) -> tls::Certs {
+    tls::generate_test_certs(
+        &id.clone().into(),
+        Duration::from_secs(0),
+        Duration::from_secs(100),
+    )
+}
+
+/// csr measures generating a key and CSR, done for every workload certificate we request.
+fn csr(c: &mut Criterion) {
+    let mut group = c.benchmark_group("csr");
+    for (name, key_type) in [
+        ("p256", KeyType::Ec(EcCurve::P256)),
+        ("rsa2048", KeyType::Rsa(2048)),
+    ] {
+        let options = CsrOptions {
+            san: Identity::default().to_string(),
+            key_type,
+            ..Default::default()
+        };
+        group.bench_function(name, |b| b.iter(|| options.generate().unwrap()));
+    }
+    group.finish();
+}
+
+/// context measures building the TLS contexts, done for every connection.
+fn context(c: &mut Criterion) {
+    let id = Identity::default();
+    let certs = test_certs(&id);
+    let mut group = c.benchmark_group("context");
+    group.bench_function("mtls_acceptor", |b| {
+        b.iter(|| certs.mtls_acceptor(black_box(None)).unwrap())
+    });
+    group.bench_function("connector", |b| {
+        b.iter(|| certs.connector(black_box(&id)).unwrap())
+    });
+    group.finish();
+}
+
+/// handshake measures a TLS 1.3 mutual handshake, in memory so the network does not add noise.
+fn handshake(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let id = Identity::default();
+    let certs = test_certs(&id);
+    let acceptor = &certs.mtls_acceptor(None).unwrap();
+    let connector = &certs.connector(&id).unwrap();
+    let mut group = c.benchmark_group("handshake");
+    group.bench_function("mtls", |b| {
+        b.to_async(&rt).iter(|| async move {
+            let (client, server) = tokio::io::duplex(16 * 1024);
+            let mut cfg = connector.configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            let (accepted, connected) = tokio::join!(
+                tokio_boring::accept(&acceptor, server),
+                tokio_boring::connect(cfg, "", client),
+            );
+            accepted.unwrap();
+            connected.unwrap();
+        })
+    });
+    group.finish();
+}
+
+// Number of handshakes started at once by timer_skew, and how often its timer is set to fire.
+const BURST: usize = 32;
+const TICK: Duration = Duration::from_millis(1);
+
+/// timer_skew measures how late a timer fires on the runtime running a burst of RSA handshakes,
+/// with the handshakes run inline or offloaded to the handshake threads. The time reported is the
+/// worst lateness seen during a burst, not how long the burst took.
+fn timer_skew(c: &mut Criterion) {
+    let id = Identity::default();
+    let key_type = KeyType::Rsa(2048);
+    let (ca_cert, ca_key) = tls::generate_test_ca_with_key_type("bench", key_type);
+    let certs = tls::generate_test_certs_with_key_type(
+        &id.clone().into(),
+        Duration::from_secs(0),
+        Duration::from_secs(100),
+        key_type,
+        &ca_cert,
+        &ca_key,
+    );
+    let acceptor = &certs.mtls_acceptor(None).unwrap();
+    let connector = &certs.connector(&id).unwrap();
+    let offloaded = tls::HandshakeOffload::default();
+    offloaded.enable(2).unwrap();
+    let inline = tls::HandshakeOffload::default();
+    // A single thread, so that handshakes run inline compete with the timer.
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("timer_skew");
+    for (name, offload) in [("inline", &inline), ("offloaded", &offloaded)] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                rt.block_on(async {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        total += burst(offload, acceptor, connector).await;
+                    }
+                    total
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+// Runs BURST handshakes with offload, returning the worst lateness of a timer meanwhile.
+async fn burst(
+    offload: &tls::HandshakeOffload,
+    acceptor: &SslAcceptor,
+    connector: &SslConnector,
+) -> Duration {
+    let handshakes = futures::future::join_all((0..BURST).map(|_| {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut cfg = connector.configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let acceptor = acceptor.clone();
+        async move {
+            let (accepted, connected) = tokio::join!(
+                offload.run(async move { tokio_boring::accept(&acceptor, server).await.is_ok() }),
+                offload.run(async move { tokio_boring::connect(cfg, "", client).await.is_ok() }),
+            );
+            assert!(accepted && connected);
+        }
+    }));
+    tokio::pin!(handshakes);
+    let mut worst = Duration::ZERO;
+    loop {
+        let start = Instant::now();
+        tokio::select! {
+            _ = &mut handshakes => return worst,
+            _ = tokio::time::sleep(TICK) => {
+                worst = worst.max(start.elapsed().saturating_sub(TICK));
+            }
+        }
+    }
+}
+
+/// san compares verifying the SAN of a peer certificate to parsing all of its SANs, which is what
+/// verification used to do for every handshake.
+fn san(c: &mut Criterion) {
+    let id = Identity::default();
+    let certs = test_certs(&id);
+    let cert = certs.x509();
+    let mut group = c.benchmark_group("san");
+    group.bench_function("verify", |b| {
+        b.iter(|| cert.verify_san(black_box(&id)).unwrap())
+    });
+    group.bench_function("verify_trust_domain", |b| {
+        b.iter(|| cert.verify_san_trust_domain(black_box(&id)).unwrap())
+    });
+    group.bench_function("extract", |b| {
+        b.iter(|| assert!(tls::extract_sans(black_box(cert)).contains(&id)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, csr, context, handshake, timer_skew, san);
+criterion_main!(benches);
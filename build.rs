@@ -22,6 +22,8 @@ fn main() -> Result<(), anyhow::Error> {
         "proto/workload.proto",
         "proto/authorization.proto",
         "proto/citadel.proto",
+        "proto/secret.proto",
+        "proto/sds.proto",
     ]
     .iter()
     .map(|name| std::env::current_dir().unwrap().join(name))
@@ -15,6 +15,7 @@
 use bytes::Bytes;
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use std::{net::SocketAddr, time::Duration};
@@ -39,12 +40,12 @@ use tracing::{error, info, warn};
 
 use crate::config::Config;
 use crate::hyper_util::{empty_response, plaintext_response, Server};
-use crate::identity::SecretManager;
+use crate::identity::{Identity, SecretManager};
 use crate::tls::asn1_time_to_system_time;
 use crate::version::BuildInfo;
 use crate::workload::LocalConfig;
 use crate::workload::WorkloadInformation;
-use crate::{signal, telemetry};
+use crate::{signal, telemetry, tls};
 
 struct State {
     workload_info: WorkloadInformation,
@@ -76,6 +77,33 @@ pub struct CertDump {
     expiration_time: String,
 }
 
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct CertsSummary {
+    identity: String,
+    state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    certs: Option<tls::CertsInfo>,
+    // Lifetime requested from the CA, and the one it granted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requested_lifetime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    granted_lifetime: Option<String>,
+}
+
+/// TlsCheckDump reports TLS handshakes with each of the configured control plane endpoints.
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct TlsCheckDump {
+    xds: Vec<tls::ControlPlaneCheck>,
+    ca: Vec<tls::ControlPlaneCheck>,
+}
+
+/// ChannelsDump describes the connectivity of the control plane channels.
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct ChannelsDump {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ca: Option<tls::ChannelHealth>,
+}
+
 #[derive(serde::Serialize, Debug, Clone, Default)]
 pub struct CertsDump {
     identity: String,
@@ -134,6 +162,14 @@ impl Service {
                     // req, // bring this back if we start using it
                 )
                 .await),
+                "/certs" => Ok(handle_certs(state.cert_manager.borrow()).await),
+                "/channels" => Ok(handle_channels(state.cert_manager.borrow())),
+                "/cert_events" => Ok(handle_cert_events(state.cert_manager.borrow())),
+                "/refresh_certs" => {
+                    Ok(handle_refresh_certs(state.cert_manager.borrow(), req).await)
+                }
+                "/debug/tls-check" => Ok(handle_tls_check(&state.config, req).await),
+                "/tls/reload" => Ok(handle_tls_reload(&state.config, req)),
                 "/logging" => Ok(handle_logging(req).await),
                 "/" => Ok(handle_dashboard(req).await),
                 _ => Ok(empty_response(hyper::StatusCode::NOT_FOUND)),
@@ -158,6 +194,27 @@ async fn handle_dashboard(_req: Request<Incoming>) -> Response<Full<Bytes>> {
         ),
         ("quitquitquit", "shut down the server"),
         ("config_dump", "dump the current Ztunnel configuration"),
+        ("certs", "dump the cached workload certificates"),
+        (
+            "refresh_certs",
+            "request new workload certificates, optionally only for ?identity=<spiffe id>",
+        ),
+        (
+            "channels",
+            "show the health of the control plane connections",
+        ),
+        (
+            "cert_events",
+            "show the recent certificate lifecycle events, one JSON object per line",
+        ),
+        (
+            "debug/tls-check",
+            "check TLS handshakes with the control plane, using the configured roots",
+        ),
+        (
+            "tls/reload",
+            "show the TLS runtime config, or reload it from its file with a POST",
+        ),
         ("logging", "query/changing logging levels"),
     ];
 
@@ -234,6 +291,184 @@ async fn dump_certs(cert_manager: &SecretManager) -> Vec<CertsDump> {
     dump
 }
 
+async fn handle_certs(cert_manager: &SecretManager) -> Response<Full<Bytes>> {
+    let requested_lifetime = cert_manager
+        .requested_cert_lifetime()
+        .map(|d| format!("{d:?}"));
+    let mut dump = cert_manager
+        .collect_certs(|id, certs| {
+            use crate::identity::CertState::*;
+            let (state, certs, granted_lifetime) = match certs {
+                Initializing(_) => ("Initializing".to_string(), None, None),
+                Unavailable(err) => (format!("Unavailable: {err}"), None, None),
+                Available(certs) => (
+                    "Available".to_string(),
+                    Some(certs.dump()),
+                    Some(format!("{:?}", certs.lifetime())),
+                ),
+            };
+            CertsSummary {
+                identity: id.to_string(),
+                state,
+                certs,
+                requested_lifetime: requested_lifetime.clone(),
+                granted_lifetime,
+            }
+        })
+        .await;
+    // Sort for determinism.
+    dump.sort_by(|a, b| a.identity.cmp(&b.identity));
+
+    let vec = serde_json::to_vec(&dump).unwrap();
+    let mut response = Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(vec.into())
+        .unwrap();
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn handle_channels(cert_manager: &SecretManager) -> Response<Full<Bytes>> {
+    let dump = ChannelsDump {
+        ca: cert_manager.ca_health(),
+    };
+    let vec = serde_json::to_vec(&dump).unwrap();
+    let mut response = Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(vec.into())
+        .unwrap();
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn handle_cert_events(cert_manager: &SecretManager) -> Response<Full<Bytes>> {
+    let mut body = Vec::new();
+    for event in cert_manager.events().recent() {
+        serde_json::to_writer(&mut body, &event).unwrap();
+        body.push(b'\n');
+    }
+    let mut response = Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(body.into())
+        .unwrap();
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    response
+}
+
+async fn handle_refresh_certs(
+    cert_manager: &SecretManager,
+    req: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    if req.method() != hyper::Method::POST {
+        return empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let id = req.uri().query().and_then(|q| {
+        url::form_urlencoded::parse(q.as_bytes())
+            .find(|(k, _)| k == "identity")
+            .map(|(_, v)| v.into_owned())
+    });
+    match id {
+        None => {
+            cert_manager.force_refresh_all().await;
+            plaintext_response(
+                hyper::StatusCode::OK,
+                "refreshing all certificates\n".into(),
+            )
+        }
+        Some(id) => match Identity::from_str(&id) {
+            Err(e) => plaintext_response(hyper::StatusCode::BAD_REQUEST, format!("{e}\n")),
+            Ok(id) if cert_manager.force_refresh(&id).await => {
+                plaintext_response(hyper::StatusCode::OK, format!("refreshing {id}\n"))
+            }
+            Ok(id) => plaintext_response(
+                hyper::StatusCode::NOT_FOUND,
+                format!("no certificate for {id}\n"),
+            ),
+        },
+    }
+}
+
+#[derive(serde::Serialize)]
+struct TlsRuntimeDump {
+    generation: u64,
+    config: tls::TlsRuntimeConfig,
+}
+
+//curl -X POST http://127.0.0.1:15000/tls/reload
+fn handle_tls_reload(config: &Config, req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let runtime = tls::TlsRuntime::global();
+    match *req.method() {
+        hyper::Method::GET => {}
+        hyper::Method::POST => {
+            let Some(path) = &config.tls_runtime_config else {
+                return plaintext_response(
+                    hyper::StatusCode::NOT_FOUND,
+                    "no tls runtime config file is set\n".into(),
+                );
+            };
+            if let Err(e) = runtime.reload_file(path) {
+                return plaintext_response(hyper::StatusCode::BAD_REQUEST, format!("{e}\n"));
+            }
+        }
+        _ => return empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
+    }
+    let dump = TlsRuntimeDump {
+        generation: runtime.generation(),
+        config: (*runtime.load()).clone(),
+    };
+    let vec = serde_json::to_vec(&dump).unwrap();
+    let mut response = Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(vec.into())
+        .unwrap();
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+// Bounds each handshake of /debug/tls-check, so an unresponsive endpoint cannot hang the request.
+const TLS_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+//curl -X POST http://127.0.0.1:15000/debug/tls-check
+async fn handle_tls_check(config: &Config, req: Request<Incoming>) -> Response<Full<Bytes>> {
+    if req.method() != hyper::Method::POST {
+        return empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    tls_check(config, TLS_CHECK_TIMEOUT).await
+}
+
+async fn tls_check(config: &Config, timeout: Duration) -> Response<Full<Bytes>> {
+    let connector = tls::ConnectorConfig::from(config);
+    let check = |endpoints: Vec<tls::Endpoint>| {
+        let connector = &connector;
+        futures::future::join_all(endpoints.into_iter().map(move |e| async move {
+            tls::check_control_plane(&e.address, &e.root_cert, connector, timeout).await
+        }))
+    };
+    let (xds, ca) = tokio::join!(check(config.xds_endpoints()), check(config.ca_endpoints()));
+    let dump = TlsCheckDump { xds, ca };
+    let status = if dump.xds.iter().chain(&dump.ca).all(|c| c.success) {
+        hyper::StatusCode::OK
+    } else {
+        hyper::StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let vec = serde_json::to_vec(&dump).unwrap();
+    let mut response = Response::builder().status(status).body(vec.into()).unwrap();
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
 async fn handle_pprof(_req: Request<Incoming>) -> Response<Full<Bytes>> {
     let guard = pprof::ProfilerGuardBuilder::default()
         .frequency(1000)
@@ -460,7 +695,9 @@ mod tests {
     use std::sync::Mutex;
 
     use super::dump_certs;
+    use super::handle_certs;
     use super::handle_config_dump;
+    use super::tls_check;
     use super::ConfigDump;
 
     fn diff_json<'a>(a: &'a serde_json::Value, b: &'a serde_json::Value) -> String {
@@ -647,6 +884,56 @@ mod tests {
         pending_fetch.await.unwrap().unwrap();
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_handle_certs() {
+        let manager = identity::mock::new_secret_manager_cfg(identity::mock::SecretManagerConfig {
+            cert_lifetime: Duration::from_secs(7 * 60 * 60),
+            fetch_latency: Duration::from_secs(1),
+            epoch: Some(
+                chrono::DateTime::parse_from_rfc3339("2023-03-11T05:57:26Z")
+                    .unwrap()
+                    .into(),
+            ),
+        });
+        for i in 0..2 {
+            manager
+                .fetch_certificate(&identity::Identity::Spiffe {
+                    trust_domain: "trust_domain".to_string(),
+                    namespace: "namespace".to_string(),
+                    service_account: format!("sa-{i}"),
+                })
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(60 * 60 - 1)).await;
+        }
+
+        let body = handle_certs(&manager)
+            .await
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        assert!(!String::from_utf8_lossy(&body).contains("PRIVATE KEY"));
+        let got: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let got = got.as_array().unwrap();
+        assert_eq!(got.len(), 2);
+        for (dump, (sa, valid_from, expiration)) in got.iter().zip([
+            ("sa-0", "2023-03-11T05:57:26Z", "2023-03-11T12:57:26Z"),
+            ("sa-1", "2023-03-11T06:57:26Z", "2023-03-11T13:57:26Z"),
+        ]) {
+            let id = format!("spiffe://trust_domain/ns/namespace/sa/{sa}");
+            assert_eq!(dump["identity"], id.as_str());
+            assert_eq!(dump["state"], "Available");
+            let cert = &dump["certs"]["cert"];
+            assert_eq!(cert["not_before"], valid_from);
+            assert_eq!(cert["not_after"], expiration);
+            assert_eq!(cert["sans"], serde_json::json!([id]));
+            assert_eq!(cert["issuer"], "O=cluster.local");
+            assert_eq!(dump["certs"]["chain"].as_array().unwrap().len(), 1);
+        }
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_dump_config() {
         let manager = identity::mock::new_secret_manager_cfg(identity::mock::SecretManagerConfig {
@@ -811,4 +1098,60 @@ mod tests {
         // the config dump at all from our internal types
         assert!(resp_str.contains("defaultnw/127.0.0.2"));
     }
+
+    #[tokio::test]
+    async fn test_tls_check() {
+        use tokio_stream::StreamExt;
+
+        let certs = crate::tls::generate_test_certs(
+            &std::net::IpAddr::from([127, 0, 0, 1]).into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let root = crate::config::RootCert::Static(certs.chain().unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut tls_stream = crate::hyper_util::tls_server(
+            crate::tls::ControlPlaneCertProvider(certs),
+            listener,
+            None,
+        );
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Some(conn) = tls_stream.next().await {
+                conns.push(conn);
+            }
+        });
+
+        let mut config = construct_config(ProxyConfig::default()).unwrap();
+        config.xds_address = Some(format!("https://{addr}"));
+        config.xds_root_cert = root;
+        config.xds_failover_endpoints = Vec::new();
+        config.ca_address = None;
+        config.ca_failover_endpoints = Vec::new();
+        let resp = tls_check(&config, Duration::from_secs(5)).await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let got: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let xds = &got["xds"][0];
+        assert_eq!(xds["success"], true);
+        assert_eq!(xds["version"], "TLSv1.3");
+        assert_eq!(
+            xds["peer_chain"][0]["sans"],
+            serde_json::json!(["127.0.0.1"])
+        );
+        assert_eq!(got["ca"], serde_json::json!([]));
+
+        // Nothing listens on the CA address.
+        let unused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        config.ca_address = Some(format!("https://{}", unused.local_addr().unwrap()));
+        drop(unused);
+        let resp = tls_check(&config, Duration::from_secs(5)).await;
+        assert_eq!(resp.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let got: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(got["xds"][0]["success"], true);
+        assert_eq!(got["ca"][0]["success"], false);
+        assert!(got["ca"][0]["error"].is_string());
+    }
 }
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -20,11 +21,11 @@ use std::time::Duration;
 
 use anyhow::Context;
 use prometheus_client::registry::Registry;
-use tracing::Instrument;
+use tracing::{error, warn, Instrument};
 
 use crate::identity::SecretManager;
 use crate::metrics::Metrics;
-use crate::{admin, config, identity, proxy, readiness, signal, stats, workload};
+use crate::{admin, config, identity, proxy, readiness, sds, signal, stats, tls, workload};
 
 pub async fn build_with_cert(
     config: config::Config,
@@ -32,6 +33,12 @@ pub async fn build_with_cert(
 ) -> anyhow::Result<Bound> {
     let mut registry = Registry::default();
     let metrics = Arc::new(Metrics::from(&mut registry));
+    cert_manager.register_metrics(registry.sub_registry_with_prefix("istio"));
+    tls::DnsCache::global().register_metrics(registry.sub_registry_with_prefix("istio"));
+    tls::HandshakeOffload::global().register_metrics(registry.sub_registry_with_prefix("istio"));
+    tls::TlsBuffers::global().register_metrics(registry.sub_registry_with_prefix("istio"));
+    tls::SanMetrics::global().register_metrics(registry.sub_registry_with_prefix("istio"));
+    tls::RetryMetrics::global().register_metrics(registry.sub_registry_with_prefix("istio"));
 
     let shutdown = signal::Shutdown::new();
     // Setup a drain channel. drain_tx is used to trigger a drain, which will complete
@@ -42,6 +49,13 @@ pub async fn build_with_cert(
 
     let ready = readiness::Ready::new();
     let proxy_task = ready.register_task("proxy listeners");
+    if config.ca_connectivity_readiness {
+        let cert_manager = cert_manager.clone();
+        ready.register_check("CA connectivity", move || match cert_manager.ca_health() {
+            Some(health) => health.check(),
+            None => Ok(()),
+        });
+    }
     let workload_manager = workload::WorkloadManager::new(
         config.clone(),
         metrics.clone(),
@@ -78,10 +92,20 @@ pub async fn build_with_cert(
     )
     .await?;
     drop(proxy_task);
+    let sds_server = match &config.sds_socket {
+        Some(path) => Some(
+            sds::Service::new(path.clone(), cert_manager.clone(), drain_rx.clone())
+                .context("sds server starts")?,
+        ),
+        None => None,
+    };
 
     // spawn all tasks that should run in the main thread
     admin_server.spawn();
     stats_server.spawn();
+    if let Some(sds_server) = sds_server {
+        sds_server.spawn();
+    }
     tokio::spawn(workload_manager.run());
 
     let proxy_addresses = proxy.addresses();
@@ -118,6 +142,28 @@ pub async fn build_with_cert(
 }
 
 pub async fn build(config: config::Config) -> anyhow::Result<Bound> {
+    // Nothing is served, readiness included, until the TLS configuration is known to be usable.
+    validate_tls_config(config.clone()).await?;
+    // Applies to every TLS context, including the CA client built below.
+    tls::require_fips(config.fips)?;
+    tls::set_security_level(config.openssl_security_level);
+    tls::set_sigalgs(config.tls_sigalgs.clone());
+    tls::set_record_options(config.tls_records);
+    tls::set_max_cert_list(config.tls_max_cert_list);
+    tls::set_san_enforcement(config.san_enforcement);
+    tls::set_cert_policy(config.cert_policy.clone());
+    tls::DnsCache::global().set_config(config.dns_cache);
+    if let Some(threads) = config.tls_handshake_threads {
+        tls::HandshakeOffload::global()
+            .enable(threads)
+            .context("tls handshake threads start")?;
+    }
+    if let Some(path) = &config.tls_runtime_config {
+        tls::TlsRuntime::global().reload_file(path)?;
+        #[cfg(unix)]
+        tokio::spawn(reload_tls_runtime_on_sighup(path.clone()));
+    }
+
     let cert_manager = if config.fake_ca {
         identity::mock::new_secret_manager(Duration::from_secs(86400))
     } else {
@@ -126,6 +172,17 @@ pub async fn build(config: config::Config) -> anyhow::Result<Bound> {
     build_with_cert(config, cert_manager).await
 }
 
+async fn validate_tls_config(config: config::Config) -> anyhow::Result<()> {
+    let res = tokio::task::spawn_blocking(move || tls::validate_config(&config)).await?;
+    if let Err(errors) = res {
+        for e in &errors {
+            error!("invalid TLS configuration: {e}");
+        }
+        anyhow::bail!("{} problem(s) found in the TLS configuration", errors.len());
+    }
+    Ok(())
+}
+
 pub struct Bound {
     pub admin_address: SocketAddr,
     pub proxy_addresses: proxy::Addresses,
@@ -148,3 +205,23 @@ impl Bound {
         Ok(())
     }
 }
+
+// Reloads the TLS runtime config from path on every SIGHUP. Invalid configs are logged, and the
+// current one kept.
+#[cfg(unix)]
+async fn reload_tls_runtime_on_sighup(path: PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("failed to watch SIGHUP, tls runtime config is only reloaded by the admin server: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(e) = tls::TlsRuntime::global().reload_file(&path) {
+            warn!("failed to reload tls runtime config: {e}");
+        }
+    }
+}
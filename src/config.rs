@@ -18,15 +18,17 @@ use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-use std::{env, fs};
+use std::{env, fs, io};
 
 use anyhow::anyhow;
 use bytes::Bytes;
 use hyper::http::uri::InvalidUri;
 use hyper::Uri;
+use ipnet::IpNet;
 use tokio::time;
+use zeroize::Zeroizing;
 
-use crate::identity;
+use crate::{identity, socket, tls};
 
 const KUBERNETES_SERVICE_HOST: &str = "KUBERNETES_SERVICE_HOST";
 const NETWORK: &str = "NETWORK";
@@ -39,9 +41,77 @@ const XDS_ON_DEMAND: &str = "XDS_ON_DEMAND";
 const XDS_ADDRESS: &str = "XDS_ADDRESS";
 const CA_ADDRESS: &str = "CA_ADDRESS";
 const FAKE_CA: &str = "FAKE_CA";
+const CA_CONNECTIVITY_READINESS: &str = "CA_CONNECTIVITY_READINESS";
 const ZTUNNEL_WORKER_THREADS: &str = "ZTUNNEL_WORKER_THREADS";
 const ENABLE_ORIG_SRC: &str = "ENABLE_ORIG_SRC";
 const PROXY_CONFIG: &str = "PROXY_CONFIG";
+const HTTPS_PROXY: &str = "HTTPS_PROXY";
+const NO_PROXY: &str = "NO_PROXY";
+const CONTROL_PLANE_CONNECT_TIMEOUT: &str = "CONTROL_PLANE_CONNECT_TIMEOUT";
+const CONTROL_PLANE_KEEPALIVE: &str = "CONTROL_PLANE_KEEPALIVE";
+const TRUST_BUNDLES: &str = "TRUST_BUNDLES";
+const CONTROL_PLANE_HAPPY_EYEBALLS_TIMEOUT: &str = "CONTROL_PLANE_HAPPY_EYEBALLS_TIMEOUT";
+const CONTROL_PLANE_ROOT_CERT_CHECK_INTERVAL: &str = "CONTROL_PLANE_ROOT_CERT_CHECK_INTERVAL";
+const CONTROL_PLANE_ALPN: &str = "CONTROL_PLANE_ALPN";
+const CONTROL_PLANE_PINS: &str = "CONTROL_PLANE_PINS";
+const CONTROL_PLANE_RETRY_MAX_ATTEMPTS: &str = "CONTROL_PLANE_RETRY_MAX_ATTEMPTS";
+const CONTROL_PLANE_RETRY_PER_TRY_TIMEOUT: &str = "CONTROL_PLANE_RETRY_PER_TRY_TIMEOUT";
+const CONTROL_PLANE_RETRY_INITIAL_BACKOFF: &str = "CONTROL_PLANE_RETRY_INITIAL_BACKOFF";
+const CONTROL_PLANE_RETRY_MAX_BACKOFF: &str = "CONTROL_PLANE_RETRY_MAX_BACKOFF";
+const CONTROL_PLANE_RETRY_DEADLINE: &str = "CONTROL_PLANE_RETRY_DEADLINE";
+const DNS_CACHE_TTL: &str = "DNS_CACHE_TTL";
+const DNS_NEGATIVE_CACHE_TTL: &str = "DNS_NEGATIVE_CACHE_TTL";
+const DNS_CACHE_MAX_STALE: &str = "DNS_CACHE_MAX_STALE";
+const CONTROL_PLANE_STREAM_WINDOW_SIZE: &str = "CONTROL_PLANE_STREAM_WINDOW_SIZE";
+const CONTROL_PLANE_CONNECTION_WINDOW_SIZE: &str = "CONTROL_PLANE_CONNECTION_WINDOW_SIZE";
+const CONTROL_PLANE_MAX_FRAME_SIZE: &str = "CONTROL_PLANE_MAX_FRAME_SIZE";
+const CONTROL_PLANE_MAX_CONCURRENT_STREAMS: &str = "CONTROL_PLANE_MAX_CONCURRENT_STREAMS";
+const CONTROL_PLANE_MAX_MESSAGE_SIZE: &str = "CONTROL_PLANE_MAX_MESSAGE_SIZE";
+const KEY_PASSPHRASE: &str = "KEY_PASSPHRASE";
+const CERT_EXPIRY_DANGER_WINDOW: &str = "CERT_EXPIRY_DANGER_WINDOW";
+const KEY_PASSPHRASE_FILE: &str = "KEY_PASSPHRASE_FILE";
+const CERT_CACHE_CAPACITY: &str = "CERT_CACHE_CAPACITY";
+const CERT_CACHE_IDLE_TIMEOUT: &str = "CERT_CACHE_IDLE_TIMEOUT";
+const CERT_PREFETCH_CONCURRENCY: &str = "CERT_PREFETCH_CONCURRENCY";
+const MAX_ACCEPTED_CERT_LIFETIME: &str = "MAX_ACCEPTED_CERT_LIFETIME";
+const MAX_ACCEPTED_CERT_LIFETIME_MODE: &str = "MAX_ACCEPTED_CERT_LIFETIME_MODE";
+const FIPS_REQUIRED: &str = "FIPS_REQUIRED";
+const OPENSSL_SECURITY_LEVEL: &str = "OPENSSL_SECURITY_LEVEL";
+const NORMALIZE_CERT_CHAIN: &str = "NORMALIZE_CERT_CHAIN";
+const TLS_SIGALGS: &str = "TLS_SIGALGS";
+const TLS_MAX_SEND_FRAGMENT: &str = "TLS_MAX_SEND_FRAGMENT";
+const TLS_RELEASE_BUFFERS: &str = "TLS_RELEASE_BUFFERS";
+const TLS_MAX_CERT_LIST: &str = "TLS_MAX_CERT_LIST";
+const SAN_ENFORCEMENT: &str = "SAN_ENFORCEMENT";
+const TLS_HANDSHAKE_THREADS: &str = "TLS_HANDSHAKE_THREADS";
+const TLS_RUNTIME_CONFIG: &str = "TLS_RUNTIME_CONFIG";
+const WORKLOAD_KEY_TYPE: &str = "WORKLOAD_KEY_TYPE";
+const WORKLOAD_CERT_TTL: &str = "WORKLOAD_CERT_TTL";
+const TCP_NODELAY: &str = "TCP_NODELAY";
+const TCP_KEEPALIVE_IDLE: &str = "TCP_KEEPALIVE_IDLE";
+const TCP_KEEPALIVE_INTERVAL: &str = "TCP_KEEPALIVE_INTERVAL";
+const TCP_KEEPALIVE_COUNT: &str = "TCP_KEEPALIVE_COUNT";
+const SOCKET_MARK: &str = "SOCKET_MARK";
+const IP_TOS: &str = "IP_TOS";
+const ALLOW_WEAK_CERTIFICATES: &str = "ALLOW_WEAK_CERTIFICATES";
+const WORKLOAD_CERT_FILE: &str = "WORKLOAD_CERT_FILE";
+const WORKLOAD_KEY_FILE: &str = "WORKLOAD_KEY_FILE";
+const WORKLOAD_CHAIN_FILE: &str = "WORKLOAD_CHAIN_FILE";
+const SDS_SOCKET_PATH: &str = "SDS_SOCKET_PATH";
+const AUTH_TOKEN_REFRESH_WINDOW: &str = "AUTH_TOKEN_REFRESH_WINDOW";
+const CA_AUDIENCE: &str = "CA_AUDIENCE";
+const CA_HEADERS: &str = "CA_HEADERS";
+const WORKLOAD_TOKEN_DIR: &str = "WORKLOAD_TOKEN_DIR";
+const WORKLOAD_ROOT_CERTS: &str = "WORKLOAD_ROOT_CERTS";
+const TRUST_DOMAIN_ALIASES: &str = "TRUST_DOMAIN_ALIASES";
+const INBOUND_PASSTHROUGH_PORTS: &str = "INBOUND_PASSTHROUGH_PORTS";
+const INBOUND_PASSTHROUGH_CIDRS: &str = "INBOUND_PASSTHROUGH_CIDRS";
+const INBOUND_IDLE_TIMEOUT: &str = "INBOUND_IDLE_TIMEOUT";
+const MAX_CONNECTIONS_PER_IDENTITY: &str = "MAX_CONNECTIONS_PER_IDENTITY";
+const CONNECTION_LIMIT_EXEMPT_IDENTITIES: &str = "CONNECTION_LIMIT_EXEMPT_IDENTITIES";
+const TLS_MAX_OPEN_STREAMS: &str = "TLS_MAX_OPEN_STREAMS";
+const TLS_SHED_MAX_RSS: &str = "TLS_SHED_MAX_RSS";
+const TLS_SHED_SEND_ALERT: &str = "TLS_SHED_SEND_ALERT";
 
 const DEFAULT_WORKER_THREADS: u16 = 2;
 const DEFAULT_ADMIN_PORT: u16 = 15000;
@@ -49,12 +119,15 @@ const DEFAULT_READINESS_PORT: u16 = 15021;
 const DEFAULT_STATS_PORT: u16 = 15020;
 const DEFAULT_SELFTERM_DEADLINE: Duration = Duration::from_secs(5);
 const DEFAULT_CLUSTER_ID: &str = "Kubernetes";
+const DEFAULT_AUTH_TOKEN_REFRESH_WINDOW: Duration = Duration::from_secs(5 * 60);
 
 const ISTIO_META_PREFIX: &str = "ISTIO_META_";
 
 /// Fetch the XDS/CA root cert file path based on below constants
 const XDS_ROOT_CA_ENV: &str = "XDS_ROOT_CA";
 const CA_ROOT_CA_ENV: &str = "CA_ROOT_CA";
+const XDS_FAILOVER_ROOT_CA_ENV: &str = "XDS_FAILOVER_ROOT_CA";
+const CA_FAILOVER_ROOT_CA_ENV: &str = "CA_FAILOVER_ROOT_CA";
 const DEFAULT_ROOT_CERT_PROVIDER: &str = "./var/run/secrets/istio/root-cert.pem";
 const CERT_SYSTEM: &str = "SYSTEM";
 
@@ -64,6 +137,8 @@ const PROXY_MODE_SHARED: &str = "shared";
 #[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum RootCert {
     File(PathBuf),
+    /// Directory holding one root per file; every *.pem and *.crt file is loaded.
+    Directory(PathBuf),
     Static(#[serde(skip)] Bytes),
     Default,
 }
@@ -74,6 +149,45 @@ pub enum ConfigSource {
     Static(Bytes),
 }
 
+impl Config {
+    /// ca_endpoints returns the CA endpoints, in the order they are used. Empty when the CA is
+    /// not used.
+    pub fn ca_endpoints(&self) -> Vec<tls::Endpoint> {
+        Self::endpoints(
+            &self.ca_address,
+            &self.ca_root_cert,
+            &self.ca_failover_endpoints,
+        )
+    }
+
+    /// xds_endpoints returns the XDS endpoints, in the order they are used. Empty when XDS is not
+    /// used.
+    pub fn xds_endpoints(&self) -> Vec<tls::Endpoint> {
+        Self::endpoints(
+            &self.xds_address,
+            &self.xds_root_cert,
+            &self.xds_failover_endpoints,
+        )
+    }
+
+    fn endpoints(
+        address: &Option<String>,
+        root_cert: &RootCert,
+        failover: &[tls::Endpoint],
+    ) -> Vec<tls::Endpoint> {
+        let Some(address) = address else {
+            return Vec::new();
+        };
+        let primary = tls::Endpoint {
+            address: address.clone(),
+            root_cert: root_cert.clone(),
+        };
+        std::iter::once(primary)
+            .chain(failover.iter().cloned())
+            .collect()
+    }
+}
+
 impl ConfigSource {
     pub async fn read_to_string(&self) -> anyhow::Result<String> {
         Ok(match self {
@@ -83,6 +197,68 @@ impl ConfigSource {
     }
 }
 
+/// Where to read the passphrase for encrypted private keys from. Only the location is kept in
+/// config; the passphrase itself is read on demand so it never ends up in a config dump.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum KeyPassphraseSource {
+    File(PathBuf),
+    /// Name of the environment variable holding the passphrase.
+    Env(String),
+}
+
+impl KeyPassphraseSource {
+    pub fn load(&self) -> io::Result<Zeroizing<Vec<u8>>> {
+        let mut passphrase = Zeroizing::new(match self {
+            KeyPassphraseSource::File(path) => fs::read(path)?,
+            KeyPassphraseSource::Env(var) => env::var_os(var)
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("{var} is not set"))
+                })?
+                .to_string_lossy()
+                .as_bytes()
+                .to_vec(),
+        });
+        // files are commonly written with a trailing newline, which is not part of the passphrase
+        while passphrase.ends_with(b"\n") || passphrase.ends_with(b"\r") {
+            passphrase.pop();
+        }
+        if passphrase.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "key passphrase is empty",
+            ));
+        }
+        Ok(passphrase)
+    }
+}
+
+/// Paths of PEM files holding the workload certificate, when it is provisioned by an external agent
+/// such as cert-manager or SPIRE instead of being signed by the CA.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CertFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Intermediates and root, unless they follow the leaf in the cert file.
+    pub chain: Option<PathBuf>,
+}
+
+/// Inbound ports that are passed through to the workload untouched instead of terminating mTLS,
+/// such as health check ports or protocols that cannot be tunneled.
+#[derive(serde::Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct InboundPassthrough {
+    pub ports: Vec<u16>,
+    /// If not empty, only connections to these destinations are passed through.
+    pub cidrs: Vec<IpNet>,
+}
+
+impl InboundPassthrough {
+    /// matches tells whether connections to dst are passed through.
+    pub fn matches(&self, dst: SocketAddr) -> bool {
+        self.ports.contains(&dst.port())
+            && (self.cidrs.is_empty() || self.cidrs.iter().any(|c| c.contains(&dst.ip())))
+    }
+}
+
 #[derive(serde::Serialize, Default, Clone, Debug, PartialEq, Eq)]
 pub enum ProxyMode {
     #[default]
@@ -120,10 +296,105 @@ pub struct Config {
     pub ca_address: Option<String>,
     /// Root cert for CA TLS verification.
     pub ca_root_cert: RootCert,
+    /// If true, the process is not ready while the connection to the CA is failing.
+    pub ca_connectivity_readiness: bool,
+    /// CA endpoints used, in order, when ca_address is unreachable. CA_ADDRESS accepts a comma
+    /// separated list, the first address being ca_address. Roots are read from the matching entry
+    /// of the comma separated CA_FAILOVER_ROOT_CA, defaulting to ca_root_cert.
+    pub ca_failover_endpoints: Vec<tls::Endpoint>,
     /// XDS address to use. If unset, XDS will not be used.
     pub xds_address: Option<String>,
     /// Root cert for XDS TLS verification.
     pub xds_root_cert: RootCert,
+    /// XDS endpoints used, in order, when xds_address is unreachable. Configured like
+    /// ca_failover_endpoints, with XDS_ADDRESS and XDS_FAILOVER_ROOT_CA.
+    pub xds_failover_endpoints: Vec<tls::Endpoint>,
+    /// PEM root bundles of federated trust domains, keyed by trust domain. When set, peers must
+    /// chain to a root of their own trust domain.
+    pub trust_bundles: HashMap<String, PathBuf>,
+    /// Passphrase for encrypted private keys loaded from files.
+    pub key_passphrase: Option<KeyPassphraseSource>,
+    /// Workload certificate files, used instead of the CA when set.
+    pub cert_files: Option<CertFiles>,
+    /// When a workload certificate cannot be refreshed, serving it within this window before its
+    /// expiry is logged and counted.
+    pub cert_expiry_danger_window: Duration,
+    /// Maximum number of workload certificates kept, evicting the least recently used ones.
+    /// Unbounded if unset.
+    pub cert_cache_capacity: Option<usize>,
+    /// Workload certificates that were not requested for this long are evicted. Never if unset.
+    pub cert_cache_idle_timeout: Option<Duration>,
+    /// Maximum number of workload certificates prefetched at a time, before any connection needs
+    /// them.
+    pub cert_prefetch_concurrency: u16,
+    /// Workload certificates valid for longer than this are refreshed early, or rejected in
+    /// strict mode. Unbounded if unset.
+    pub max_accepted_cert_lifetime: Option<identity::MaxCertLifetime>,
+    /// If true, startup fails unless TLS runs in FIPS mode.
+    pub fips: bool,
+    /// OpenSSL security level (0 to 5) applied to every TLS context. Only the key strength
+    /// requirements are enforced. Disabled if unset.
+    pub openssl_security_level: Option<u32>,
+    /// Signature algorithms allowed in every TLS handshake, in OpenSSL list format such as
+    /// "ECDSA+SHA384". BoringSSL defaults are used if unset.
+    pub tls_sigalgs: Option<String>,
+    /// Record size and buffering of every TLS connection.
+    pub tls_records: tls::RecordOptions,
+    /// Largest certificate chain, in bytes, accepted from clients of inbound TLS connections. The
+    /// BoringSSL default of 100 KiB is used if unset.
+    pub tls_max_cert_list: Option<usize>,
+    /// Whether peers lacking the expected SAN are rejected (`enforce`, the default) or only
+    /// logged and counted (`monitor`), to dry-run stricter verification.
+    pub san_enforcement: tls::SanEnforcement,
+    /// Number of dedicated threads TLS handshakes of the proxy run on, so that their crypto does
+    /// not delay established connections under high connection churn. Handshakes run on the
+    /// runtime workers if unset.
+    pub tls_handshake_threads: Option<usize>,
+    /// File holding the tls::TlsRuntimeConfig, the TLS settings reloaded on SIGHUP or a POST to
+    /// the /tls/reload admin endpoint.
+    pub tls_runtime_config: Option<PathBuf>,
+    /// Type of the keys generated for workload certificates: EC_P256 (the default), EC_P384,
+    /// RSA_2048, RSA_3072 or RSA_4096.
+    pub workload_key_type: tls::KeyType,
+    /// Lifetime requested for workload certificates. The CA may issue shorter lived ones, and
+    /// certificates are refreshed based on the lifetime actually issued.
+    pub workload_cert_ttl: Duration,
+    /// TCP options of the connections TLS is run over, inbound and outbound.
+    pub socket: socket::SocketConfig,
+    /// Minimum strength of loaded certificates. Weak certificates are only logged if not
+    /// enforced.
+    pub cert_policy: tls::CertPolicy,
+    /// Unix socket serving workload certificates to co-located proxies over SDS. Disabled if
+    /// unset.
+    pub sds_socket: Option<PathBuf>,
+    /// HTTP proxy used to reach the CA and XDS servers, tunneling with CONNECT.
+    pub https_proxy: Option<String>,
+    /// Hosts that are reached directly, even if https_proxy is set.
+    pub no_proxy: Vec<String>,
+    /// Timeout for establishing a TCP connection to the CA and XDS servers.
+    pub control_plane_connect_timeout: Duration,
+    /// TCP keepalive idle time for connections to the CA and XDS servers.
+    pub control_plane_keepalive: Duration,
+    /// How long to wait on the preferred address family before racing the other, when the CA or
+    /// XDS address resolves to both IPv4 and IPv6 addresses (RFC 6555).
+    pub control_plane_happy_eyeballs_timeout: Duration,
+    /// How often root certificate files of the CA and XDS servers are checked for rotation.
+    pub control_plane_root_cert_check_interval: Duration,
+    /// Application protocols offered to the CA and XDS servers: "h2" (default), "h2,http/1.1"
+    /// for load balancers requiring http/1.1 to be offered, or "none" for TLS terminating
+    /// proxies forwarding h2c.
+    pub control_plane_alpn: tls::ControlPlaneAlpn,
+    /// h2 flow control settings and size limits of the CA and XDS connections.
+    pub control_plane_limits: tls::ChannelLimits,
+    /// Base64 SHA-256 hashes of public keys (SPKI), one of which the CA and XDS servers must
+    /// present in their verified chain, such as the serving or root certificate's. Not pinned if
+    /// empty.
+    pub control_plane_pins: Vec<String>,
+    /// Retries of the idempotent CA requests that fail transiently. Timeouts of zero disable the
+    /// per-try timeout or the overall deadline.
+    pub control_plane_retry: tls::GrpcRetryPolicy,
+    /// How long resolutions of the CA, XDS and proxy hosts are reused.
+    pub dns_cache: tls::DnsCacheConfig,
     /// YAML config for local XDS workloads
     #[serde(skip_serializing)]
     pub local_xds_config: Option<ConfigSource>,
@@ -134,6 +405,36 @@ pub struct Config {
     pub fake_ca: bool,
     #[serde(skip_serializing)]
     pub auth: identity::AuthSource,
+    /// The CA token is re-read once it is within this window of its expiry, in addition to
+    /// whenever the token file changes.
+    pub auth_token_refresh_window: Duration,
+    /// Token audience expected by the CA, sent with every CA request.
+    pub ca_audience: Option<String>,
+    /// Additional gRPC metadata sent with every CA request, such as ClusterID.
+    pub ca_headers: HashMap<String, String>,
+    /// Directory holding the service account tokens of workloads, as
+    /// `<namespace>/<service account>/token`. If set, certificates are requested on behalf of each
+    /// workload with its own token.
+    pub workload_token_dir: Option<PathBuf>,
+    /// PEM bundle of the roots workload peers are verified against, reloaded when it changes so
+    /// that old and new roots can be trusted together during a root rotation. New roots returned
+    /// by the CA are trusted as well. If unset, peers must chain to the root of our own
+    /// certificate.
+    pub workload_root_certs: Option<PathBuf>,
+    /// File listing trust domains whose identities are accepted in place of each other, separated
+    /// by commas or whitespace, such as the old and new trust domain during a migration. It is
+    /// reloaded when it changes.
+    pub trust_domain_aliases: Option<PathBuf>,
+    /// Inbound ports bypassing mTLS.
+    pub inbound_passthrough: InboundPassthrough,
+    /// Time after which inbound mTLS connections without any traffic are closed, if set.
+    pub inbound_idle_timeout: Option<Duration>,
+    /// Cap on the concurrent inbound mTLS connections of each client identity. Unlimited if
+    /// unset.
+    pub identity_limits: Option<tls::IdentityLimits>,
+    /// When new inbound mTLS connections are refused under resource pressure. None are refused if
+    /// unset.
+    pub load_shed: Option<tls::LoadShedConfig>,
     // How long ztunnel should wait for in-flight requesthandlers to finish processing
     // before giving up when ztunnel is self-terminating (when instructed via the Admin API)
     pub self_termination_deadline: time::Duration,
@@ -158,6 +459,10 @@ pub enum Error {
     ProxyConfig(anyhow::Error),
     #[error("invalid uri: {0}")]
     InvalidUri(#[from] Arc<InvalidUri>),
+    #[error("unix domain socket uri has no path: {0}")]
+    EmptyUdsPath(String),
+    #[error("{0} and {1} must be set together")]
+    MissingEnvVar(String, String),
 }
 
 impl From<InvalidUri> for Error {
@@ -166,6 +471,139 @@ impl From<InvalidUri> for Error {
     }
 }
 
+// Parses MAX_ACCEPTED_CERT_LIFETIME, with MAX_ACCEPTED_CERT_LIFETIME_MODE either `soft` (the
+// default) or `strict`.
+fn parse_max_cert_lifetime() -> Result<Option<identity::MaxCertLifetime>, Error> {
+    let mode = match parse::<String>(MAX_ACCEPTED_CERT_LIFETIME_MODE)?.as_deref() {
+        None | Some("soft") => identity::CertLifetimeMode::Soft,
+        Some("strict") => identity::CertLifetimeMode::Strict,
+        Some(mode) => {
+            return Err(Error::EnvVar(
+                MAX_ACCEPTED_CERT_LIFETIME_MODE.to_string(),
+                mode.to_string(),
+            ))
+        }
+    };
+    Ok(parse::<GoDuration>(MAX_ACCEPTED_CERT_LIFETIME)?
+        .map(|d| d.0)
+        .filter(|d| !d.is_zero())
+        .map(|max| identity::MaxCertLifetime { max, mode }))
+}
+
+// Parses SAN_ENFORCEMENT, either `enforce` (the default) or `monitor`.
+fn parse_san_enforcement() -> Result<tls::SanEnforcement, Error> {
+    match parse::<String>(SAN_ENFORCEMENT)?.as_deref() {
+        None | Some("enforce") => Ok(tls::SanEnforcement::Enforce),
+        Some("monitor") => Ok(tls::SanEnforcement::Monitor),
+        Some(mode) => Err(Error::EnvVar(SAN_ENFORCEMENT.to_string(), mode.to_string())),
+    }
+}
+
+// Parses the lifetime requested for workload certificates, which the CA takes in whole seconds.
+fn parse_workload_cert_ttl() -> Result<Duration, Error> {
+    match parse::<GoDuration>(WORKLOAD_CERT_TTL)? {
+        None => Ok(identity::DEFAULT_WORKLOAD_CERT_TTL),
+        Some(GoDuration(ttl)) if ttl >= Duration::from_secs(1) => Ok(ttl),
+        Some(_) => Err(Error::EnvVar(
+            WORKLOAD_CERT_TTL.to_string(),
+            env::var(WORKLOAD_CERT_TTL).unwrap_or_default(),
+        )),
+    }
+}
+
+// Parses the control plane channel limits, in bytes except for the number of streams.
+fn parse_channel_limits() -> Result<tls::ChannelLimits, Error> {
+    let default = tls::ChannelLimits::default();
+    let limits = tls::ChannelLimits {
+        initial_stream_window_size: parse_default(
+            CONTROL_PLANE_STREAM_WINDOW_SIZE,
+            default.initial_stream_window_size,
+        )?,
+        initial_connection_window_size: parse_default(
+            CONTROL_PLANE_CONNECTION_WINDOW_SIZE,
+            default.initial_connection_window_size,
+        )?,
+        max_frame_size: parse_default(CONTROL_PLANE_MAX_FRAME_SIZE, default.max_frame_size)?,
+        max_concurrent_streams: parse_default(
+            CONTROL_PLANE_MAX_CONCURRENT_STREAMS,
+            default.max_concurrent_streams,
+        )?,
+        max_message_size: parse_default(CONTROL_PLANE_MAX_MESSAGE_SIZE, default.max_message_size)?,
+    };
+    // h2 only allows frames between 16KiB and 16MiB.
+    if !(16 * 1024..=16 * 1024 * 1024 - 1).contains(&limits.max_frame_size) {
+        return Err(Error::EnvVar(
+            CONTROL_PLANE_MAX_FRAME_SIZE.to_string(),
+            limits.max_frame_size.to_string(),
+        ));
+    }
+    if limits.max_concurrent_streams == 0 {
+        return Err(Error::EnvVar(
+            CONTROL_PLANE_MAX_CONCURRENT_STREAMS.to_string(),
+            "0".to_string(),
+        ));
+    }
+    Ok(limits)
+}
+
+// Parses CONTROL_PLANE_ROOT_CERT_CHECK_INTERVAL, which must not be zero.
+fn parse_root_cert_check_interval() -> Result<Duration, Error> {
+    match parse::<GoDuration>(CONTROL_PLANE_ROOT_CERT_CHECK_INTERVAL)? {
+        None => Ok(tls::DEFAULT_ROOT_CERT_CHECK_INTERVAL),
+        Some(GoDuration(interval)) if interval.is_zero() => Err(Error::EnvVar(
+            CONTROL_PLANE_ROOT_CERT_CHECK_INTERVAL.to_string(),
+            "0s".to_string(),
+        )),
+        Some(GoDuration(interval)) => Ok(interval),
+    }
+}
+
+// Parses the retry policy of control plane requests. A zero timeout or deadline disables it.
+fn parse_retry_policy() -> Result<tls::GrpcRetryPolicy, Error> {
+    let default = tls::GrpcRetryPolicy::default();
+    let optional = |env: &str, default: Option<Duration>| -> Result<Option<Duration>, Error> {
+        Ok(match parse::<GoDuration>(env)? {
+            None => default,
+            Some(GoDuration(d)) if d.is_zero() => None,
+            Some(GoDuration(d)) => Some(d),
+        })
+    };
+    let policy = tls::GrpcRetryPolicy {
+        max_attempts: parse_default(CONTROL_PLANE_RETRY_MAX_ATTEMPTS, default.max_attempts)?,
+        per_try_timeout: optional(CONTROL_PLANE_RETRY_PER_TRY_TIMEOUT, default.per_try_timeout)?,
+        initial_backoff: parse::<GoDuration>(CONTROL_PLANE_RETRY_INITIAL_BACKOFF)?
+            .map(|d| d.0)
+            .unwrap_or(default.initial_backoff),
+        max_backoff: parse::<GoDuration>(CONTROL_PLANE_RETRY_MAX_BACKOFF)?
+            .map(|d| d.0)
+            .unwrap_or(default.max_backoff),
+        deadline: optional(CONTROL_PLANE_RETRY_DEADLINE, default.deadline)?,
+        methods: Vec::new(),
+    };
+    // The first attempt always counts, so there is nothing to disable retries with but 1.
+    if policy.max_attempts == 0 {
+        return Err(Error::EnvVar(
+            CONTROL_PLANE_RETRY_MAX_ATTEMPTS.to_string(),
+            "0".to_string(),
+        ));
+    }
+    Ok(policy)
+}
+
+fn parse_cert_files() -> Result<Option<CertFiles>, Error> {
+    let cert = parse::<PathBuf>(WORKLOAD_CERT_FILE)?;
+    let key = parse::<PathBuf>(WORKLOAD_KEY_FILE)?;
+    let chain = parse::<PathBuf>(WORKLOAD_CHAIN_FILE)?;
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(Some(CertFiles { cert, key, chain })),
+        (None, None) if chain.is_none() => Ok(None),
+        _ => Err(Error::MissingEnvVar(
+            WORKLOAD_CERT_FILE.to_string(),
+            WORKLOAD_KEY_FILE.to_string(),
+        )),
+    }
+}
+
 /// GoDuration wraps a Duration to implement golang Duration parsing semantics
 struct GoDuration(Duration);
 
@@ -214,42 +652,49 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
     } else {
         "https://localhost:15012".to_string()
     };
-    let xds_address = validate_uri(empty_to_none(
+    let (xds_address, xds_failover_addresses) = split_addresses(
         parse(XDS_ADDRESS)?
             .or(pc.discovery_address)
             .or_else(|| Some(default_istiod_address.clone())),
-    ))?;
+    );
+    let xds_address = validate_uri(empty_to_none(xds_address))?;
 
     let cluster_id = parse_default(CLUSTER_ID, DEFAULT_CLUSTER_ID.to_string())?;
 
     let fake_ca = parse_default(FAKE_CA, false)?;
-    let ca_address = validate_uri(empty_to_none(if fake_ca {
+    let (ca_address, ca_failover_addresses) = split_addresses(if fake_ca {
         None
     } else {
         Some(parse_default(CA_ADDRESS, default_istiod_address)?)
-    }))?;
+    });
+    let ca_address = validate_uri(empty_to_none(ca_address))?;
 
     let xds_root_cert_provider =
         parse_default(XDS_ROOT_CA_ENV, DEFAULT_ROOT_CERT_PROVIDER.to_string())?;
-    let xds_root_cert = if Path::new(&xds_root_cert_provider).exists() {
-        RootCert::File(xds_root_cert_provider.into())
-    } else if xds_root_cert_provider.eq(&CERT_SYSTEM.to_string()) {
-        // handle SYSTEM special case for xds
+    let xds_root_cert = if is_uds(&xds_address) && parse::<String>(XDS_ROOT_CA_ENV)?.is_none() {
+        // unix domain sockets are plaintext, so only use a root cert if explicitly requested
         RootCert::Default
     } else {
-        RootCert::Static(Bytes::from(xds_root_cert_provider))
+        parse_root_cert(xds_root_cert_provider)
     };
 
     let ca_root_cert_provider =
         parse_default(CA_ROOT_CA_ENV, DEFAULT_ROOT_CERT_PROVIDER.to_string())?;
-    let ca_root_cert = if Path::new(&ca_root_cert_provider).exists() {
-        RootCert::File(ca_root_cert_provider.into())
-    } else if ca_root_cert_provider.eq(&CERT_SYSTEM.to_string()) {
-        // handle SYSTEM special case for ca
+    let ca_root_cert = if is_uds(&ca_address) && parse::<String>(CA_ROOT_CA_ENV)?.is_none() {
         RootCert::Default
     } else {
-        RootCert::Static(Bytes::from(ca_root_cert_provider))
+        parse_root_cert(ca_root_cert_provider)
     };
+    let xds_failover_endpoints = failover_endpoints(
+        xds_failover_addresses,
+        XDS_FAILOVER_ROOT_CA_ENV,
+        &xds_root_cert,
+    )?;
+    let ca_failover_endpoints = failover_endpoints(
+        ca_failover_addresses,
+        CA_FAILOVER_ROOT_CA_ENV,
+        &ca_root_cert,
+    )?;
 
     Ok(Config {
         window_size: 4 * 1024 * 1024,
@@ -293,14 +738,113 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
 
         xds_address,
         xds_root_cert,
+        xds_failover_endpoints,
         ca_address,
         ca_root_cert,
+        ca_failover_endpoints,
+        trust_bundles: parse_trust_bundles()?,
+        key_passphrase: parse::<PathBuf>(KEY_PASSPHRASE_FILE)?
+            .map(KeyPassphraseSource::File)
+            .or_else(|| {
+                env::var_os(KEY_PASSPHRASE)
+                    .map(|_| KeyPassphraseSource::Env(KEY_PASSPHRASE.to_string()))
+            }),
+        cert_files: parse_cert_files()?,
+        cert_expiry_danger_window: parse::<GoDuration>(CERT_EXPIRY_DANGER_WINDOW)?
+            .map(|d| d.0)
+            .unwrap_or(identity::DEFAULT_CERT_EXPIRY_DANGER_WINDOW),
+        cert_cache_capacity: parse::<usize>(CERT_CACHE_CAPACITY)?.filter(|c| *c > 0),
+        cert_cache_idle_timeout: parse::<GoDuration>(CERT_CACHE_IDLE_TIMEOUT)?
+            .map(|d| d.0)
+            .filter(|d| !d.is_zero()),
+        cert_prefetch_concurrency: parse_default(
+            CERT_PREFETCH_CONCURRENCY,
+            identity::DEFAULT_CERT_PREFETCH_CONCURRENCY,
+        )?,
+        max_accepted_cert_lifetime: parse_max_cert_lifetime()?,
+        fips: parse_default(FIPS_REQUIRED, false)?,
+        cert_policy: tls::CertPolicy {
+            enforce: !parse_default(ALLOW_WEAK_CERTIFICATES, false)?,
+            normalize_chain: parse_default(NORMALIZE_CERT_CHAIN, true)?,
+            ..Default::default()
+        },
+        openssl_security_level: match parse::<u32>(OPENSSL_SECURITY_LEVEL)? {
+            Some(level) if level > 5 => {
+                return Err(Error::EnvVar(
+                    OPENSSL_SECURITY_LEVEL.to_string(),
+                    level.to_string(),
+                ))
+            }
+            level => level,
+        },
+        tls_sigalgs: empty_to_none(parse(TLS_SIGALGS)?),
+        tls_records: tls::RecordOptions {
+            max_send_fragment: match parse::<usize>(TLS_MAX_SEND_FRAGMENT)? {
+                Some(max) if !(512..=tls::MAX_RECORD_SIZE).contains(&max) => {
+                    return Err(Error::EnvVar(
+                        TLS_MAX_SEND_FRAGMENT.to_string(),
+                        max.to_string(),
+                    ))
+                }
+                max => max,
+            },
+            release_buffers: parse_default(TLS_RELEASE_BUFFERS, true)?,
+        },
+        tls_max_cert_list: parse::<usize>(TLS_MAX_CERT_LIST)?,
+        san_enforcement: parse_san_enforcement()?,
+        tls_handshake_threads: parse::<usize>(TLS_HANDSHAKE_THREADS)?.filter(|n| *n > 0),
+        tls_runtime_config: parse::<PathBuf>(TLS_RUNTIME_CONFIG)?,
+        workload_key_type: parse_default(WORKLOAD_KEY_TYPE, tls::KeyType::default())?,
+        workload_cert_ttl: parse_workload_cert_ttl()?,
+        socket: parse_socket_config()?,
+        sds_socket: parse::<PathBuf>(SDS_SOCKET_PATH)?,
+        https_proxy: validate_proxy(empty_to_none(parse(HTTPS_PROXY)?))?,
+        no_proxy: parse::<String>(NO_PROXY)?
+            .map(|np| {
+                np.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        control_plane_connect_timeout: parse::<GoDuration>(CONTROL_PLANE_CONNECT_TIMEOUT)?
+            .map(|d| d.0)
+            .unwrap_or(tls::DEFAULT_CONTROL_PLANE_CONNECT_TIMEOUT),
+        control_plane_keepalive: parse::<GoDuration>(CONTROL_PLANE_KEEPALIVE)?
+            .map(|d| d.0)
+            .unwrap_or(tls::DEFAULT_CONTROL_PLANE_KEEPALIVE),
+        control_plane_happy_eyeballs_timeout: parse::<GoDuration>(
+            CONTROL_PLANE_HAPPY_EYEBALLS_TIMEOUT,
+        )?
+        .map(|d| d.0)
+        .unwrap_or(tls::DEFAULT_CONTROL_PLANE_HAPPY_EYEBALLS_TIMEOUT),
+        control_plane_root_cert_check_interval: parse_root_cert_check_interval()?,
+        control_plane_alpn: parse_default(CONTROL_PLANE_ALPN, tls::ControlPlaneAlpn::default())?,
+        control_plane_limits: parse_channel_limits()?,
+        control_plane_pins: parse_pins()?,
+        control_plane_retry: parse_retry_policy()?,
+        dns_cache: parse_dns_cache_config()?,
         local_xds_config: parse::<PathBuf>(LOCAL_XDS_PATH)?.map(ConfigSource::File),
         xds_on_demand: parse_default(XDS_ON_DEMAND, false)?,
         proxy_metadata: pc.proxy_metadata,
 
         fake_ca,
         auth: identity::AuthSource::Token(PathBuf::from(r"./var/run/secrets/tokens/istio-token")),
+        auth_token_refresh_window: parse::<GoDuration>(AUTH_TOKEN_REFRESH_WINDOW)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_AUTH_TOKEN_REFRESH_WINDOW),
+        ca_audience: empty_to_none(parse(CA_AUDIENCE)?),
+        ca_connectivity_readiness: parse_default(CA_CONNECTIVITY_READINESS, false)?,
+        ca_headers: parse_ca_headers()?,
+        workload_token_dir: parse::<PathBuf>(WORKLOAD_TOKEN_DIR)?,
+        workload_root_certs: parse::<PathBuf>(WORKLOAD_ROOT_CERTS)?,
+        trust_domain_aliases: parse::<PathBuf>(TRUST_DOMAIN_ALIASES)?,
+        inbound_passthrough: parse_inbound_passthrough()?,
+        inbound_idle_timeout: parse::<GoDuration>(INBOUND_IDLE_TIMEOUT)?
+            .map(|d| d.0)
+            .filter(|d| !d.is_zero()),
+        identity_limits: parse_identity_limits()?,
+        load_shed: parse_load_shed()?,
 
         num_worker_threads: parse_default(
             ZTUNNEL_WORKER_THREADS,
@@ -315,11 +859,231 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
     })
 }
 
+// Parses TRUST_BUNDLES, formatted as `td-a=/path/a.pem,td-b=/path/b.pem`.
+fn parse_trust_bundles() -> Result<HashMap<String, PathBuf>, Error> {
+    let Some(bundles) = parse::<String>(TRUST_BUNDLES)? else {
+        return Ok(HashMap::new());
+    };
+    bundles
+        .split(',')
+        .filter(|b| !b.trim().is_empty())
+        .map(|b| match b.trim().split_once('=') {
+            Some((td, path)) if !td.is_empty() && !path.is_empty() => {
+                Ok((td.to_string(), PathBuf::from(path)))
+            }
+            _ => Err(Error::EnvVar(TRUST_BUNDLES.to_string(), bundles.clone())),
+        })
+        .collect()
+}
+
+// Parses CA_HEADERS, formatted as `key-a=value-a,key-b=value-b`.
+fn parse_ca_headers() -> Result<HashMap<String, String>, Error> {
+    let Some(headers) = parse::<String>(CA_HEADERS)? else {
+        return Ok(HashMap::new());
+    };
+    headers
+        .split(',')
+        .filter(|h| !h.trim().is_empty())
+        .map(|h| match h.trim().split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(Error::EnvVar(CA_HEADERS.to_string(), headers.clone())),
+        })
+        .collect()
+}
+
+// Parses INBOUND_PASSTHROUGH_PORTS, formatted as `8080,9090`, and INBOUND_PASSTHROUGH_CIDRS,
+// formatted as `10.0.0.0/8,fd00::/8`.
+fn parse_inbound_passthrough() -> Result<InboundPassthrough, Error> {
+    fn parse_list<T: FromStr>(env: &str) -> Result<Vec<T>, Error> {
+        let Some(list) = parse::<String>(env)? else {
+            return Ok(Vec::new());
+        };
+        list.split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| Error::EnvVar(env.to_string(), list.clone()))
+            })
+            .collect()
+    }
+    Ok(InboundPassthrough {
+        ports: parse_list(INBOUND_PASSTHROUGH_PORTS)?,
+        cidrs: parse_list(INBOUND_PASSTHROUGH_CIDRS)?,
+    })
+}
+
+// Parses the control plane pins, separated by commas. Each must be a base64 SHA-256 hash.
+fn parse_pins() -> Result<Vec<String>, Error> {
+    let Some(list) = parse::<String>(CONTROL_PLANE_PINS)? else {
+        return Ok(Vec::new());
+    };
+    list.split(',')
+        .map(str::trim)
+        .filter(|pin| !pin.is_empty())
+        .map(|pin| match base64::decode(pin) {
+            Ok(hash) if hash.len() == 32 => Ok(pin.to_string()),
+            _ => Err(Error::EnvVar(
+                CONTROL_PLANE_PINS.to_string(),
+                pin.to_string(),
+            )),
+        })
+        .collect()
+}
+
+fn parse_dns_cache_config() -> Result<tls::DnsCacheConfig, Error> {
+    let default = tls::DnsCacheConfig::default();
+    let duration = |env: &str, default: Duration| -> Result<Duration, Error> {
+        Ok(parse::<GoDuration>(env)?.map(|d| d.0).unwrap_or(default))
+    };
+    Ok(tls::DnsCacheConfig {
+        ttl: duration(DNS_CACHE_TTL, default.ttl)?,
+        negative_ttl: duration(DNS_NEGATIVE_CACHE_TTL, default.negative_ttl)?,
+        max_stale: duration(DNS_CACHE_MAX_STALE, default.max_stale)?,
+    })
+}
+
+// Parses the per-identity connection cap. Exempt identities are separated by commas, formatted as
+// `spiffe://cluster.local/ns/istio-system/sa/gateway`.
+fn parse_identity_limits() -> Result<Option<tls::IdentityLimits>, Error> {
+    let Some(max_connections) = parse::<usize>(MAX_CONNECTIONS_PER_IDENTITY)? else {
+        return Ok(None);
+    };
+    let exempt = match parse::<String>(CONNECTION_LIMIT_EXEMPT_IDENTITIES)? {
+        Some(list) => list
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                id.parse().map_err(|_| {
+                    Error::EnvVar(CONNECTION_LIMIT_EXEMPT_IDENTITIES.to_string(), list.clone())
+                })
+            })
+            .collect::<Result<_, _>>()?,
+        None => Default::default(),
+    };
+    Ok(Some(tls::IdentityLimits {
+        max_connections,
+        exempt,
+    }))
+}
+
+// Parses when new TLS connections are refused. TLS_SHED_MAX_RSS is in bytes. Shedding is only
+// enabled if TLS_MAX_OPEN_STREAMS or TLS_SHED_MAX_RSS is set.
+fn parse_load_shed() -> Result<Option<tls::LoadShedConfig>, Error> {
+    let max_streams = parse::<usize>(TLS_MAX_OPEN_STREAMS)?;
+    let max_rss_bytes = parse::<u64>(TLS_SHED_MAX_RSS)?;
+    if max_streams.is_none() && max_rss_bytes.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(tls::LoadShedConfig {
+        max_streams,
+        max_rss_bytes,
+        send_alert: parse_default(TLS_SHED_SEND_ALERT, false)?,
+    }))
+}
+
+// Parses the TCP options of TLS connections. Keepalive is only enabled with TCP_KEEPALIVE_IDLE.
+fn parse_socket_config() -> Result<socket::SocketConfig, Error> {
+    let keepalive = match parse::<GoDuration>(TCP_KEEPALIVE_IDLE)? {
+        Some(idle) => Some(socket::Keepalive {
+            idle: idle.0,
+            interval: parse::<GoDuration>(TCP_KEEPALIVE_INTERVAL)?.map(|d| d.0),
+            retries: parse::<u32>(TCP_KEEPALIVE_COUNT)?,
+        }),
+        None => None,
+    };
+    Ok(socket::SocketConfig {
+        nodelay: parse::<bool>(TCP_NODELAY)?,
+        keepalive,
+        mark: parse::<u32>(SOCKET_MARK)?,
+        tos: match parse::<u32>(IP_TOS)? {
+            Some(tos) if tos > 255 => {
+                return Err(Error::EnvVar(IP_TOS.to_string(), tos.to_string()))
+            }
+            tos => tos,
+        },
+    })
+}
+
+fn validate_proxy(proxy: Option<String>) -> Result<Option<String>, Error> {
+    let Some(proxy) = proxy else {
+        return Ok(None);
+    };
+    if let Err(e) = crate::tls::HttpProxy::parse(&proxy) {
+        return Err(Error::EnvVar(HTTPS_PROXY.to_string(), e.to_string()));
+    }
+    Ok(Some(proxy))
+}
+
+// Reads a root cert provider: a directory of roots, a file, SYSTEM for the system roots, or
+// otherwise a PEM bundle.
+fn parse_root_cert(provider: String) -> RootCert {
+    if Path::new(&provider).is_dir() {
+        RootCert::Directory(provider.into())
+    } else if Path::new(&provider).exists() {
+        RootCert::File(provider.into())
+    } else if provider == CERT_SYSTEM {
+        RootCert::Default
+    } else {
+        RootCert::Static(Bytes::from(provider))
+    }
+}
+
+// Splits a comma separated list of control plane addresses into the primary address and the
+// addresses to fail over to.
+fn split_addresses(addresses: Option<String>) -> (Option<String>, Vec<String>) {
+    let Some(addresses) = addresses else {
+        return (None, Vec::new());
+    };
+    let mut addresses = addresses.split(',').map(|a| a.trim().to_string());
+    let primary = addresses.next();
+    (primary, addresses.filter(|a| !a.is_empty()).collect())
+}
+
+// Pairs failover addresses with the root cert provider at the same position in the comma separated
+// roots_env. Addresses without one use the primary root, or no root for unix domain sockets.
+fn failover_endpoints(
+    addresses: Vec<String>,
+    roots_env: &str,
+    primary_root: &RootCert,
+) -> Result<Vec<tls::Endpoint>, Error> {
+    let roots: Vec<String> = parse::<String>(roots_env)?
+        .map(|r| r.split(',').map(|p| p.trim().to_string()).collect())
+        .unwrap_or_default();
+    addresses
+        .into_iter()
+        .enumerate()
+        .map(|(i, address)| {
+            let address = validate_uri(Some(address))?.expect("address is set");
+            let root_cert = match roots.get(i).filter(|p| !p.is_empty()) {
+                Some(provider) => parse_root_cert(provider.clone()),
+                None if is_uds(&Some(address.clone())) => RootCert::Default,
+                None => primary_root.clone(),
+            };
+            Ok(tls::Endpoint { address, root_cert })
+        })
+        .collect()
+}
+
+fn is_uds(uri_str: &Option<String>) -> bool {
+    uri_str
+        .as_ref()
+        .map(|u| u.starts_with(tls::UDS_SCHEME_PREFIX))
+        .unwrap_or(false)
+}
+
 // tries to parse the URI so we can fail early
 fn validate_uri(uri_str: Option<String>) -> Result<Option<String>, Error> {
     let Some(uri_str) = uri_str else {
         return Ok(uri_str);
     };
+    if let Some(path) = uri_str.strip_prefix(tls::UDS_SCHEME_PREFIX) {
+        if path.is_empty() {
+            return Err(Error::EmptyUdsPath(uri_str));
+        }
+        return Ok(Some(uri_str));
+    }
     let uri = Uri::try_from(&uri_str)?;
     if uri.scheme().is_none() {
         return Ok(Some("https://".to_owned() + &uri_str));
@@ -486,4 +1250,164 @@ pub mod tests {
         assert_eq!(cfg.proxy_metadata["NO_PREFIX"], "no-prefix");
         assert_eq!(cfg.proxy_metadata["INCLUDE_THIS"], "foobar-env");
     }
+
+    #[test]
+    fn validate_uds_uri() {
+        assert_eq!(
+            validate_uri(Some("unix:///var/run/istiod.sock".to_string())).unwrap(),
+            Some("unix:///var/run/istiod.sock".to_string())
+        );
+        assert!(validate_uri(Some("unix://".to_string())).is_err());
+        assert!(is_uds(&Some("unix:///var/run/istiod.sock".to_string())));
+        assert!(!is_uds(&Some("https://istiod:15012".to_string())));
+        assert!(!is_uds(&None));
+    }
+
+    #[test]
+    fn inbound_passthrough() {
+        env::set_var(INBOUND_PASSTHROUGH_PORTS, "8080, 9090");
+        env::set_var(INBOUND_PASSTHROUGH_CIDRS, "10.0.0.0/8");
+        let passthrough = parse_inbound_passthrough().unwrap();
+        env::remove_var(INBOUND_PASSTHROUGH_PORTS);
+        env::remove_var(INBOUND_PASSTHROUGH_CIDRS);
+        assert_eq!(passthrough.ports, vec![8080, 9090]);
+        assert!(passthrough.matches("10.1.2.3:8080".parse().unwrap()));
+        assert!(!passthrough.matches("10.1.2.3:80".parse().unwrap()));
+        assert!(!passthrough.matches("192.168.0.1:8080".parse().unwrap()));
+
+        // Without CIDRs, the ports are passed through for every destination.
+        let passthrough = InboundPassthrough {
+            ports: vec![8080],
+            cidrs: Vec::new(),
+        };
+        assert!(passthrough.matches("192.168.0.1:8080".parse().unwrap()));
+        assert!(passthrough.matches("[fd00::1]:8080".parse().unwrap()));
+        assert!(!InboundPassthrough::default().matches("10.1.2.3:8080".parse().unwrap()));
+    }
+
+    #[test]
+    fn control_plane_pins() {
+        let pin = base64::encode([7u8; 32]);
+        env::set_var(CONTROL_PLANE_PINS, format!("{pin}, "));
+        assert_eq!(parse_pins().unwrap(), vec![pin]);
+        env::set_var(CONTROL_PLANE_PINS, base64::encode([7u8; 20]));
+        assert!(parse_pins().is_err());
+        env::remove_var(CONTROL_PLANE_PINS);
+        assert!(parse_pins().unwrap().is_empty());
+    }
+
+    #[test]
+    fn control_plane_root_cert_check_interval() {
+        env::set_var(CONTROL_PLANE_ROOT_CERT_CHECK_INTERVAL, "1m");
+        assert_eq!(
+            parse_root_cert_check_interval().unwrap(),
+            Duration::from_secs(60)
+        );
+        env::set_var(CONTROL_PLANE_ROOT_CERT_CHECK_INTERVAL, "0s");
+        assert!(parse_root_cert_check_interval().is_err());
+        env::remove_var(CONTROL_PLANE_ROOT_CERT_CHECK_INTERVAL);
+        assert_eq!(
+            parse_root_cert_check_interval().unwrap(),
+            tls::DEFAULT_ROOT_CERT_CHECK_INTERVAL
+        );
+    }
+
+    #[test]
+    fn control_plane_retry() {
+        env::set_var(CONTROL_PLANE_RETRY_MAX_ATTEMPTS, "5");
+        env::set_var(CONTROL_PLANE_RETRY_PER_TRY_TIMEOUT, "0s");
+        env::set_var(CONTROL_PLANE_RETRY_DEADLINE, "1m");
+        let policy = parse_retry_policy().unwrap();
+        assert_eq!(policy.max_attempts, 5);
+        assert_eq!(policy.per_try_timeout, None);
+        assert_eq!(policy.deadline, Some(Duration::from_secs(60)));
+        assert_eq!(
+            policy.initial_backoff,
+            tls::GrpcRetryPolicy::default().initial_backoff
+        );
+        env::set_var(CONTROL_PLANE_RETRY_MAX_ATTEMPTS, "0");
+        assert!(parse_retry_policy().is_err());
+        env::remove_var(CONTROL_PLANE_RETRY_MAX_ATTEMPTS);
+        env::remove_var(CONTROL_PLANE_RETRY_PER_TRY_TIMEOUT);
+        env::remove_var(CONTROL_PLANE_RETRY_DEADLINE);
+    }
+
+    #[test]
+    fn identity_limits() {
+        assert_eq!(parse_identity_limits().unwrap(), None);
+        env::set_var(MAX_CONNECTIONS_PER_IDENTITY, "100");
+        env::set_var(
+            CONNECTION_LIMIT_EXEMPT_IDENTITIES,
+            "spiffe://cluster.local/ns/istio-system/sa/gateway, ",
+        );
+        let limits = parse_identity_limits().unwrap().unwrap();
+        assert_eq!(limits.max_connections, 100);
+        assert_eq!(
+            limits.exempt.into_iter().collect::<Vec<_>>(),
+            vec!["spiffe://cluster.local/ns/istio-system/sa/gateway"
+                .parse::<identity::Identity>()
+                .unwrap()]
+        );
+
+        env::set_var(CONNECTION_LIMIT_EXEMPT_IDENTITIES, "gateway");
+        assert!(parse_identity_limits().is_err());
+        env::remove_var(MAX_CONNECTIONS_PER_IDENTITY);
+        env::remove_var(CONNECTION_LIMIT_EXEMPT_IDENTITIES);
+    }
+
+    #[test]
+    fn load_shed() {
+        assert_eq!(parse_load_shed().unwrap(), None);
+        env::set_var(TLS_SHED_SEND_ALERT, "true");
+        assert_eq!(parse_load_shed().unwrap(), None);
+        env::set_var(TLS_MAX_OPEN_STREAMS, "1000");
+        assert_eq!(
+            parse_load_shed().unwrap(),
+            Some(tls::LoadShedConfig {
+                max_streams: Some(1000),
+                max_rss_bytes: None,
+                send_alert: true,
+            })
+        );
+        env::set_var(TLS_SHED_MAX_RSS, "512MB");
+        assert!(parse_load_shed().is_err());
+        env::remove_var(TLS_MAX_OPEN_STREAMS);
+        env::remove_var(TLS_SHED_MAX_RSS);
+        env::remove_var(TLS_SHED_SEND_ALERT);
+    }
+
+    #[test]
+    fn workload_cert_ttl() {
+        assert_eq!(
+            parse_workload_cert_ttl().unwrap(),
+            identity::DEFAULT_WORKLOAD_CERT_TTL
+        );
+        env::set_var(WORKLOAD_CERT_TTL, "1h");
+        assert_eq!(
+            parse_workload_cert_ttl().unwrap(),
+            Duration::from_secs(60 * 60)
+        );
+        env::set_var(WORKLOAD_CERT_TTL, "500ms");
+        assert!(parse_workload_cert_ttl().is_err());
+        env::remove_var(WORKLOAD_CERT_TTL);
+    }
+
+    #[test]
+    fn key_passphrase() {
+        let path = env::temp_dir().join(format!("ztunnel-passphrase-{}", rand::random::<u64>()));
+        fs::write(&path, "secret\n").unwrap();
+        let source = KeyPassphraseSource::File(path.clone());
+        assert_eq!(source.load().unwrap().as_slice(), b"secret");
+        fs::write(&path, "\n").unwrap();
+        assert!(source.load().is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(source.load().is_err());
+
+        let var = format!("ZTUNNEL_TEST_PASSPHRASE_{}", rand::random::<u64>());
+        let source = KeyPassphraseSource::Env(var.clone());
+        assert!(source.load().is_err());
+        env::set_var(&var, "hunter2");
+        assert_eq!(source.load().unwrap().as_slice(), b"hunter2");
+        env::remove_var(&var);
+    }
 }
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::{
@@ -28,25 +29,44 @@ use hyper::client;
 use hyper::rt::Sleep;
 use hyper::server::conn::{http1, http2};
 use hyper::{Request, Response};
-use hyper_util::client::connect::HttpConnector;
-use tokio::net::{TcpListener, TcpStream};
+use hyper_util::client::connect::{Connected, Connection, HttpConnector};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
 use tokio_stream::Stream;
 use tracing::{debug, info, warn};
 
-use crate::tls::{BoringTlsAcceptor, CertProvider};
+use crate::socket::SocketConfig;
+use crate::tls::{
+    BoringTlsAcceptor, CertProvider, DrainSignal, IdentityLimiter, IdentityLimits, LoadShedPolicy,
+    MaybeTls, PassthroughTlsAcceptor, PermissiveTlsAcceptor, TlsError, TlsMetrics,
+};
 
 pub fn tls_server<T: CertProvider + Clone + 'static>(
     acceptor: T,
     listener: TcpListener,
+    metrics: Option<Arc<dyn TlsMetrics>>,
 ) -> impl Stream<Item = tokio_boring::SslStream<TcpStream>> {
     use tokio_stream::StreamExt;
-    let boring_acceptor = BoringTlsAcceptor { acceptor };
+    let boring_acceptor = BoringTlsAcceptor {
+        acceptor,
+        metrics,
+        drain: Default::default(),
+        authorizer: None,
+        limiter: None,
+        socket: Default::default(),
+        shed: None,
+        sni_routing: false,
+    };
 
     tls_listener::builder(boring_acceptor)
         .listen(listener)
         .filter_map(|conn| {
             // Avoid 'By default, if a client fails the TLS handshake, that is treated as an error, and the TlsListener will return an Err'
             match conn {
+                Err(tls_listener::Error::TlsAcceptError(err)) => {
+                    warn!(reason = %err.classification(), "TLS handshake error: {}", err);
+                    None
+                }
                 Err(err) => {
                     warn!("TLS handshake error: {}", err);
                     None
@@ -59,6 +79,91 @@ pub fn tls_server<T: CertProvider + Clone + 'static>(
         })
 }
 
+/// maybe_tls_server is like tls_server, but lets clients that do not start with a TLS handshake
+/// through as plaintext connections, for example to migrate clients to mTLS.
+pub fn maybe_tls_server<T: CertProvider + Clone + 'static>(
+    acceptor: T,
+    listener: TcpListener,
+    metrics: Option<Arc<dyn TlsMetrics>>,
+) -> impl Stream<Item = MaybeTls> {
+    use tokio_stream::StreamExt;
+    let acceptor = PermissiveTlsAcceptor(BoringTlsAcceptor {
+        acceptor,
+        metrics,
+        drain: Default::default(),
+        authorizer: None,
+        limiter: None,
+        socket: Default::default(),
+        shed: None,
+        sni_routing: false,
+    });
+
+    tls_listener::builder(acceptor)
+        .listen(listener)
+        .filter_map(|conn| match conn {
+            Err(tls_listener::Error::TlsAcceptError(err)) => {
+                warn!(reason = %err.classification(), "TLS handshake error: {}", err);
+                None
+            }
+            Err(err) => {
+                warn!("TLS handshake error: {}", err);
+                None
+            }
+            Ok(s) => Some(s),
+        })
+}
+
+/// passthrough_tls_server is like tls_server, but yields the connections the certificate provider
+/// passes through as MaybeTls::Passthrough, so they can be proxied without TLS.
+///
+/// Once drain is signaled, new connections are refused and the stream ends when the grace period
+/// of handshakes in flight is over. Socket options are applied to every accepted connection, and
+/// the connections of each client identity are capped by limits, if set. New connections are
+/// refused while shed reports resource pressure.
+pub fn passthrough_tls_server<T: CertProvider + Clone + 'static>(
+    acceptor: T,
+    listener: TcpListener,
+    metrics: Option<Arc<dyn TlsMetrics>>,
+    drain: DrainSignal,
+    socket: SocketConfig,
+    limits: Option<IdentityLimits>,
+    shed: Option<LoadShedPolicy>,
+) -> impl Stream<Item = MaybeTls> {
+    use tokio_stream::StreamExt;
+    let acceptor = PassthroughTlsAcceptor(BoringTlsAcceptor {
+        acceptor,
+        metrics,
+        drain: drain.clone(),
+        authorizer: None,
+        limiter: limits.map(IdentityLimiter::new),
+        socket,
+        shed,
+        sni_routing: false,
+    });
+
+    let accepted = tls_listener::builder(acceptor).listen(listener);
+    futures_util::StreamExt::take_until(accepted, Box::pin(async move { drain.expired().await }))
+        .filter_map(|conn| match conn {
+            Err(tls_listener::Error::TlsAcceptError(TlsError::Draining)) => {
+                debug!("refused connection while draining");
+                None
+            }
+            Err(tls_listener::Error::TlsAcceptError(TlsError::Shed(reason))) => {
+                debug!(%reason, "refused connection under resource pressure");
+                None
+            }
+            Err(tls_listener::Error::TlsAcceptError(err)) => {
+                warn!(reason = %err.classification(), "TLS handshake error: {}", err);
+                None
+            }
+            Err(err) => {
+                warn!("TLS handshake error: {}", err);
+                None
+            }
+            Ok(s) => Some(s),
+        })
+}
+
 #[derive(Clone)]
 /// An Executor that uses the tokio runtime.
 pub struct TokioExecutor;
@@ -154,6 +259,72 @@ where
         .build_http()
 }
 
+/// UdsConnector dials a fixed unix domain socket path. The request URI is ignored, other than to
+/// pick the authority sent to the server.
+#[derive(Clone, Debug)]
+pub struct UdsConnector {
+    path: PathBuf,
+}
+
+impl UdsConnector {
+    pub fn new(path: PathBuf) -> Self {
+        UdsConnector { path }
+    }
+}
+
+impl tower::Service<hyper::Uri> for UdsConnector {
+    type Response = UdsStream;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: hyper::Uri) -> Self::Future {
+        let path = self.path.clone();
+        Box::pin(async move { Ok(UdsStream(UnixStream::connect(path).await?)) })
+    }
+}
+
+/// UdsStream wraps a UnixStream so it can be used as a hyper client connection.
+#[derive(Debug)]
+pub struct UdsStream(UnixStream);
+
+impl Connection for UdsStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for UdsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UdsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
 pub fn empty_response(code: hyper::StatusCode) -> Response<Full<Bytes>> {
     Response::builder()
         .status(code)
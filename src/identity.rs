@@ -13,7 +13,9 @@
 // limitations under the License.
 
 use crate::tls;
+use std::path::PathBuf;
 use std::str::Utf8Error;
+use std::time::Duration;
 
 mod caclient;
 pub use caclient::*;
@@ -24,6 +26,15 @@ pub use manager::*;
 mod auth;
 pub use auth::*;
 
+mod file;
+pub use file::*;
+
+mod events;
+pub use events::*;
+
+mod source;
+pub use source::*;
+
 pub mod mock {
     pub use super::caclient::mock::CaClient;
     pub use super::manager::mock::{
@@ -47,4 +58,83 @@ pub enum Error {
     Spiffe(String),
     #[error("the identity is no longer needed")]
     Forgotten,
+    #[error("no certificate for {0} yet, it is being fetched")]
+    CertsPending(Identity),
+    #[error("invalid trust bundle: {0}")]
+    TrustBundle(tls::Error),
+    #[error("invalid root certificates: {0}")]
+    RootCerts(tls::Error),
+    #[error("certificate for {0} has expired and could not be refreshed")]
+    CertificateExpired(Identity),
+    #[error("invalid certificate: {0}")]
+    InvalidCertificate(tls::Error),
+    #[error("failed to read certificate file {0:?}: {1}")]
+    ReadCertFile(PathBuf, String),
+    #[error("failed to load key passphrase: {0}")]
+    KeyPassphrase(String),
+    #[error("failed to load CA token: {0}")]
+    AuthToken(String),
+    #[error("invalid CA request metadata: {0}")]
+    InvalidCaMetadata(String),
+    #[error("certificate for {0} is valid for {1:?}, more than the maximum of {2:?}")]
+    CertLifetimeExceeded(Identity, Duration, Duration),
+    #[error("failed to read trust domain aliases {0:?}: {1}")]
+    TrustDomainAliases(PathBuf, String),
+}
+
+impl Error {
+    /// code returns a stable identifier of the kind of error, usable as a metric label. Errors
+    /// wrapping a tls::Error use its code.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Signing(e)
+            | Error::TrustBundle(e)
+            | Error::RootCerts(e)
+            | Error::InvalidCertificate(e) => e.code(),
+            Error::SigningRequest(_) => "SIGNING_REQUEST",
+            Error::Utf8(_) | Error::EmptyResponse(_) => "INVALID_RESPONSE",
+            Error::SanError(_) => "SAN_MISMATCH",
+            Error::Spiffe(_) => "INVALID_IDENTITY",
+            Error::Forgotten => "FORGOTTEN",
+            Error::CertsPending(_) => "CERTS_PENDING",
+            Error::CertificateExpired(_) => "CERT_EXPIRED",
+            Error::ReadCertFile(..) => "READ_CERT_FILE",
+            Error::KeyPassphrase(_) => "KEY_PASSPHRASE",
+            Error::AuthToken(_) => "AUTH_TOKEN",
+            Error::InvalidCaMetadata(_) | Error::TrustDomainAliases(..) => "INVALID_CONFIG",
+            Error::CertLifetimeExceeded(..) => "CERT_LIFETIME_EXCEEDED",
+        }
+    }
+
+    /// is_retryable tells whether the same request may succeed later: the CA was unreachable or
+    /// overloaded, or files and tokens may be fixed in place.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::SigningRequest(status) => matches!(
+                status.code(),
+                tonic::Code::Unavailable
+                    | tonic::Code::DeadlineExceeded
+                    | tonic::Code::ResourceExhausted
+                    | tonic::Code::Aborted
+                    | tonic::Code::Unknown
+            ),
+            Error::ReadCertFile(..)
+            | Error::AuthToken(_)
+            | Error::CertLifetimeExceeded(..)
+            | Error::CertsPending(_) => true,
+            Error::Signing(_)
+            | Error::Utf8(_)
+            | Error::SanError(_)
+            | Error::EmptyResponse(_)
+            | Error::Spiffe(_)
+            | Error::Forgotten
+            | Error::TrustBundle(_)
+            | Error::RootCerts(_)
+            | Error::CertificateExpired(_)
+            | Error::InvalidCertificate(_)
+            | Error::KeyPassphrase(_)
+            | Error::InvalidCaMetadata(_)
+            | Error::TrustDomainAliases(..) => false,
+        }
+    }
 }
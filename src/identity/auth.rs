@@ -13,11 +13,15 @@
 // limitations under the License.
 
 use std::io;
-use std::path::PathBuf;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use tonic::metadata::AsciiMetadataValue;
 use tonic::service::Interceptor;
 use tonic::{Code, Request, Status};
+use zeroize::Zeroizing;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum AuthSource {
@@ -42,22 +46,206 @@ impl AuthSource {
     }
 }
 
-impl Interceptor for AuthSource {
+/// TokenProvider attaches the token of an AuthSource to CA and XDS requests. Projected service
+/// account tokens are rotated by Kubernetes, so the token is cached and re-read whenever the file
+/// changes, or once it is within refresh_window of its expiry.
+#[derive(Clone, Debug)]
+pub struct TokenProvider {
+    source: AuthSource,
+    refresh_window: Duration,
+    cache: Arc<Mutex<Option<CachedToken>>>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    token: Zeroizing<Vec<u8>>,
+    version: Option<FileVersion>,
+    expiry: Option<SystemTime>,
+}
+
+// Identifies the contents of a file without reading it. The inode changes when the file is
+// atomically replaced, as Kubernetes does for projected volumes.
+#[derive(Debug, PartialEq, Eq)]
+struct FileVersion {
+    modified: SystemTime,
+    len: u64,
+    ino: u64,
+}
+
+impl FileVersion {
+    fn of(path: &Path) -> Option<FileVersion> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(FileVersion {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+            ino: metadata.ino(),
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TokenError {
+    #[error("failed to read token {0:?}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("token is not a valid header value")]
+    InvalidHeader,
+}
+
+impl TokenProvider {
+    pub fn new(source: AuthSource, refresh_window: Duration) -> TokenProvider {
+        TokenProvider {
+            source,
+            refresh_window,
+            cache: Default::default(),
+        }
+    }
+
+    /// token returns the current token, re-reading it if the file changed or the token is about
+    /// to expire.
+    pub fn token(&self) -> Result<Zeroizing<Vec<u8>>, TokenError> {
+        let AuthSource::Token(path) = &self.source;
+        let version = FileVersion::of(path);
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(cached) = cache.as_ref() {
+            let expiring = cached
+                .expiry
+                .map(|exp| SystemTime::now() + self.refresh_window >= exp)
+                .unwrap_or(false);
+            if version.is_some() && cached.version == version && !expiring {
+                return Ok(cached.token.clone());
+            }
+        }
+        let token = Zeroizing::new(
+            self.source
+                .load()
+                .map_err(|e| TokenError::Read(path.to_owned(), e))?,
+        );
+        *cache = Some(CachedToken {
+            token: token.clone(),
+            version,
+            expiry: jwt_expiry(&token),
+        });
+        Ok(token)
+    }
+
+    fn bearer(&self) -> Result<AsciiMetadataValue, TokenError> {
+        let token = self.token()?;
+        let mut bearer = Zeroizing::new(b"Bearer ".to_vec());
+        bearer.extend_from_slice(&token);
+        AsciiMetadataValue::try_from(bearer.as_slice()).map_err(|_| TokenError::InvalidHeader)
+    }
+}
+
+// Returns the exp claim of a JWT. The token is not verified, the expiry is only used to know when
+// to re-read it.
+fn jwt_expiry(token: &[u8]) -> Option<SystemTime> {
+    #[derive(serde::Deserialize)]
+    struct Claims {
+        exp: u64,
+    }
+
+    let payload = std::str::from_utf8(token).ok()?.trim().split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(claims.exp))
+}
+
+impl Interceptor for TokenProvider {
     fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
-        let token = self
-            .load()
-            .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))
-            .map(|mut t| {
-                let mut bearer: Vec<u8> = b"Bearer ".to_vec();
-                bearer.append(&mut t);
-                bearer
-            })
-            .and_then(|b| {
-                AsciiMetadataValue::try_from(b)
-                    .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))
-            })?;
-
-        request.metadata_mut().insert("authorization", token);
+        let bearer = self
+            .bearer()
+            .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))?;
+        request.metadata_mut().insert("authorization", bearer);
         Ok(request)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use tonic::service::Interceptor;
+    use tonic::Request;
+
+    use super::{jwt_expiry, AuthSource, TokenProvider};
+
+    fn jwt(exp: u64) -> String {
+        let payload = base64::encode_config(
+            format!(r#"{{"sub":"ztunnel","exp":{exp}}}"#),
+            base64::URL_SAFE_NO_PAD,
+        );
+        format!("eyJhbGciOiJSUzI1NiJ9.{payload}.c2lnbmF0dXJl")
+    }
+
+    fn authorization(provider: &mut TokenProvider) -> String {
+        let req = provider.call(Request::new(())).unwrap();
+        req.metadata()
+            .get("authorization")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn expiry() {
+        assert_eq!(
+            jwt_expiry(jwt(1700000000).as_bytes()),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000))
+        );
+        // The test token has no exp claim.
+        let token = std::fs::read("src/test_helpers/fake-jwt").unwrap();
+        assert_eq!(jwt_expiry(&token), None);
+        assert_eq!(jwt_expiry(b"not a jwt"), None);
+    }
+
+    #[test]
+    fn swapped_token_is_attached() {
+        let dir = std::env::temp_dir().join(format!("ztunnel-token-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("istio-token");
+        let far = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        std::fs::write(&path, jwt(far)).unwrap();
+
+        let mut provider =
+            TokenProvider::new(AuthSource::Token(path.clone()), Duration::from_secs(60));
+        assert_eq!(authorization(&mut provider), format!("Bearer {}", jwt(far)));
+
+        // Swap the file the way Kubernetes updates projected volumes.
+        let rotated = dir.join("istio-token.new");
+        std::fs::write(&rotated, jwt(far + 1)).unwrap();
+        std::fs::rename(&rotated, &path).unwrap();
+        assert_eq!(
+            authorization(&mut provider),
+            format!("Bearer {}", jwt(far + 1))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let err = provider.clone().call(Request::new(())).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn expiring_token_is_reread() {
+        let dir = std::env::temp_dir().join(format!("ztunnel-token-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let path: PathBuf = dir.join("istio-token");
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        std::fs::write(&path, jwt(now + 30)).unwrap();
+        let provider = TokenProvider::new(AuthSource::Token(path.clone()), Duration::from_secs(60));
+        assert_eq!(*provider.token().unwrap(), jwt(now + 30).into_bytes());
+
+        // The token is within the refresh window, so it is read again even though the file is
+        // unchanged.
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(provider.token().is_err());
+    }
+}
@@ -12,71 +12,257 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use prost_types::value::Kind;
 use prost_types::Struct;
 use tonic::codegen::InterceptedService;
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue, MetadataMap};
+use tonic::service::Interceptor;
 
 use tracing::{instrument, warn};
 
-use crate::config::RootCert;
-use crate::identity::auth::AuthSource;
+use crate::identity::auth::TokenProvider;
 use crate::identity::manager::Identity;
 use crate::identity::Error;
-use crate::tls::{self, SanChecker, TlsGrpcChannel};
+use crate::time::{Clock, SystemClock};
+use crate::tls::{self, FailoverChannel};
 use crate::xds::istio::ca::istio_certificate_service_client::IstioCertificateServiceClient;
 use crate::xds::istio::ca::IstioCertificateRequest;
 
+/// Default lifetime requested for workload certificates.
+pub const DEFAULT_WORKLOAD_CERT_TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+/// Metadata key carrying the token audience expected by the CA.
+pub const CA_AUDIENCE_METADATA: &str = "audience";
+
+/// CaAuth attaches the token and the configured metadata to every CA request. Interceptors run for
+/// each request, so requests made after the channel reconnects carry them as well.
+#[derive(Clone, Debug)]
+pub struct CaAuth {
+    token: TokenProvider,
+    metadata: MetadataMap,
+}
+
+impl CaAuth {
+    /// new validates the audience and extra metadata, which must be valid ASCII gRPC metadata.
+    pub fn new(
+        token: TokenProvider,
+        audience: Option<&str>,
+        headers: &HashMap<String, String>,
+    ) -> Result<CaAuth, Error> {
+        let mut metadata = MetadataMap::new();
+        let audience = audience.map(|aud| (CA_AUDIENCE_METADATA, aud));
+        for (key, value) in audience
+            .into_iter()
+            .chain(headers.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        {
+            let invalid = || Error::InvalidCaMetadata(key.to_string());
+            let key = AsciiMetadataKey::from_bytes(key.to_lowercase().as_bytes())
+                .map_err(|_| invalid())?;
+            let value = AsciiMetadataValue::try_from(value).map_err(|_| invalid())?;
+            metadata.insert(key, value);
+        }
+        Ok(CaAuth { token, metadata })
+    }
+}
+
+impl Interceptor for CaAuth {
+    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+        let mut request = self.token.call(request)?;
+        for kv in self.metadata.iter() {
+            if let tonic::metadata::KeyAndValueRef::Ascii(key, value) = kv {
+                request.metadata_mut().insert(key.clone(), value.clone());
+            }
+        }
+        Ok(request)
+    }
+}
+
+/// ImpersonatedCsr requests certificates on behalf of workloads. The channel stays authenticated
+/// as ztunnel, while each request carries the service account token of the workload it is for,
+/// read from `<token_dir>/<namespace>/<service account>/token`. Identities whose namespace or
+/// service account could leave token_dir, such as `..` or `a/b` once percent-decoded, are
+/// rejected.
+#[derive(Clone, Debug)]
+pub struct ImpersonatedCsr {
+    token_dir: PathBuf,
+}
+
+impl ImpersonatedCsr {
+    pub fn new(token_dir: PathBuf) -> ImpersonatedCsr {
+        ImpersonatedCsr { token_dir }
+    }
+
+    /// token returns the service account token of the workload with the Identity.
+    pub async fn token(&self, id: &Identity) -> Result<String, Error> {
+        let Identity::Spiffe {
+            namespace,
+            service_account,
+            ..
+        } = id;
+        let mut path = self.token_dir.clone();
+        for segment in [namespace, service_account] {
+            if segment.is_empty() || segment.contains('/') || segment.contains("..") {
+                return Err(Error::AuthToken(format!(
+                    "{id} cannot be mapped to a token path: invalid segment {segment:?}"
+                )));
+            }
+            path.push(segment);
+        }
+        path.push("token");
+        let read_err = |e: io::Error| Error::AuthToken(format!("{}: {e}", path.display()));
+        let token = tokio::fs::read(&path).await.map_err(read_err)?;
+        if token.is_empty() {
+            return Err(read_err(io::Error::new(
+                io::ErrorKind::Other,
+                "token file exists, but was empty",
+            )));
+        }
+        String::from_utf8(token)
+            .map(|t| t.trim().to_string())
+            .map_err(|e| Error::Utf8(e.utf8_error()))
+    }
+}
+
+// CreateCertificate only signs the CSR it is given, so it is safe to retry.
+const CREATE_CERTIFICATE: &str = "/istio.v1.auth.IstioCertificateService/CreateCertificate";
+
 pub struct CaClient {
-    pub client: IstioCertificateServiceClient<InterceptedService<TlsGrpcChannel, AuthSource>>,
+    pub client:
+        IstioCertificateServiceClient<InterceptedService<tls::GrpcRetry<FailoverChannel>, CaAuth>>,
+    // Shares the retry counter and failover state of the client's channel.
+    retry: tls::GrpcRetry<FailoverChannel>,
     pub enable_impersonated_identity: bool,
+    token: TokenProvider,
+    impersonated_csr: Option<ImpersonatedCsr>,
+    key_type: tls::KeyType,
+    cert_ttl: Duration,
+    // Issued certificates are checked to be valid at the time of this clock.
+    clock: Arc<dyn Clock>,
 }
 
 impl CaClient {
+    /// new creates a client for the CA at the first reachable of endpoints, in order.
     pub fn new(
-        address: String,
-        root_cert: RootCert,
-        auth: AuthSource,
+        endpoints: Vec<tls::Endpoint>,
+        auth: CaAuth,
         enable_impersonated_identity: bool,
+        connector: tls::ConnectorConfig,
     ) -> Result<CaClient, Error> {
-        let svc = tls::grpc_connector(address, root_cert)?;
+        let retry = tls::GrpcRetryPolicy {
+            methods: vec![CREATE_CERTIFICATE.to_string()],
+            ..connector.retry.clone()
+        };
+        let svc = tls::grpc_failover_connector(endpoints, connector)?;
         // let client = IstioCertificateServiceClient::new(svc);
         // let svc =
         //     tower_hyper_http_body_compat::Hyper1HttpServiceAsTowerService03HttpService::new(svc);
-        let client = IstioCertificateServiceClient::with_interceptor(svc, auth);
+        let svc = tls::GrpcRetry::new(svc, retry);
+        let client = IstioCertificateServiceClient::with_interceptor(svc.clone(), auth.clone());
         Ok(CaClient {
             client,
+            retry: svc,
             enable_impersonated_identity,
+            token: auth.token,
+            impersonated_csr: None,
+            key_type: Default::default(),
+            cert_ttl: DEFAULT_WORKLOAD_CERT_TTL,
+            clock: Arc::new(SystemClock),
         })
     }
+
+    /// with_impersonated_csr requests each certificate with the token of the workload it is for.
+    pub fn with_impersonated_csr(mut self, impersonated_csr: ImpersonatedCsr) -> CaClient {
+        self.impersonated_csr = Some(impersonated_csr);
+        self
+    }
+
+    /// with_key_type sets the type of the keys generated for certificates. P-256 by default.
+    pub fn with_key_type(mut self, key_type: tls::KeyType) -> CaClient {
+        self.key_type = key_type;
+        self
+    }
+
+    /// with_cert_ttl sets the lifetime requested for certificates, 24 hours by default. The CA
+    /// may issue certificates valid for less.
+    pub fn with_cert_ttl(mut self, ttl: Duration) -> CaClient {
+        self.cert_ttl = ttl;
+        self
+    }
+
+    /// with_clock sets the clock issued certificates are checked to be valid at, the system clock
+    /// by default.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> CaClient {
+        self.clock = clock;
+        self
+    }
+
+    /// retries returns the number of CA requests sent again after failing transiently.
+    pub fn retries(&self) -> u64 {
+        self.retry.retries()
+    }
+
+    /// health returns the connectivity of the CA channel.
+    pub fn health(&self) -> tls::ChannelHealth {
+        self.retry.get_ref().health()
+    }
+
+    /// failovers returns the number of times the client switched to another CA endpoint.
+    pub fn failovers(&self) -> u64 {
+        self.retry.get_ref().failovers()
+    }
+
+    fn impersonates(&self) -> bool {
+        self.enable_impersonated_identity || self.impersonated_csr.is_some()
+    }
 }
 
 impl CaClient {
     #[instrument(skip_all)]
     async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        // Load the token up front, so that a missing token is reported as such rather than as a
+        // generic gRPC failure. It is cached for the interceptor.
+        self.token
+            .token()
+            .map_err(|e| Error::AuthToken(e.to_string()))?;
         let cs = tls::CsrOptions {
             san: id.to_string(),
+            key_type: self.key_type,
+            ..Default::default()
         }
-        .generate()?;
+        .generate_async()
+        .await?;
         let csr: Vec<u8> = cs.csr;
+        // pkey is zeroized when it goes out of scope, after it has been parsed into Certs.
         let pkey = cs.pkey;
 
         let csr = std::str::from_utf8(&csr).map_err(Error::Utf8)?.to_string();
         let req = IstioCertificateRequest {
             csr,
-            validity_duration: 60 * 60 * 24, // 24 hours
+            validity_duration: self.cert_ttl.as_secs() as i64,
             metadata: {
-                if self.enable_impersonated_identity {
-                    Some(Struct {
-                        fields: BTreeMap::from([(
-                            "ImpersonatedIdentity".into(),
+                if self.impersonates() {
+                    let mut fields = BTreeMap::from([(
+                        "ImpersonatedIdentity".into(),
+                        prost_types::Value {
+                            kind: Some(Kind::StringValue(id.to_string())),
+                        },
+                    )]);
+                    if let Some(impersonated_csr) = &self.impersonated_csr {
+                        fields.insert(
+                            "ImpersonatedIdentityToken".into(),
                             prost_types::Value {
-                                kind: Some(Kind::StringValue(id.to_string())),
+                                kind: Some(Kind::StringValue(impersonated_csr.token(id).await?)),
                             },
-                        )]),
-                    })
+                        );
+                    }
+                    Some(Struct { fields })
                 } else {
                     None
                 }
@@ -99,12 +285,17 @@ impl CaClient {
             warn!("no chain certs for: {}", id);
             vec![]
         };
-        let certs = tls::cert_from(&pkey, leaf, chain);
-        if self.enable_impersonated_identity {
-            certs
-                .verify_san(id)
-                .map_err(|_| Error::SanError(id.to_owned()))?;
-        }
+        let certs = tls::cert_from(&pkey, leaf, chain).map_err(Error::InvalidCertificate)?;
+        // The CA signs for whatever identity the request is authenticated as, so a certificate
+        // for another workload must not be accepted into the cache. A broken chain or validity
+        // would otherwise only be noticed by peers during handshakes.
+        let roots: Vec<_> = certs.iter_chain().last().cloned().into_iter().collect();
+        certs
+            .validate(id, &roots, self.clock.as_ref())
+            .map_err(|e| match e {
+                tls::Error::SanMismatch(..) => Error::SanError(id.to_owned()),
+                e => Error::InvalidCertificate(e),
+            })?;
         Ok(certs)
     }
 }
@@ -114,6 +305,14 @@ impl crate::identity::CaClientTrait for CaClient {
     async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
         self.fetch_certificate(id).await
     }
+
+    fn channel_health(&self) -> Option<tls::ChannelHealth> {
+        Some(self.health())
+    }
+
+    fn requested_lifetime(&self) -> Option<Duration> {
+        Some(self.cert_ttl)
+    }
 }
 
 pub mod mock {
@@ -133,11 +332,16 @@ pub mod mock {
     struct ClientState {
         fetches: Vec<Identity>,
         gen: CertGenerator,
+        // Number of upcoming fetch_certificate calls that fail, as if the CA was unreachable.
+        failures: u32,
     }
 
     #[derive(Clone)]
     pub struct ClientConfig {
+        // Lifetime of issued certificates. If a lifetime is requested, longer requests are
+        // clamped to it.
         pub cert_lifetime: Duration,
+        pub requested_lifetime: Option<Duration>,
         pub time_conv: crate::time::Converter,
         // If non-zero, causes fetch_certificate calls to sleep for the specified duration before
         // returning. This is helpful to let tests that pause tokio time get more control over code
@@ -150,6 +354,7 @@ pub mod mock {
             Self {
                 fetch_latency: Duration::ZERO,
                 cert_lifetime: Duration::from_secs(10),
+                requested_lifetime: None,
                 time_conv: crate::time::Converter::new(),
             }
         }
@@ -184,6 +389,12 @@ pub mod mock {
             self.state.write().await.fetches.clear();
         }
 
+        // Makes the next n fetch_certificate calls fail with an Unavailable status. Failed calls
+        // are not recorded as fetches.
+        pub async fn set_failures(&self, n: u32) {
+            self.state.write().await.failures = n;
+        }
+
         async fn fetch_certificate(&self, id: &Identity) -> Result<Certs, Error> {
             let Identity::Spiffe {
                 trust_domain: td,
@@ -207,9 +418,19 @@ pub mod mock {
                 .time_conv
                 .instant_to_system_time(Instant::now().into())
                 .expect("SystemTime cannot represent current time. Was the process started in extreme future?");
-            let not_after = not_before + self.cfg.cert_lifetime;
+            let lifetime = match self.cfg.requested_lifetime {
+                Some(requested) => requested.min(self.cfg.cert_lifetime),
+                None => self.cfg.cert_lifetime,
+            };
+            let not_after = not_before + lifetime;
 
             let mut state = self.state.write().await;
+            if state.failures > 0 {
+                state.failures -= 1;
+                return Err(Error::SigningRequest(tonic::Status::unavailable(
+                    "injected failure",
+                )));
+            }
             let certs = state
                 .gen
                 .new_certs(&id.to_owned().into(), not_before, not_after);
@@ -223,6 +444,10 @@ pub mod mock {
         async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
             self.fetch_certificate(id).await
         }
+
+        fn requested_lifetime(&self) -> Option<Duration> {
+            self.cfg.requested_lifetime
+        }
     }
 }
 
@@ -232,16 +457,23 @@ mod tests {
 
     use matches::assert_matches;
 
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+
     use crate::{
-        identity::{Error, Identity},
-        test_helpers, tls,
+        identity::{
+            AuthSource, CaAuth, CertState, Error, Identity, ImpersonatedCsr, SecretManager,
+            TokenProvider,
+        },
+        test_helpers::ca::{CaServer, CaServerOptions, Signer},
+        tls,
         xds::istio::ca::IstioCertificateResponse,
     };
 
     async fn test_ca_client_with_response(
         res: IstioCertificateResponse,
     ) -> Result<tls::Certs, Error> {
-        let (mock, ca_client) = test_helpers::ca::CaServer::spawn().await;
+        let (mock, ca_client) = CaServer::spawn().await;
         mock.send(Ok(res)).unwrap();
         ca_client.fetch_certificate(&Identity::default()).await
     }
@@ -253,6 +485,16 @@ mod tests {
         assert_matches!(res, Err(Error::EmptyResponse(_)));
     }
 
+    // Fetches the default identity from a CA signing with signer.
+    async fn test_ca_client_with_signer(signer: Signer) -> Result<tls::Certs, Error> {
+        let (_, ca_client) = CaServer::spawn_with(CaServerOptions {
+            signer: Some(signer),
+            ..Default::default()
+        })
+        .await;
+        ca_client.fetch_certificate(&Identity::default()).await
+    }
+
     #[tokio::test]
     async fn wrong_identity() {
         let id = Identity::Spiffe {
@@ -260,11 +502,9 @@ mod tests {
             namespace: "foo".to_string(),
             trust_domain: "cluster.local".to_string(),
         };
-        let certs =
-            tls::generate_test_certs(&id.into(), Duration::from_secs(0), Duration::from_secs(0));
-
-        let res = test_ca_client_with_response(IstioCertificateResponse {
-            cert_chain: vec![String::from_utf8(certs.x509().to_pem().unwrap()).unwrap()],
+        let res = test_ca_client_with_signer(Signer {
+            identity: id,
+            ..Default::default()
         })
         .await;
         assert_matches!(res, Err(Error::SanError(_)));
@@ -272,15 +512,217 @@ mod tests {
 
     #[tokio::test]
     async fn fetch_certificate() {
+        let res = test_ca_client_with_signer(Default::default()).await;
+        assert_matches!(res, Ok(_));
+    }
+
+    #[tokio::test]
+    async fn key_mismatch() {
+        // A certificate for another key than the one of our CSR.
         let certs = tls::generate_test_certs(
             &Identity::default().into(),
             Duration::from_secs(0),
-            Duration::from_secs(0),
+            Duration::from_secs(100),
         );
         let res = test_ca_client_with_response(IstioCertificateResponse {
             cert_chain: vec![String::from_utf8(certs.x509().to_pem().unwrap()).unwrap()],
         })
         .await;
+        assert_matches!(
+            res,
+            Err(Error::InvalidCertificate(tls::Error::KeyCertMismatch))
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_certificate() {
+        let res = test_ca_client_with_signer(Signer {
+            lifetime: Duration::ZERO,
+            ..Default::default()
+        })
+        .await;
+        assert_matches!(
+            res,
+            Err(Error::InvalidCertificate(tls::Error::CertificateValidity(
+                _
+            )))
+        );
+    }
+
+    #[tokio::test]
+    async fn audience() {
+        let (_, ca_client) = CaServer::spawn_with(CaServerOptions {
+            expected_audience: Some("istio-ca".to_string()),
+            signer: Some(Default::default()),
+            ..Default::default()
+        })
+        .await;
+        let err = ca_client.fetch_certificate(&Identity::default()).await;
+        assert_matches!(err, Err(Error::SigningRequest(s)) if s.code() == tonic::Code::Unauthenticated);
+
+        let (_, ca_client) = CaServer::spawn_with(CaServerOptions {
+            expected_audience: Some("istio-ca".to_string()),
+            audience: Some("istio-ca".to_string()),
+            signer: Some(Default::default()),
+        })
+        .await;
+        let res = ca_client.fetch_certificate(&Identity::default()).await;
         assert_matches!(res, Ok(_));
     }
+
+    // Writes the token of the default identity into a new token directory.
+    fn token_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ztunnel-tokens-{}", rand::random::<u64>()));
+        let Identity::Spiffe {
+            namespace,
+            service_account,
+            ..
+        } = Identity::default();
+        std::fs::create_dir_all(dir.join(namespace).join(service_account)).unwrap();
+        std::fs::write(
+            dir.join(namespace).join(service_account).join("token"),
+            "workload-token\n",
+        )
+        .unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn impersonated_token() {
+        let dir = token_dir();
+        let csr = ImpersonatedCsr::new(dir.clone());
+        assert_eq!(
+            csr.token(&Identity::default()).await.unwrap(),
+            "workload-token"
+        );
+        let identity = |namespace: &str, service_account: &str| Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: namespace.to_string(),
+            service_account: service_account.to_string(),
+        };
+        assert_matches!(
+            csr.token(&identity("other", "default")).await,
+            Err(Error::AuthToken(_))
+        );
+        // Segments that could leave the token directory are rejected before any read.
+        for (namespace, service_account) in [
+            ("..", "default"),
+            ("default", ".."),
+            ("a/b", "default"),
+            ("/etc", "default"),
+            ("", "default"),
+        ] {
+            let res = csr.token(&identity(namespace, service_account)).await;
+            assert_matches!(res, Err(Error::AuthToken(msg)) if msg.contains("invalid segment"));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_failed_requests() {
+        // Fail more requests than the CA client retries, so the failure reaches the
+        // SecretManager.
+        let (_, ca_client) = CaServer::spawn_with(CaServerOptions {
+            signer: Some(Signer::default()),
+            failures: tls::GrpcRetryPolicy::default().max_attempts,
+            latency: Duration::from_millis(10),
+            ..Default::default()
+        })
+        .await;
+        let secret_manager = SecretManager::new_with_client(ca_client);
+        let id = Identity::default();
+        assert_matches!(
+            secret_manager.fetch_certificate(&id).await,
+            Err(Error::SigningRequest(s)) if s.code() == tonic::Code::Unavailable
+        );
+        // The failed request is retried with a backoff, and the issued certificate validated.
+        let mut state = secret_manager.subscribe(&id).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !matches!(*state.borrow_and_update(), CertState::Available(_)) {
+                state.changed().await.unwrap();
+            }
+        })
+        .await
+        .expect("certificate was not issued after the CA recovered");
+        let certs = secret_manager.fetch_certificate(&id).await.unwrap();
+        tls::SanChecker::verify_san(&certs, &id).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_unavailable_rpcs() {
+        let (_, ca_client) = CaServer::spawn_with(CaServerOptions {
+            signer: Some(Signer::default()),
+            failures: 2,
+            ..Default::default()
+        })
+        .await;
+        let id = Identity::default();
+        let certs = ca_client.fetch_certificate(&id).await.unwrap();
+        tls::SanChecker::verify_san(&certs, &id).unwrap();
+        assert_eq!(ca_client.retries(), 2);
+    }
+
+    #[tokio::test]
+    async fn plaintext_malformed_chain() {
+        let path = std::env::temp_dir().join(format!("ztunnel-ca-{}.sock", rand::random::<u64>()));
+        let (_, ca_client) = CaServer::spawn_with(CaServerOptions {
+            signer: Some(Signer::default()),
+            malformed_chain: true,
+            uds: Some(path.clone()),
+            ..Default::default()
+        })
+        .await;
+        let res = ca_client.fetch_certificate(&Identity::default()).await;
+        assert_matches!(res, Err(Error::InvalidCertificate(_)));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn impersonated_san_mismatch() {
+        let dir = token_dir();
+        let other = Identity::Spiffe {
+            service_account: "other-sa".to_string(),
+            namespace: "foo".to_string(),
+            trust_domain: "cluster.local".to_string(),
+        };
+        let (_, ca_client) = CaServer::spawn_with(CaServerOptions {
+            signer: Some(Signer {
+                identity: other,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await;
+        let mut ca_client = ca_client.with_impersonated_csr(ImpersonatedCsr::new(dir.clone()));
+        ca_client.enable_impersonated_identity = false;
+
+        let secret_manager = SecretManager::new_with_client(ca_client);
+        let mut registry = Registry::default();
+        secret_manager.register_metrics(&mut registry);
+        let res = secret_manager.fetch_certificate(&Identity::default()).await;
+        assert_matches!(res, Err(Error::SanError(_)));
+
+        let mut metrics = String::new();
+        encode(&mut metrics, &registry).unwrap();
+        assert!(metrics.contains(r#"reason="SanMismatch"} 1"#), "{metrics}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_metadata() {
+        let token = TokenProvider::new(
+            AuthSource::Token("src/test_helpers/fake-jwt".into()),
+            Duration::ZERO,
+        );
+        let headers = [("ClusterID".to_string(), "Kubernetes".to_string())].into();
+        assert_matches!(
+            CaAuth::new(token.clone(), Some("istio-ca"), &headers),
+            Ok(_)
+        );
+        let headers = [("bad key".to_string(), "value".to_string())].into();
+        assert_matches!(
+            CaAuth::new(token, None, &headers),
+            Err(Error::InvalidCaMetadata(_))
+        );
+    }
 }
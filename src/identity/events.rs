@@ -0,0 +1,227 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use boring::x509::X509Ref;
+use serde::Serializer;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::tls;
+
+use super::Identity;
+
+// Default for how many events are buffered for subscribers, and kept for the admin endpoint.
+pub const DEFAULT_CERT_EVENTS_CAPACITY: usize = 128;
+
+/// CertEvent is a step in the lifecycle of a workload certificate. Events never contain key
+/// material.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum CertEvent {
+    /// The first certificate of an identity was issued.
+    CertIssued {
+        #[serde(serialize_with = "serialize_display")]
+        identity: Identity,
+        serial: String,
+        #[serde(serialize_with = "serialize_time")]
+        not_after: SystemTime,
+    },
+    /// The certificate of an identity was replaced by a new one.
+    CertRotated {
+        #[serde(serialize_with = "serialize_display")]
+        identity: Identity,
+        serial: String,
+        #[serde(serialize_with = "serialize_time")]
+        not_after: SystemTime,
+    },
+    /// The certificate of an identity expired before it could be refreshed.
+    CertExpired {
+        #[serde(serialize_with = "serialize_display")]
+        identity: Identity,
+    },
+    /// The certificate of an identity no longer chains to a trusted root, such as after the root
+    /// store was updated, and is being refreshed.
+    CertInvalidated {
+        #[serde(serialize_with = "serialize_display")]
+        identity: Identity,
+    },
+    /// Fetching a certificate for an identity failed, with the code of the error.
+    FetchFailed {
+        #[serde(serialize_with = "serialize_display")]
+        identity: Identity,
+        error_code: &'static str,
+    },
+    /// A new root returned by the CA was added to the root store.
+    RootUpdated { serial: String },
+}
+
+impl CertEvent {
+    pub(super) fn issued(identity: &Identity, certs: &tls::Certs, rotated: bool) -> CertEvent {
+        let identity = identity.to_owned();
+        let serial = serial(certs.x509());
+        let not_after = certs.not_after();
+        if rotated {
+            CertEvent::CertRotated {
+                identity,
+                serial,
+                not_after,
+            }
+        } else {
+            CertEvent::CertIssued {
+                identity,
+                serial,
+                not_after,
+            }
+        }
+    }
+
+    pub(super) fn root_updated(root: &X509Ref) -> CertEvent {
+        CertEvent::RootUpdated {
+            serial: serial(root),
+        }
+    }
+}
+
+fn serial(cert: &X509Ref) -> String {
+    cert.serial_number()
+        .to_bn()
+        .map(|bn| bn.to_string())
+        .unwrap_or_default()
+}
+
+fn serialize_display<S: Serializer, T: std::fmt::Display>(v: &T, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_str(v)
+}
+
+fn serialize_time<S: Serializer>(t: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+    let dt: chrono::DateTime<chrono::Utc> = (*t).into();
+    s.serialize_str(&dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+/// CertEvents publishes CertEvents to subscribers, and keeps the most recent ones. Publishing
+/// never blocks: subscribers that fall more than the capacity behind miss the oldest events.
+/// Clones share the channel.
+#[derive(Clone)]
+pub struct CertEvents {
+    tx: broadcast::Sender<CertEvent>,
+    recent: Arc<Mutex<VecDeque<CertEvent>>>,
+    capacity: usize,
+}
+
+impl Default for CertEvents {
+    fn default() -> Self {
+        Self::new(DEFAULT_CERT_EVENTS_CAPACITY)
+    }
+}
+
+impl CertEvents {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        CertEvents {
+            tx: broadcast::channel(capacity).0,
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// subscribe returns a receiver of the events published from now on.
+    pub fn subscribe(&self) -> CertEventReceiver {
+        CertEventReceiver {
+            rx: self.tx.subscribe(),
+            missed: 0,
+        }
+    }
+
+    /// recent returns the last events published, oldest first.
+    pub fn recent(&self) -> Vec<CertEvent> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub(super) fn publish(&self, event: CertEvent) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == self.capacity {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // Sending only fails when there are no subscribers.
+        let _ = self.tx.send(event);
+    }
+}
+
+/// CertEventReceiver receives the events of a CertEvents subscription.
+pub struct CertEventReceiver {
+    rx: broadcast::Receiver<CertEvent>,
+    missed: u64,
+}
+
+impl CertEventReceiver {
+    /// recv returns the next event, or None once the CertEvents is dropped. Events missed because
+    /// the receiver fell behind are skipped, and counted in missed.
+    pub async fn recv(&mut self) -> Option<CertEvent> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("certificate event subscriber fell behind, skipping {n} events");
+                    self.missed += n;
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// missed returns how many events were skipped because the receiver fell behind.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CertEvent, CertEvents};
+    use crate::identity::Identity;
+
+    fn failed(n: usize) -> CertEvent {
+        CertEvent::FetchFailed {
+            identity: Identity::default(),
+            error_code: if n % 2 == 0 { "EVEN" } else { "ODD" },
+        }
+    }
+
+    #[tokio::test]
+    async fn lagging_subscriber() {
+        let events = CertEvents::new(2);
+        let mut rx = events.subscribe();
+        for n in 0..5 {
+            events.publish(failed(n));
+        }
+        // Only the last events are kept, and the receiver resumes from the oldest of them.
+        assert_eq!(events.recent(), vec![failed(3), failed(4)]);
+        assert_eq!(rx.recv().await, Some(failed(3)));
+        assert_eq!(rx.missed(), 3);
+        assert_eq!(rx.recv().await, Some(failed(4)));
+
+        let json = serde_json::to_string(&failed(0)).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"FetchFailed","identity":"spiffe://cluster.local/ns/default/sa/default","error_code":"EVEN"}"#
+        );
+        drop(events);
+        assert_eq!(rx.recv().await, None);
+    }
+}
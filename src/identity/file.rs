@@ -0,0 +1,351 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use crate::config::{CertFiles, KeyPassphraseSource};
+use crate::identity::{CaClientTrait, Error, Identity};
+use crate::tls::{self, SanChecker};
+
+// Kubernetes mounts each file of a secret as a symlink into the ..data directory, itself a
+// symlink to the current version of the secret that is swapped atomically on updates.
+const K8S_DATA_DIR: &str = "..data";
+
+// How often, and how long apart, loading inconsistent files is retried before failing a fetch.
+const INCONSISTENT_RETRIES: u32 = 5;
+const INCONSISTENT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// FileCertProvider serves a workload certificate provisioned into files by an external agent,
+/// such as cert-manager or SPIRE, in place of a CaClient. The files are re-read on every fetch, so
+/// the SecretManager picks up rotated files on its regular refreshes, or right away when
+/// watching the files with SecretManager::watch_cert_files.
+///
+/// Files mounted from a Kubernetes secret are read from the same version of the secret, so an
+/// update swapping the files cannot be seen half done. A key that does not match the certificate,
+/// such as while an agent rewrites the files one by one, is retried for a short while.
+pub struct FileCertProvider {
+    files: CertFiles,
+    passphrase: Option<KeyPassphraseSource>,
+}
+
+impl FileCertProvider {
+    /// new checks that the files hold a certificate and a matching private key, so that a
+    /// misconfiguration is reported at startup rather than on the first connection.
+    pub fn new(
+        files: CertFiles,
+        passphrase: Option<KeyPassphraseSource>,
+    ) -> Result<FileCertProvider, Error> {
+        let provider = FileCertProvider { files, passphrase };
+        provider.load()?;
+        Ok(provider)
+    }
+
+    pub fn load(&self) -> Result<tls::Certs, Error> {
+        let snapshot = CertSnapshot::read(&self.files)?;
+        let chain = snapshot.chain.iter().map(|pem| pem.as_slice()).collect();
+        let certs = match &self.passphrase {
+            Some(passphrase) => {
+                let passphrase = passphrase
+                    .load()
+                    .map_err(|e| Error::KeyPassphrase(e.to_string()))?;
+                tls::cert_from_encrypted(&snapshot.key, &passphrase, &snapshot.cert, chain)
+            }
+            None => tls::cert_from(&snapshot.key, &snapshot.cert, chain),
+        };
+        certs.map_err(Error::InvalidCertificate)
+    }
+
+    // load_consistent loads the files, retrying while the key does not match the certificate.
+    async fn load_consistent(&self) -> Result<tls::Certs, Error> {
+        let mut attempt = 0;
+        loop {
+            match self.load() {
+                Err(Error::InvalidCertificate(tls::Error::KeyCertMismatch))
+                    if attempt < INCONSISTENT_RETRIES =>
+                {
+                    attempt += 1;
+                    warn!(
+                        attempt,
+                        "certificate files are inconsistent, the key does not match the certificate; retrying"
+                    );
+                    tokio::time::sleep(INCONSISTENT_RETRY_DELAY).await;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// CertSnapshot holds the contents of CertFiles, read from the same version of a Kubernetes
+/// secret.
+#[derive(PartialEq, Eq)]
+pub(super) struct CertSnapshot {
+    key: Zeroizing<Vec<u8>>,
+    cert: Zeroizing<Vec<u8>>,
+    chain: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl CertSnapshot {
+    pub(super) fn read(files: &CertFiles) -> Result<CertSnapshot, Error> {
+        // Each ..data symlink is resolved once, so that a swap between reads cannot mix versions.
+        let mut data_dirs = HashMap::new();
+        let mut read_file = |path: &Path| read(&snapshot_path(path, &mut data_dirs));
+        Ok(CertSnapshot {
+            key: read_file(&files.key)?,
+            cert: read_file(&files.cert)?,
+            chain: files.chain.as_deref().map(&mut read_file).transpose()?,
+        })
+    }
+}
+
+// snapshot_path returns where to read path from: from the version ..data points to, if path is
+// in a directory mounted from a Kubernetes secret, and path itself otherwise.
+fn snapshot_path(path: &Path, data_dirs: &mut HashMap<PathBuf, Option<PathBuf>>) -> PathBuf {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.to_path_buf();
+    };
+    let data_dir = data_dirs.entry(dir.to_path_buf()).or_insert_with(|| {
+        std::fs::read_link(dir.join(K8S_DATA_DIR))
+            .ok()
+            .map(|target| dir.join(target))
+    });
+    match data_dir {
+        Some(data_dir) if data_dir.join(name).exists() => data_dir.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn read(path: &Path) -> Result<Zeroizing<Vec<u8>>, Error> {
+    std::fs::read(path)
+        .map(Zeroizing::new)
+        .map_err(|e| Error::ReadCertFile(path.to_path_buf(), e.to_string()))
+}
+
+#[async_trait]
+impl CaClientTrait for FileCertProvider {
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        let certs = self.load_consistent().await?;
+        certs
+            .verify_san(id)
+            .map_err(|_| Error::SanError(id.to_owned()))?;
+        Ok(certs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use boring::bn::BigNum;
+    use boring::x509::X509Ref;
+    use matches::assert_matches;
+
+    use crate::config::CertFiles;
+    use crate::identity::{CaClientTrait, Error, Identity, SecretManager};
+    use crate::tls::{self, generate_test_certs, Certs};
+
+    use super::FileCertProvider;
+
+    fn write_certs(files: &CertFiles, certs: &Certs) {
+        let key = certs.private_key().in_memory().unwrap().clone();
+        std::fs::write(&files.key, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        std::fs::write(&files.cert, certs.x509().to_pem().unwrap()).unwrap();
+        std::fs::write(files.chain.as_ref().unwrap(), certs.chain().unwrap()).unwrap();
+    }
+
+    fn files_in(dir: &Path) -> CertFiles {
+        CertFiles {
+            cert: dir.join("tls.crt"),
+            key: dir.join("tls.key"),
+            chain: Some(dir.join("ca.crt")),
+        }
+    }
+
+    fn test_files() -> (PathBuf, CertFiles) {
+        let dir = std::env::temp_dir().join(format!("ztunnel-certs-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let files = files_in(&dir);
+        (dir, files)
+    }
+
+    // Writes certs as a new version of a Kubernetes secret mounted in dir. The version is only
+    // used once ..data is swapped to it.
+    fn write_secret_version(dir: &Path, version: &str, certs: &Certs) {
+        let version_dir = dir.join(version);
+        std::fs::create_dir(&version_dir).unwrap();
+        write_certs(&files_in(&version_dir), certs);
+    }
+
+    // Swaps ..data to version atomically, as the kubelet does.
+    fn swap_secret_version(dir: &Path, version: &str) {
+        let tmp = dir.join("..data_tmp");
+        symlink(version, &tmp).unwrap();
+        std::fs::rename(&tmp, dir.join("..data")).unwrap();
+    }
+
+    fn serial(cert: &X509Ref) -> BigNum {
+        cert.serial_number().to_bn().unwrap()
+    }
+
+    // Returns the serial number of the certificate presented by a server using certs.
+    async fn presented_serial(certs: &Certs, id: &Identity) -> BigNum {
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = certs.acceptor().unwrap();
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let client = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let mut cfg = client.connector(id).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let stream = tokio_boring::connect(cfg, "", client_io).await.unwrap();
+        server.await.unwrap().unwrap();
+        serial(&stream.ssl().peer_certificate().unwrap())
+    }
+
+    #[tokio::test]
+    async fn rotated_files_are_served() {
+        let id = Identity::default();
+        let (dir, files) = test_files();
+        let initial = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        write_certs(&files, &initial);
+
+        let provider = FileCertProvider::new(files.clone(), None).unwrap();
+        let secret_manager = SecretManager::new_with_client(provider);
+        secret_manager.watch_cert_files(files.clone(), Duration::from_millis(10));
+        let certs = secret_manager.fetch_certificate(&id).await.unwrap();
+        assert_eq!(presented_serial(&certs, &id).await, serial(initial.x509()));
+
+        let rotated = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        write_certs(&files, &rotated);
+        let mut served = None;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let certs = secret_manager.fetch_certificate(&id).await.unwrap();
+            if serial(certs.x509()) == serial(rotated.x509()) {
+                served = Some(certs);
+                break;
+            }
+        }
+        let served = served.expect("rotated certificate was not loaded");
+        assert_eq!(presented_serial(&served, &id).await, serial(rotated.x509()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_files() {
+        let id = Identity::default();
+        let (dir, files) = test_files();
+        assert_matches!(
+            FileCertProvider::new(files.clone(), None),
+            Err(Error::ReadCertFile(_, _))
+        );
+
+        // The key of another certificate.
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        write_certs(&files, &certs);
+        let (_, other_key) = tls::generate_test_ca("other");
+        std::fs::write(&files.key, other_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        assert_matches!(
+            FileCertProvider::new(files, None),
+            Err(Error::InvalidCertificate(tls::Error::KeyCertMismatch))
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn kubernetes_secret_swap() {
+        let id = Identity::default();
+        let (dir, files) = test_files();
+        let issue = || {
+            generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            )
+        };
+        let (v1, v2) = (issue(), issue());
+        write_secret_version(&dir, "..v1", &v1);
+        swap_secret_version(&dir, "..v1");
+        for name in ["tls.crt", "tls.key", "ca.crt"] {
+            symlink(Path::new("..data").join(name), dir.join(name)).unwrap();
+        }
+        let provider = FileCertProvider::new(files, None).unwrap();
+        assert_eq!(serial(provider.load().unwrap().x509()), serial(v1.x509()));
+
+        // Files of a version are only read once ..data points to it.
+        write_secret_version(&dir, "..v2", &v2);
+        assert_eq!(serial(provider.load().unwrap().x509()), serial(v1.x509()));
+        swap_secret_version(&dir, "..v2");
+        std::fs::remove_dir_all(dir.join("..v1")).unwrap();
+        assert_eq!(serial(provider.load().unwrap().x509()), serial(v2.x509()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn inconsistent_files_are_retried() {
+        let id = Identity::default();
+        let (dir, files) = test_files();
+        let issue = || {
+            generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            )
+        };
+        let (initial, rotated) = (issue(), issue());
+        write_certs(&files, &initial);
+        let provider = FileCertProvider::new(files.clone(), None).unwrap();
+
+        // An agent rewriting the files one by one, having only written the new key so far.
+        let key = rotated.private_key().in_memory().unwrap().clone();
+        std::fs::write(&files.key, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        assert_matches!(
+            provider.load(),
+            Err(Error::InvalidCertificate(tls::Error::KeyCertMismatch))
+        );
+
+        let fetch = tokio::spawn({
+            let id = id.clone();
+            async move { provider.fetch_certificate(&id).await }
+        });
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        write_certs(&files, &rotated);
+        let certs = fetch.await.unwrap().unwrap();
+        assert_eq!(serial(certs.x509()), serial(rotated.x509()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
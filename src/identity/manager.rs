@@ -13,26 +13,73 @@
 // limitations under the License.
 
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Write;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::SystemTime;
 
-use crate::config::ProxyMode;
+use crate::config::{CertFiles, ProxyMode};
 use async_trait::async_trait;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
 
 use prometheus_client::encoding::{EncodeLabelValue, LabelValueEncoder};
+use prometheus_client::registry::Registry;
+use rand::Rng;
 use tokio::sync::{mpsc, watch, Mutex};
 use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{debug, info, warn};
 
+use crate::metrics::identity as metrics;
+use crate::time::{Clock, SystemClock};
 use crate::tls;
 
-use super::CaClient;
+use super::file::CertSnapshot;
 use super::Error::{self, Spiffe};
+use super::{
+    CaAuth, CaClient, CertEvent, CertEvents, CertsSource, FileCertProvider, ImpersonatedCsr,
+    TokenProvider,
+};
+
+// Failed refreshes are retried with exponential backoff, bounded by the max delay.
+const CERT_REFRESH_FAILURE_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const CERT_REFRESH_FAILURE_MAX_BACKOFF: Duration = Duration::from_secs(60);
+// Default for how long before expiry serving a certificate that could not be refreshed is logged
+// and counted as dangerous.
+pub const DEFAULT_CERT_EXPIRY_DANGER_WINDOW: Duration = Duration::from_secs(30 * 60);
+// Default for how many certificates can be prefetched at a time, leaving room for fetches that
+// block connections.
+pub const DEFAULT_CERT_PREFETCH_CONCURRENCY: u16 = 4;
+// How often certificate files are checked for changes.
+const CERT_FILES_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// How often the expiry metrics of cached certificates are updated, in addition to every rotation.
+const CERT_EXPIRY_METRICS_INTERVAL: Duration = Duration::from_secs(30);
+// How often certificates are checked to still chain to a trusted root, in addition to every update
+// of the root store.
+const ROOT_REVALIDATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// MaxCertLifetime bounds the validity of workload certificates accepted from the CA, so that a
+/// misconfigured CA handing out long lived certificates does not defeat rotation.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaxCertLifetime {
+    pub max: Duration,
+    pub mode: CertLifetimeMode,
+}
 
-const CERT_REFRESH_FAILURE_RETRY_DELAY: Duration = Duration::from_secs(60);
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CertLifetimeMode {
+    /// Certificates valid for longer are used, with a warning, but refreshed as if they were
+    /// valid for the maximum lifetime.
+    #[default]
+    Soft,
+    /// Certificates valid for longer are rejected, and fetched again later.
+    Strict,
+}
 
+/// Identity is a SPIFFE identity. Its segments are percent-decoded when parsed, so they may hold
+/// any character, including `/`, and may be `..`: they come from peer certificates and requests,
+/// and must be validated before being used to build file paths or similar.
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum Identity {
     Spiffe {
@@ -48,6 +95,9 @@ impl EncodeLabelValue for Identity {
     }
 }
 
+/// Characters escaped when formatting an Identity, so that it parses back to the same Identity.
+const SEGMENT: &AsciiSet = &CONTROLS.add(b' ').add(b'%').add(b'/');
+
 impl FromStr for Identity {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -57,17 +107,27 @@ impl FromStr for Identity {
         if !s.starts_with(URI_PREFIX) {
             return Err(Spiffe(s.to_string()));
         }
-        let split: Vec<_> = s[URI_PREFIX.len()..].split('/').collect();
+        let mut split: Vec<_> = s[URI_PREFIX.len()..].split('/').collect();
+        // Tolerate a single trailing slash, as emitted by some CAs.
+        if split.len() == 6 && split[5].is_empty() {
+            split.pop();
+        }
         if split.len() != 5 {
             return Err(Spiffe(s.to_string()));
         }
         if split[1] != NAMESPACE || split[3] != SERVICE_ACCOUNT {
             return Err(Spiffe(s.to_string()));
         }
+        let decode = |segment: &str| {
+            percent_decode_str(segment)
+                .decode_utf8()
+                .map(|s| s.into_owned())
+                .map_err(|_| Spiffe(s.to_string()))
+        };
         Ok(Identity::Spiffe {
-            trust_domain: split[0].to_string(),
-            namespace: split[2].to_string(),
-            service_account: split[4].to_string(),
+            trust_domain: decode(split[0])?,
+            namespace: decode(split[2])?,
+            service_account: decode(split[4])?,
         })
     }
 }
@@ -81,7 +141,10 @@ impl fmt::Display for Identity {
                 service_account,
             } => write!(
                 f,
-                "spiffe://{trust_domain}/ns/{namespace}/sa/{service_account}"
+                "spiffe://{}/ns/{}/sa/{}",
+                utf8_percent_encode(trust_domain, SEGMENT),
+                utf8_percent_encode(namespace, SEGMENT),
+                utf8_percent_encode(service_account, SEGMENT),
             ),
         }
     }
@@ -103,6 +166,17 @@ impl Default for Identity {
 #[async_trait]
 pub trait CaClientTrait: Send + Sync {
     async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error>;
+
+    /// channel_health returns the connectivity of the client to the CA, if it has one.
+    fn channel_health(&self) -> Option<tls::ChannelHealth> {
+        None
+    }
+
+    /// requested_lifetime returns the lifetime requested for certificates, if the client asks for
+    /// one.
+    fn requested_lifetime(&self) -> Option<Duration> {
+        None
+    }
 }
 
 #[derive(PartialOrd, PartialEq, Eq, Ord, Debug, Copy, Clone)]
@@ -142,6 +216,11 @@ struct CertChannel {
     // While this makes the code simpler, do note that it makes it impossible to use sender closure
     // as an indication of the background task failing.
     tx: watch::Sender<CertState>,
+    // When the certificate was last requested, used to evict idle and least recently used
+    // identities.
+    last_used: Instant,
+    // Whether the Identity was first requested by a prefetch.
+    prefetched: bool,
 }
 
 #[derive(Eq, PartialEq)]
@@ -166,16 +245,29 @@ impl PartialOrd for PendingPriority {
 // Implements the actual logic behind SecretManager.
 struct Worker {
     client: Box<dyn CaClientTrait>,
-    // For now, certificates contain SystemTime so we need to convert it to Instant. Using Converter
-    // allows us to work on Instants without referring to the current SystemTime, which allows for
-    // time control in unit tests.
-    //
-    // TODO: Change tls::Certs to use Instant instead of SystemTime.
-    time_conv: crate::time::Converter,
+    // Certificates contain SystemTime, so expiry and refresh times are compared against this
+    // clock rather than SystemTime::now(), which allows for time control in unit tests.
+    clock: Arc<dyn Clock>,
     // Maps Identity to the certificate state.
     certs: Mutex<HashMap<Identity, CertChannel>>,
     // How many concurrent fetch_certificate calls can be pending at a time.
     concurrency: u16,
+    // How many of those can be prefetches (Warmup priority).
+    prefetch_concurrency: u16,
+    // Serving a certificate this close to its expiry, because refreshes keep failing, is logged
+    // and counted.
+    danger_window: Duration,
+    // Maximum number of identities in `certs`, if bounded.
+    capacity: Option<usize>,
+    // Identities not requested for this long are evicted, if set.
+    idle_timeout: Option<Duration>,
+    // Roots peers are verified against, if set. New roots returned by the CA are added to it.
+    root_store: Option<tls::RootCertStore>,
+    // Bounds the lifetime of certificates returned by the CA, if set.
+    max_cert_lifetime: Option<MaxCertLifetime>,
+    metrics: metrics::Metrics,
+    // Lifecycle events of the certificates, published as they happen.
+    events: CertEvents,
 }
 
 impl Worker {
@@ -189,9 +281,17 @@ impl Worker {
         }
         let worker = Arc::new(Self {
             client,
-            time_conv: cfg.time_conv,
+            clock: cfg.clock,
             concurrency: cfg.concurrency,
+            prefetch_concurrency: cfg.prefetch_concurrency.max(1),
+            danger_window: cfg.danger_window,
+            capacity: cfg.capacity,
+            idle_timeout: cfg.idle_timeout,
+            root_store: cfg.root_store,
+            max_cert_lifetime: cfg.max_cert_lifetime,
             certs: Default::default(),
+            metrics: Default::default(),
+            events: Default::default(),
         });
 
         // Process requests in the background. The task will terminate on its own when the
@@ -204,6 +304,30 @@ impl Worker {
         self.certs.lock().await.contains_key(id)
     }
 
+    // Returns the identities whose current certificate no longer chains to a root of store,
+    // recording them as invalidated. The caller must request their refresh.
+    async fn revalidate(&self, store: &tls::RootCertStore) -> Vec<Identity> {
+        let certs = self.certs.lock().await;
+        let mut invalidated = Vec::new();
+        for (id, chan) in certs.iter() {
+            let state = chan.rx.borrow();
+            let CertState::Available(current) = &*state else {
+                continue;
+            };
+            if let Err(e) = store.verify(current.x509(), current.iter_chain().map(|c| &**c)) {
+                warn!(
+                    "certificate for {id} no longer chains to a trusted root, refreshing it: {e}"
+                );
+                self.metrics.record_forced_refresh(id);
+                self.events.publish(CertEvent::CertInvalidated {
+                    identity: id.to_owned(),
+                });
+                invalidated.push(id.to_owned());
+            }
+        }
+        invalidated
+    }
+
     // Manages certificate updates. Since all the work is done in a single task, the code is
     // lock-free. This is OK as the code is I/O bound so we don't need the extra parallelism.
     async fn run(&self, mut requests: mpsc::Receiver<Request>) {
@@ -232,9 +356,51 @@ impl Worker {
         // refresh. In other words, at any point in time, there are no high-priority
         // (not Background) items scheduled to run in the future.
         let mut pending: PriorityQueue<Identity, PendingPriority> = PriorityQueue::new();
+        // Number of consecutive failed refreshes, per Identity, used for backoff.
+        let mut failures: HashMap<Identity, u32> = HashMap::new();
+        // Identities being prefetched, a subset of `processing`.
+        let mut prefetching: HashSet<Identity> = HashSet::new();
+        let mut expiry_tick = tokio::time::interval_at(
+            Instant::now() + CERT_EXPIRY_METRICS_INTERVAL,
+            CERT_EXPIRY_METRICS_INTERVAL,
+        );
+        expiry_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // Idle identities are checked for every idle_timeout, so they are evicted after being idle
+        // for between one and two idle_timeouts.
+        let idle_period = self.idle_timeout.unwrap_or(CERT_EXPIRY_METRICS_INTERVAL);
+        let mut idle_tick = tokio::time::interval_at(Instant::now() + idle_period, idle_period);
+        idle_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        // Stops refreshing an Identity that is no longer in the `certs` map.
+        fn forget(
+            id: Identity,
+            processing: &mut HashMap<Identity, Fetch>,
+            pending: &mut PriorityQueue<Identity, PendingPriority>,
+            failures: &mut HashMap<Identity, u32>,
+        ) {
+            failures.remove(&id);
+            match processing.get(&id) {
+                None => {
+                    pending.remove(&id);
+                }
+                Some(Fetch::Processing) => {
+                    processing.insert(id, Fetch::Forgetting);
+                }
+                Some(Fetch::Forgetting) => (),
+            }
+        }
 
         'main: loop {
-            let next = pending.peek().map(|(_, PendingPriority(_, ts))| *ts);
+            let next = match pending.peek() {
+                // Pending is ordered by priority, so there are no RealTime fetches waiting behind
+                // the prefetch.
+                Some((_, PendingPriority(Priority::Warmup, _)))
+                    if prefetching.len() >= self.prefetch_concurrency as usize =>
+                {
+                    None
+                }
+                next => next.map(|(_, PendingPriority(_, ts))| *ts),
+            };
             tokio::select! {
                 // Handle requests from SecretManager. Those are generally split between the
                 // client-side processing (operations on the `certs` map) and the worker-side
@@ -275,20 +441,13 @@ impl Worker {
                             // managing the Identity. Do nothing.
                             continue 'main;
                         }
-                        match processing.get(&id) {
-                            None => {
-                                pending.remove(&id);
-                            },
-                            Some(Fetch::Processing) => {
-                                processing.insert(id, Fetch::Forgetting);
-                            },
-                            Some(Fetch::Forgetting) => (),
-                        }
+                        forget(id, &mut processing, &mut pending, &mut failures);
                     },
                     None => break 'main,
                 },
                 // Handle fetch results.
                 Some((id, res)) = fetches.next() => {
+                    prefetching.remove(&id);
                     match processing.remove(&id) {
                         Some(Fetch::Processing) => (),
                         Some(Fetch::Forgetting) => continue 'main,
@@ -296,25 +455,46 @@ impl Worker {
                     }
                     let (state, refresh_at) = match res {
                         Err(err) => {
-                            let refresh_at = Instant::now() + CERT_REFRESH_FAILURE_RETRY_DELAY;
-                            (CertState::Unavailable(err), refresh_at)
+                            self.metrics.record_rotation_failure(&id, &err);
+                            self.events.publish(CertEvent::FetchFailed {
+                                identity: id.clone(),
+                                error_code: err.code(),
+                            });
+                            let failed = failures.entry(id.clone()).or_default();
+                            let refresh_at = Instant::now() + refresh_backoff(*failed);
+                            *failed = failed.saturating_add(1);
+                            match self.cert_remaining(&id).await {
+                                Some(Ok(remaining)) => {
+                                    // Keep serving the current certificate until it expires, a
+                                    // failed refresh should not make things worse.
+                                    if remaining < self.danger_window {
+                                        warn!("failed to refresh certificate for {id}, which expires in {remaining:?}: {err}");
+                                    } else {
+                                        warn!("failed to refresh certificate for {id}, keeping the current one: {err}");
+                                    }
+                                    if self.has_id(&id).await {
+                                        pending.push_increase(id, PendingPriority(Priority::Background, refresh_at));
+                                    }
+                                    continue 'main;
+                                },
+                                Some(Err(_)) => {
+                                    warn!("failed to refresh expired certificate for {id}: {err}");
+                                    self.events.publish(CertEvent::CertExpired { identity: id.clone() });
+                                    (CertState::Unavailable(Error::CertificateExpired(id.clone())), refresh_at)
+                                },
+                                None => (CertState::Unavailable(err), refresh_at),
+                            }
                         },
                         Ok(certs) => {
                             let certs: tls::Certs = certs; // Type annotation.
-                            let refresh_at = self.time_conv.system_time_to_instant(certs.refresh_at());
-                            let refresh_at = if let Some(t) = refresh_at {
-                                t.into()
-                            } else {
-                                // Malformed certificate (not_after is way too much into the
-                                // past or the future). Queue another refresh soon.
-                                //
-                                // TODO: This is a bit inconsistent since we still return the
-                                // certificate to the caller successfully. Basically the
-                                // behavior is silly, but simple and avoid panics in time math.
-                                // We'll try to get rid of the SystemTime <-> Instant
-                                // conversion here, so for now leaving the code as is.
-                                Instant::now()
-                            };
+                            failures.remove(&id);
+                            self.metrics.record_rotation(&id);
+                            self.check_granted_lifetime(&id, &certs);
+                            let rotated = self.cert_remaining(&id).await.is_some();
+                            self.events.publish(CertEvent::issued(&id, &certs, rotated));
+                            self.trust_root(&certs);
+                            self.record_expiry(&id, &certs);
+                            let refresh_at = Instant::now() + self.until(certs.refresh_at());
                             (CertState::Available(certs), refresh_at)
                         },
                     };
@@ -322,12 +502,31 @@ impl Worker {
                         pending.push_increase(id, PendingPriority(Priority::Background, refresh_at));
                     }
                 },
+                _ = expiry_tick.tick() => {
+                    for (id, chan) in self.certs.lock().await.iter() {
+                        if let CertState::Available(certs) = &*chan.rx.borrow() {
+                            self.record_expiry(id, certs);
+                        }
+                    }
+                },
+                _ = idle_tick.tick(), if self.idle_timeout.is_some() => {
+                    for id in self.evict_idle().await {
+                        forget(id, &mut processing, &mut pending, &mut failures);
+                    }
+                },
                 // Initiate the next fetch.
                 true = maybe_sleep_until(next), if fetches.len() < self.concurrency as usize => {
-                    let (id, _) = pending.pop().unwrap();
+                    let (id, PendingPriority(pri, _)) = pending.pop().unwrap();
+                    if pri == Priority::Warmup {
+                        prefetching.insert(id.to_owned());
+                    }
                     processing.insert(id.to_owned(), Fetch::Processing);
                     fetches.push(async move {
-                        let res = self.client.fetch_certificate(&id).await;
+                        let res = self
+                            .client
+                            .fetch_certificate(&id)
+                            .await
+                            .and_then(|certs| self.check_lifetime(&id, certs));
                         (id, res)
                     });
                 },
@@ -337,6 +536,105 @@ impl Worker {
         while fetches.next().await.is_some() {}
     }
 
+    // Records the lifetime the CA granted, warning if it is much shorter than requested. Refreshes
+    // are based on the granted lifetime either way.
+    fn check_granted_lifetime(&self, id: &Identity, certs: &tls::Certs) {
+        let granted = certs.lifetime();
+        let requested = self.client.requested_lifetime();
+        self.metrics.record_lifetime(id, requested, granted);
+        match requested {
+            Some(requested) if granted < requested * 9 / 10 => warn!(
+                "certificate for {id} is valid for {granted:?}, much less than the {requested:?} requested; the CA likely clamped it"
+            ),
+            _ => {}
+        }
+    }
+
+    // Applies max_cert_lifetime to certificates returned by the CA: too long lived certificates
+    // are rejected in strict mode, and refreshed as if they had the maximum lifetime otherwise.
+    fn check_lifetime(&self, id: &Identity, certs: tls::Certs) -> Result<tls::Certs, Error> {
+        let Some(limit) = self.max_cert_lifetime else {
+            return Ok(certs);
+        };
+        let lifetime = certs.lifetime();
+        if lifetime <= limit.max {
+            return Ok(certs);
+        }
+        match limit.mode {
+            CertLifetimeMode::Strict => {
+                Err(Error::CertLifetimeExceeded(id.clone(), lifetime, limit.max))
+            }
+            CertLifetimeMode::Soft => {
+                warn!(
+                    "certificate for {id} is valid for {lifetime:?}, more than the maximum of {:?}; refreshing it early",
+                    limit.max
+                );
+                Ok(certs.with_lifetime_cap(limit.max))
+            }
+        }
+    }
+
+    // Adds the root of certificates issued by the CA to the root store, so that peers issued by a
+    // new root are trusted as soon as the CA starts signing with it.
+    fn trust_root(&self, certs: &tls::Certs) {
+        let (Some(store), Some(root)) = (&self.root_store, certs.iter_chain().last()) else {
+            return;
+        };
+        if store.add(root.to_owned()) {
+            info!("trusting new root certificate returned by the CA");
+            self.events.publish(CertEvent::root_updated(root));
+        }
+    }
+
+    // Removes identities that were not requested for idle_timeout from the `certs` map, returning
+    // them.
+    async fn evict_idle(&self) -> Vec<Identity> {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return Vec::new();
+        };
+        let mut certs = self.certs.lock().await;
+        let now = Instant::now();
+        let idle: Vec<Identity> = certs
+            .iter()
+            .filter(|(_, chan)| now.duration_since(chan.last_used) >= idle_timeout)
+            .map(|(id, _)| id.to_owned())
+            .collect();
+        for id in &idle {
+            debug!("evicting idle certificate for {id}");
+            certs.remove(id);
+        }
+        self.metrics.record_cached(certs.len());
+        idle
+    }
+
+    fn record_expiry(&self, id: &Identity, certs: &tls::Certs) {
+        self.metrics.record_expiry(id, self.remaining(certs));
+    }
+
+    // Returns how long the Identity's current certificate is valid for (or how long ago it
+    // expired), if it has one.
+    async fn cert_remaining(&self, id: &Identity) -> Option<Result<Duration, Duration>> {
+        let certs = self.certs.lock().await;
+        let state = certs.get(id)?.rx.borrow();
+        match &*state {
+            CertState::Available(certs) => Some(self.remaining(certs)),
+            _ => None,
+        }
+    }
+
+    // Returns the time left until the certificate expires, or how long ago it expired.
+    fn remaining(&self, certs: &tls::Certs) -> Result<Duration, Duration> {
+        certs
+            .not_after()
+            .duration_since(self.clock.now())
+            .map_err(|e| e.duration())
+    }
+
+    // Returns the time left until t, or zero if it has passed.
+    fn until(&self, t: SystemTime) -> Duration {
+        t.duration_since(self.clock.now()).unwrap_or(Duration::ZERO)
+    }
+
     // Returns whether the Identity is still managed.
     async fn update_certs(&self, id: &Identity, certs: CertState) -> bool {
         // Both errors (lack of entry in the `certs` map and a send error) are handled the same way
@@ -353,6 +651,14 @@ impl Worker {
     }
 }
 
+// Returns the delay before retrying a refresh that failed `failures` times in a row before.
+fn refresh_backoff(failures: u32) -> Duration {
+    let backoff = CERT_REFRESH_FAILURE_INITIAL_BACKOFF
+        .saturating_mul(1 << failures.min(16))
+        .mul_f64(rand::thread_rng().gen_range(0.8..1.2));
+    backoff.min(CERT_REFRESH_FAILURE_MAX_BACKOFF)
+}
+
 // tokio::select evaluates each pattern before checking the (optional) associated condition. Work
 // around that by returning false to fail the pattern match when sleep is not viable.
 async fn maybe_sleep_until(till: Option<Instant>) -> bool {
@@ -371,8 +677,15 @@ enum Request {
 }
 
 pub struct SecretManagerConfig {
-    time_conv: crate::time::Converter,
+    clock: Arc<dyn Clock>,
     concurrency: u16,
+    // How many of the concurrent fetch_certificate calls can be prefetches.
+    prefetch_concurrency: u16,
+    danger_window: Duration,
+    capacity: Option<usize>,
+    idle_timeout: Option<Duration>,
+    root_store: Option<tls::RootCertStore>,
+    max_cert_lifetime: Option<MaxCertLifetime>,
 }
 
 /// SecretManager provides a wrapper around a CaClient with caching.
@@ -382,25 +695,98 @@ pub struct SecretManager {
     // sent for must have a corresponding entry in the worker's certs map (which is where the
     // result can be read from).
     requests: mpsc::Sender<Request>,
+    // Federated trust domain roots, attached to every certificate handed out.
+    trust_bundle: Option<tls::TrustBundle>,
+    // Peer identities rejected by every certificate handed out.
+    deny_list: tls::DenyList,
+    // Trust domains accepted in place of each other by every certificate handed out.
+    trust_domain_aliases: Option<tls::TrustDomainAliases>,
 }
 
 impl SecretManager {
     pub fn new(cfg: crate::config::Config) -> Result<Self, Error> {
-        let caclient = CaClient::new(
-            cfg.ca_address.unwrap(),
-            cfg.ca_root_cert,
-            cfg.auth,
-            cfg.proxy_mode == ProxyMode::Shared,
-        )?;
-        Ok(Self::new_with_client(caclient))
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let client: Box<dyn CaClientTrait> = match &cfg.cert_files {
+            Some(files) => Box::new(FileCertProvider::new(
+                files.clone(),
+                cfg.key_passphrase.clone(),
+            )?),
+            None => {
+                let connector = tls::ConnectorConfig::from(&cfg);
+                let client = CaClient::new(
+                    cfg.ca_endpoints(),
+                    CaAuth::new(
+                        TokenProvider::new(cfg.auth.clone(), cfg.auth_token_refresh_window),
+                        cfg.ca_audience.as_deref(),
+                        &cfg.ca_headers,
+                    )?,
+                    cfg.proxy_mode == ProxyMode::Shared,
+                    connector,
+                )?
+                .with_key_type(cfg.workload_key_type)
+                .with_cert_ttl(cfg.workload_cert_ttl)
+                .with_clock(clock.clone());
+                match &cfg.workload_token_dir {
+                    Some(dir) => {
+                        Box::new(client.with_impersonated_csr(ImpersonatedCsr::new(dir.to_owned())))
+                    }
+                    None => Box::new(client),
+                }
+            }
+        };
+        let root_store = match &cfg.workload_root_certs {
+            Some(path) => {
+                let store = tls::RootCertStore::from_file(path).map_err(Error::RootCerts)?;
+                store.watch_file(path.to_owned(), CERT_FILES_CHECK_INTERVAL);
+                Some(store)
+            }
+            None => None,
+        };
+        let revalidate = root_store.is_some();
+        let (mut secret_manager, _) = Self::new_internal(
+            client,
+            SecretManagerConfig {
+                clock,
+                concurrency: 8,
+                prefetch_concurrency: cfg.cert_prefetch_concurrency,
+                danger_window: cfg.cert_expiry_danger_window,
+                capacity: cfg.cert_cache_capacity,
+                idle_timeout: cfg.cert_cache_idle_timeout,
+                root_store,
+                max_cert_lifetime: cfg.max_accepted_cert_lifetime,
+            },
+        );
+        if let Some(files) = cfg.cert_files {
+            secret_manager.watch_cert_files(files, CERT_FILES_CHECK_INTERVAL);
+        }
+        if revalidate {
+            secret_manager.revalidate_on_root_change(ROOT_REVALIDATION_INTERVAL);
+        }
+        if !cfg.trust_bundles.is_empty() {
+            secret_manager.trust_bundle =
+                Some(tls::TrustBundle::from_files(&cfg.trust_bundles).map_err(Error::TrustBundle)?);
+        }
+        if let Some(path) = &cfg.trust_domain_aliases {
+            let aliases = tls::TrustDomainAliases::from_file(path)
+                .map_err(|e| Error::TrustDomainAliases(path.to_owned(), e.to_string()))?;
+            aliases.watch_file(path.to_owned(), CERT_FILES_CHECK_INTERVAL);
+            secret_manager.trust_domain_aliases = Some(aliases);
+        }
+        Ok(secret_manager)
     }
 
     pub fn new_with_client<C: 'static + CaClientTrait>(client: C) -> Self {
         Self::new_internal(
             Box::new(client),
             SecretManagerConfig {
-                time_conv: crate::time::Converter::new(),
+                clock: Arc::new(SystemClock),
                 concurrency: 8,
+                prefetch_concurrency: DEFAULT_CERT_PREFETCH_CONCURRENCY,
+                danger_window: DEFAULT_CERT_EXPIRY_DANGER_WINDOW,
+                capacity: None,
+                idle_timeout: None,
+                root_store: None,
+                max_cert_lifetime: None,
             },
         )
         .0
@@ -416,6 +802,9 @@ impl SecretManager {
             Self {
                 worker,
                 requests: tx,
+                trust_bundle: None,
+                deny_list: Default::default(),
+                trust_domain_aliases: None,
             },
             handle,
         )
@@ -433,10 +822,22 @@ impl SecretManager {
         pri: Priority,
     ) -> Result<watch::Receiver<CertState>, Error> {
         let mut certs = self.worker.certs.lock().await;
-        match certs.get(id) {
+        match certs.get_mut(id) {
             // Identity found in cache and is already being refreshed. Bump the priority if needed.
             Some(st) => {
+                st.last_used = Instant::now();
                 let rx = st.rx.clone();
+                if pri == Priority::RealTime {
+                    match *rx.borrow() {
+                        CertState::Available(_) if st.prefetched => {
+                            self.worker.metrics.cert_prefetch_hits.inc();
+                        }
+                        CertState::Available(_) => (),
+                        _ => {
+                            self.worker.metrics.cert_on_demand_fetches.inc();
+                        }
+                    }
+                }
                 drop(certs);
 
                 if let Some(existing_pri) = init_pri(&rx) {
@@ -449,8 +850,24 @@ impl SecretManager {
             // New identity, start managing it and return the newly created channel.
             None => {
                 let (tx, rx) = watch::channel(CertState::Initializing(pri));
-                certs.insert(id.to_owned(), CertChannel { rx: rx.clone(), tx });
+                certs.insert(
+                    id.to_owned(),
+                    CertChannel {
+                        rx: rx.clone(),
+                        tx,
+                        last_used: Instant::now(),
+                        prefetched: pri == Priority::Warmup,
+                    },
+                );
+                if pri == Priority::RealTime {
+                    self.worker.metrics.cert_on_demand_fetches.inc();
+                }
+                let evicted = self.evict_lru(&mut certs, id);
+                self.worker.metrics.record_cached(certs.len());
                 drop(certs);
+                if let Some(evicted) = evicted {
+                    self.post(Request::Forget(evicted)).await;
+                }
                 // Notify the background worker to start refreshing the certificate.
                 self.post(Request::Fetch(id.to_owned(), pri)).await;
                 Ok(rx)
@@ -458,14 +875,110 @@ impl SecretManager {
         }
     }
 
-    async fn wait(&self, mut rx: watch::Receiver<CertState>) -> Result<tls::Certs, Error> {
+    // Removes the least recently used Identity, other than the one just added, if the `certs` map
+    // is over capacity. The caller must notify the worker to stop refreshing the returned Identity.
+    fn evict_lru(
+        &self,
+        certs: &mut HashMap<Identity, CertChannel>,
+        added: &Identity,
+    ) -> Option<Identity> {
+        let capacity = self.worker.capacity?;
+        if certs.len() <= capacity {
+            return None;
+        }
+        let lru = certs
+            .iter()
+            .filter(|(id, _)| *id != added)
+            .min_by_key(|(_, chan)| chan.last_used)
+            .map(|(id, _)| id.to_owned())?;
+        debug!("certificate cache is full, evicting {lru}");
+        certs.remove(&lru);
+        Some(lru)
+    }
+
+    // Attaches the peer verification settings shared by all certificates.
+    fn with_policy(&self, certs: tls::Certs) -> tls::Certs {
+        let mut certs = certs.with_deny_list(&self.deny_list);
+        if let Some(store) = &self.worker.root_store {
+            certs = certs.with_root_store(store);
+        }
+        if let Some(aliases) = &self.trust_domain_aliases {
+            certs = certs.with_trust_domain_aliases(aliases);
+        }
+        match &self.trust_bundle {
+            Some(bundle) => certs.with_trust_bundle(bundle),
+            None => certs,
+        }
+    }
+
+    /// requested_cert_lifetime returns the lifetime requested from the CA for certificates, or None
+    /// when certificates are not fetched from a CA.
+    pub fn requested_cert_lifetime(&self) -> Option<Duration> {
+        self.worker.client.requested_lifetime()
+    }
+
+    /// ca_health returns the connectivity of the CA channel, or None when certificates are not
+    /// fetched from a CA.
+    pub fn ca_health(&self) -> Option<tls::ChannelHealth> {
+        self.worker.client.channel_health()
+    }
+
+    /// register_metrics exposes certificate expiry and rotation metrics in the registry.
+    pub fn register_metrics(&self, registry: &mut Registry) {
+        self.worker.metrics.register(registry);
+    }
+
+    /// events returns the channel certificate lifecycle events are published to.
+    pub fn events(&self) -> &CertEvents {
+        &self.worker.events
+    }
+
+    /// root_store returns the roots peers are verified against, if configured. It can be updated
+    /// at runtime, for example to stop trusting an old root once a root rotation completes.
+    pub fn root_store(&self) -> Option<&tls::RootCertStore> {
+        self.worker.root_store.as_ref()
+    }
+
+    /// deny_list returns the identities that are rejected during handshakes. It can be modified at
+    /// runtime, affecting certificates that were already handed out.
+    pub fn deny_list(&self) -> &tls::DenyList {
+        &self.deny_list
+    }
+
+    /// trust_domain_aliases returns the trust domains accepted in place of each other, if
+    /// configured, along with how many peers were accepted under each of them.
+    pub fn trust_domain_aliases(&self) -> Option<&tls::TrustDomainAliases> {
+        self.trust_domain_aliases.as_ref()
+    }
+
+    // Fails certificates that have expired, instead of handing them out to be presented to peers.
+    fn check_expiry(&self, id: &Identity, certs: &tls::Certs) -> Result<(), Error> {
+        match self.worker.remaining(certs) {
+            Err(_) => Err(Error::CertificateExpired(id.to_owned())),
+            Ok(remaining) => {
+                if remaining < self.worker.danger_window {
+                    self.worker.metrics.record_near_expiry(id);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn wait(
+        &self,
+        id: &Identity,
+        mut rx: watch::Receiver<CertState>,
+    ) -> Result<tls::Certs, Error> {
         loop {
             tokio::select! {
                 // Wait for the initial value if not ready yet.
                 res = rx.changed() => match res {
                     Ok(()) => match *rx.borrow() {
                         CertState::Unavailable(ref err) => return Err(err.to_owned()),
-                        CertState::Available(ref certs) => return Ok(certs.to_owned()),
+                        CertState::Available(ref certs) => {
+                            self.check_expiry(id, certs)?;
+                            return Ok(self.with_policy(certs.to_owned()));
+                        }
                         // Another call bumped up the priority, but still fetching the first
                         // certificate.
                         CertState::Initializing(_) => (),
@@ -488,15 +1001,127 @@ impl SecretManager {
         // This method is intentionally left simple, since since unit tests are based on start_fetch
         // and wait. Any changes should go to one of those two methods, and if that proves
         // impossible - unit testing strategy may need to be rethinked.
-        self.wait(self.start_fetch(id, pri).await?).await
+        self.wait(id, self.start_fetch(id, pri).await?).await
     }
 
+    /// fetch_certificate returns the certificate for the Identity, fetching it if needed.
+    /// Concurrent calls for the same Identity share a single CSR and CA request, which keeps
+    /// running even if some of the callers give up waiting.
     pub async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
         self.fetch_certificate_pri(id, Priority::RealTime).await
     }
 
+    /// prefetch_certificate starts fetching the certificate for the Identity in the background,
+    /// without waiting for it, so that it is available by the time a connection needs it.
+    /// Prefetches are limited to prefetch_concurrency CA requests at a time, and are served after
+    /// fetches that block connections.
+    pub async fn prefetch_certificate(&self, id: &Identity) -> Result<(), Error> {
+        self.start_fetch(id, Priority::Warmup).await.map(|_| ())
+    }
+
+    /// subscribe fetches the certificate for the Identity if needed, returning a receiver that is
+    /// notified whenever the certificate is rotated. The receiver is closed once the Identity is
+    /// no longer managed.
+    pub async fn subscribe(&self, id: &Identity) -> Result<watch::Receiver<CertState>, Error> {
+        self.start_fetch(id, Priority::RealTime).await
+    }
+
+    /// force_refresh requests a new certificate for the Identity right away, instead of waiting for
+    /// the current one to be due for a refresh. The current certificate keeps being served until
+    /// the new one is available, and is kept if the refresh fails. If a refresh is already in
+    /// progress, no new one is started. Returns false if the Identity is not managed.
+    pub async fn force_refresh(&self, id: &Identity) -> bool {
+        if !self.worker.has_id(id).await {
+            return false;
+        }
+        self.post(Request::Fetch(id.to_owned(), Priority::RealTime))
+            .await;
+        true
+    }
+
+    /// watch_cert_files refreshes every certificate whenever the contents of the files change, so
+    /// that certificates rotated by an external agent are served right away. The files are
+    /// compared every interval, which also detects atomic symlink swaps. Certificates are only
+    /// refreshed once the files stayed unchanged for an interval, so that an agent writing the
+    /// files one by one triggers a single refresh, with all of them updated.
+    pub fn watch_cert_files(&self, files: CertFiles, interval: Duration) {
+        let requests = self.requests.downgrade();
+        let worker = Arc::downgrade(&self.worker);
+        tokio::spawn(async move {
+            let read = || CertSnapshot::read(&files).ok();
+            let mut contents = read();
+            let mut changed = false;
+            loop {
+                tokio::time::sleep(interval).await;
+                // Stop once the SecretManager is dropped.
+                let (Some(requests), Some(worker)) = (requests.upgrade(), worker.upgrade()) else {
+                    return;
+                };
+                let latest = read();
+                if latest != contents {
+                    contents = latest;
+                    changed = true;
+                    continue;
+                }
+                if !changed {
+                    continue;
+                }
+                changed = false;
+                info!("certificate files changed, reloading certificates");
+                let ids: Vec<Identity> = worker.certs.lock().await.keys().cloned().collect();
+                for id in ids {
+                    let _ = requests.send(Request::Fetch(id, Priority::RealTime)).await;
+                }
+            }
+        });
+    }
+
+    /// revalidate_on_root_change checks that every certificate still chains to a root of the root
+    /// store, whenever the store is updated and every interval. Certificates that do not, such as
+    /// ones issued under a root or intermediate that was removed, are refreshed right away, and
+    /// CertEvent::CertInvalidated is published for them. Does nothing without a root store.
+    pub fn revalidate_on_root_change(&self, interval: Duration) {
+        let Some(store) = self.worker.root_store.clone() else {
+            return;
+        };
+        let mut updates = store.subscribe();
+        let requests = self.requests.downgrade();
+        let worker = Arc::downgrade(&self.worker);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    // The store is held by this task, so updates are never closed.
+                    _ = updates.changed() => {}
+                    _ = tokio::time::sleep(interval) => {}
+                }
+                // Stop once the SecretManager is dropped.
+                let (Some(requests), Some(worker)) = (requests.upgrade(), worker.upgrade()) else {
+                    return;
+                };
+                for id in worker.revalidate(&store).await {
+                    let _ = requests.send(Request::Fetch(id, Priority::RealTime)).await;
+                }
+            }
+        });
+    }
+
+    /// force_refresh_all calls force_refresh for every managed Identity.
+    pub async fn force_refresh_all(&self) {
+        let ids: Vec<Identity> = self.worker.certs.lock().await.keys().cloned().collect();
+        for id in ids {
+            self.force_refresh(&id).await;
+        }
+    }
+
+    /// forget_certificate stops managing the Identity, for example once no workload uses it
+    /// anymore. Pending refreshes are cancelled and the certificate is dropped, which zeroizes its
+    /// private key once no connection references it anymore.
     pub async fn forget_certificate(&self, id: &Identity) {
-        if self.worker.certs.lock().await.remove(id).is_some() {
+        let mut certs = self.worker.certs.lock().await;
+        let removed = certs.remove(id).is_some();
+        self.worker.metrics.record_cached(certs.len());
+        drop(certs);
+        if removed {
             self.post(Request::Forget(id.clone())).await;
         }
     }
@@ -512,6 +1137,22 @@ impl SecretManager {
     }
 }
 
+#[async_trait]
+impl CertsSource for SecretManager {
+    async fn certs_for(&self, id: &Identity) -> Result<Arc<tls::Certs>, Error> {
+        let rx = self.start_fetch(id, Priority::RealTime).await?;
+        let state = rx.borrow();
+        match &*state {
+            CertState::Available(certs) => {
+                self.check_expiry(id, certs)?;
+                Ok(Arc::new(self.with_policy(certs.to_owned())))
+            }
+            CertState::Unavailable(err) => Err(err.to_owned()),
+            CertState::Initializing(_) => Err(Error::CertsPending(id.to_owned())),
+        }
+    }
+}
+
 // Matches CertState::Initializing(pri) from a Receiver, wrapped in a function to make borrow
 // lifetimes more manageable.
 fn init_pri(rx: &watch::Receiver<CertState>) -> Option<Priority> {
@@ -551,6 +1192,7 @@ pub mod mock {
         let time_conv = crate::time::Converter::new_at(cfg.epoch.unwrap_or_else(SystemTime::now));
         let client = MockCaClient::new(mock::ClientConfig {
             cert_lifetime: cfg.cert_lifetime,
+            requested_lifetime: None,
             fetch_latency: cfg.fetch_latency,
             time_conv: time_conv.clone(),
         });
@@ -558,8 +1200,14 @@ pub mod mock {
             SecretManager::new_internal(
                 Box::new(client),
                 super::SecretManagerConfig {
-                    time_conv,
+                    clock: Arc::new(time_conv),
                     concurrency: 2,
+                    prefetch_concurrency: 2,
+                    danger_window: super::DEFAULT_CERT_EXPIRY_DANGER_WINDOW,
+                    capacity: None,
+                    idle_timeout: None,
+                    root_store: None,
+                    max_cert_lifetime: None,
                 },
             )
             .0,
@@ -581,6 +1229,7 @@ mod tests {
 
     use crate::identity::caclient::mock::CaClient as MockCaClient;
     use crate::identity::{self, *};
+    use crate::time::Clock;
 
     use super::{mock, *};
 
@@ -702,6 +1351,7 @@ mod tests {
     const MILLISEC: Duration = Duration::from_millis(1);
     const SEC: Duration = Duration::from_secs(1);
     const CERT_HALFLIFE: Duration = Duration::from_secs(50);
+    const DANGER_WINDOW: Duration = Duration::from_secs(20);
 
     // Represents common test case setup.
     struct Test {
@@ -725,6 +1375,11 @@ mod tests {
     }
 
     fn setup(concurrency: u16) -> Test {
+        setup_with(concurrency, |_| ())
+    }
+
+    // Like setup, but allows overriding the SecretManagerConfig.
+    fn setup_with(concurrency: u16, f: impl FnOnce(&mut SecretManagerConfig)) -> Test {
         // Tests that use this function rely on Tokio's test time pause and auto-advance. It gets a
         // bit tricky so a few things to remember:
         //  - When *all* futures are blocked waiting for a specific time, the runtime will
@@ -739,14 +1394,20 @@ mod tests {
             time_conv: time_conv.clone(),
             fetch_latency: SEC,
             cert_lifetime: 2 * CERT_HALFLIFE,
+            requested_lifetime: None,
         });
-        let (secret_manager, worker) = SecretManager::new_internal(
-            Box::new(caclient.clone()),
-            SecretManagerConfig {
-                time_conv,
-                concurrency,
-            },
-        );
+        let mut cfg = SecretManagerConfig {
+            clock: Arc::new(time_conv),
+            concurrency,
+            prefetch_concurrency: concurrency,
+            danger_window: DANGER_WINDOW,
+            capacity: None,
+            idle_timeout: None,
+            root_store: None,
+            max_cert_lifetime: None,
+        };
+        f(&mut cfg);
+        let (secret_manager, worker) = SecretManager::new_internal(Box::new(caclient.clone()), cfg);
         Test {
             worker,
             caclient,
@@ -797,7 +1458,7 @@ mod tests {
             tokio::time::sleep(NANOSEC).await;
             let sm = test.secret_manager.clone();
             let rx = sm.start_fetch(&id, pri).await.unwrap();
-            tasks.push(tokio::spawn(async move { sm.wait(rx).await }));
+            tasks.push(tokio::spawn(async move { sm.wait(&id, rx).await }));
             // Now the request has either started (for the first request) or is queued in the
             // background worker.
         }
@@ -893,6 +1554,34 @@ mod tests {
         test.tear_down().await;
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_fetches_single_flight() {
+        let test = setup(1);
+        let id = identity("id1");
+        let fetch = || {
+            let sm = test.secret_manager.clone();
+            let id = id.clone();
+            tokio::spawn(async move { sm.fetch_certificate(&id).await })
+        };
+
+        // Some callers give up while the fetch is in progress, which must not affect the rest.
+        let cancelled: Vec<_> = (0..10).map(|_| fetch()).collect();
+        let tasks: Vec<_> = (0..50).map(|_| fetch()).collect();
+        tokio::time::sleep(SEC / 2).await;
+        for task in cancelled {
+            task.abort();
+        }
+
+        let mut results = futures::future::join_all(tasks).await.into_iter();
+        let want = results.next().unwrap().unwrap().unwrap();
+        for got in results {
+            assert_eq!(got.unwrap().unwrap(), want);
+        }
+        assert_eq!(test.caclient.fetches().await, vec![id.clone()]);
+
+        test.tear_down().await;
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_duplicate_requests() {
         let test = setup(1);
@@ -909,11 +1598,11 @@ mod tests {
         let mut rxs_iter = rxs.into_iter();
         let want = test
             .secret_manager
-            .wait(rxs_iter.next().unwrap())
+            .wait(&id, rxs_iter.next().unwrap())
             .await
             .unwrap();
         for rx in rxs_iter {
-            let got = test.secret_manager.wait(rx).await.unwrap();
+            let got = test.secret_manager.wait(&id, rx).await.unwrap();
             assert_eq!(got, want);
         }
         assert_eq!(test.caclient.fetches().await.len(), 1);
@@ -964,6 +1653,452 @@ mod tests {
         test.tear_down().await;
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_force_refresh() {
+        let test = setup(1);
+        let id = identity("test");
+        let serial = |certs: &tls::Certs| certs.x509().serial_number().to_bn().unwrap();
+
+        let initial = test.secret_manager.fetch_certificate(&id).await.unwrap();
+        test.caclient.clear_fetches().await;
+
+        // Concurrent refreshes are deduplicated.
+        assert!(test.secret_manager.force_refresh(&id).await);
+        assert!(test.secret_manager.force_refresh(&id).await);
+        assert!(!test.secret_manager.force_refresh(&identity("other")).await);
+        // The current certificate is served while the refresh is in progress.
+        tokio::time::sleep(SEC / 2).await;
+        let current = test.secret_manager.fetch_certificate(&id).await.unwrap();
+        assert_eq!(serial(&current), serial(&initial));
+
+        tokio::time::sleep(SEC).await;
+        assert_eq!(test.caclient.fetches().await, vec![id.clone()]);
+        let refreshed = test.secret_manager.fetch_certificate(&id).await.unwrap();
+        assert_ne!(serial(&refreshed), serial(&initial));
+
+        test.secret_manager.force_refresh_all().await;
+        tokio::time::sleep(SEC + MILLISEC).await;
+        assert_eq!(test.caclient.fetches().await, vec![id.clone(), id.clone()]);
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_revalidate_on_root_change() {
+        let store = tls::RootCertStore::default();
+        let test = setup_with(1, |cfg| cfg.root_store = Some(store.clone()));
+        let id = identity("test");
+        let serial = |certs: &tls::Certs| certs.x509().serial_number().to_bn().unwrap();
+
+        // The root of the CA is trusted once it issued a certificate.
+        let initial = test.secret_manager.fetch_certificate(&id).await.unwrap();
+        let root_a = initial.iter_chain().last().unwrap().clone();
+        assert!(store.contains(&root_a));
+        test.secret_manager
+            .revalidate_on_root_change(Duration::from_secs(60 * 60));
+        test.caclient.clear_fetches().await;
+        let mut events = test.secret_manager.events().subscribe();
+
+        // Once root A is swapped for root B, the certificate no longer chains to a trusted root.
+        let (root_b, _) = tls::generate_test_ca("root-b");
+        store.set(vec![root_b]);
+        tokio::time::sleep(2 * SEC).await;
+        assert_eq!(test.caclient.fetches().await, vec![id.clone()]);
+        assert_eq!(
+            events.recv().await,
+            Some(CertEvent::CertInvalidated {
+                identity: id.clone()
+            })
+        );
+        let forced = test
+            .secret_manager
+            .worker
+            .metrics
+            .cert_forced_refreshes
+            .get_or_create(&crate::metrics::identity::CertLabels {
+                identity: id.clone(),
+            })
+            .get();
+        assert_eq!(forced, 1);
+        let refreshed = test.secret_manager.fetch_certificate(&id).await.unwrap();
+        assert_ne!(serial(&refreshed), serial(&initial));
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lru_eviction() {
+        let test = setup_with(1, |cfg| cfg.capacity = Some(2));
+        let start = Instant::now();
+        let (id1, id2, id3) = (identity("id1"), identity("id2"), identity("id3"));
+
+        test.secret_manager.fetch_certificate(&id1).await.unwrap();
+        test.secret_manager.fetch_certificate(&id2).await.unwrap();
+        // Using id1 again makes id2 the least recently used.
+        test.secret_manager.fetch_certificate(&id1).await.unwrap();
+        test.secret_manager.fetch_certificate(&id3).await.unwrap();
+
+        assert_eq!(test.secret_manager.cache_len().await, 2);
+        let mut cached = test.secret_manager.collect_certs(|id, _| id.clone()).await;
+        cached.sort_by_key(|id| id.to_string());
+        assert_eq!(cached, vec![id1.clone(), id3.clone()]);
+        assert_eq!(
+            test.secret_manager.worker.metrics.cached_identities.get(),
+            2
+        );
+
+        // Only the cached identities keep being refreshed.
+        test.caclient.clear_fetches().await;
+        tokio::time::sleep_until(start + 2 * CERT_HALFLIFE).await;
+        let fetches = test.caclient.fetches().await;
+        assert!(fetches.contains(&id1));
+        assert!(fetches.contains(&id3));
+        assert!(
+            !fetches.contains(&id2),
+            "evicted identity refreshed: {fetches:?}"
+        );
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_eviction() {
+        let idle_timeout = 10 * SEC;
+        let test = setup_with(1, |cfg| cfg.idle_timeout = Some(idle_timeout));
+        let (busy, idle) = (identity("busy"), identity("idle"));
+
+        test.secret_manager.fetch_certificate(&busy).await.unwrap();
+        test.secret_manager.fetch_certificate(&idle).await.unwrap();
+        for _ in 0..4 {
+            tokio::time::sleep(idle_timeout / 2).await;
+            test.secret_manager.fetch_certificate(&busy).await.unwrap();
+        }
+
+        assert_eq!(
+            test.secret_manager.collect_certs(|id, _| id.clone()).await,
+            vec![busy.clone()]
+        );
+        assert_eq!(
+            test.secret_manager.worker.metrics.cached_identities.get(),
+            1
+        );
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_prefetch() {
+        let test = setup_with(4, |cfg| cfg.prefetch_concurrency = 2);
+        let metrics = test.secret_manager.worker.metrics.clone();
+        let ids: Vec<Identity> = (0..4).map(|n| identity_n("id", n)).collect();
+        for id in &ids {
+            test.secret_manager.prefetch_certificate(id).await.unwrap();
+        }
+
+        // Prefetches are rate limited.
+        tokio::time::sleep(SEC + MILLISEC).await;
+        assert_eq!(test.caclient.fetches().await.len(), 2);
+        tokio::time::sleep(SEC).await;
+        assert_eq!(test.caclient.fetches().await.len(), 4);
+
+        // Connections arriving after the prefetch completed never wait on the CA.
+        test.caclient.clear_fetches().await;
+        let start = Instant::now();
+        for id in &ids {
+            test.secret_manager.fetch_certificate(id).await.unwrap();
+        }
+        assert_eq!(Instant::now(), start);
+        assert_eq!(test.caclient.fetches().await, vec![]);
+        assert_eq!(metrics.cert_prefetch_hits.get(), 4);
+        assert_eq!(metrics.cert_on_demand_fetches.get(), 0);
+
+        test.secret_manager
+            .fetch_certificate(&identity("other"))
+            .await
+            .unwrap();
+        assert_eq!(metrics.cert_on_demand_fetches.get(), 1);
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_prefetch_does_not_delay_realtime() {
+        let test = setup_with(2, |cfg| cfg.prefetch_concurrency = 1);
+        for n in 0..4 {
+            test.secret_manager
+                .prefetch_certificate(&identity_n("prefetch", n))
+                .await
+                .unwrap();
+        }
+        // One fetch slot is kept free of prefetches, so an on-demand fetch is not queued behind
+        // them.
+        let start = Instant::now();
+        test.secret_manager
+            .fetch_certificate(&identity("realtime"))
+            .await
+            .unwrap();
+        assert!(Instant::now() - start < 2 * SEC);
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_while_revalidate() {
+        let test = setup(1);
+        let start = Instant::now();
+        let id = identity("test");
+        let serial = |certs: &tls::Certs| certs.x509().serial_number().to_bn().unwrap();
+
+        let initial = test.secret_manager.fetch_certificate(&id).await.unwrap();
+        test.caclient.set_failures(4).await;
+        // The refresh is due at start + 1s + CERT_HALFLIFE. While the CA keeps failing, the current
+        // certificate is served; once the CA recovers, the new one is served with no gap.
+        let mut rotated = false;
+        for i in 0..30 {
+            tokio::time::sleep_until(start + CERT_HALFLIFE + i * SEC).await;
+            let certs = test.secret_manager.fetch_certificate(&id).await.unwrap();
+            assert!(!certs.is_expired_at(test.secret_manager.worker.clock.now()));
+            if serial(&certs) != serial(&initial) {
+                rotated = true;
+                break;
+            }
+        }
+        assert!(
+            rotated,
+            "certificate was not rotated after the CA recovered"
+        );
+        assert_eq!(
+            test.secret_manager
+                .worker
+                .metrics
+                .cert_rotation_failures
+                .get_or_create(&crate::metrics::identity::CertRotationFailure {
+                    identity: id.clone(),
+                    reason: crate::metrics::identity::CertRotationFailureReason::SigningRequest,
+                })
+                .get(),
+            4
+        );
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_cert_fails() {
+        let test = setup(1);
+        let start = Instant::now();
+        let id = identity("test");
+        let near_expiry = || {
+            test.secret_manager
+                .worker
+                .metrics
+                .certs_served_near_expiry
+                .get_or_create(&crate::metrics::identity::CertLabels {
+                    identity: id.clone(),
+                })
+                .get()
+        };
+
+        test.secret_manager.fetch_certificate(&id).await.unwrap();
+        test.caclient.set_failures(u32::MAX).await;
+
+        // The certificate is issued at start + 1s and expires 2 * CERT_HALFLIFE later.
+        let expiry = start + SEC + 2 * CERT_HALFLIFE;
+        tokio::time::sleep_until(expiry - DANGER_WINDOW - SEC).await;
+        test.secret_manager.fetch_certificate(&id).await.unwrap();
+        assert_eq!(near_expiry(), 0);
+        tokio::time::sleep_until(expiry - SEC).await;
+        test.secret_manager.fetch_certificate(&id).await.unwrap();
+        assert_eq!(near_expiry(), 1);
+
+        tokio::time::sleep_until(expiry + MILLISEC).await;
+        assert_matches!(
+            test.secret_manager.fetch_certificate(&id).await,
+            Err(Error::CertificateExpired(_))
+        );
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_cert_lifetime() {
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+        const MINUTE: Duration = Duration::from_secs(60);
+        let setup = |mode| {
+            let time_conv = crate::time::Converter::new();
+            let caclient = MockCaClient::new(caclient::mock::ClientConfig {
+                time_conv: time_conv.clone(),
+                fetch_latency: SEC,
+                cert_lifetime: 365 * DAY,
+                requested_lifetime: None,
+            });
+            let (secret_manager, worker) = SecretManager::new_internal(
+                Box::new(caclient.clone()),
+                SecretManagerConfig {
+                    clock: Arc::new(time_conv),
+                    concurrency: 1,
+                    prefetch_concurrency: 1,
+                    danger_window: DANGER_WINDOW,
+                    capacity: None,
+                    idle_timeout: None,
+                    root_store: None,
+                    max_cert_lifetime: Some(MaxCertLifetime { max: DAY, mode }),
+                },
+            );
+            Test {
+                worker,
+                caclient,
+                secret_manager: Arc::new(secret_manager),
+            }
+        };
+        let id = identity("test");
+
+        // In soft mode, the certificate is used but refreshed after half the maximum lifetime.
+        let test = setup(CertLifetimeMode::Soft);
+        let start = Instant::now();
+        let certs = test.secret_manager.fetch_certificate(&id).await.unwrap();
+        assert_eq!(certs.lifetime(), 365 * DAY);
+        tokio::time::sleep_until(start + DAY / 2 - MINUTE).await;
+        assert_eq!(test.caclient.fetches().await.len(), 1);
+        tokio::time::sleep_until(start + DAY / 2 + MINUTE).await;
+        assert_eq!(test.caclient.fetches().await.len(), 2);
+        test.tear_down().await;
+
+        // In strict mode, the certificate is rejected.
+        let test = setup(CertLifetimeMode::Strict);
+        assert_matches!(
+            test.secret_manager.fetch_certificate(&id).await,
+            Err(Error::CertLifetimeExceeded(_, lifetime, DAY)) if lifetime == 365 * DAY
+        );
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clamped_cert_lifetime() {
+        const HOUR: Duration = Duration::from_secs(60 * 60);
+        const MINUTE: Duration = Duration::from_secs(60);
+        let time_conv = crate::time::Converter::new();
+        // The CA issues certificates valid for at most an hour, whatever is requested.
+        let caclient = MockCaClient::new(caclient::mock::ClientConfig {
+            time_conv: time_conv.clone(),
+            fetch_latency: SEC,
+            cert_lifetime: HOUR,
+            requested_lifetime: Some(24 * HOUR),
+        });
+        let (secret_manager, worker) = SecretManager::new_internal(
+            Box::new(caclient.clone()),
+            SecretManagerConfig {
+                clock: Arc::new(time_conv),
+                concurrency: 1,
+                prefetch_concurrency: 1,
+                danger_window: DANGER_WINDOW,
+                capacity: None,
+                idle_timeout: None,
+                root_store: None,
+                max_cert_lifetime: None,
+            },
+        );
+        let test = Test {
+            worker,
+            caclient,
+            secret_manager: Arc::new(secret_manager),
+        };
+        let id = identity("test");
+        assert_eq!(
+            test.secret_manager.requested_cert_lifetime(),
+            Some(24 * HOUR)
+        );
+
+        // Refreshes happen after half the granted lifetime, not the requested one.
+        let start = Instant::now();
+        let certs = test.secret_manager.fetch_certificate(&id).await.unwrap();
+        assert_eq!(certs.lifetime(), HOUR);
+        tokio::time::sleep_until(start + HOUR / 2 - MINUTE).await;
+        assert_eq!(test.caclient.fetches().await.len(), 1);
+        tokio::time::sleep_until(start + HOUR / 2 + MINUTE).await;
+        assert_eq!(test.caclient.fetches().await.len(), 2);
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cert_events() {
+        let test = setup(1);
+        let id = identity("test");
+        let mut events = test.secret_manager.events().subscribe();
+        let issued = |certs: &tls::Certs, rotated| CertEvent::issued(&id, certs, rotated);
+
+        let initial = test.secret_manager.fetch_certificate(&id).await.unwrap();
+        test.caclient.set_failures(1).await;
+        assert!(test.secret_manager.force_refresh(&id).await);
+        // The failed refresh is retried after a backoff of about a second.
+        tokio::time::sleep(5 * SEC).await;
+        let refreshed = test.secret_manager.fetch_certificate(&id).await.unwrap();
+
+        let expected = vec![
+            issued(&initial, false),
+            CertEvent::FetchFailed {
+                identity: id.clone(),
+                error_code: "SIGNING_REQUEST",
+            },
+            issued(&refreshed, true),
+        ];
+        for event in &expected {
+            assert_eq!(events.recv().await.as_ref(), Some(event));
+        }
+        assert_eq!(events.missed(), 0);
+        assert_eq!(test.secret_manager.events().recent(), expected);
+        test.tear_down().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cert_metrics() {
+        let test = setup(1);
+        let start = Instant::now();
+        let metrics = test.secret_manager.worker.metrics.clone();
+        let id = identity("test");
+        let expiry = || {
+            metrics
+                .cert_expiry_seconds
+                .get_or_create(&crate::metrics::identity::CertLabels {
+                    identity: id.clone(),
+                })
+                .get()
+        };
+
+        test.secret_manager.fetch_certificate(&id).await.unwrap();
+        let initial = expiry();
+        assert!(initial > 0 && initial <= (2 * CERT_HALFLIFE).as_secs() as i64);
+        assert_eq!(
+            metrics
+                .cert_rotations
+                .get_or_create(&crate::metrics::identity::CertLabels {
+                    identity: id.clone(),
+                })
+                .get(),
+            1
+        );
+
+        // The gauge keeps decreasing between rotations.
+        tokio::time::sleep_until(start + CERT_EXPIRY_METRICS_INTERVAL + SEC).await;
+        let later = expiry();
+        assert!(later < initial, "{later} >= {initial}");
+        assert!(later > 0);
+
+        let failing = Identity::Spiffe {
+            trust_domain: "error".to_string(),
+            namespace: "forgotten".to_string(),
+            service_account: "sa".to_string(),
+        };
+        assert!(test
+            .secret_manager
+            .fetch_certificate(&failing)
+            .await
+            .is_err());
+        assert_eq!(
+            metrics
+                .cert_rotation_failures
+                .get_or_create(&crate::metrics::identity::CertRotationFailure {
+                    identity: failing,
+                    reason: crate::metrics::identity::CertRotationFailureReason::Other,
+                })
+                .get(),
+            1
+        );
+        test.tear_down().await;
+    }
+
     #[test]
     fn identity_from_string() {
         assert_eq!(
@@ -1000,7 +2135,38 @@ mod tests {
         );
         assert_matches!(Identity::from_str("td/ns/ns/sa/sa"), Err(_));
         assert_matches!(Identity::from_str("spiffe://td/ns/ns/sa"), Err(_));
-        assert_matches!(Identity::from_str("spiffe://td/ns/ns/sa/sa/"), Err(_));
+        assert_eq!(
+            Identity::from_str("spiffe://td/ns/ns/sa/sa/").ok(),
+            Some(Identity::Spiffe {
+                trust_domain: "td".to_string(),
+                namespace: "ns".to_string(),
+                service_account: "sa".to_string(),
+            })
+        );
+        assert_eq!(
+            Identity::from_str("spiffe://td/ns/my%20ns/sa/a%2Fb").ok(),
+            Some(Identity::Spiffe {
+                trust_domain: "td".to_string(),
+                namespace: "my ns".to_string(),
+                service_account: "a/b".to_string(),
+            })
+        );
+        assert_matches!(Identity::from_str("spiffe://td/ns/ns/sa/sa//"), Err(_));
+        assert_matches!(Identity::from_str("spiffe://td/ns/ns/sa/%FF"), Err(_));
         assert_matches!(Identity::from_str("spiffe://td/ns/ns/foobar/sa/"), Err(_));
     }
+
+    #[test]
+    fn identity_round_trip() {
+        for id in [
+            Identity::default(),
+            Identity::Spiffe {
+                trust_domain: "example.org".to_string(),
+                namespace: "my ns".to_string(),
+                service_account: "a/b%c".to_string(),
+            },
+        ] {
+            assert_eq!(Identity::from_str(&id.to_string()).unwrap(), id);
+        }
+    }
 }
@@ -0,0 +1,99 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::tls;
+
+use super::{Error, Identity};
+
+/// ConnectionIdentity is who a proxied connection is made for, carried from the accept of the
+/// traffic to the outbound connect: the identity of the workload the traffic comes from, whose
+/// certificate is presented, and the identity expected of the peer, if known.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionIdentity {
+    pub source: Identity,
+    pub destination: Option<Identity>,
+}
+
+impl ConnectionIdentity {
+    /// certs returns the certificate to present on the connection, that of its source.
+    pub async fn certs(&self, certs: &dyn CertsSource) -> Result<Arc<tls::Certs>, Error> {
+        certs.certs_for(&self.source).await
+    }
+}
+
+/// CertsSource provides the certificates of the local identities, such as the SecretManager with
+/// its cache.
+#[async_trait]
+pub trait CertsSource: Send + Sync {
+    /// certs_for returns the current certificate of id, without waiting for one to be fetched.
+    /// If id has no certificate yet, its fetch is started and Error::CertsPending is returned.
+    async fn certs_for(&self, id: &Identity) -> Result<Arc<tls::Certs>, Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use matches::assert_matches;
+
+    use crate::identity::{mock, Error, Identity};
+    use crate::tls;
+
+    use super::ConnectionIdentity;
+
+    fn identity(name: &str) -> Identity {
+        Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "default".to_string(),
+            service_account: name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn presents_source_certs() {
+        let manager = mock::new_secret_manager_cfg(mock::SecretManagerConfig {
+            cert_lifetime: Duration::from_secs(100),
+            fetch_latency: Duration::from_millis(50),
+            epoch: None,
+        });
+        let server = identity("server");
+        let server_certs = manager.fetch_certificate(&server).await.unwrap();
+
+        for name in ["a", "b"] {
+            let conn = ConnectionIdentity {
+                source: identity(name),
+                destination: Some(server.clone()),
+            };
+            // The first lookup starts the fetch, rather than waiting for it.
+            assert_matches!(
+                conn.certs(manager.as_ref()).await,
+                Err(Error::CertsPending(id)) if id == conn.source
+            );
+            manager.fetch_certificate(&conn.source).await.unwrap();
+
+            let certs = conn.certs(manager.as_ref()).await.unwrap();
+            let handshake =
+                tls::loopback_handshake(&certs, &server_certs, conn.destination.as_ref().unwrap())
+                    .await
+                    .unwrap();
+            // The server sees the certificate of the source of the connection.
+            assert_eq!(handshake.client_identities, vec![conn.source.clone()]);
+            assert_eq!(handshake.server_identities, vec![server.clone()]);
+        }
+    }
+}
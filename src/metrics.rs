@@ -17,7 +17,9 @@ use std::mem;
 use prometheus_client::registry::Registry;
 use tracing::error;
 
+pub mod identity;
 mod meta;
+mod tls;
 #[allow(non_camel_case_types)]
 pub mod traffic;
 pub mod xds;
@@ -28,6 +30,7 @@ pub struct Metrics {
     #[allow(dead_code)]
     meta: meta::Metrics,
     traffic: traffic::Metrics,
+    tls: tls::Metrics,
 }
 
 impl Metrics {
@@ -36,6 +39,7 @@ impl Metrics {
             xds: xds::Metrics::new(registry),
             meta: meta::Metrics::new(registry),
             traffic: traffic::Metrics::new(registry),
+            tls: tls::Metrics::new(registry),
         }
     }
 }
@@ -0,0 +1,212 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+
+use crate::identity::{self, Identity};
+use crate::tls;
+
+/// Metrics about workload certificates, owned by the SecretManager. Since the SecretManager is
+/// created before the metrics registry, the metrics are registered separately with `register`.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    pub(crate) cert_expiry_seconds: Family<CertLabels, Gauge>,
+    pub(crate) cert_rotations: Family<CertLabels, Counter>,
+    pub(crate) cert_rotation_failures: Family<CertRotationFailure, Counter>,
+    pub(crate) cert_forced_refreshes: Family<CertLabels, Counter>,
+    pub(crate) certs_served_near_expiry: Family<CertLabels, Counter>,
+    pub(crate) cached_identities: Gauge,
+    pub(crate) cert_prefetch_hits: Counter,
+    pub(crate) cert_on_demand_fetches: Counter,
+    pub(crate) cert_requested_lifetime_seconds: Gauge,
+    pub(crate) cert_granted_lifetime_seconds: Family<CertLabels, Gauge>,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct CertLabels {
+    pub identity: Identity,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct CertRotationFailure {
+    pub identity: Identity,
+    pub reason: CertRotationFailureReason,
+}
+
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, EncodeLabelValue)]
+pub enum CertRotationFailureReason {
+    Signing,
+    SigningRequest,
+    InvalidResponse,
+    SanMismatch,
+    KeyMismatch,
+    UntrustedChain,
+    InvalidValidity,
+    Other,
+}
+
+impl From<&identity::Error> for CertRotationFailureReason {
+    fn from(err: &identity::Error) -> Self {
+        match err {
+            identity::Error::Signing(_) => CertRotationFailureReason::Signing,
+            identity::Error::SigningRequest(_) => CertRotationFailureReason::SigningRequest,
+            identity::Error::SanError(_) => CertRotationFailureReason::SanMismatch,
+            identity::Error::InvalidCertificate(tls::Error::KeyCertMismatch) => {
+                CertRotationFailureReason::KeyMismatch
+            }
+            identity::Error::InvalidCertificate(
+                tls::Error::UntrustedChain(_) | tls::Error::BrokenChain { .. },
+            ) => CertRotationFailureReason::UntrustedChain,
+            identity::Error::InvalidCertificate(tls::Error::CertificateValidity(_))
+            | identity::Error::CertLifetimeExceeded(..) => {
+                CertRotationFailureReason::InvalidValidity
+            }
+            identity::Error::Utf8(_)
+            | identity::Error::EmptyResponse(_)
+            | identity::Error::InvalidCertificate(_) => CertRotationFailureReason::InvalidResponse,
+            _ => CertRotationFailureReason::Other,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "cert_expiry_seconds",
+            "The number of seconds until the workload certificate expires",
+            self.cert_expiry_seconds.clone(),
+        );
+        registry.register(
+            "cert_rotations",
+            "The total number of successful workload certificate rotations",
+            self.cert_rotations.clone(),
+        );
+        registry.register(
+            "cert_rotation_failures",
+            "The total number of failed workload certificate rotations",
+            self.cert_rotation_failures.clone(),
+        );
+        registry.register(
+            "cert_forced_refreshes",
+            "The total number of workload certificates refreshed because they no longer chained \
+             to a trusted root",
+            self.cert_forced_refreshes.clone(),
+        );
+        registry.register(
+            "certs_served_near_expiry",
+            "The total number of times a workload certificate close to expiry was handed out, \
+             because it could not be refreshed",
+            self.certs_served_near_expiry.clone(),
+        );
+        registry.register(
+            "cached_identities",
+            "The number of identities with a cached workload certificate",
+            self.cached_identities.clone(),
+        );
+        registry.register(
+            "cert_prefetch_hits",
+            "The total number of connections served a prefetched workload certificate",
+            self.cert_prefetch_hits.clone(),
+        );
+        registry.register(
+            "cert_on_demand_fetches",
+            "The total number of connections that waited for a workload certificate to be fetched",
+            self.cert_on_demand_fetches.clone(),
+        );
+        registry.register(
+            "cert_requested_lifetime_seconds",
+            "The lifetime requested from the CA for workload certificates",
+            self.cert_requested_lifetime_seconds.clone(),
+        );
+        registry.register(
+            "cert_granted_lifetime_seconds",
+            "The lifetime of the last workload certificate issued by the CA",
+            self.cert_granted_lifetime_seconds.clone(),
+        );
+    }
+
+    pub(crate) fn record_rotation(&self, id: &Identity) {
+        self.cert_rotations
+            .get_or_create(&CertLabels {
+                identity: id.to_owned(),
+            })
+            .inc();
+    }
+
+    pub(crate) fn record_rotation_failure(&self, id: &Identity, err: &identity::Error) {
+        self.cert_rotation_failures
+            .get_or_create(&CertRotationFailure {
+                identity: id.to_owned(),
+                reason: err.into(),
+            })
+            .inc();
+    }
+
+    pub(crate) fn record_forced_refresh(&self, id: &Identity) {
+        self.cert_forced_refreshes
+            .get_or_create(&CertLabels {
+                identity: id.to_owned(),
+            })
+            .inc();
+    }
+
+    pub(crate) fn record_near_expiry(&self, id: &Identity) {
+        self.certs_served_near_expiry
+            .get_or_create(&CertLabels {
+                identity: id.to_owned(),
+            })
+            .inc();
+    }
+
+    pub(crate) fn record_lifetime(
+        &self,
+        id: &Identity,
+        requested: Option<Duration>,
+        granted: Duration,
+    ) {
+        if let Some(requested) = requested {
+            self.cert_requested_lifetime_seconds
+                .set(requested.as_secs() as i64);
+        }
+        self.cert_granted_lifetime_seconds
+            .get_or_create(&CertLabels {
+                identity: id.to_owned(),
+            })
+            .set(granted.as_secs() as i64);
+    }
+
+    pub(crate) fn record_cached(&self, count: usize) {
+        self.cached_identities.set(count as i64);
+    }
+
+    // Records the time left until the certificate expires. Expired certificates are reported as a
+    // negative value.
+    pub(crate) fn record_expiry(&self, id: &Identity, remaining: Result<Duration, Duration>) {
+        let seconds = match remaining {
+            Ok(d) => d.as_secs() as i64,
+            Err(d) => -(d.as_secs() as i64),
+        };
+        self.cert_expiry_seconds
+            .get_or_create(&CertLabels {
+                identity: id.to_owned(),
+            })
+            .set(seconds);
+    }
+}
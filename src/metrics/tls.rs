@@ -0,0 +1,269 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+use crate::identity::Identity;
+use crate::tls::{HandshakeDirection, HandshakeFailureClass, ShedReason, TlsMetrics};
+
+pub(super) struct Metrics {
+    pub(super) handshake_duration: Family<Handshake, Histogram, fn() -> Histogram>,
+    pub(super) handshakes: Family<HandshakeOutcome, Counter>,
+    pub(super) negotiated: Family<Negotiated, Counter>,
+    pub(super) drain_rejected: Family<Handshake, Counter>,
+    pub(super) idle_closed: Family<Handshake, Counter>,
+    pub(super) denied: Family<DeniedConnection, Counter>,
+    pub(super) limited: Family<LimitedConnection, Counter>,
+    pub(super) abandoned: Family<Handshake, Counter>,
+    pub(super) shed: Family<ShedConnection, Counter>,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct Handshake {
+    pub direction: Direction,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct HandshakeOutcome {
+    pub direction: Direction,
+    /// The class of the failure, or "none" for successful handshakes.
+    pub failure: String,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct DeniedConnection {
+    pub direction: Direction,
+    pub reason: String,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct LimitedConnection {
+    pub direction: Direction,
+    pub identity: String,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct ShedConnection {
+    pub direction: Direction,
+    pub reason: String,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct Negotiated {
+    pub direction: Direction,
+    pub version: String,
+    pub cipher: String,
+}
+
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, EncodeLabelValue)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl From<HandshakeDirection> for Direction {
+    fn from(direction: HandshakeDirection) -> Self {
+        match direction {
+            HandshakeDirection::Inbound => Direction::Inbound,
+            HandshakeDirection::Outbound => Direction::Outbound,
+        }
+    }
+}
+
+fn duration_histogram() -> Histogram {
+    // From 1ms to ~4s.
+    Histogram::new(exponential_buckets(0.001, 2.0, 13))
+}
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let handshake_duration =
+            Family::<Handshake, Histogram, fn() -> Histogram>::new_with_constructor(
+                duration_histogram,
+            );
+        registry.register(
+            "tls_handshake_duration_seconds",
+            "The duration of TLS handshakes, successful or not",
+            handshake_duration.clone(),
+        );
+        let handshakes = Family::default();
+        registry.register(
+            "tls_handshakes",
+            "The total number of TLS handshakes, by outcome",
+            handshakes.clone(),
+        );
+        let negotiated = Family::default();
+        registry.register(
+            "tls_connections",
+            "The total number of TLS connections established, by negotiated version and cipher",
+            negotiated.clone(),
+        );
+
+        let drain_rejected = Family::default();
+        registry.register(
+            "tls_drain_rejected_connections",
+            "The total number of connections refused, or handshakes cut short, while draining",
+            drain_rejected.clone(),
+        );
+
+        let idle_closed = Family::default();
+        registry.register(
+            "tls_idle_closed_connections",
+            "The total number of TLS connections closed after being idle for too long",
+            idle_closed.clone(),
+        );
+
+        let denied = Family::default();
+        registry.register(
+            "tls_denied_connections",
+            "The total number of TLS connections closed after their handshake as they were not authorized",
+            denied.clone(),
+        );
+
+        let limited = Family::default();
+        registry.register(
+            "tls_limited_connections",
+            "The total number of TLS connections closed after their handshake as their client identity had too many connections open",
+            limited.clone(),
+        );
+
+        let abandoned = Family::default();
+        registry.register(
+            "tls_abandoned_accepts",
+            "The total number of connections closed by their client before the TLS handshake started",
+            abandoned.clone(),
+        );
+
+        let shed = Family::default();
+        registry.register(
+            "tls_shed_connections",
+            "The total number of connections refused before their TLS handshake because of resource pressure",
+            shed.clone(),
+        );
+
+        Self {
+            handshake_duration,
+            handshakes,
+            negotiated,
+            drain_rejected,
+            idle_closed,
+            denied,
+            limited,
+            abandoned,
+            shed,
+        }
+    }
+}
+
+impl TlsMetrics for super::Metrics {
+    fn handshake_duration(&self, direction: HandshakeDirection, duration: Duration) {
+        self.tls
+            .handshake_duration
+            .get_or_create(&Handshake {
+                direction: direction.into(),
+            })
+            .observe(duration.as_secs_f64());
+    }
+
+    fn handshake_succeeded(&self, direction: HandshakeDirection, version: &str, cipher: &str) {
+        self.tls
+            .handshakes
+            .get_or_create(&HandshakeOutcome {
+                direction: direction.into(),
+                failure: "none".to_string(),
+            })
+            .inc();
+        self.tls
+            .negotiated
+            .get_or_create(&Negotiated {
+                direction: direction.into(),
+                version: version.to_string(),
+                cipher: cipher.to_string(),
+            })
+            .inc();
+    }
+
+    fn handshake_failed(&self, direction: HandshakeDirection, class: HandshakeFailureClass) {
+        self.tls
+            .handshakes
+            .get_or_create(&HandshakeOutcome {
+                direction: direction.into(),
+                failure: class.to_string(),
+            })
+            .inc();
+    }
+
+    fn drain_rejected(&self, direction: HandshakeDirection) {
+        self.tls
+            .drain_rejected
+            .get_or_create(&Handshake {
+                direction: direction.into(),
+            })
+            .inc();
+    }
+
+    fn idle_closed(&self, direction: HandshakeDirection) {
+        self.tls
+            .idle_closed
+            .get_or_create(&Handshake {
+                direction: direction.into(),
+            })
+            .inc();
+    }
+
+    fn connection_denied(&self, direction: HandshakeDirection, reason: &str) {
+        self.tls
+            .denied
+            .get_or_create(&DeniedConnection {
+                direction: direction.into(),
+                reason: reason.to_string(),
+            })
+            .inc();
+    }
+
+    fn connection_limited(&self, direction: HandshakeDirection, identity: &Identity) {
+        self.tls
+            .limited
+            .get_or_create(&LimitedConnection {
+                direction: direction.into(),
+                identity: identity.to_string(),
+            })
+            .inc();
+    }
+
+    fn accept_abandoned(&self, direction: HandshakeDirection) {
+        self.tls
+            .abandoned
+            .get_or_create(&Handshake {
+                direction: direction.into(),
+            })
+            .inc();
+    }
+
+    fn accept_shed(&self, direction: HandshakeDirection, reason: ShedReason) {
+        self.tls
+            .shed
+            .get_or_create(&ShedConnection {
+                direction: direction.into(),
+                reason: reason.to_string(),
+            })
+            .inc();
+    }
+}
@@ -59,6 +59,7 @@ pub(super) struct ProxyInputs {
     workloads: WorkloadInformation,
     metrics: Arc<Metrics>,
     pool: pool::Pool,
+    connectors: tls::ConnectorCache,
 }
 
 impl Proxy {
@@ -75,6 +76,7 @@ impl Proxy {
             cert_manager,
             metrics,
             pool: pool::Pool::new(),
+            connectors: Default::default(),
             hbone_port: 0,
         };
         // We setup all the listeners first so we can capture any errors that should block startup
@@ -16,7 +16,7 @@ use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 use drain::Watch;
@@ -29,15 +29,18 @@ use tokio::net::{TcpListener, TcpStream};
 use tracing::{debug, error, info, instrument, trace, trace_span, warn, Instrument};
 
 use crate::baggage::parse_baggage_header;
-use crate::config::Config;
-use crate::identity::SecretManager;
+use crate::config::{Config, InboundPassthrough};
+use crate::identity::{Identity, SecretManager};
 use crate::metrics::traffic::{ConnectionOpen, Reporter};
 use crate::metrics::{traffic, Metrics, Recorder};
 use crate::proxy::inbound::InboundConnect::{DirectPath, Hbone};
 use crate::proxy::{ProxyInputs, TraceParent, BAGGAGE_HEADER, TRACEPARENT_HEADER};
 use crate::rbac::Connection;
 use crate::socket::to_canonical;
-use crate::tls::TlsError;
+use crate::tls::{
+    Accept, AcceptMeta, DrainSignal, IdleTimeoutStream, LoadShedPolicy, MaybeTls, TlsError,
+    WorkloadCertResolver,
+};
 use crate::workload::{
     address, gatewayaddress, GatewayAddress, NetworkAddress, Workload, WorkloadInformation,
 };
@@ -45,6 +48,12 @@ use crate::{proxy, rbac};
 
 use super::Error;
 
+// How long TLS handshakes in flight when draining starts are given to complete.
+const HANDSHAKE_DRAIN_GRACE: Duration = Duration::from_secs(5);
+
+// How often the resident memory is sampled for load shedding.
+const SHED_MEMORY_INTERVAL: Duration = Duration::from_secs(1);
+
 pub(super) struct Inbound {
     cfg: Config,
     listener: TcpListener,
@@ -85,19 +94,59 @@ impl Inbound {
     pub(super) async fn run(self) {
         // let (tx, rx) = oneshot::channel();
         let acceptor = InboundCertProvider {
-            workloads: self.workloads.clone(),
             cert_manager: self.cert_manager.clone(),
-            network: self.cfg.network.clone(),
+            resolver: Arc::new(WorkloadStoreCertResolver {
+                workloads: self.workloads.clone(),
+                network: self.cfg.network.clone(),
+            }),
+            passthrough: self.cfg.inbound_passthrough.clone(),
         };
         let workloads = self.workloads;
-        let drain_stream = self.drain.clone();
-        let stream = crate::hyper_util::tls_server(acceptor, self.listener);
-        let mut stream = stream.take_until(Box::pin(drain_stream.signaled()));
+        // Once drain is signaled, new connections are refused, and the stream ends when
+        // handshakes in flight completed or were given up on.
+        let tls_drain = DrainSignal::default();
+        tokio::spawn({
+            let tls_drain = tls_drain.clone();
+            let drain = self.drain.clone();
+            async move {
+                let _release = drain.signaled().await;
+                tls_drain.drain(HANDSHAKE_DRAIN_GRACE);
+            }
+        });
+        let shed = self.cfg.load_shed.clone().map(|cfg| {
+            let policy = LoadShedPolicy::new(cfg);
+            policy.watch_memory(SHED_MEMORY_INTERVAL);
+            policy
+        });
+        let mut stream = Box::pin(crate::hyper_util::passthrough_tls_server(
+            acceptor,
+            self.listener,
+            Some(self.metrics.clone()),
+            tls_drain,
+            self.cfg.socket,
+            self.cfg.identity_limits.clone(),
+            shed,
+        ));
         while let Some(socket) = stream.next().await {
             let workloads = workloads.clone();
             let metrics = self.metrics.clone();
             let drain = self.drain.clone();
             let network = self.cfg.network.clone();
+            let socket = match socket {
+                MaybeTls::Tls(socket) => socket,
+                MaybeTls::Passthrough(conn) => {
+                    let enable_original_source = self.cfg.enable_original_source;
+                    tokio::task::spawn(Self::serve_passthrough(
+                        conn,
+                        workloads,
+                        network,
+                        enable_original_source.unwrap_or_default(),
+                        metrics,
+                    ));
+                    continue;
+                }
+                MaybeTls::Plain(_) => unreachable!("plaintext clients are rejected"),
+            };
             tokio::task::spawn(async move {
                 let dst = crate::socket::orig_dst_addr_or_default(socket.get_ref());
                 let conn = rbac::Connection {
@@ -110,6 +159,11 @@ impl Inbound {
                     dst,
                 };
                 debug!(%conn, "accepted connection");
+                let socket = IdleTimeoutStream::new(
+                    socket,
+                    self.cfg.inbound_idle_timeout,
+                    Some(metrics.clone()),
+                );
                 let enable_original_source = self.cfg.enable_original_source;
                 let serve = crate::hyper_util::http2_server()
                     .initial_stream_window_size(self.cfg.window_size)
@@ -143,6 +197,67 @@ impl Inbound {
         info!("all inbound connections drained");
     }
 
+    /// serve_passthrough proxies a connection to a passthrough port to its original destination,
+    /// without TLS. RBAC still applies, to a connection without a peer identity.
+    #[instrument(name="inbound_passthrough", skip_all, fields(peer_ip=?conn.peer_addr().ok()))]
+    async fn serve_passthrough(
+        conn: TcpStream,
+        workloads: WorkloadInformation,
+        network: String,
+        enable_original_source: bool,
+        metrics: Arc<Metrics>,
+    ) {
+        let Ok(src) = conn.peer_addr() else {
+            return;
+        };
+        let conn_info = rbac::Connection {
+            src_identity: None,
+            src_ip: to_canonical(src).ip(),
+            dst_network: network.clone(), // inbound request must be on our network
+            dst: crate::socket::orig_dst_addr_or_default(&conn),
+        };
+        debug!(conn=%conn_info, "accepted passthrough connection");
+        if !workloads.assert_rbac(&conn_info).await {
+            info!(conn=%conn_info, "RBAC rejected");
+            return;
+        }
+        let source = workloads
+            .fetch_workload(&NetworkAddress {
+                network: network.clone(),
+                address: conn_info.src_ip,
+            })
+            .await;
+        let destination = workloads
+            .fetch_workload(&NetworkAddress {
+                network,
+                address: conn_info.dst.ip(),
+            })
+            .await;
+        let connection_metrics = traffic::ConnectionOpen {
+            reporter: Reporter::destination,
+            source,
+            derived_source: None,
+            destination,
+            connection_security_policy: traffic::SecurityPolicy::unknown,
+            destination_service: None,
+            destination_service_namespace: None,
+            destination_service_name: None,
+        };
+        if let Err(e) = Self::handle_inbound(
+            DirectPath(conn),
+            enable_original_source.then_some(conn_info.src_ip),
+            conn_info.dst,
+            metrics,
+            connection_metrics,
+            None,
+        )
+        .in_current_span()
+        .await
+        {
+            warn!(conn=%conn_info, "passthrough connection failed: {e}");
+        }
+    }
+
     /// handle_inbound serves an inbound connection with a target address `addr`.
     pub(super) async fn handle_inbound(
         request_type: InboundConnect,
@@ -451,28 +566,53 @@ pub(super) enum InboundConnect {
     Hbone(Request<Incoming>),
 }
 
+/// WorkloadStoreCertResolver resolves the identity of the workload the connection is destined to.
 #[derive(Clone)]
-struct InboundCertProvider {
-    cert_manager: Arc<SecretManager>,
+struct WorkloadStoreCertResolver {
     workloads: WorkloadInformation,
     network: String,
 }
 
 #[async_trait::async_trait]
-impl crate::tls::CertProvider for InboundCertProvider {
-    async fn fetch_cert(&mut self, fd: &TcpStream) -> Result<boring::ssl::SslAcceptor, TlsError> {
-        let orig_dst_addr = crate::socket::orig_dst_addr_or_default(fd);
-        let identity = {
-            let wip = NetworkAddress {
-                network: self.network.clone(), // inbound cert provider gets cert for the dest, which must be on our network
-                address: orig_dst_addr.ip(),
-            };
-            self.workloads
-                .fetch_workload(&wip)
-                .await
-                .ok_or(TlsError::CertificateLookup(wip))?
-                .identity()
+impl WorkloadCertResolver for WorkloadStoreCertResolver {
+    async fn resolve(
+        &self,
+        dst: SocketAddr,
+        orig_dst: Option<SocketAddr>,
+    ) -> Result<Identity, TlsError> {
+        let wip = NetworkAddress {
+            network: self.network.clone(), // inbound cert provider gets cert for the dest, which must be on our network
+            address: to_canonical(orig_dst.unwrap_or(dst)).ip(),
         };
+        Ok(self
+            .workloads
+            .fetch_workload(&wip)
+            .await
+            .ok_or(TlsError::CertificateLookup(wip))?
+            .identity())
+    }
+}
+
+#[derive(Clone)]
+struct InboundCertProvider {
+    cert_manager: Arc<SecretManager>,
+    resolver: Arc<dyn WorkloadCertResolver>,
+    /// Original destinations whose connections skip TLS.
+    passthrough: InboundPassthrough,
+}
+
+#[async_trait::async_trait]
+impl crate::tls::CertProvider for InboundCertProvider {
+    async fn fetch_cert(&mut self, _: &TcpStream, meta: &AcceptMeta) -> Result<Accept, TlsError> {
+        let orig_dst_addr = meta.destination();
+        if self.passthrough.matches(orig_dst_addr) {
+            return Ok(Accept::Passthrough);
+        }
+        let identity = self
+            .resolver
+            .resolve(meta.local, Some(orig_dst_addr))
+            .await?;
+        tracing::Span::current().record("identity", tracing::field::display(&identity));
         debug!(
             destination=?orig_dst_addr,
             %identity,
@@ -480,6 +620,140 @@ impl crate::tls::CertProvider for InboundCertProvider {
         );
         let cert = self.cert_manager.fetch_certificate(&identity).await?;
         let acc = cert.mtls_acceptor(Some(&identity))?;
-        Ok(acc)
+        Ok(acc.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::config::InboundPassthrough;
+    use crate::identity::{self, Identity};
+    use crate::tls::{BoringTlsAcceptor, MaybeTls, StaticCertResolver, TlsError};
+
+    use super::InboundCertProvider;
+
+    #[tokio::test]
+    async fn resolve_inbound_cert() {
+        let id = Identity::default();
+        let cert_manager = identity::mock::new_secret_manager(Duration::from_secs(100));
+        let client = cert_manager.fetch_certificate(&id).await.unwrap();
+        let handshake = |identities: HashMap<IpAddr, Identity>| {
+            let acceptor = BoringTlsAcceptor {
+                acceptor: InboundCertProvider {
+                    cert_manager: cert_manager.clone(),
+                    resolver: Arc::new(StaticCertResolver {
+                        network: "network".to_string(),
+                        identities,
+                    }),
+                    passthrough: Default::default(),
+                },
+                metrics: None,
+                drain: Default::default(),
+                authorizer: None,
+                limiter: None,
+                socket: Default::default(),
+                shed: None,
+                sni_routing: false,
+            };
+            let mut cfg = client.connector(&id).unwrap().configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let tcp = TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+                let (conn, _) = listener.accept().await.unwrap();
+                let client = tokio::spawn(tokio_boring::connect(cfg, "", tcp));
+                let res = acceptor.accept_maybe_tls(conn, false).await;
+                (res, client.await.unwrap().is_ok())
+            }
+        };
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+
+        // Connections to 127.0.0.1 are served the certificate of id.
+        let (res, client_ok) = handshake(HashMap::from([(localhost, id.clone())])).await;
+        assert!(res.is_ok());
+        assert!(client_ok);
+
+        // No identity is known for 127.0.0.1.
+        let (res, client_ok) = handshake(HashMap::new()).await;
+        match res {
+            Err(TlsError::CertificateLookup(addr)) => {
+                assert_eq!(addr.network, "network");
+                assert_eq!(addr.address, localhost);
+            }
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(_) => panic!("expected an error"),
+        }
+        assert!(!client_ok);
+    }
+
+    #[tokio::test]
+    async fn passthrough_ports() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let id = Identity::default();
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        let passthrough_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let passthrough_addr = passthrough_listener.local_addr().unwrap();
+        let acceptor = BoringTlsAcceptor {
+            acceptor: InboundCertProvider {
+                cert_manager: identity::mock::new_secret_manager(Duration::from_secs(100)),
+                resolver: Arc::new(StaticCertResolver {
+                    network: "network".to_string(),
+                    identities: HashMap::from([(localhost, id)]),
+                }),
+                passthrough: InboundPassthrough {
+                    ports: vec![passthrough_addr.port()],
+                    cidrs: vec!["127.0.0.0/8".parse().unwrap()],
+                },
+            },
+            metrics: None,
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        };
+        const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+        // Connections to the passthrough port are returned untouched, without waiting for the
+        // client to send anything.
+        let mut client = TcpStream::connect(passthrough_addr).await.unwrap();
+        let (conn, _) = passthrough_listener.accept().await.unwrap();
+        let mut conn = match acceptor.accept_maybe_tls(conn, false).await {
+            Ok(MaybeTls::Passthrough(conn)) => conn,
+            Ok(_) => panic!("connection was not passed through"),
+            Err(e) => panic!("unexpected error {e}"),
+        };
+        conn.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        client.write_all(REQUEST).await.unwrap();
+        let mut buf = vec![0; REQUEST.len()];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, REQUEST);
+
+        // Other ports enforce mTLS.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.write_all(REQUEST).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            acceptor.accept_maybe_tls(conn, false).await,
+            Err(TlsError::NotTls)
+        ));
     }
 }
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::time::Instant;
 
 use boring::ssl::ConnectConfiguration;
@@ -25,14 +26,15 @@ use tokio::net::{TcpListener, TcpStream};
 use tracing::{debug, error, info, info_span, trace, trace_span, warn, Instrument};
 
 use crate::config::ProxyMode;
-use crate::identity::Identity;
+use crate::identity::{self, ConnectionIdentity, Identity};
 use crate::metrics::traffic;
 use crate::metrics::traffic::Reporter;
+use crate::metrics::Metrics;
 use crate::proxy::inbound::{Inbound, InboundConnect};
 use crate::proxy::pool;
 use crate::proxy::{util, Error, ProxyInputs, TraceParent, BAGGAGE_HEADER, TRACEPARENT_HEADER};
 use crate::workload::{NetworkAddress, Protocol, Workload};
-use crate::{hyper_util, proxy, rbac, socket};
+use crate::{hyper_util, proxy, rbac, socket, tls};
 
 pub struct Outbound {
     pi: ProxyInputs,
@@ -230,13 +232,14 @@ impl OutboundConnection {
                     req.destination, req.gateway, req.request_type
                 );
 
-                let dst_identity = req
-                    .expected_identity
+                let conn_id = req.identity();
+                let dst_identity = conn_id
+                    .destination
                     .as_ref()
                     .expect("hbone requires destination workload");
 
                 let pool_key = pool::Key {
-                    src_id: req.source.identity(),
+                    src_id: conn_id.source.clone(),
                     dst_id: dst_identity.clone(),
                     dst: req.gateway,
                 };
@@ -257,15 +260,18 @@ impl OutboundConnection {
                         .enable_original_source
                         .unwrap_or_default()
                         .then_some(remote_addr);
-                    let id = &req.source.identity();
-                    let cert = self.pi.cert_manager.fetch_certificate(id).await?;
-                    let connector = cert
-                        .connector(dst_identity)?
-                        .configure()
-                        .expect("configure");
+                    let cert = self.source_certs(&conn_id).await?;
+                    let connector = self.pi.connectors.connect_config(&cert, dst_identity)?;
                     let tcp_stream = super::freebind_connect(local, req.gateway).await?;
                     tcp_stream.set_nodelay(true)?; // TODO: this is backwards of expectations
-                    let tls_stream = connect_tls(connector, tcp_stream).await?;
+                    let tls_stream = connect_tls(
+                        connector,
+                        tcp_stream,
+                        dst_identity,
+                        &self.pi.cfg.socket,
+                        &self.pi.metrics,
+                    )
+                    .await?;
                     let (request_sender, connection) = builder
                         .handshake(tls_stream)
                         .await
@@ -338,6 +344,17 @@ impl OutboundConnection {
         }
     }
 
+    // Returns the certificate of the source of conn, waiting for it if it is still being fetched.
+    async fn source_certs(&self, conn: &ConnectionIdentity) -> Result<Arc<tls::Certs>, Error> {
+        match conn.certs(self.pi.cert_manager.as_ref()).await {
+            Err(identity::Error::CertsPending(id)) => {
+                debug!(%id, "waiting for the certificate of the source identity");
+                Ok(Arc::new(self.pi.cert_manager.fetch_certificate(&id).await?))
+            }
+            res => Ok(res?),
+        }
+    }
+
     async fn build_request(
         &self,
         downstream: IpAddr,
@@ -478,6 +495,17 @@ struct Request {
     request_type: RequestType,
 }
 
+impl Request {
+    // The identities the connection to the next hop is made with: the certificate of the source
+    // workload is presented, and the expected identity is asserted of the peer.
+    fn identity(&self) -> ConnectionIdentity {
+        ConnectionIdentity {
+            source: self.source.identity(),
+            destination: self.expected_identity.clone(),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum Direction {
     Inbound,
@@ -499,10 +527,13 @@ enum RequestType {
 pub async fn connect_tls(
     mut connector: ConnectConfiguration,
     stream: TcpStream,
+    dest: &Identity,
+    socket: &socket::SocketConfig,
+    metrics: &Metrics,
 ) -> Result<tokio_boring::SslStream<TcpStream>, tokio_boring::HandshakeError<TcpStream>> {
     connector.set_verify_hostname(false);
     connector.set_use_server_name_indication(false);
-    tokio_boring::connect(connector, "", stream).await
+    crate::tls::connect(connector, stream, &dest.into(), socket, Some(metrics)).await
 }
 
 #[cfg(test)]
@@ -559,6 +590,7 @@ mod tests {
                 cfg,
                 metrics: Arc::new(Default::default()),
                 pool: pool::Pool::new(),
+                connectors: Default::default(),
             },
             id: TraceParent::new(),
         };
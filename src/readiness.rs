@@ -14,31 +14,77 @@
 
 use crate::telemetry;
 use std::collections::HashSet;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 use tracing::info;
 mod server;
 pub use server::*;
 
+type Check = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
 /// Ready tracks whether the process is ready.
-#[derive(Clone, Debug, Default)]
-pub struct Ready(Arc<Mutex<HashSet<String>>>);
+#[derive(Clone, Default)]
+pub struct Ready {
+    pending: Arc<Mutex<HashSet<String>>>,
+    // Conditions that must hold for the process to stay ready, after its tasks completed.
+    checks: Arc<Mutex<Vec<(String, Check)>>>,
+}
+
+impl fmt::Debug for Ready {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let checks: Vec<_> = self
+            .checks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(n, _)| n.clone())
+            .collect();
+        f.debug_struct("Ready")
+            .field("pending", &self.pending)
+            .field("checks", &checks)
+            .finish()
+    }
+}
 
 impl Ready {
     pub fn new() -> Ready {
-        Ready(Default::default())
+        Ready::default()
     }
 
     /// register_task allows a caller to add a dependency to be marked "ready".
     pub fn register_task(&self, name: &str) -> BlockReady {
-        self.0.lock().unwrap().insert(name.to_string());
+        self.pending.lock().unwrap().insert(name.to_string());
         BlockReady {
             parent: self.to_owned(),
             name: name.to_string(),
         }
     }
 
+    /// register_check adds a condition evaluated on every readiness probe. The process is not
+    /// ready while it returns an error.
+    pub fn register_check(
+        &self,
+        name: &str,
+        check: impl Fn() -> Result<(), String> + Send + Sync + 'static,
+    ) {
+        self.checks
+            .lock()
+            .unwrap()
+            .push((name.to_string(), Arc::new(check)));
+    }
+
     pub fn pending(&self) -> HashSet<String> {
-        self.0.lock().unwrap().clone()
+        self.pending.lock().unwrap().clone()
+    }
+
+    /// failing returns the name and error of the checks that currently fail.
+    pub fn failing(&self) -> Vec<(String, String)> {
+        // Don't run the checks with the lock held, they may be slow.
+        let checks = self.checks.lock().unwrap().clone();
+        checks
+            .into_iter()
+            .filter_map(|(name, check)| check().err().map(|e| (name, e)))
+            .collect()
     }
 }
 
@@ -56,7 +102,7 @@ impl BlockReady {
 
 impl Drop for BlockReady {
     fn drop(&mut self) {
-        let mut pending = self.parent.0.lock().unwrap();
+        let mut pending = self.parent.pending.lock().unwrap();
         let removed = pending.remove(&self.name);
         debug_assert!(removed); // It is a bug to somehow remove something twice
         let left = pending.len();
@@ -57,16 +57,29 @@ async fn handle_ready(ready: &readiness::Ready, req: Request<Incoming>) -> Respo
     match *req.method() {
         hyper::Method::GET => {
             let pending = ready.pending();
-            if pending.is_empty() {
-                return plaintext_response(hyper::StatusCode::OK, "ready\n".into());
+            if !pending.is_empty() {
+                return plaintext_response(
+                    hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "not ready, pending: {}\n",
+                        pending.into_iter().sorted().join(", ")
+                    ),
+                );
             }
-            plaintext_response(
-                hyper::StatusCode::INTERNAL_SERVER_ERROR,
-                format!(
-                    "not ready, pending: {}\n",
-                    pending.into_iter().sorted().join(", ")
-                ),
-            )
+            let failing = ready.failing();
+            if !failing.is_empty() {
+                return plaintext_response(
+                    hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "not ready, failing: {}\n",
+                        failing
+                            .into_iter()
+                            .map(|(name, err)| format!("{name} ({err})"))
+                            .join(", ")
+                    ),
+                );
+            }
+            plaintext_response(hyper::StatusCode::OK, "ready\n".into())
         }
         _ => empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
     }
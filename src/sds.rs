@@ -0,0 +1,487 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Envoy SDS (secret discovery) server, serving the workload certificates managed by ztunnel to
+//! proxies on the same node over a unix domain socket.
+//!
+//! Resource names are either a SPIFFE identity (`spiffe://td/ns/ns/sa/sa`), answered with the
+//! certificate and private key of that identity, or a trust domain (`spiffe://td`), answered with
+//! the roots of the trust domain. Roots are taken from the certificates of the trust domain that
+//! are already cached, so a trust domain resolves once one of its identities has been requested.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use drain::Watch;
+use futures::Stream;
+use prost::Message;
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{error, info, warn};
+
+use crate::identity::{CertState, Identity, SecretManager};
+use crate::tls;
+use crate::xds::extensions::transport_sockets::tls::v3::{
+    data_source, secret, CertificateValidationContext, DataSource, Secret, TlsCertificate,
+};
+use crate::xds::service::discovery::v3::{DiscoveryRequest, DiscoveryResponse};
+use crate::xds::service::secret::v3::secret_discovery_service_server::{
+    SecretDiscoveryService, SecretDiscoveryServiceServer,
+};
+use crate::xds::SECRET_TYPE;
+
+pub struct Service {
+    path: PathBuf,
+    listener: UnixListener,
+    server: SdsServer,
+    drain_rx: Watch,
+}
+
+impl Service {
+    /// new binds the SDS socket at path, replacing a stale socket left by a previous run. Only the
+    /// owner may connect to the socket, other users on the node are denied by its permissions.
+    pub fn new(
+        path: PathBuf,
+        secret_manager: Arc<SecretManager>,
+        drain_rx: Watch,
+    ) -> anyhow::Result<Self> {
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Service {
+            path,
+            listener,
+            server: SdsServer::new(secret_manager),
+            drain_rx,
+        })
+    }
+
+    pub fn spawn(self) {
+        let Service {
+            path,
+            listener,
+            server,
+            drain_rx,
+        } = self;
+        let srv = SecretDiscoveryServiceServer::new(server);
+        info!(path=%path.display(), component="sds", "listener established");
+        tokio::spawn(async move {
+            let drained = drain_rx.signaled();
+            tokio::pin!(drained);
+            loop {
+                let socket = tokio::select! {
+                    _ = &mut drained => break,
+                    res = listener.accept() => match res {
+                        Ok((socket, _)) => socket,
+                        Err(e) => {
+                            error!("failed to accept sds connection: {e}");
+                            continue;
+                        }
+                    },
+                };
+                let srv = srv.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = crate::hyper_util::http2_server()
+                        .serve_connection(
+                            socket,
+                            tower_hyper_http_body_compat::TowerService03HttpServiceAsHyper1HttpService::new(srv),
+                        )
+                        .await
+                    {
+                        warn!("error serving sds connection: {err:?}");
+                    }
+                });
+            }
+            let _ = std::fs::remove_file(&path);
+            info!(path=%path.display(), component="sds", "listener drained");
+        });
+    }
+}
+
+#[derive(Clone)]
+pub struct SdsServer {
+    secret_manager: Arc<SecretManager>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Resource {
+    Certificate(Identity),
+    TrustDomain(String),
+}
+
+impl FromStr for Resource {
+    type Err = Status;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = Identity::from_str(name) {
+            return Ok(Resource::Certificate(id));
+        }
+        match name.strip_prefix("spiffe://") {
+            Some(td) if !td.is_empty() && !td.contains('/') => {
+                Ok(Resource::TrustDomain(td.to_string()))
+            }
+            _ => Err(Status::invalid_argument(format!(
+                "unsupported resource name {name:?}"
+            ))),
+        }
+    }
+}
+
+impl SdsServer {
+    pub fn new(secret_manager: Arc<SecretManager>) -> Self {
+        SdsServer { secret_manager }
+    }
+
+    async fn secret(&self, name: &str) -> Result<Secret, Status> {
+        let secret = match name.parse()? {
+            Resource::Certificate(id) => {
+                let certs = self
+                    .secret_manager
+                    .fetch_certificate(&id)
+                    .await
+                    .map_err(|e| Status::unavailable(e.to_string()))?;
+                secret::Type::TlsCertificate(tls_certificate(&certs)?)
+            }
+            Resource::TrustDomain(td) => {
+                let roots = self.roots(&td).await?;
+                secret::Type::ValidationContext(CertificateValidationContext {
+                    trusted_ca: Some(inline_bytes(roots)),
+                })
+            }
+        };
+        Ok(Secret {
+            name: name.to_string(),
+            r#type: Some(secret),
+        })
+    }
+
+    // Returns the PEM roots of the cached certificates in the trust domain.
+    async fn roots(&self, trust_domain: &str) -> Result<Vec<u8>, Status> {
+        let mut roots = self
+            .secret_manager
+            .collect_certs(|id, state| {
+                let Identity::Spiffe {
+                    trust_domain: td, ..
+                } = id;
+                match state {
+                    CertState::Available(certs) if td == trust_domain => {
+                        certs.iter_chain().last().map(|root| root.to_pem())
+                    }
+                    _ => None,
+                }
+            })
+            .await
+            .into_iter()
+            .flatten()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Status::internal(e.to_string()))?;
+        roots.sort();
+        roots.dedup();
+        if roots.is_empty() {
+            return Err(Status::unavailable(format!(
+                "no certificate cached for trust domain {trust_domain}"
+            )));
+        }
+        Ok(roots.concat())
+    }
+
+    async fn response(&self, names: &[String], version: u64) -> Result<DiscoveryResponse, Status> {
+        let mut resources = Vec::with_capacity(names.len());
+        for name in names {
+            let secret = self.secret(name).await?;
+            resources.push(prost_types::Any {
+                type_url: SECRET_TYPE.to_string(),
+                value: secret.encode_to_vec(),
+            });
+        }
+        Ok(DiscoveryResponse {
+            version_info: version.to_string(),
+            resources,
+            type_url: SECRET_TYPE.to_string(),
+            nonce: version.to_string(),
+            ..Default::default()
+        })
+    }
+
+    // Subscribes to changes of the certificates backing the resources.
+    async fn watch(&self, names: &[String]) -> Result<Vec<watch::Receiver<CertState>>, Status> {
+        let mut watches = Vec::new();
+        for name in names {
+            if let Resource::Certificate(id) = name.parse()? {
+                let mut rx = self
+                    .secret_manager
+                    .subscribe(&id)
+                    .await
+                    .map_err(|e| Status::unavailable(e.to_string()))?;
+                rx.borrow_and_update();
+                watches.push(rx);
+            }
+        }
+        Ok(watches)
+    }
+
+    // Serves the discovery requests of a stream, pushing a new response whenever the requested
+    // resources change or one of their certificates is rotated.
+    fn stream(
+        &self,
+        mut requests: impl Stream<Item = Result<DiscoveryRequest, Status>> + Send + Unpin + 'static,
+    ) -> ReceiverStream<Result<DiscoveryResponse, Status>> {
+        let (tx, rx) = mpsc::channel(16);
+        let server = self.clone();
+        tokio::spawn(async move {
+            let mut names: Vec<String> = Vec::new();
+            let mut watches = Vec::new();
+            let mut version = 0;
+            loop {
+                tokio::select! {
+                    req = requests.next() => match req {
+                        Some(Ok(req)) => {
+                            if let Some(status) = req.error_detail {
+                                warn!("sds update for {:?} rejected: {}", names, status.message);
+                            }
+                            // Requests carrying a nonce with unchanged resources acknowledge the
+                            // previous response.
+                            if !req.response_nonce.is_empty() && req.resource_names == names {
+                                continue;
+                            }
+                            names = req.resource_names;
+                            match server.watch(&names).await {
+                                Ok(w) => watches = w,
+                                Err(status) => {
+                                    let _ = tx.send(Err(status)).await;
+                                    return;
+                                }
+                            }
+                        }
+                        None => return,
+                        Some(Err(status)) => {
+                            warn!("sds stream failed: {status}");
+                            return;
+                        }
+                    },
+                    (i, ok) = changed(&mut watches) => {
+                        if !ok {
+                            // The certificate is no longer managed, resubscribe to keep serving
+                            // it.
+                            match server.watch(&names).await {
+                                Ok(w) => watches = w,
+                                Err(status) => {
+                                    let _ = tx.send(Err(status)).await;
+                                    return;
+                                }
+                            }
+                        } else if !matches!(*watches[i].borrow(), CertState::Available(_)) {
+                            // Failed refreshes keep the current certificate, only push updates.
+                            continue;
+                        }
+                    },
+                }
+                version += 1;
+                let res = server.response(&names, version).await;
+                let failed = res.is_err();
+                if tx.send(res).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        ReceiverStream::new(rx)
+    }
+}
+
+// Converts Certs to the certificate chain (leaf first, without the root) and private key expected
+// by Envoy.
+fn tls_certificate(certs: &tls::Certs) -> Result<TlsCertificate, Status> {
+    let internal = |e: &dyn std::error::Error| Status::internal(e.to_string());
+    let mut chain = certs.x509().to_pem().map_err(|e| internal(&e))?;
+    let intermediates = certs.iter_chain().count().saturating_sub(1);
+    for cert in certs.iter_chain().take(intermediates) {
+        chain.extend(cert.to_pem().map_err(|e| internal(&e))?);
+    }
+    // Keys held by a key engine cannot be handed out.
+    let key = certs
+        .private_key()
+        .in_memory()
+        .ok_or_else(|| Status::failed_precondition("private key is held by a key engine"))?
+        .private_key_to_pem_pkcs8()
+        .map_err(|e| internal(&e))?;
+    Ok(TlsCertificate {
+        certificate_chain: Some(inline_bytes(chain)),
+        private_key: Some(inline_bytes(key)),
+    })
+}
+
+fn inline_bytes(data: Vec<u8>) -> DataSource {
+    DataSource {
+        specifier: Some(data_source::Specifier::InlineBytes(data)),
+    }
+}
+
+// Resolves once any of the watched certificates changes, returning its index.
+async fn changed(watches: &mut [watch::Receiver<CertState>]) -> (usize, bool) {
+    if watches.is_empty() {
+        return futures::future::pending().await;
+    }
+    let (res, i, _) =
+        futures::future::select_all(watches.iter_mut().map(|w| Box::pin(w.changed()))).await;
+    (i, res.is_ok())
+}
+
+#[tonic::async_trait]
+impl SecretDiscoveryService for SdsServer {
+    type StreamSecretsStream =
+        Pin<Box<dyn Stream<Item = Result<DiscoveryResponse, Status>> + Send>>;
+
+    async fn stream_secrets(
+        &self,
+        request: Request<Streaming<DiscoveryRequest>>,
+    ) -> Result<Response<Self::StreamSecretsStream>, Status> {
+        Ok(Response::new(Box::pin(self.stream(request.into_inner()))))
+    }
+
+    async fn fetch_secrets(
+        &self,
+        request: Request<DiscoveryRequest>,
+    ) -> Result<Response<DiscoveryResponse>, Status> {
+        let req = request.into_inner();
+        self.response(&req.resource_names, 1)
+            .await
+            .map(Response::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use prost::Message;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tokio_stream::StreamExt;
+    use tonic::Request;
+
+    use crate::identity::{self, Identity};
+    use crate::xds::extensions::transport_sockets::tls::v3::{data_source, secret, Secret};
+    use crate::xds::service::discovery::v3::{DiscoveryRequest, DiscoveryResponse};
+    use crate::xds::service::secret::v3::secret_discovery_service_server::SecretDiscoveryService;
+    use crate::xds::SECRET_TYPE;
+
+    use super::{Resource, SdsServer};
+
+    fn secrets(res: &DiscoveryResponse) -> Vec<Secret> {
+        res.resources
+            .iter()
+            .map(|any| {
+                assert_eq!(any.type_url, SECRET_TYPE);
+                Secret::decode(any.value.as_slice()).unwrap()
+            })
+            .collect()
+    }
+
+    fn certificate_chain(secret: &Secret) -> Vec<u8> {
+        let Some(secret::Type::TlsCertificate(cert)) = &secret.r#type else {
+            panic!("not a certificate: {secret:?}");
+        };
+        let Some(data_source::Specifier::InlineBytes(chain)) = cert
+            .certificate_chain
+            .as_ref()
+            .and_then(|c| c.specifier.clone())
+        else {
+            panic!("no certificate chain: {secret:?}");
+        };
+        chain
+    }
+
+    #[test]
+    fn resource_names() {
+        assert_eq!(
+            "spiffe://cluster.local/ns/istio-system/sa/ztunnel"
+                .parse::<Resource>()
+                .unwrap(),
+            Resource::Certificate(Identity::default())
+        );
+        assert_eq!(
+            "spiffe://cluster.local".parse::<Resource>().unwrap(),
+            Resource::TrustDomain("cluster.local".to_string())
+        );
+        assert!("default".parse::<Resource>().is_err());
+        assert!("spiffe://cluster.local/ns".parse::<Resource>().is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_secrets() {
+        let secret_manager = identity::mock::new_secret_manager(Duration::from_secs(10));
+        let server = SdsServer::new(secret_manager.clone());
+        let id = Identity::default();
+        let res = server
+            .fetch_secrets(Request::new(DiscoveryRequest {
+                resource_names: vec![id.to_string(), "spiffe://cluster.local".to_string()],
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let secrets = secrets(&res);
+        assert_eq!(secrets.len(), 2);
+        let certs = secret_manager.fetch_certificate(&id).await.unwrap();
+        assert_eq!(
+            certificate_chain(&secrets[0]),
+            certs.x509().to_pem().unwrap()
+        );
+        assert!(matches!(
+            secrets[1].r#type,
+            Some(secret::Type::ValidationContext(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_pushes_rotated_certificate() {
+        let secret_manager = identity::mock::new_secret_manager(Duration::from_secs(10));
+        let server = SdsServer::new(secret_manager.clone());
+        let id = Identity::default();
+
+        let (tx, rx) = mpsc::channel(4);
+        let mut responses = server.stream(ReceiverStream::new(rx).map(Ok));
+
+        let req = DiscoveryRequest {
+            resource_names: vec![id.to_string()],
+            type_url: SECRET_TYPE.to_string(),
+            ..Default::default()
+        };
+        tx.send(req.clone()).await.unwrap();
+        let first = responses.next().await.unwrap().unwrap();
+        let initial = certificate_chain(&secrets(&first)[0]);
+
+        // Acknowledging the response does not trigger another one.
+        tx.send(DiscoveryRequest {
+            version_info: first.version_info.clone(),
+            response_nonce: first.nonce.clone(),
+            ..req
+        })
+        .await
+        .unwrap();
+
+        assert!(secret_manager.force_refresh(&id).await);
+        let second = responses.next().await.unwrap().unwrap();
+        assert_ne!(second.version_info, first.version_info);
+        assert_ne!(certificate_chain(&secrets(&second)[0]), initial);
+    }
+}
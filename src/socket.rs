@@ -14,6 +14,8 @@
 
 use std::io::Error;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use tokio::io;
 use tokio::net::TcpListener;
@@ -22,7 +24,7 @@ use tokio::net::TcpSocket;
 #[cfg(target_os = "linux")]
 use {
     realm_io,
-    socket2::{Domain, SockRef},
+    socket2::{Domain, SockAddr, SockRef},
     std::io::ErrorKind,
     tracing::warn,
 };
@@ -49,59 +51,173 @@ pub fn set_freebind_and_transparent(socket: &TcpSocket) -> io::Result<()> {
     Ok(())
 }
 
+/// SocketConfig tunes the TCP connections TLS is run over: it is applied to dialed connections
+/// before their handshake, and to accepted ones before they are wrapped. Options left unset keep
+/// the system defaults.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketConfig {
+    /// TCP_NODELAY, disabling Nagle's algorithm.
+    pub nodelay: Option<bool>,
+    pub keepalive: Option<Keepalive>,
+    /// SO_MARK, for policy routing. Requires CAP_NET_ADMIN; without it, the mark is skipped with a
+    /// warning.
+    pub mark: Option<u32>,
+    /// IP_TOS for IPv4, or IPV6_TCLASS for IPv6.
+    pub tos: Option<u32>,
+}
+
+/// Keepalive enables TCP keepalive probes once a connection is idle for idle, every interval, and
+/// closes it after retries unanswered probes. Unset values keep the system defaults.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Option<Duration>,
+    pub retries: Option<u32>,
+}
+
+// Set once SO_MARK was denied, to warn only once and stop trying.
+static MARK_DENIED: AtomicBool = AtomicBool::new(false);
+
+impl SocketConfig {
+    pub fn apply(&self, stream: &tokio::net::TcpStream) -> io::Result<()> {
+        let sock = socket2::SockRef::from(stream);
+        if let Some(nodelay) = self.nodelay {
+            sock.set_nodelay(nodelay)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            sock.set_tcp_keepalive(&keepalive.into())?;
+        }
+        if let Some(tos) = self.tos {
+            match stream.local_addr()? {
+                SocketAddr::V4(_) => sock.set_tos(tos)?,
+                #[cfg(target_os = "linux")]
+                SocketAddr::V6(_) => linux::set_ipv6_tclass(&sock, tos)?,
+                #[cfg(not(target_os = "linux"))]
+                SocketAddr::V6(_) => {}
+            }
+        }
+        if let Some(mark) = self.mark {
+            set_mark(&sock, mark)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&Keepalive> for socket2::TcpKeepalive {
+    fn from(keepalive: &Keepalive) -> Self {
+        let ka = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+        #[cfg(target_os = "linux")]
+        let ka = match keepalive.interval {
+            Some(interval) => ka.with_interval(interval),
+            None => ka,
+        };
+        #[cfg(target_os = "linux")]
+        let ka = match keepalive.retries {
+            Some(retries) => ka.with_retries(retries),
+            None => ka,
+        };
+        ka
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(sock: &SockRef, mark: u32) -> io::Result<()> {
+    if MARK_DENIED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    match sock.set_mark(mark) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            if !MARK_DENIED.swap(true, Ordering::Relaxed) {
+                warn!("cannot set SO_MARK without CAP_NET_ADMIN, connections are not marked");
+            }
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_: &socket2::SockRef, _: u32) -> io::Result<()> {
+    if !MARK_DENIED.swap(true, Ordering::Relaxed) {
+        tracing::warn!("SO_MARK is not supported on this operating system");
+    }
+    Ok(())
+}
+
 pub fn to_canonical(addr: SocketAddr) -> SocketAddr {
-    // another match has to be used for IPv4 and IPv6 support
-    // @zhlsunshine TODO: to_canonical() should be used when it becomes stable a function in Rust
-    let ip = match addr.ip() {
-        IpAddr::V4(_) => return addr,
+    match addr {
+        SocketAddr::V4(_) => addr,
+        SocketAddr::V6(_) => SocketAddr::from((to_canonical_ip(addr.ip()), addr.port())),
+    }
+}
+
+/// to_canonical_ip converts an IPv4-mapped IPv6 address, as reported by dual-stack sockets, to the
+/// IPv4 address. Other addresses are returned as is.
+pub fn to_canonical_ip(ip: IpAddr) -> IpAddr {
+    // @zhlsunshine TODO: IpAddr::to_canonical() should be used once it is stable in our MSRV
+    match ip {
+        IpAddr::V4(_) => ip,
         IpAddr::V6(i) => match i.octets() {
             [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
                 IpAddr::V4(Ipv4Addr::new(a, b, c, d))
             }
-            _ => return addr,
+            _ => ip,
         },
-    };
-    SocketAddr::from((ip, addr.port()))
+    }
 }
 
 pub fn orig_dst_addr_or_default(stream: &tokio::net::TcpStream) -> SocketAddr {
-    to_canonical(match orig_dst_addr(stream) {
+    match orig_dst(stream) {
         Ok(addr) => addr,
-        _ => stream.local_addr().expect("must get local address"),
-    })
+        Err(e) => {
+            #[cfg(target_os = "linux")]
+            warn!(
+                peer=?stream.peer_addr().ok(),
+                local=?stream.local_addr().ok(),
+                "failed to read SO_ORIGINAL_DST: {e:?}"
+            );
+            #[cfg(not(target_os = "linux"))]
+            let _ = e;
+            to_canonical(stream.local_addr().expect("must get local address"))
+        }
+    }
 }
 
+/// orig_dst returns the address a connection was sent to before it was redirected to ztunnel.
+/// Connections redirected with REDIRECT carry it in SO_ORIGINAL_DST; with TPROXY, the socket is
+/// transparent and bound to it. Fails if the connection was not redirected, or on platforms
+/// without either, so callers typically fall back to the local address.
 #[cfg(target_os = "linux")]
-fn orig_dst_addr(stream: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
+pub fn orig_dst(stream: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
     let sock = SockRef::from(stream);
     // Dual-stack IPv4/IPv6 sockets require us to check both options.
-    match linux::original_dst(&sock) {
-        Ok(addr) => Ok(addr.as_socket().expect("failed to convert to SocketAddr")),
-        Err(e4) => match linux::original_dst_ipv6(&sock) {
-            Ok(addr) => Ok(addr.as_socket().expect("failed to convert to SocketAddr")),
-            Err(e6) => {
-                if !sock.ip_transparent().unwrap_or(false) {
-                    // In TPROXY mode, this is normal, so don't bother logging
-                    warn!(
-                        peer=?stream.peer_addr().unwrap(),
-                        local=?stream.local_addr().unwrap(),
-                        "failed to read SO_ORIGINAL_DST: {e4:?}, {e6:?}"
-                    );
-                }
-                Err(e6)
-            }
-        },
+    match linux::original_dst(&sock).or_else(|_| linux::original_dst_ipv6(&sock)) {
+        Ok(addr) => from_sockaddr(&addr),
+        Err(_) if sock.ip_transparent().unwrap_or(false) => stream.local_addr().map(to_canonical),
+        Err(e) => Err(e),
     }
 }
 
 #[cfg(not(target_os = "linux"))]
-fn orig_dst_addr(_: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
+pub fn orig_dst(_: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
     Err(io::Error::new(
         io::ErrorKind::Other,
         "SO_ORIGINAL_DST not supported on this operating system",
     ))
 }
 
+// Converts an original destination read from the kernel, which is only meaningful for IPv4 and
+// IPv6.
+#[cfg(target_os = "linux")]
+fn from_sockaddr(addr: &SockAddr) -> io::Result<SocketAddr> {
+    addr.as_socket().map(to_canonical).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "original destination is not an IP address",
+        )
+    })
+}
+
 #[cfg(not(target_os = "linux"))]
 pub fn set_freebind_and_transparent(_: &TcpSocket) -> io::Result<()> {
     Err(io::Error::new(
@@ -143,6 +259,23 @@ mod linux {
         Ok(())
     }
 
+    pub fn set_ipv6_tclass(sock: &SockRef, tclass: u32) -> io::Result<()> {
+        unsafe {
+            let optval = tclass as libc::c_int;
+            let ret = libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::IPPROTO_IPV6,
+                libc::IPV6_TCLASS,
+                &optval as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&optval) as libc::socklen_t,
+            );
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
     pub fn original_dst(sock: &SockRef) -> io::Result<SockAddr> {
         sock.original_dst()
     }
@@ -175,3 +308,80 @@ pub async fn relay(
 ) -> Result<(u64, u64), Error> {
     tokio::io::copy_bidirectional(downstream, upstream).await
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{orig_dst, orig_dst_addr_or_default, Keepalive, SocketConfig};
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn from_sockaddr() {
+        use socket2::SockAddr;
+
+        use super::from_sockaddr;
+
+        let v4: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        assert_eq!(from_sockaddr(&SockAddr::from(v4)).unwrap(), v4);
+        let v6: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        assert_eq!(from_sockaddr(&SockAddr::from(v6)).unwrap(), v6);
+        // Dual-stack sockets report IPv4 destinations as mapped IPv6 addresses.
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:8080".parse().unwrap();
+        assert_eq!(from_sockaddr(&SockAddr::from(mapped)).unwrap(), v4);
+        let unix = SockAddr::unix("/tmp/ztunnel.sock").unwrap();
+        assert!(from_sockaddr(&unix).is_err());
+    }
+
+    #[tokio::test]
+    async fn orig_dst_fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+
+        // Connections that were not redirected have no original destination, and are taken as
+        // sent to the local address.
+        assert!(orig_dst(&conn).is_err());
+        assert_eq!(orig_dst_addr_or_default(&conn), addr);
+    }
+    #[tokio::test]
+    async fn socket_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+
+        // Unset options leave the socket untouched.
+        SocketConfig::default().apply(&client).unwrap();
+        assert!(!client.nodelay().unwrap());
+
+        let cfg = SocketConfig {
+            nodelay: Some(true),
+            keepalive: Some(Keepalive {
+                idle: Duration::from_secs(30),
+                interval: Some(Duration::from_secs(5)),
+                retries: Some(3),
+            }),
+            // Denied without CAP_NET_ADMIN, which is not an error.
+            mark: Some(0x539),
+            tos: None,
+        };
+        for stream in [&client, &conn] {
+            cfg.apply(stream).unwrap();
+            let sock = socket2::SockRef::from(stream);
+            assert!(sock.nodelay().unwrap());
+            assert!(sock.keepalive().unwrap());
+            assert_eq!(sock.keepalive_time().unwrap(), Duration::from_secs(30));
+            #[cfg(target_os = "linux")]
+            {
+                assert_eq!(sock.keepalive_interval().unwrap(), Duration::from_secs(5));
+                assert_eq!(sock.keepalive_retries().unwrap(), 3);
+            }
+        }
+    }
+}
@@ -12,7 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
@@ -24,7 +26,9 @@ use tracing::error;
 
 use crate::config::RootCert;
 
-use crate::identity::{AuthSource, CaClient};
+use crate::identity::{
+    AuthSource, CaAuth, CaClient, Identity, TokenProvider, CA_AUDIENCE_METADATA,
+};
 use crate::xds::istio::ca::istio_certificate_service_server::{
     IstioCertificateService, IstioCertificateServiceServer,
 };
@@ -37,17 +41,102 @@ use crate::{
 #[derive(Clone)]
 pub struct CaServer {
     response: watch::Receiver<Result<IstioCertificateResponse, tonic::Status>>,
+    // When set, requests not carrying this audience are rejected.
+    audience: Option<String>,
+    signer: Option<Signer>,
+    latency: Duration,
+    // Number of upcoming requests to fail.
+    failures: Arc<AtomicU32>,
+    malformed_chain: bool,
+}
+
+/// Signer makes the server sign the CSRs it receives with the test root, instead of returning the
+/// mocked response.
+#[derive(Clone, Debug)]
+pub struct Signer {
+    /// The identity certificates are issued for, regardless of the one requested.
+    pub identity: Identity,
+    pub lifetime: Duration,
+}
+
+impl Default for Signer {
+    fn default() -> Self {
+        Signer {
+            identity: Identity::default(),
+            lifetime: Duration::from_secs(100),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CaServerOptions {
+    /// Audience the server requires on requests.
+    pub expected_audience: Option<String>,
+    /// Audience the client sends.
+    pub audience: Option<String>,
+    pub signer: Option<Signer>,
+    /// Delay before each response.
+    pub latency: Duration,
+    /// Number of requests, starting with the first one, that fail with an unavailable status.
+    pub failures: u32,
+    /// Replace the root of signed chains with an unparsable certificate.
+    pub malformed_chain: bool,
+    /// Serve plaintext on this unix socket, instead of TLS on a local TCP port.
+    pub uds: Option<PathBuf>,
 }
 
 impl CaServer {
     pub async fn spawn() -> (
         watch::Sender<Result<IstioCertificateResponse, tonic::Status>>,
         CaClient,
+    ) {
+        Self::spawn_with(Default::default()).await
+    }
+
+    pub async fn spawn_with(
+        opts: CaServerOptions,
+    ) -> (
+        watch::Sender<Result<IstioCertificateResponse, tonic::Status>>,
+        CaClient,
     ) {
         let default = Err(tonic::Status::not_found("mock not set"));
         let (tx, rx) = watch::channel(default);
 
-        let server = CaServer { response: rx };
+        let server = CaServer {
+            response: rx,
+            audience: opts.expected_audience,
+            signer: opts.signer,
+            latency: opts.latency,
+            failures: Arc::new(AtomicU32::new(opts.failures)),
+            malformed_chain: opts.malformed_chain,
+        };
+        let srv = IstioCertificateServiceServer::new(server);
+        let (address, root_cert) = match &opts.uds {
+            Some(path) => {
+                Self::serve_uds(srv, path);
+                (format!("unix://{}", path.display()), RootCert::Default)
+            }
+            None => Self::serve_tls(srv).await,
+        };
+        let client = CaClient::new(
+            vec![tls::Endpoint { address, root_cert }],
+            CaAuth::new(
+                TokenProvider::new(
+                    AuthSource::Token(PathBuf::from(r"src/test_helpers/fake-jwt")),
+                    Duration::ZERO,
+                ),
+                opts.audience.as_deref(),
+                &Default::default(),
+            )
+            .unwrap(),
+            true,
+            Default::default(),
+        )
+        .unwrap();
+        (tx, client)
+    }
+
+    async fn serve_tls(srv: IstioCertificateServiceServer<CaServer>) -> (String, RootCert) {
         let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
         let server_addr = listener.local_addr().unwrap();
         let certs = tls::generate_test_certs(
@@ -57,8 +146,7 @@ impl CaServer {
         );
         let root_cert = RootCert::Static(certs.chain().unwrap());
         let acceptor = tls::ControlPlaneCertProvider(certs);
-        let mut tls_stream = crate::hyper_util::tls_server(acceptor, listener);
-        let srv = IstioCertificateServiceServer::new(server);
+        let mut tls_stream = crate::hyper_util::tls_server(acceptor, listener, None);
         tokio::spawn(async move {
             while let Some(socket) = tls_stream.next().await {
                 let srv = srv.clone();
@@ -73,22 +161,64 @@ impl CaServer {
                 }
             }
         });
-        let client = CaClient::new(
-            "https://".to_string() + &server_addr.to_string(),
-            root_cert,
-            AuthSource::Token(PathBuf::from(r"src/test_helpers/fake-jwt")),
-            true,
-        )
-        .unwrap();
-        (tx, client)
+        ("https://".to_string() + &server_addr.to_string(), root_cert)
+    }
+
+    fn serve_uds(srv: IstioCertificateServiceServer<CaServer>, path: &Path) {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let srv = srv.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = crate::hyper_util::http2_server()
+                        .serve_connection(
+                            socket,
+                            tower_hyper_http_body_compat::TowerService03HttpServiceAsHyper1HttpService::new(srv)
+                        )
+                        .await
+                    {
+                        error!("Error serving connection: {:?}", err);
+                    }
+                });
+            }
+        });
     }
 }
 #[async_trait]
 impl IstioCertificateService for CaServer {
     async fn create_certificate(
         &self,
-        _request: tonic::Request<IstioCertificateRequest>,
+        request: tonic::Request<IstioCertificateRequest>,
     ) -> Result<tonic::Response<IstioCertificateResponse>, tonic::Status> {
+        if let Some(audience) = &self.audience {
+            let sent = request.metadata().get(CA_AUDIENCE_METADATA);
+            if sent.and_then(|aud| aud.to_str().ok()) != Some(audience) {
+                return Err(tonic::Status::unauthenticated("unexpected audience"));
+            }
+        }
+        tokio::time::sleep(self.latency).await;
+        let fail = self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if fail {
+            return Err(tonic::Status::unavailable("injected failure"));
+        }
+        if let Some(signer) = &self.signer {
+            let mut cert_chain = tls::sign_test_csr(
+                request.get_ref().csr.as_bytes(),
+                &signer.identity,
+                signer.lifetime,
+            );
+            if self.malformed_chain {
+                *cert_chain.last_mut().unwrap() =
+                    "-----BEGIN CERTIFICATE-----\nbm90IGEgY2VydA==\n-----END CERTIFICATE-----\n"
+                        .to_string();
+            }
+            return Ok(tonic::Response::new(IstioCertificateResponse {
+                cert_chain,
+            }));
+        }
         let b = self.response.borrow();
         match &*b {
             Ok(res) => Ok(tonic::Response::new(res.clone())),
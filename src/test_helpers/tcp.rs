@@ -198,7 +198,7 @@ impl HboneTestServer {
             Duration::from_secs(100),
         );
         let acceptor = tls::ControlPlaneCertProvider(certs);
-        let mut tls_stream = crate::hyper_util::tls_server(acceptor, self.listener);
+        let mut tls_stream = crate::hyper_util::tls_server(acceptor, self.listener, None);
         let mode = self.mode;
         while let Some(socket) = tls_stream.next().await {
             if let Err(err) = http2::Builder::new(TokioExecutor)
@@ -69,7 +69,7 @@ impl AdsServer {
         let root_cert = RootCert::Static(certs.chain().unwrap());
         let acceptor = tls::ControlPlaneCertProvider(certs);
         let listener_addr_string = "https://".to_string() + &server_addr.to_string();
-        let mut tls_stream = crate::hyper_util::tls_server(acceptor, listener);
+        let mut tls_stream = crate::hyper_util::tls_server(acceptor, listener, None);
         let srv = AggregatedDiscoveryServiceServer::new(server);
         tokio::spawn(async move {
             while let Some(socket) = tls_stream.next().await {
@@ -14,6 +14,22 @@
 
 use std::time::{Instant, SystemTime};
 
+/// Clock tells the current time. It allows code that compares SystemTimes, such as certificate
+/// expiry checks, to run against a controlled time in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// SystemClock is the real clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
 #[derive(Clone)]
 pub struct Converter {
     now: Instant,
@@ -64,6 +80,14 @@ impl Converter {
     }
 }
 
+/// A Converter is a clock following tokio's clock, so it advances with paused time in tests.
+impl Clock for Converter {
+    fn now(&self) -> SystemTime {
+        self.instant_to_system_time(tokio::time::Instant::now().into_std())
+            .unwrap_or(self.sys_now)
+    }
+}
+
 impl Default for Converter {
     fn default() -> Self {
         Self::new()
@@ -83,4 +107,15 @@ mod tests {
         let later = conv.system_time_to_instant(sys_now + DELAY);
         assert_eq!(later, Some(now + DELAY));
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn converter_clock_follows_paused_time() {
+        use super::Clock;
+
+        const DELAY: Duration = Duration::from_secs(3600);
+        let conv = super::Converter::new();
+        let start = conv.now();
+        tokio::time::sleep(DELAY).await;
+        assert_eq!(conv.now().duration_since(start).unwrap(), DELAY);
+    }
 }
@@ -12,24 +12,226 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// BoringSSL is the only TLS backend: it is re-exported here, used by the proxy and identity layers,
+// and always linked. The tls-rustls feature adds crate::tls::rustls, a rustls implementation of
+// certificate handling and the control plane client for code that wants to use rustls there. It
+// does not replace BoringSSL, which stays a required dependency.
+pub mod aliases;
+pub mod authorize;
 pub mod boring;
+pub mod buffers;
+pub mod check;
+#[cfg(test)]
+mod conformance;
+pub mod connector;
+pub mod copy;
+pub mod cork;
+pub mod dns;
+pub mod explain;
+pub mod failover;
+pub mod idle;
+pub mod key_provider;
+pub mod limit;
+pub mod listener;
+pub mod metrics;
+pub mod offload;
+pub mod pool;
+pub mod retry;
+pub mod root_store;
+pub mod runtime;
+#[cfg(feature = "tls-rustls")]
+pub mod rustls;
+#[cfg(test)]
+mod san_cases;
+pub mod shed;
+pub mod sni;
+pub mod trust_bundle;
+pub mod validate;
+pub mod xfcc;
 
+#[cfg(all(feature = "tls-rustls", feature = "fips"))]
+compile_error!(
+    "feature \"tls-rustls\" cannot be combined with \"fips\", its crypto is not FIPS validated"
+);
+
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+pub use crate::tls::aliases::*;
+pub use crate::tls::authorize::*;
 pub use crate::tls::boring::*;
+pub use crate::tls::buffers::*;
+pub use crate::tls::check::*;
+pub use crate::tls::connector::*;
+pub use crate::tls::copy::*;
+pub use crate::tls::cork::*;
+pub use crate::tls::dns::*;
+pub use crate::tls::explain::*;
+pub use crate::tls::failover::*;
+pub use crate::tls::idle::*;
+pub use crate::tls::key_provider::*;
+pub use crate::tls::limit::*;
+pub use crate::tls::listener::*;
+pub use crate::tls::metrics::*;
+pub use crate::tls::offload::*;
+pub use crate::tls::pool::*;
+pub use crate::tls::retry::*;
+pub use crate::tls::root_store::*;
+pub use crate::tls::runtime::*;
+pub use crate::tls::shed::*;
+pub use crate::tls::sni::*;
+pub use crate::tls::trust_bundle::*;
+pub use crate::tls::validate::*;
+pub use crate::tls::xfcc::*;
 use ::boring::error::ErrorStack;
 use hyper::http::uri::InvalidUri;
 
+use crate::identity::Identity;
+
+/// UDS_SCHEME_PREFIX marks control plane addresses that are unix domain socket paths. They are
+/// handled separately, as they cannot be parsed as a Uri.
+pub const UDS_SCHEME_PREFIX: &str = "unix://";
+
 #[derive(thiserror::Error, Debug, Clone)]
 pub enum Error {
-    #[error("invalid operation: {0:?}")]
+    #[error("invalid operation: {}", Explained(.0))]
     SslError(#[from] ErrorStack),
 
-    #[error("invalid root certificate: {0}")]
-    InvalidRootCert(ErrorStack),
+    #[error("failed to parse root certificate: {0}")]
+    RootCertParse(#[source] ErrorStack),
 
     #[error("invalid uri: {0}")]
     InvalidUri(#[from] Arc<InvalidUri>),
+
+    #[error("root certificate cannot be used with unix domain socket {0:?}")]
+    UdsRootCert(PathBuf),
+
+    #[error("no root certificates found")]
+    RootCertEmpty,
+
+    #[error("failed to read root certificate directory {0:?}: {1}")]
+    RootCertDirectory(PathBuf, String),
+
+    #[error("failed to read root certificate {0:?}: {1}")]
+    RootCertIo(PathBuf, #[source] Arc<std::io::Error>),
+
+    #[error("unsupported key type: {0:?}")]
+    UnsupportedKeyType(KeyType),
+
+    #[error("invalid proxy: {0}")]
+    InvalidProxy(String),
+
+    #[error("invalid ALPN setting {0:?}, expected h2, h2,http/1.1 or none")]
+    InvalidAlpn(String),
+
+    #[error("invalid private key: {0}")]
+    InvalidPrivateKey(ErrorStack),
+
+    #[error("invalid certificate: {0}")]
+    InvalidCertificate(ErrorStack),
+
+    #[error("private key does not match the certificate")]
+    KeyCertMismatch,
+
+    #[error("certificate is not for {0}, got {1:?}")]
+    SanMismatch(Identity, Vec<Identity>),
+
+    #[error("certificate chain is not trusted: {0}")]
+    UntrustedChain(String),
+
+    #[error("certificate chain is broken: issuer {missing_issuer:?} is missing")]
+    BrokenChain { missing_issuer: String },
+
+    #[error("invalid certificate validity: {0}")]
+    CertificateValidity(String),
+
+    #[error("failed to decrypt private key: incorrect passphrase")]
+    KeyPassphrase,
+
+    #[error("invalid pkcs12 bundle: {0}")]
+    InvalidPkcs12(ErrorStack),
+
+    #[error("pkcs12 bundle has no private key for the leaf certificate")]
+    Pkcs12MissingKey,
+
+    #[error("pkcs12 bundle has no certificate chain")]
+    Pkcs12MissingChain,
+
+    #[error("tls handshake has not completed")]
+    HandshakeIncomplete,
+
+    #[error("key engine failed: {0}")]
+    KeyEngine(String),
+
+    #[error("private key {1:?} from engine {0:?} does not match the certificate: {2}")]
+    EngineKeyMismatch(String, String, String),
+
+    #[error("FIPS mode is required, but the TLS library was not built with FIPS support")]
+    FipsUnavailable,
+
+    #[error("key provides {0} bits of security, security level {1} requires at least {2}")]
+    SecurityLevel(u32, u32, u32),
+
+    #[error("weak certificate: {reason}")]
+    WeakCertificate { reason: String },
+
+    #[error("invalid key type {0:?}, expected EC_P256, EC_P384, RSA_2048, RSA_3072 or RSA_4096")]
+    InvalidKeyType(String),
+
+    #[error("invalid signature algorithms {0:?}: {1}")]
+    InvalidSigalgs(String, ErrorStack),
+
+    #[error("invalid cipher list {0:?}: {1}")]
+    InvalidCipherList(String, ErrorStack),
+
+    #[error("invalid tls runtime config {0:?}: {1}")]
+    RuntimeConfig(PathBuf, String),
+
+    #[error("key generation did not complete: {0}")]
+    KeyGeneration(String),
+}
+
+impl Error {
+    /// code returns a stable identifier of the kind of error, usable as a metric label. Codes are
+    /// never changed once added.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::SslError(_) => "SSL",
+            Error::RootCertParse(_) => "INVALID_ROOT_CERT",
+            Error::InvalidUri(_)
+            | Error::UdsRootCert(_)
+            | Error::InvalidProxy(_)
+            | Error::InvalidAlpn(_)
+            | Error::InvalidKeyType(_)
+            | Error::InvalidSigalgs(..)
+            | Error::InvalidCipherList(..)
+            | Error::RuntimeConfig(..) => "INVALID_CONFIG",
+            Error::RootCertEmpty => "NO_ROOT_CERTS",
+            Error::RootCertDirectory(..) | Error::RootCertIo(..) => "READ_ROOT_CERT",
+            Error::UnsupportedKeyType(_) => "UNSUPPORTED_KEY_TYPE",
+            Error::InvalidPrivateKey(_) | Error::KeyPassphrase => "INVALID_KEY",
+            Error::InvalidCertificate(_) => "INVALID_CERT",
+            Error::KeyCertMismatch | Error::EngineKeyMismatch(..) => "KEY_MISMATCH",
+            Error::SanMismatch(..) => "SAN_MISMATCH",
+            Error::UntrustedChain(_) => "UNKNOWN_CA",
+            Error::BrokenChain { .. } => "BROKEN_CHAIN",
+            Error::CertificateValidity(_) => "CERT_VALIDITY",
+            Error::InvalidPkcs12(_) | Error::Pkcs12MissingKey | Error::Pkcs12MissingChain => {
+                "INVALID_PKCS12"
+            }
+            Error::HandshakeIncomplete => "HANDSHAKE_INCOMPLETE",
+            Error::KeyEngine(_) => "KEY_ENGINE",
+            Error::FipsUnavailable => "FIPS_UNAVAILABLE",
+            Error::SecurityLevel(..) => "SECURITY_LEVEL",
+            Error::WeakCertificate { .. } => "WEAK_CERTIFICATE",
+            Error::KeyGeneration(_) => "KEY_GENERATION",
+        }
+    }
+
+    // Wraps a failure to read the root certificate at path.
+    fn root_cert_io(path: &Path, err: std::io::Error) -> Self {
+        Error::RootCertIo(path.to_path_buf(), Arc::new(err))
+    }
 }
 
 impl From<InvalidUri> for Error {
@@ -0,0 +1,204 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::identity::Identity;
+
+/// TrustDomainAliases is a set of trust domains treated as one while a mesh migrates from one to
+/// another, such as cluster.local and corp.example: a peer expected as
+/// spiffe://corp.example/ns/x/sa/y is also accepted as spiffe://cluster.local/ns/x/sa/y, and the
+/// other way around. It can be updated at runtime; clones share the same set.
+#[derive(Clone, Debug, Default)]
+pub struct TrustDomainAliases {
+    domains: Arc<RwLock<Vec<String>>>,
+    // Number of peers accepted, by the trust domain of the identity they presented.
+    matched: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl TrustDomainAliases {
+    /// new returns the set of domains, typically the primary trust domain followed by its aliases.
+    pub fn new(domains: Vec<String>) -> Self {
+        let aliases = TrustDomainAliases::default();
+        aliases.set(domains);
+        aliases
+    }
+
+    /// from_file reads the trust domains from path, separated by commas or whitespace.
+    pub fn from_file(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::new(parse_domains(&std::fs::read_to_string(path)?)))
+    }
+
+    pub fn set(&self, domains: Vec<String>) {
+        *self.domains.write().unwrap() = domains;
+    }
+
+    pub fn domains(&self) -> Vec<String> {
+        self.domains.read().unwrap().clone()
+    }
+
+    /// aliases returns the other trust domains treated as trust_domain, or nothing if it is not
+    /// part of the set.
+    pub fn aliases(&self, trust_domain: &str) -> Vec<String> {
+        let domains = self.domains.read().unwrap();
+        if !domains.iter().any(|d| d == trust_domain) {
+            return Vec::new();
+        }
+        domains
+            .iter()
+            .filter(|d| *d != trust_domain)
+            .cloned()
+            .collect()
+    }
+
+    /// alias_identities returns identity under each alias of its trust domain.
+    pub fn alias_identities(&self, identity: &Identity) -> Vec<Identity> {
+        let Identity::Spiffe {
+            trust_domain,
+            namespace,
+            service_account,
+        } = identity;
+        self.aliases(trust_domain)
+            .into_iter()
+            .map(|trust_domain| Identity::Spiffe {
+                trust_domain,
+                namespace: namespace.clone(),
+                service_account: service_account.clone(),
+            })
+            .collect()
+    }
+
+    /// record_match records a peer accepted with one of identities, counting it against the first
+    /// trust domain of the set they belong to.
+    pub(super) fn record_match(&self, identities: &[Identity]) {
+        let domains = self.domains.read().unwrap();
+        let matched = identities
+            .iter()
+            .find_map(|Identity::Spiffe { trust_domain, .. }| {
+                domains.iter().find(|d| *d == trust_domain)
+            });
+        if let Some(trust_domain) = matched {
+            *self
+                .matched
+                .lock()
+                .unwrap()
+                .entry(trust_domain.clone())
+                .or_default() += 1;
+        }
+    }
+
+    /// matched returns the number of peers accepted by the trust domain they presented, to tell
+    /// how far a migration went.
+    pub fn matched(&self) -> HashMap<String, u64> {
+        self.matched.lock().unwrap().clone()
+    }
+
+    /// watch_file replaces the trust domains with those in path whenever it changes. The file is
+    /// compared every interval. Unreadable files are ignored, keeping the current trust domains.
+    pub fn watch_file(&self, path: PathBuf, interval: Duration) {
+        let domains = Arc::downgrade(&self.domains);
+        let mut contents = std::fs::read_to_string(&path).ok();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                // Stop once the aliases are dropped.
+                let Some(domains) = domains.upgrade() else {
+                    return;
+                };
+                match std::fs::read_to_string(&path) {
+                    Ok(latest) if Some(&latest) != contents.as_ref() => {
+                        info!("trust domain aliases in {path:?} changed, reloading them");
+                        *domains.write().unwrap() = parse_domains(&latest);
+                        contents = Some(latest);
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("keeping the current trust domain aliases: {e}"),
+                }
+            }
+        });
+    }
+}
+
+/// parse_domains parses trust domains separated by commas or whitespace.
+pub fn parse_domains(domains: &str) -> Vec<String> {
+    domains
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|d| !d.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::identity::Identity;
+
+    use super::{parse_domains, TrustDomainAliases};
+
+    fn identity(trust_domain: &str) -> Identity {
+        Identity::Spiffe {
+            trust_domain: trust_domain.to_string(),
+            namespace: "x".to_string(),
+            service_account: "y".to_string(),
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let aliases = TrustDomainAliases::new(parse_domains("corp.example, cluster.local\n"));
+        assert_eq!(aliases.aliases("corp.example"), vec!["cluster.local"]);
+        assert_eq!(aliases.aliases("cluster.local"), vec!["corp.example"]);
+        assert!(aliases.aliases("other.example").is_empty());
+        assert_eq!(
+            aliases.alias_identities(&identity("corp.example")),
+            vec![identity("cluster.local")]
+        );
+
+        aliases.record_match(&[identity("cluster.local")]);
+        aliases.record_match(&[identity("other.example")]);
+        assert_eq!(aliases.matched().get("cluster.local"), Some(&1));
+        assert_eq!(aliases.matched().len(), 1);
+
+        // Updates apply to every clone.
+        let clone = aliases.clone();
+        aliases.set(Vec::new());
+        assert!(clone.aliases("corp.example").is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_file() {
+        let path = std::env::temp_dir().join(format!("ztunnel-aliases-{}", rand::random::<u64>()));
+        std::fs::write(&path, "corp.example,cluster.local").unwrap();
+        let aliases = TrustDomainAliases::from_file(&path).unwrap();
+        aliases.watch_file(path.clone(), Duration::from_secs(10));
+        assert_eq!(aliases.aliases("corp.example"), vec!["cluster.local"]);
+
+        std::fs::write(&path, "corp.example").unwrap();
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(aliases.aliases("corp.example").is_empty());
+
+        // A file that went away keeps the current aliases.
+        std::fs::write(&path, "corp.example old.example").unwrap();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        std::fs::remove_file(&path).unwrap();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(aliases.aliases("corp.example"), vec!["old.example"]);
+    }
+}
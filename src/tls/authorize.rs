@@ -0,0 +1,55 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::net::SocketAddr;
+
+use crate::identity::Identity;
+
+/// ConnectionInfo describes an inbound connection whose TLS handshake completed, for
+/// AuthorizeConnection to decide on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The identities of the verified client certificate.
+    pub peer_identities: Vec<Identity>,
+    pub source: SocketAddr,
+    /// The original destination of the connection, before it was redirected to ztunnel.
+    pub destination: SocketAddr,
+    pub destination_port: u16,
+}
+
+/// Authorization is the decision of AuthorizeConnection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Authorization {
+    Allow,
+    /// Deny closes the connection. The reason is used as a metric label, so it should be one of
+    /// a few fixed values, such as the name of the policy that denied the connection.
+    Deny(String),
+}
+
+/// AuthorizeConnection decides whether an inbound connection may proceed once its TLS handshake
+/// completed, before any data is read from it. It is the place for L4 authorization: whether the
+/// client identity may reach the destination.
+pub trait AuthorizeConnection: Send + Sync {
+    fn authorize(&self, conn: &ConnectionInfo) -> Authorization;
+}
+
+/// AllowAll allows every connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllowAll;
+
+impl AuthorizeConnection for AllowAll {
+    fn authorize(&self, _: &ConnectionInfo) -> Authorization {
+        Authorization::Allow
+    }
+}
@@ -1,6 +1,8 @@
-use std::fmt::Debug;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Debug};
 use std::future::Future;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 // Copyright Istio Authors
 //
@@ -16,20 +18,27 @@ use std::pin::Pin;
 // See the License for the specific language governing permissions and
 // limitations under the License.
 use std::str::FromStr;
-use std::task::{Context, Poll};
+use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::task::{ready, Context, Poll};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use boring::asn1::{Asn1Time, Asn1TimeRef};
 use boring::bn::BigNum;
 use boring::ec::{EcGroup, EcKey};
+use boring::error::ErrorStack;
+use boring::ex_data;
 use boring::hash::MessageDigest;
 use boring::nid::Nid;
+use boring::pkcs12::Pkcs12;
 use boring::pkey;
 use boring::pkey::{PKey, Private};
+use boring::rsa::Rsa;
 use boring::ssl::{self, SslContextBuilder};
 use boring::stack::Stack;
 use boring::x509::extension::{
     AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+    SubjectKeyIdentifier,
 };
 use boring::x509::verify::X509CheckFlags;
 use boring::x509::{self, X509StoreContext, X509StoreContextRef, X509VerifyResult};
@@ -37,17 +46,32 @@ use bytes::Bytes;
 use http_body_1::{Body, Frame};
 use hyper::body::Incoming;
 use hyper::{Request, Response, Uri};
-use rand::RngCore;
+use once_cell::sync::Lazy;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::registry::Registry;
+use rand::{Rng, RngCore};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tonic::body::BoxBody;
 use tower_hyper_http_body_compat::{HttpBody04ToHttpBody1, HttpBody1ToHttpBody04};
-use tracing::{error, info};
+use tracing::{debug, debug_span, error, info, warn, Instrument};
+use zeroize::Zeroizing;
 
 use crate::config::RootCert;
 use crate::identity::{self, Identity};
+use crate::socket::{to_canonical_ip, SocketConfig};
+use crate::time::Clock;
 use crate::workload::NetworkAddress;
 
-use super::Error;
+use super::buffers::set_buffer_size;
+use super::metrics::record_handshake;
+use super::{
+    explain_handshake, peek_sni, AlpnCheckConnector, Authorization, AuthorizeConnection, ChainPins,
+    ChannelLimits, ConnectionInfo, ConnectorConfig, ControlPlaneAlpn, Error, HandshakeDirection,
+    HandshakeOffload, IdentityLimiter, IdentityLimits, IdentityPermit, LoadShedPolicy,
+    PinnedConnector, PrivateKeyProvider, ProxyConnector, RootCertStore, ShedReason, StreamGuard,
+    TlsBuffers, TlsMetrics, TlsRuntime, TlsRuntimeConfig, TrustBundle, UDS_SCHEME_PREFIX,
+};
 
 pub fn asn1_time_to_system_time(time: &Asn1TimeRef) -> SystemTime {
     let unix_time = Asn1Time::from_unix(0).unwrap().diff(time).unwrap();
@@ -60,50 +84,357 @@ fn system_time_to_asn1_time(time: SystemTime) -> Option<Asn1Time> {
     Asn1Time::from_unix(ts.try_into().ok()?).ok()
 }
 
-pub fn cert_from(key: &[u8], cert: &[u8], chain: Vec<&[u8]>) -> Certs {
-    let key = pkey::PKey::private_key_from_pem(key).unwrap();
-    let cert = x509::X509::from_pem(cert).unwrap();
-    let ztunnel_cert = ZtunnelCert::new(cert);
-    let chain = chain
-        .into_iter()
-        .map(|pem| ZtunnelCert::new(x509::X509::from_pem(pem).unwrap()))
-        .collect();
-    Certs {
-        cert: ztunnel_cert,
-        chain,
-        key,
+/// cert_from builds Certs from a PEM key, leaf and chain. The leaf PEM may be followed by its
+/// chain, and each chain PEM may hold several certificates, as is common for files provisioned by
+/// cert-manager or SPIRE.
+pub fn cert_from(key: &[u8], cert: &[u8], chain: Vec<&[u8]>) -> Result<Certs, Error> {
+    let key = pkey::PKey::private_key_from_pem(key).map_err(Error::InvalidPrivateKey)?;
+    certs_from_pem(key, cert, chain, &cert_policy())
+}
+
+/// cert_from_encrypted builds Certs from a passphrase protected PEM key, such as an encrypted
+/// PKCS#8 key, along with the PEM leaf and chain.
+pub fn cert_from_encrypted(
+    key: &[u8],
+    passphrase: &[u8],
+    cert: &[u8],
+    chain: Vec<&[u8]>,
+) -> Result<Certs, Error> {
+    let key = pkey::PKey::private_key_from_pem_passphrase(key, passphrase).map_err(|e| {
+        if is_encrypted_key(key) {
+            Error::KeyPassphrase
+        } else {
+            Error::InvalidPrivateKey(e)
+        }
+    })?;
+    certs_from_pem(key, cert, chain, &cert_policy())
+}
+
+fn certs_from_pem(
+    key: PKey<Private>,
+    cert: &[u8],
+    chain: Vec<&[u8]>,
+    policy: &CertPolicy,
+) -> Result<Certs, Error> {
+    let mut certs = x509::X509::stack_from_pem(cert).map_err(Error::InvalidCertificate)?;
+    if certs.is_empty() {
+        return Err(Error::InvalidCertificate(ErrorStack::get()));
+    }
+    let leaf = certs.remove(0);
+    for pem in chain {
+        certs.extend(x509::X509::stack_from_pem(pem).map_err(Error::InvalidCertificate)?);
+    }
+    let public_key = leaf.public_key()?;
+    if !public_key.public_eq(&key) {
+        return Err(Error::KeyCertMismatch);
+    }
+    if policy.normalize_chain {
+        certs = normalize_chain(&leaf, certs)?;
+    }
+    let certs = Certs {
+        cert: ZtunnelCert::new(leaf),
+        chain: certs.into_iter().map(ZtunnelCert::new).collect(),
+        key: key.into(),
+        policy: Default::default(),
+        lifetime_cap: None,
+    };
+    certs.check_policy(policy)?;
+    Ok(certs)
+}
+
+// is_encrypted_key returns true if pem is a well formed key that requires a passphrase, which tells
+// a wrong passphrase apart from a malformed key.
+fn is_encrypted_key(pem: &[u8]) -> bool {
+    let mut prompted = false;
+    let _ = pkey::PKey::private_key_from_pem_callback(pem, |_| {
+        prompted = true;
+        Err(ErrorStack::get())
+    });
+    prompted
+}
+
+fn is_self_signed(cert: &x509::X509Ref) -> bool {
+    cert.issued(cert) == X509VerifyResult::OK
+}
+
+// normalize_chain sorts certs so that each one issued the one before it, starting from leaf. This
+// puts intermediates before the root, as setup_ctx expects, whatever order CAs and files deliver
+// them in. The root may be missing, as long as no certificate is left out of the path: a
+// certificate whose issuer is missing fails with Error::BrokenChain. Certificates that are not
+// part of a complete path to a root are dropped.
+fn normalize_chain(
+    leaf: &x509::X509Ref,
+    mut certs: Vec<x509::X509>,
+) -> Result<Vec<x509::X509>, Error> {
+    let mut ordered: Vec<x509::X509> = Vec::with_capacity(certs.len());
+    let mut subject = leaf.to_owned();
+    while !is_self_signed(&subject) {
+        let Some(i) = certs
+            .iter()
+            .position(|c| c.issued(&subject) == X509VerifyResult::OK)
+        else {
+            break;
+        };
+        let issuer = certs.remove(i);
+        subject = issuer.clone();
+        ordered.push(issuer);
+    }
+    if certs.is_empty() {
+        if !is_self_signed(&subject) {
+            debug!("certificate chain does not include its root");
+        }
+        return Ok(ordered);
+    }
+    if !is_self_signed(&subject) {
+        return Err(Error::BrokenChain {
+            missing_issuer: name_to_string(subject.issuer_name()),
+        });
     }
+    warn!(
+        "ignoring {} certificates not in the chain of the leaf",
+        certs.len()
+    );
+    Ok(ordered)
 }
 
 pub struct CertSign {
     pub csr: Vec<u8>,
-    pub pkey: Vec<u8>,
+    // PEM encoded private key; wiped from memory on drop.
+    pub pkey: Zeroizing<Vec<u8>>,
+}
+
+impl Debug for CertSign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertSign")
+            .field("csr", &String::from_utf8_lossy(&self.csr))
+            .field("pkey", &REDACTED)
+            .finish()
+    }
+}
+
+// Placeholder printed in place of private key material.
+const REDACTED: &str = "<redacted>";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EcCurve {
+    P256,
+    P384,
+}
+
+/// KeyType is the type of private key generated for a CSR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    Ec(EcCurve),
+    /// RSA with the given modulus size in bits. Only 2048, 3072 and 4096 are allowed.
+    Rsa(u32),
+}
+
+impl Default for KeyType {
+    fn default() -> Self {
+        KeyType::Ec(EcCurve::P256)
+    }
+}
+
+impl KeyType {
+    fn generate(&self) -> Result<PKey<Private>, Error> {
+        match self {
+            KeyType::Ec(curve) => {
+                let nid = match curve {
+                    EcCurve::P256 => Nid::X9_62_PRIME256V1,
+                    EcCurve::P384 => Nid::SECP384R1,
+                };
+                let group = EcGroup::from_curve_name(nid)?;
+                let ec_key = EcKey::generate(&group)?;
+                Ok(PKey::from_ec_key(ec_key)?)
+            }
+            KeyType::Rsa(bits @ (2048 | 3072 | 4096)) => Ok(PKey::from_rsa(Rsa::generate(*bits)?)?),
+            KeyType::Rsa(_) => Err(Error::UnsupportedKeyType(*self)),
+        }
+    }
+
+    fn digest(&self) -> MessageDigest {
+        match self {
+            KeyType::Ec(EcCurve::P384) => MessageDigest::sha384(),
+            _ => MessageDigest::sha256(),
+        }
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "EC_P256" => Ok(KeyType::Ec(EcCurve::P256)),
+            "EC_P384" => Ok(KeyType::Ec(EcCurve::P384)),
+            "RSA_2048" => Ok(KeyType::Rsa(2048)),
+            "RSA_3072" => Ok(KeyType::Rsa(3072)),
+            "RSA_4096" => Ok(KeyType::Rsa(4096)),
+            _ => Err(Error::InvalidKeyType(s.to_string())),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureDigest {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl SignatureDigest {
+    fn message_digest(&self) -> MessageDigest {
+        match self {
+            SignatureDigest::Sha256 => MessageDigest::sha256(),
+            SignatureDigest::Sha384 => MessageDigest::sha384(),
+            SignatureDigest::Sha512 => MessageDigest::sha512(),
+        }
+    }
+
+    // The digest of a certificate signature algorithm, if it is one of ours.
+    fn of_signature(algorithm: Nid) -> Option<SignatureDigest> {
+        match algorithm {
+            Nid::SHA256WITHRSAENCRYPTION | Nid::ECDSA_WITH_SHA256 => Some(SignatureDigest::Sha256),
+            Nid::SHA384WITHRSAENCRYPTION | Nid::ECDSA_WITH_SHA384 => Some(SignatureDigest::Sha384),
+            Nid::SHA512WITHRSAENCRYPTION | Nid::ECDSA_WITH_SHA512 => Some(SignatureDigest::Sha512),
+            _ => None,
+        }
+    }
 }
 
+/// CertPolicy is the minimum strength of the certificates we load: the size of their keys and
+/// the digests they are signed with. Peers with strict policies would otherwise reject weak
+/// certificates at handshake time, with opaque errors. Self-signed roots are not checked.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CertPolicy {
+    pub min_rsa_bits: u32,
+    pub min_ec_bits: u32,
+    pub allowed_digests: Vec<SignatureDigest>,
+    /// If false, weak certificates are only logged.
+    pub enforce: bool,
+    /// If true, the chains of loaded certificates are reordered from the leaf up to the root, and
+    /// chains with a missing link are rejected. Otherwise they are used in the order given, which
+    /// must be intermediates first.
+    pub normalize_chain: bool,
+}
+
+impl Default for CertPolicy {
+    fn default() -> Self {
+        CertPolicy {
+            min_rsa_bits: 2048,
+            min_ec_bits: 256,
+            allowed_digests: vec![
+                SignatureDigest::Sha256,
+                SignatureDigest::Sha384,
+                SignatureDigest::Sha512,
+            ],
+            enforce: true,
+            normalize_chain: true,
+        }
+    }
+}
+
+impl CertPolicy {
+    // Returns why cert is too weak for the policy, if it is.
+    fn weakness(&self, cert: &x509::X509Ref) -> Result<Option<String>, Error> {
+        let key = cert.public_key()?;
+        let (kind, min_bits) = match key.id() {
+            pkey::Id::RSA => ("RSA", self.min_rsa_bits),
+            pkey::Id::EC => ("EC", self.min_ec_bits),
+            _ => ("", 0),
+        };
+        if key.bits() < min_bits {
+            return Ok(Some(format!(
+                "{} bit {kind} key, at least {min_bits} bits are required",
+                key.bits()
+            )));
+        }
+        let algorithm = cert.signature_algorithm().object().nid();
+        match SignatureDigest::of_signature(algorithm) {
+            Some(digest) if self.allowed_digests.contains(&digest) => Ok(None),
+            _ => Ok(Some(format!(
+                "signed with {}",
+                algorithm.long_name().unwrap_or("an unknown algorithm")
+            ))),
+        }
+    }
+}
+
+static CERT_POLICY: Lazy<RwLock<CertPolicy>> = Lazy::new(Default::default);
+
+/// set_cert_policy sets the policy certificates loaded afterwards are checked against.
+pub fn set_cert_policy(policy: CertPolicy) {
+    *CERT_POLICY.write().unwrap() = policy;
+}
+
+pub fn cert_policy() -> CertPolicy {
+    CERT_POLICY.read().unwrap().clone()
+}
+
+#[derive(Default)]
 pub struct CsrOptions {
     pub san: String,
+    pub key_type: KeyType,
+    /// Organization (O) to set in the CSR subject. The subject is left empty if neither this nor
+    /// common_name is set.
+    pub organization: Option<String>,
+    /// Common name (CN) to set in the CSR subject.
+    pub common_name: Option<String>,
+    /// Digest used to sign the CSR. Defaults to SHA-256, or SHA-384 for P-384 keys.
+    pub signature_digest: Option<SignatureDigest>,
+    /// IP addresses to request as SANs, in addition to san.
+    pub ip_sans: Vec<IpAddr>,
+}
+
+/// generate_csr generates a private key and a CSR for it, as specified by options.
+pub fn generate_csr(options: &CsrOptions) -> Result<CertSign, Error> {
+    options.generate()
 }
 
 impl CsrOptions {
+    /// generate_async generates the key and CSR on the blocking thread pool, as key generation
+    /// takes hundreds of milliseconds for RSA keys and would stall the runtime's workers.
+    pub async fn generate_async(self) -> Result<CertSign, Error> {
+        tokio::task::spawn_blocking(move || self.generate())
+            .await
+            .map_err(|e| Error::KeyGeneration(e.to_string()))?
+    }
+
     pub fn generate(&self) -> Result<CertSign, Error> {
-        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
-        let ec_key = EcKey::generate(&group)?;
-        let pkey = PKey::from_ec_key(ec_key)?;
+        let pkey = self.key_type.generate()?;
 
         let mut csr = x509::X509ReqBuilder::new()?;
         csr.set_pubkey(&pkey)?;
+        if self.organization.is_some() || self.common_name.is_some() {
+            let mut subject = x509::X509NameBuilder::new()?;
+            if let Some(o) = &self.organization {
+                subject.append_entry_by_nid(Nid::ORGANIZATIONNAME, o)?;
+            }
+            if let Some(cn) = &self.common_name {
+                subject.append_entry_by_nid(Nid::COMMONNAME, cn)?;
+            }
+            csr.set_subject_name(&subject.build())?;
+        }
         let mut extensions = Stack::new()?;
-        let subject_alternative_name = SubjectAlternativeName::new()
-            .uri(&self.san)
+        let mut subject_alternative_name = SubjectAlternativeName::new();
+        subject_alternative_name.uri(&self.san);
+        for ip in &self.ip_sans {
+            subject_alternative_name.ip(&ip.to_string());
+        }
+        let subject_alternative_name = subject_alternative_name
             .critical()
             .build(&csr.x509v3_context(None))
             .unwrap();
         extensions.push(subject_alternative_name)?;
         csr.add_extensions(&extensions)?;
-        csr.sign(&pkey, MessageDigest::sha256())?;
+        let digest = match self.signature_digest {
+            Some(d) => d.message_digest(),
+            None => self.key_type.digest(),
+        };
+        csr.sign(&pkey, digest)?;
 
         let csr = csr.build();
-        let pkey_pem = pkey.private_key_to_pem_pkcs8()?;
+        let pkey_pem = Zeroizing::new(pkey.private_key_to_pem_pkcs8()?);
         let csr_pem = csr.to_pem()?;
         Ok(CertSign {
             csr: csr_pem,
@@ -131,13 +462,112 @@ impl ZtunnelCert {
     }
 }
 
-#[derive(Clone, Debug)]
+/// CertInfo describes a certificate for debugging. It never includes key material.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CertInfo {
+    pub serial_number: String,
+    pub not_before: String,
+    pub not_after: String,
+    pub sans: Vec<String>,
+    pub issuer: String,
+}
+
+fn rfc3339(t: SystemTime) -> String {
+    let dt: chrono::DateTime<chrono::Utc> = t.into();
+    dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+impl CertInfo {
+    fn new(cert: &ZtunnelCert) -> CertInfo {
+        let x509 = &cert.x509;
+        CertInfo {
+            serial_number: x509
+                .serial_number()
+                .to_bn()
+                .map(|bn| bn.to_string())
+                .unwrap_or_default(),
+            not_before: rfc3339(cert.not_before),
+            not_after: rfc3339(cert.not_after),
+            sans: extract_all_sans(x509).iter().map(San::to_string).collect(),
+            issuer: name_to_string(x509.issuer_name()),
+        }
+    }
+
+    /// from_x509 describes a certificate not loaded as part of a Certs, such as a peer's.
+    pub fn from_x509(x509: &x509::X509Ref) -> CertInfo {
+        CertInfo::new(&ZtunnelCert::new(x509.to_owned()))
+    }
+}
+
+/// name_to_string formats a subject or issuer name as comma separated attributes, like O=org.
+pub fn name_to_string(name: &x509::X509NameRef) -> String {
+    name.entries()
+        .map(|e| {
+            format!(
+                "{}={}",
+                e.object().nid().short_name().unwrap_or("?"),
+                e.data()
+                    .as_utf8()
+                    .map(|d| d.to_string())
+                    .unwrap_or_default()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn ip_from_bytes(b: &[u8]) -> Option<IpAddr> {
+    match b.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(b).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(b).ok()?)),
+        _ => None,
+    }
+}
+
+/// CertsInfo describes the leaf and chain of a Certs.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CertsInfo {
+    pub cert: CertInfo,
+    pub chain: Vec<CertInfo>,
+}
+
+#[derive(Clone)]
 pub struct Certs {
     // the leaf cert
     cert: ZtunnelCert,
     // the remainder of the chain, not including the leaf cert
     chain: Vec<ZtunnelCert>,
-    key: pkey::PKey<pkey::Private>,
+    key: PrivateKeyProvider,
+    // additional checks applied when verifying peers
+    policy: PeerPolicy,
+    // if set, the certificate is refreshed as if it was valid for at most this long
+    lifetime_cap: Option<Duration>,
+}
+
+// PeerPolicy holds peer verification settings shared by every TLS context built from a Certs.
+#[derive(Clone, Debug, Default)]
+struct PeerPolicy {
+    // roots of federated trust domains, including our own. If set, peers must chain to a root of
+    // their own trust domain.
+    trust_bundle: Option<Arc<TrustBundle>>,
+    // identities that are rejected, even if otherwise valid
+    deny_list: Option<DenyList>,
+    // if set, the roots peers must chain to, in place of our own root
+    root_store: Option<RootCertStore>,
+    // trust domains whose identities are accepted in place of each other
+    trust_domain_aliases: Option<TrustDomainAliases>,
+}
+
+impl Debug for Certs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certs")
+            .field("cert", &self.cert)
+            .field("chain", &self.chain)
+            .field("key", &self.key)
+            .field("policy", &self.policy)
+            .field("lifetime_cap", &self.lifetime_cap)
+            .finish()
+    }
 }
 
 impl PartialEq for Certs {
@@ -147,17 +577,42 @@ impl PartialEq for Certs {
             .to_der()
             .iter()
             .eq(other.cert.x509.to_der().iter())
-            && self
-                .key
-                .private_key_to_der()
-                .iter()
-                .eq(other.key.private_key_to_der().iter())
+            && self.key == other.key
             && self.cert.not_after == other.cert.not_after
             && self.cert.not_before == other.cert.not_before
     }
 }
 
 impl Certs {
+    /// from_pkcs12 loads the key, leaf and chain from a DER encoded PKCS#12 bundle.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<Certs, Error> {
+        let p12 = Pkcs12::from_der(der)
+            .and_then(|p12| p12.parse(password))
+            .map_err(Error::InvalidPkcs12)?;
+        if !p12
+            .cert
+            .public_key()
+            .map(|k| k.public_eq(&p12.pkey))
+            .unwrap_or(false)
+        {
+            return Err(Error::Pkcs12MissingKey);
+        }
+        // PKCS#12 bags are unordered, so the chain is always normalized.
+        let chain = normalize_chain(&p12.cert, p12.chain.into_iter().flatten().collect())?;
+        if chain.is_empty() {
+            return Err(Error::Pkcs12MissingChain);
+        }
+        let certs = Certs {
+            chain: chain.into_iter().map(ZtunnelCert::new).collect(),
+            cert: ZtunnelCert::new(p12.cert),
+            key: p12.pkey.into(),
+            policy: Default::default(),
+            lifetime_cap: None,
+        };
+        certs.check_policy(&cert_policy())?;
+        Ok(certs)
+    }
+
     pub fn chain(&self) -> Result<Bytes, Error> {
         Ok(self.chain[0].x509.to_pem()?.into())
     }
@@ -168,28 +623,70 @@ impl Certs {
         self.chain.iter().map(|zcert| &zcert.x509)
     }
 
-    pub fn is_expired(&self) -> bool {
-        SystemTime::now() > self.cert.not_after
+    /// has_root tells whether the chain ends with a self-signed root. Otherwise, peers must
+    /// already hold the root the chain leads to.
+    pub fn has_root(&self) -> bool {
+        self.chain.last().map_or(false, |c| is_self_signed(&c.x509))
+    }
+
+    pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+        self.is_expired_at(clock.now())
+    }
+
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        now > self.cert.not_after
+    }
+
+    /// dump describes the certificates for debugging. It does not include the private key.
+    pub fn dump(&self) -> CertsInfo {
+        CertsInfo {
+            cert: CertInfo::new(&self.cert),
+            chain: self.chain.iter().map(CertInfo::new).collect(),
+        }
+    }
+
+    pub fn not_after(&self) -> SystemTime {
+        self.cert.not_after
+    }
+
+    /// lifetime returns how long the certificate is valid for, from not_before to not_after.
+    pub fn lifetime(&self) -> Duration {
+        self.cert
+            .not_after
+            .duration_since(self.cert.not_before)
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// with_lifetime_cap makes refreshes happen as if the certificate was valid for at most max,
+    /// for CAs that issue certificates with a much longer lifetime than rotation expects.
+    pub fn with_lifetime_cap(mut self, max: Duration) -> Certs {
+        self.lifetime_cap = Some(max);
+        self
+    }
+
+    // The lifetime refreshes are based on, taking the cap into account.
+    fn effective_lifetime(&self) -> Duration {
+        match self.lifetime_cap {
+            Some(max) => self.lifetime().min(max),
+            None => self.lifetime(),
+        }
     }
 
     pub fn refresh_at(&self) -> SystemTime {
         match self.cert.not_after.duration_since(self.cert.not_before) {
-            Ok(valid_for) => self.cert.not_before + valid_for / 2,
+            Ok(_) => self.cert.not_before + self.effective_lifetime() / 2,
             Err(_) => self.cert.not_after,
         }
     }
 
-    pub fn get_duration_until_refresh(&self) -> Duration {
-        let halflife = self
-            .cert
-            .not_after
-            .duration_since(self.cert.not_before)
-            .unwrap_or_else(|_| std::time::Duration::from_secs(0))
-            / 2;
+    pub fn get_duration_until_refresh(&self, clock: &dyn Clock) -> Duration {
+        self.get_duration_until_refresh_at(clock.now())
+    }
+
+    pub fn get_duration_until_refresh_at(&self, now: SystemTime) -> Duration {
+        let halflife = self.effective_lifetime() / 2;
         // If now() is earlier than not_before, we need to refresh ASAP, so return 0.
-        let elapsed = SystemTime::now()
-            .duration_since(self.cert.not_before)
-            .unwrap_or(halflife);
+        let elapsed = now.duration_since(self.cert.not_before).unwrap_or(halflife);
         halflife
             .checked_sub(elapsed)
             .unwrap_or_else(|| Duration::from_secs(0))
@@ -198,619 +695,6769 @@ impl Certs {
     pub fn x509(&self) -> &x509::X509 {
         &self.cert.x509
     }
-}
-
-#[derive(Clone, Debug)]
-pub struct TlsGrpcChannel {
-    uri: Uri,
-    client: hyper_util::client::legacy::Client<
-        hyper_boring::HttpsConnector<hyper_util::client::connect::HttpConnector>,
-        BoxBody1,
-    >,
-}
 
-/// grpc_connector provides a client TLS channel for gRPC requests.
-pub fn grpc_connector(uri: String, root_cert: RootCert) -> Result<TlsGrpcChannel, Error> {
-    let mut conn = ssl::SslConnector::builder(ssl::SslMethod::tls_client())?;
+    /// identities returns the SPIFFE identities the certificate is for.
+    pub fn identities(&self) -> Vec<Identity> {
+        extract_sans(&self.cert.x509)
+    }
 
-    let uri = Uri::try_from(uri)?;
-    let is_localhost_call = uri.host() == Some("localhost");
-    conn.set_verify(ssl::SslVerifyMode::PEER);
-    conn.set_alpn_protos(Alpn::H2.encode())?;
-    conn.set_min_proto_version(Some(ssl::SslVersion::TLS1_2))?;
-    conn.set_max_proto_version(Some(ssl::SslVersion::TLS1_3))?;
-    match root_cert {
-        RootCert::File(f) => {
-            conn.set_ca_file(f).map_err(Error::InvalidRootCert)?;
-        }
-        RootCert::Static(b) => {
-            conn.cert_store_mut()
-                .add_cert(x509::X509::from_pem(&b).map_err(Error::InvalidRootCert)?)
-                .map_err(Error::InvalidRootCert)?;
+    /// with_trust_bundle enables per trust domain root verification of peers. Our own trust
+    /// domain is added to the bundle, trusting our own root.
+    pub fn with_trust_bundle(mut self, bundle: &TrustBundle) -> Certs {
+        let mut bundle = bundle.clone();
+        if let Some(Identity::Spiffe { trust_domain, .. }) = extract_sans(&self.cert.x509).first() {
+            bundle.add(
+                trust_domain,
+                self.chain
+                    .last()
+                    .map(|c| c.x509.clone())
+                    .into_iter()
+                    .collect(),
+            );
         }
-        RootCert::Default => {} // Already configured to use system root certs
+        self.policy.trust_bundle = Some(Arc::new(bundle));
+        self
     }
-    let mut http = hyper_util::client::connect::HttpConnector::new();
-    http.enforce_http(false);
-    let mut https = hyper_boring::HttpsConnector::with_connector(http, conn)?;
-    https.set_callback(move |cc, _| {
-        if is_localhost_call {
-            // Follow Istio logic to allow localhost calls: https://github.com/istio/istio/blob/373fc89518c986c9f48ed3cd891930da6fdc8628/pkg/istio-agent/xds_proxy.go#L735
-            cc.set_verify_hostname(false);
-            let param = cc.param_mut();
-            param.set_hostflags(X509CheckFlags::NO_PARTIAL_WILDCARDS);
-            param.set_host("istiod.istio-system.svc").unwrap();
-        }
-        Ok(())
-    });
 
-    // Configure hyper's client to be h2 only and build with the
-    // correct https connector.
-    let client = hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new())
-        .http2_only(true)
-        .http2_keep_alive_interval(Duration::from_secs(30))
-        .http2_keep_alive_timeout(Duration::from_secs(10))
-        .timer(crate::hyper_util::TokioTimer)
-        .build(https);
+    /// with_deny_list rejects peers presenting any identity in the deny list. The list is shared,
+    /// so changes apply to subsequent handshakes without rebuilding contexts.
+    pub fn with_deny_list(mut self, deny_list: &DenyList) -> Certs {
+        self.policy.deny_list = Some(deny_list.clone());
+        self
+    }
 
-    Ok(TlsGrpcChannel { uri, client })
-}
+    /// with_root_store verifies peers against the roots in the store, instead of the root of our
+    /// own chain. The store is read whenever a TLS context is built, so updates to it apply to new
+    /// connections.
+    pub fn with_root_store(mut self, store: &RootCertStore) -> Certs {
+        self.policy.root_store = Some(store.clone());
+        self
+    }
 
-type BoxBody1 = HttpBody04ToHttpBody1<BoxBody>;
+    /// with_trust_domain_aliases accepts peers under any alias of the trust domain they are
+    /// expected in, inbound and outbound. The aliases are read during each handshake, so updates
+    /// to them apply to new connections.
+    pub fn with_trust_domain_aliases(mut self, aliases: &TrustDomainAliases) -> Certs {
+        self.policy.trust_domain_aliases = Some(aliases.clone());
+        self
+    }
 
-#[derive(Default)]
-pub enum DefaultIncoming {
-    Some(Incoming),
-    #[default]
-    Empty,
-}
+    /// with_private_key replaces the key, for example with one held by a KeyEngine. The key must
+    /// match the leaf certificate: engine keys are checked by signing a challenge.
+    pub fn with_private_key(mut self, key: PrivateKeyProvider) -> Result<Certs, Error> {
+        key.check(&self.cert.x509)?;
+        self.key = key;
+        Ok(self)
+    }
 
-impl Body for DefaultIncoming {
-    type Data = Bytes;
-    type Error = hyper::Error;
+    pub fn private_key(&self) -> &PrivateKeyProvider {
+        &self.key
+    }
 
-    fn poll_frame(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
-        match self.get_mut() {
-            DefaultIncoming::Some(ref mut i) => Pin::new(i).poll_frame(cx),
-            DefaultIncoming::Empty => Pin::new(&mut http_body_util::Empty::<Bytes>::new())
-                .poll_frame(cx)
-                .map_err(|_| unreachable!()),
+    /// check_policy checks the leaf and intermediates against policy. Weak certificates are an
+    /// error if the policy is enforced, and logged otherwise.
+    pub fn check_policy(&self, policy: &CertPolicy) -> Result<(), Error> {
+        for cert in std::iter::once(&self.cert).chain(self.chain.iter()) {
+            let cert = &cert.x509;
+            // Roots are trusted as configured, whatever their strength.
+            if is_self_signed(cert) {
+                continue;
+            }
+            let Some(reason) = policy.weakness(cert)? else {
+                continue;
+            };
+            let subject = cert
+                .subject_name()
+                .entries()
+                .next()
+                .and_then(|e| e.data().as_utf8().ok())
+                .map(|s| s.to_string())
+                .unwrap_or_default();
+            let reason = format!("{subject:?}: {reason}");
+            if policy.enforce {
+                return Err(Error::WeakCertificate { reason });
+            }
+            warn!("using weak certificate {reason}");
         }
+        Ok(())
+    }
+
+    /// validate checks a freshly issued certificate before it is used: the private key matches
+    /// the leaf, the leaf is for the expected identity, the certificates are strong enough for the
+    /// policy set with set_cert_policy, the chain verifies up to one of the roots and the
+    /// certificate is valid at the time of clock. Chain verification is skipped if no roots are
+    /// given.
+    pub fn validate(
+        &self,
+        expected_identity: &Identity,
+        roots: &[x509::X509],
+        clock: &dyn Clock,
+    ) -> Result<(), Error> {
+        self.key.check(&self.cert.x509)?;
+        self.check_policy(&cert_policy())?;
+        if self.verify_san(expected_identity).is_err() {
+            return Err(Error::SanMismatch(
+                expected_identity.to_owned(),
+                extract_sans(&self.cert.x509),
+            ));
+        }
+        let now = clock.now();
+        if self.cert.not_after <= now {
+            return Err(Error::CertificateValidity(
+                "certificate has expired".to_string(),
+            ));
+        }
+        if self.cert.not_before > now + MAX_NOT_BEFORE_SKEW {
+            return Err(Error::CertificateValidity(format!(
+                "certificate is not valid until {}",
+                rfc3339(self.cert.not_before)
+            )));
+        }
+        if roots.is_empty() {
+            return Ok(());
+        }
+        let mut store = x509::store::X509StoreBuilder::new()?;
+        for root in roots {
+            store.add_cert(root.clone())?;
+        }
+        let store = store.build();
+        let mut chain = Stack::new()?;
+        for cert in self.iter_chain() {
+            chain.push(cert.clone())?;
+        }
+        let mut ctx = X509StoreContext::new()?;
+        let result = ctx.init(&store, &self.cert.x509, &chain, |ctx| {
+            use foreign_types::ForeignTypeRef;
+            // The chain is verified at the same time as the leaf, rather than at the system time.
+            let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            unsafe { boring_sys::X509_STORE_CTX_set_time(ctx.as_ptr(), 0, secs as _) };
+            ctx.verify_cert()?;
+            Ok(ctx.error())
+        })?;
+        if result != X509VerifyResult::OK {
+            return Err(Error::UntrustedChain(result.error_string().to_string()));
+        }
+        Ok(())
     }
 }
 
-impl tower::Service<Request<BoxBody>> for TlsGrpcChannel {
-    type Response = Response<HttpBody1ToHttpBody04<DefaultIncoming>>;
-    type Error = hyper_util::client::legacy::Error;
-    // type Error = hyper::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+// How far in the future a newly issued certificate may start being valid, allowing for clock skew
+// with the CA.
+const MAX_NOT_BEFORE_SKEW: Duration = Duration::from_secs(10 * 60);
 
-    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Ok(()).into()
-    }
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(15);
 
-    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
-        let mut req = req.map(HttpBody04ToHttpBody1::new);
+type HttpsClient = hyper_util::client::legacy::Client<
+    AlpnCheckConnector<PinnedConnector<hyper_boring::HttpsConnector<ProxyConnector>>>,
+    BoxBody1,
+>;
+type UdsClient = hyper_util::client::legacy::Client<crate::hyper_util::UdsConnector, BoxBody1>;
 
-        let uri = Uri::builder()
-            .scheme(self.uri.scheme().unwrap().to_owned())
-            .authority(self.uri.authority().unwrap().to_owned())
-            .path_and_query(req.uri().path_and_query().unwrap().to_owned())
-            .build()
-            .unwrap();
-        *req.uri_mut() = uri;
-        let future = self.client.request(req);
-        Box::pin(async move {
-            let res = future.await?;
-            Ok(res
-                .map(DefaultIncoming::Some)
-                .map(HttpBody1ToHttpBody04::new))
-        })
-    }
+#[derive(Clone, Debug)]
+enum Transport {
+    Tls(RootCert, ConnectorConfig),
+    Uds(PathBuf, ChannelLimits),
 }
 
-impl Certs {
-    fn verify_mode() -> ssl::SslVerifyMode {
-        ssl::SslVerifyMode::PEER | ssl::SslVerifyMode::FAIL_IF_NO_PEER_CERT
-    }
+#[derive(Debug)]
+enum GrpcClient {
+    Tls(HttpsClient),
+    Uds(UdsClient),
+}
 
-    pub fn mtls_acceptor(&self, dest_id: Option<&Identity>) -> Result<ssl::SslAcceptor, Error> {
-        let _ctx = ssl::SslContext::builder(ssl::SslMethod::tls_server())?;
-        // mozilla_intermediate_v5 is the only variant that enables TLSv1.3, so we use that.
-        let mut conn = ssl::SslAcceptor::mozilla_intermediate_v5(ssl::SslMethod::tls_server())?;
-        self.setup_ctx(&mut conn)?;
+type GrpcResponseFuture = Pin<
+    Box<dyn Future<Output = Result<Response<Incoming>, hyper_util::client::legacy::Error>> + Send>,
+>;
 
-        if let Some(dest_id) = dest_id {
-            // Validate that the source cert shares the same trust domain
-            conn.set_verify_callback(
-                Self::verify_mode(),
-                Verifier::SanTrustDomain(dest_id.clone()).callback(),
-            );
+impl GrpcClient {
+    fn request(&self, req: Request<BoxBody1>) -> GrpcResponseFuture {
+        match self {
+            GrpcClient::Tls(c) => Box::pin(c.request(req)),
+            GrpcClient::Uds(c) => Box::pin(c.request(req)),
         }
-
-        Ok(conn.build())
     }
+}
 
-    pub fn acceptor(&self) -> Result<ssl::SslAcceptor, Error> {
-        let _ctx = ssl::SslContext::builder(ssl::SslMethod::tls_server())?;
-        // mozilla_intermediate_v5 is the only variant that enables TLSv1.3, so we use that.
-        let mut conn = ssl::SslAcceptor::mozilla_intermediate_v5(ssl::SslMethod::tls_server())?;
-        self.setup_ctx(&mut conn)?;
+#[derive(Debug)]
+pub struct TlsGrpcChannel {
+    uri: Uri,
+    transport: Transport,
+    // Shared across all clones of the channel, so a broken connection observed by one caller is
+    // rebuilt once for everyone.
+    inner: Arc<Mutex<ChannelInner>>,
+    reconnects: Arc<AtomicU64>,
+    // Pending backoff timer for this clone, set while poll_ready waits for the reconnect deadline.
+    backoff: Option<Pin<Box<tokio::time::Sleep>>>,
+    // Permits for requests in flight, held until their response body is dropped.
+    streams: Arc<tokio::sync::Semaphore>,
+}
 
-        conn.set_verify_callback(ssl::SslVerifyMode::NONE, Verifier::None.callback());
-        Ok(conn.build())
-    }
+#[derive(Debug)]
+struct ChannelInner {
+    client: GrpcClient,
+    // Set for RootCert::File and Directory until the first poll_ready, which starts watching the
+    // root in the background, so the client can be rebuilt when the root is rotated.
+    root_cert_watch: Option<RootCertWatch>,
+    // Number of consecutive connection failures, used to compute the reconnect backoff.
+    failures: u32,
+    // Set when the connection is known to be broken; the client is rebuilt once it elapses.
+    reconnect_at: Option<tokio::time::Instant>,
+    // Reported by TlsGrpcChannel::health.
+    last_error: Option<String>,
+    last_success: Option<SystemTime>,
+}
 
-    pub fn connector(&self, dest_id: &Identity) -> Result<ssl::SslConnector, Error> {
-        let mut conn = ssl::SslConnector::builder(ssl::SslMethod::tls_client())?;
-        self.setup_ctx(&mut conn)?;
+/// ChannelState is the connectivity of a TlsGrpcChannel to its server.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelState {
+    /// No request completed yet.
+    Connecting,
+    /// The last request completed.
+    Ready,
+    /// The last connection attempts failed.
+    Failing,
+}
 
-        // client verifies SAN
-        conn.set_verify_callback(
-            Self::verify_mode(),
-            Verifier::San(dest_id.clone()).callback(),
-        );
+/// ChannelHealth describes the connectivity of a TlsGrpcChannel, for readiness and debugging.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChannelHealth {
+    pub state: ChannelState,
+    pub consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Time the last request completed, in RFC 3339 format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<String>,
+}
 
-        Ok(conn.build())
-    }
+// RootCertWatch periodically re-reads root cert files to detect rotation.
+// The files are small, so we just compare contents, which also handles atomic symlink swaps.
+#[derive(Debug)]
+struct RootCertWatch {
+    path: PathBuf,
+    contents: Option<Vec<u8>>,
+    interval: Duration,
+}
 
-    fn setup_ctx(&self, conn: &mut SslContextBuilder) -> Result<(), Error> {
-        // general TLS options
-        conn.set_alpn_protos(Alpn::H2.encode())?;
-        conn.set_min_proto_version(Some(ssl::SslVersion::TLS1_3))?;
-        conn.set_max_proto_version(Some(ssl::SslVersion::TLS1_3))?;
+impl RootCertWatch {
+    fn new(path: PathBuf, interval: Duration) -> Self {
+        RootCertWatch {
+            contents: Self::read(&path),
+            path,
+            interval,
+        }
+    }
 
-        // key and certs
-        conn.set_private_key(&self.key)?;
-        conn.set_certificate(&self.cert.x509)?;
-        for (i, chain_cert) in self.chain.iter().enumerate() {
-            // Only include intermediate certs in the chain.
-            // The last cert is the root cert which should already exist on the peer.
-            if i < (self.chain.len() - 1) {
-                // This is an intermediate cert that should be added to the cert chain
-                conn.add_extra_chain_cert(chain_cert.x509.clone())?;
+    // Rebuilds the client of the channel whenever the root cert changes, until the channel is
+    // dropped. The files are read, and the client built, off the runtime and without holding the
+    // lock of the channel. Connections made by the old client stay up; only new connections trust
+    // the new root.
+    async fn run(mut self, inner: Weak<Mutex<ChannelInner>>, uri: Uri, transport: Transport) {
+        let start = tokio::time::Instant::now() + self.interval;
+        let mut interval = tokio::time::interval_at(start, self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if inner.strong_count() == 0 {
+                return;
+            }
+            let path = self.path.clone();
+            let Ok(contents) = tokio::task::spawn_blocking(move || Self::read(&path)).await else {
+                continue;
+            };
+            if contents == self.contents {
+                continue;
+            }
+            self.contents = contents;
+            let (uri, transport) = (uri.clone(), transport.clone());
+            let client =
+                tokio::task::spawn_blocking(move || build_grpc_client(&uri, &transport)).await;
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            match client {
+                Ok(Ok(client)) => {
+                    inner.lock().unwrap().client = client;
+                    info!(uri=%uri, "reloaded root certificate");
+                }
+                Ok(Err(e)) => warn!(uri=%uri, "failed to reload root certificate: {e}"),
+                Err(e) => warn!(uri=%uri, "failed to reload root certificate: {e}"),
             }
-            conn.cert_store_mut().add_cert(chain_cert.x509.clone())?;
         }
-        conn.check_private_key()?;
-
-        // by default, allow boringssl to do standard validation
-        conn.set_verify_callback(Self::verify_mode(), Verifier::None.callback());
+    }
 
-        Ok(())
+    fn read(path: &Path) -> Option<Vec<u8>> {
+        if !path.is_dir() {
+            return std::fs::read(path).ok();
+        }
+        let mut contents = Vec::new();
+        for file in root_cert_dir_files(path).ok()? {
+            contents.extend(file.to_string_lossy().as_bytes());
+            contents.extend(std::fs::read(&file).ok()?);
+        }
+        Some(contents)
     }
 }
 
-enum Verifier {
-    // Does not verify an individual identity.
-    None,
+impl Clone for TlsGrpcChannel {
+    fn clone(&self) -> Self {
+        TlsGrpcChannel {
+            uri: self.uri.clone(),
+            transport: self.transport.clone(),
+            inner: self.inner.clone(),
+            reconnects: self.reconnects.clone(),
+            backoff: None,
+            streams: self.streams.clone(),
+        }
+    }
+}
 
-    // Allows exactly one identity, making sure at least one of the presented certs matches that identity
-    San(Identity),
+#[derive(thiserror::Error, Debug)]
+pub enum ChannelError {
+    // The hyper client error only tells which step failed, so include its sources.
+    #[error("request failed: {}", error_chain(.0))]
+    Request(#[from] hyper_util::client::legacy::Error),
+    #[error("invalid request uri: {0}")]
+    InvalidUri(String),
+    #[error("failed to reconnect: {0}")]
+    Reconnect(#[from] Error),
+    #[error("request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("failed to read request body: {0}")]
+    RequestBody(tonic::Status),
+    #[error("failed to read response body: {0}")]
+    ResponseBody(hyper::Error),
+    #[error("received message of {size} bytes, larger than the limit of {limit} bytes")]
+    MessageTooLarge { size: usize, limit: usize },
+}
 
-    // Allows all identities that share the same trust domain
-    SanTrustDomain(Identity),
+impl ChannelHealth {
+    /// check fails while the channel cannot connect to its server, with the last error.
+    pub fn check(&self) -> Result<(), String> {
+        if self.state != ChannelState::Failing {
+            return Ok(());
+        }
+        Err(self
+            .last_error
+            .clone()
+            .unwrap_or_else(|| "connection failing".to_string()))
+    }
 }
 
-impl Verifier {
-    fn base_verifier(verified: bool, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
-        if !verified {
-            return Err(TlsError::Verification(ctx.error()));
-        };
-        Ok(())
+impl TlsGrpcChannel {
+    /// reconnects returns the number of times the underlying connection has been rebuilt.
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(atomic::Ordering::Relaxed)
     }
 
-    fn verifiy_san(identity: &Identity, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
-        // internally, openssl tends to .expect the results of these methods.
-        // TODO bubble up better error message
-        let ssl_idx = X509StoreContext::ssl_idx().map_err(Error::SslError)?;
-        let cert = ctx
-            .ex_data(ssl_idx)
-            .ok_or(TlsError::ExDataError)?
-            .peer_certificate()
-            .ok_or(TlsError::PeerCertError)?;
+    fn backoff(failures: u32) -> Duration {
+        let exp = failures.saturating_sub(1).min(16);
+        let base = RECONNECT_INITIAL_BACKOFF
+            .saturating_mul(1 << exp)
+            .min(RECONNECT_MAX_BACKOFF);
+        // Add jitter so many ztunnels don't hammer a restarted istiod in lockstep.
+        base.mul_f64(rand::thread_rng().gen_range(0.8..1.2))
+            .min(RECONNECT_MAX_BACKOFF)
+    }
 
-        cert.verify_san(identity)
+    /// health returns the connectivity of the channel, shared by all its clones.
+    pub fn health(&self) -> ChannelHealth {
+        let inner = self.inner.lock().unwrap();
+        let state = if inner.failures > 0 {
+            ChannelState::Failing
+        } else if inner.last_success.is_some() {
+            ChannelState::Ready
+        } else {
+            ChannelState::Connecting
+        };
+        ChannelHealth {
+            state,
+            consecutive_failures: inner.failures,
+            last_error: inner.last_error.clone(),
+            last_success: inner.last_success.map(rfc3339),
+        }
     }
 
-    fn verifiy_san_trust_domain(
-        identity: &Identity,
-        ctx: &mut X509StoreContextRef,
-    ) -> Result<(), TlsError> {
-        // internally, openssl tends to .expect the results of these methods.
-        // TODO bubble up better error message
-        let ssl_idx = X509StoreContext::ssl_idx().map_err(Error::SslError)?;
-        let cert = ctx
-            .ex_data(ssl_idx)
-            .ok_or(TlsError::ExDataError)?
-            .peer_certificate()
-            .ok_or(TlsError::PeerCertError)?;
+    fn record_failure(inner: &Mutex<ChannelInner>, err: &hyper_util::client::legacy::Error) {
+        let mut inner = inner.lock().unwrap();
+        inner.last_error = Some(error_chain(err));
+        if !is_connection_broken(err) {
+            // The server was reached, so the connection is not known to be broken.
+            return;
+        }
+        inner.failures += 1;
+        let backoff = Self::backoff(inner.failures);
+        inner.reconnect_at = Some(tokio::time::Instant::now() + backoff);
+        warn!(
+            failures = inner.failures,
+            ?backoff,
+            "control plane connection failed"
+        );
+    }
 
-        cert.verify_san_trust_domain(identity)
+    fn record_success(inner: &Mutex<ChannelInner>) {
+        let mut inner = inner.lock().unwrap();
+        inner.failures = 0;
+        inner.last_success = Some(SystemTime::now());
     }
 
-    fn verify(&self, verified: bool, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
-        Self::base_verifier(verified, ctx)?;
-        match self {
-            Self::San(identity) => Verifier::verifiy_san(identity, ctx)?,
-            Self::SanTrustDomain(identity) => Verifier::verifiy_san_trust_domain(identity, ctx)?,
-            Self::None => (),
+    // Starts watching the root cert for rotation, once. This is deferred to the first poll_ready,
+    // as channels can be built outside of a runtime.
+    fn watch_root_cert(&self) {
+        let Some(watch) = self.inner.lock().unwrap().root_cert_watch.take() else {
+            return;
         };
-        Ok(())
+        tokio::spawn(watch.run(
+            Arc::downgrade(&self.inner),
+            self.uri.clone(),
+            self.transport.clone(),
+        ));
     }
 
-    fn callback(self) -> impl Fn(bool, &mut X509StoreContextRef) -> bool {
-        move |verified, ctx| match self.verify(verified, ctx) {
-            Ok(_) => true,
+    fn reconnect(&self) -> Result<(), ChannelError> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.reconnect_at.is_none() {
+            // Another clone already rebuilt the connection.
+            return Ok(());
+        }
+        match build_grpc_client(&self.uri, &self.transport) {
+            Ok(client) => {
+                inner.client = client;
+                inner.reconnect_at = None;
+                self.reconnects.fetch_add(1, atomic::Ordering::Relaxed);
+                info!(uri=%self.uri, "rebuilt control plane connection");
+                Ok(())
+            }
             Err(e) => {
-                // TODO metrics/counters; info would be too noisy
-                info!("failed verifying TLS: {e}");
-                false
+                inner.failures += 1;
+                inner.last_error = Some(e.to_string());
+                inner.reconnect_at =
+                    Some(tokio::time::Instant::now() + Self::backoff(inner.failures));
+                Err(ChannelError::Reconnect(e))
             }
         }
     }
 }
 
-pub trait SanChecker {
-    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError>;
-    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError>;
+// Returns whether err means the connection must be rebuilt: either it could not be established,
+// or the established connection was closed, reset or sent a GOAWAY by the server.
+fn is_connection_broken(err: &hyper_util::client::legacy::Error) -> bool {
+    if err.is_connect() {
+        return true;
+    }
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<hyper::Error>() {
+            if err.is_closed() || err.is_incomplete_message() {
+                return true;
+            }
+        } else if let Some(err) = err.downcast_ref::<h2::Error>() {
+            if err.is_go_away() || err.is_io() {
+                return true;
+            }
+        } else if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            if matches!(
+                err.kind(),
+                ConnectionReset | ConnectionAborted | BrokenPipe | UnexpectedEof
+            ) {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
 }
 
-impl SanChecker for Certs {
-    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError> {
-        self.cert.x509.verify_san(identity)
+// Formats err with its sources, as the errors of the hyper client only tell which step failed.
+pub(super) fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut s = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        s = format!("{s}: {err}");
+        source = err.source();
     }
+    s
+}
 
-    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError> {
-        self.cert.x509.verify_san_trust_domain(identity)
+/// is_fips tells whether the TLS library runs in FIPS mode.
+pub fn is_fips() -> bool {
+    boring::fips::enabled()
+}
+
+/// require_fips fails if FIPS mode is required but the TLS library was not built with FIPS
+/// support, which can only be chosen at build time.
+pub fn require_fips(required: bool) -> Result<(), Error> {
+    if required && !is_fips() {
+        return Err(Error::FipsUnavailable);
     }
+    Ok(())
 }
 
-pub fn extract_sans(cert: &x509::X509) -> Vec<Identity> {
-    cert.subject_alt_names()
-        .iter()
-        .flat_map(|sans| sans.iter())
-        .filter_map(|s| s.uri())
-        .map(Identity::from_str)
-        .collect::<Result<Vec<_>, _>>()
-        .unwrap_or_default()
+// Security level applied to every TLS context, see set_security_level. 0 applies no checks.
+static SECURITY_LEVEL: AtomicU32 = AtomicU32::new(0);
+
+/// set_security_level sets the OpenSSL security level of every TLS context built afterwards.
+/// BoringSSL has no security levels, so only their key strength requirements are enforced: our
+/// own keys, and the keys of the CA and XDS servers, must provide at least 80, 112, 128, 192 or
+/// 256 bits of security for levels 1 to 5.
+pub fn set_security_level(level: Option<u32>) {
+    SECURITY_LEVEL.store(level.unwrap_or(0), atomic::Ordering::Relaxed);
 }
 
-impl SanChecker for x509::X509 {
-    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError> {
-        let sans = extract_sans(self);
-        sans.iter()
-            .find(|id| id == &identity)
-            .ok_or_else(|| TlsError::SanError(identity.to_owned(), sans.clone()))
-            .map(|_| ())
-    }
+fn security_level() -> u32 {
+    SECURITY_LEVEL.load(atomic::Ordering::Relaxed)
+}
 
-    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError> {
-        let source_trust_domain = match identity {
-            Identity::Spiffe { trust_domain, .. } => trust_domain,
-        };
-        let sans = extract_sans(self);
-        sans.iter()
-            .find(|id| match id {
-                Identity::Spiffe { trust_domain, .. } => trust_domain == source_trust_domain,
-            })
-            .ok_or_else(|| {
-                TlsError::SanTrustDomainError(source_trust_domain.to_string(), sans.clone())
-            })
-            .map(|_| ())
-    }
+static SIGALGS: Lazy<RwLock<Option<String>>> = Lazy::new(Default::default);
+
+/// set_sigalgs restricts the signature algorithms offered and accepted in the handshakes of every
+/// TLS context built afterwards, in OpenSSL list format such as "ECDSA+SHA384:rsa_pss_rsae_sha256".
+/// BoringSSL defaults are used if unset.
+pub fn set_sigalgs(sigalgs: Option<String>) {
+    *SIGALGS.write().unwrap() = sigalgs;
 }
 
-enum Alpn {
-    H2,
+fn sigalgs() -> Option<String> {
+    SIGALGS.read().unwrap().clone()
 }
 
-impl Alpn {
-    fn encode(&self) -> &[u8] {
-        match self {
-            Alpn::H2 => b"\x02h2",
-        }
-    }
+// Largest certificate chain accepted from peers, in bytes, see set_max_cert_list. 0 keeps the
+// BoringSSL default.
+static MAX_CERT_LIST: AtomicUsize = AtomicUsize::new(0);
+
+/// set_max_cert_list sets the largest certificate chain, in bytes, accepted from clients by every
+/// acceptor built afterwards. As acceptors require client certificates, it also caps the size of
+/// every handshake message, though never below 16 KiB. Larger messages fail the handshake as
+/// HandshakeFailureClass::MessageTooLarge, before the chain is parsed. The BoringSSL default of
+/// 100 KiB is kept if unset.
+pub fn set_max_cert_list(max: Option<usize>) {
+    MAX_CERT_LIST.store(max.unwrap_or(0), atomic::Ordering::Relaxed);
 }
 
-#[async_trait::async_trait]
-pub trait CertProvider: Send + Sync {
-    async fn fetch_cert(&mut self, fd: &TcpStream) -> Result<ssl::SslAcceptor, TlsError>;
+fn max_cert_list() -> Option<usize> {
+    Some(MAX_CERT_LIST.load(atomic::Ordering::Relaxed)).filter(|max| *max > 0)
 }
 
-#[derive(Clone, Debug)]
-pub struct ControlPlaneCertProvider(pub Certs);
+/// SanEnforcement is what happens to a handshake with a peer lacking the expected SAN.
+#[derive(serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SanEnforcement {
+    /// The handshake fails.
+    #[default]
+    Enforce,
+    /// The mismatch is logged and counted in the tls_monitored_san_mismatches metric, and the
+    /// handshake goes on. This is a dry run for tightening verification; peers whose chain does
+    /// not verify are still rejected.
+    Monitor,
+}
 
-#[async_trait::async_trait]
-impl CertProvider for ControlPlaneCertProvider {
-    async fn fetch_cert(&mut self, _: &TcpStream) -> Result<ssl::SslAcceptor, TlsError> {
-        let acc = self.0.acceptor()?;
-        Ok(acc)
+static SAN_MONITOR: AtomicBool = AtomicBool::new(false);
+
+/// set_san_enforcement sets how SAN mismatches are handled by every TLS context built afterwards.
+pub fn set_san_enforcement(mode: SanEnforcement) {
+    SAN_MONITOR.store(mode == SanEnforcement::Monitor, atomic::Ordering::Relaxed);
+}
+
+fn san_enforcement() -> SanEnforcement {
+    if SAN_MONITOR.load(atomic::Ordering::Relaxed) {
+        SanEnforcement::Monitor
+    } else {
+        SanEnforcement::Enforce
     }
 }
 
-#[derive(Clone)]
-pub struct BoringTlsAcceptor<F: CertProvider> {
-    /// Acceptor is a function that determines the TLS context to use. As input, the FD of the client
-    /// connection is provided.
-    pub acceptor: F,
+/// monitored_san_mismatches returns the number of handshakes that went on despite a SAN mismatch,
+/// as SanEnforcement::Monitor was set.
+pub fn monitored_san_mismatches() -> u64 {
+    SAN_METRICS.monitored_san_mismatches.get()
 }
 
-#[derive(thiserror::Error, Debug)]
-pub enum TlsError {
-    #[error("tls handshake error: {0:?}")]
-    Handshake(#[from] tokio_boring::HandshakeError<TcpStream>),
-    #[error("tls verification error: {0}")]
-    Verification(X509VerifyResult),
-    #[error("certificate lookup error: {0} is not a known destination")]
-    CertificateLookup(NetworkAddress),
-    #[error("signing error: {0}")]
-    SigningError(#[from] identity::Error),
-    #[error("san verification error: remote did not present the expected SAN ({0}), got {1:?}")]
-    SanError(Identity, Vec<Identity>),
-    #[error(
-        "san verification error: remote did not present the expected trustdomain ({0}), got {1:?}"
-    )]
-    SanTrustDomainError(String, Vec<Identity>),
-    #[error("failed getting ex data")]
-    ExDataError,
-    #[error("failed getting peer cert")]
-    PeerCertError,
-    #[error("ssl error: {0}")]
-    SslError(#[from] Error),
+#[allow(unsafe_code)]
+fn set_ctx_max_cert_list(ctx: &mut SslContextBuilder, max: usize) {
+    // SAFETY: the pointer is valid for as long as ctx, and the call only stores max.
+    unsafe { boring_sys::SSL_CTX_set_max_cert_list(ctx.as_ptr(), max) }
 }
 
-impl<F> tls_listener::AsyncTls<TcpStream> for BoringTlsAcceptor<F>
-where
-    F: CertProvider + Clone + 'static,
-{
-    type Stream = tokio_boring::SslStream<TcpStream>;
-    type Error = TlsError;
-    type AcceptFuture = Pin<Box<dyn Future<Output = Result<Self::Stream, Self::Error>> + Send>>;
+/// RecordOptions tunes how TLS records are sent, trading per-connection memory and latency for
+/// throughput.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordOptions {
+    /// Largest amount of plaintext sent in a record, between 512 and 16384 bytes. Smaller records
+    /// can be decrypted sooner by the peer, larger ones take less CPU for bulk transfers. The
+    /// BoringSSL default of 16384 is used if unset.
+    pub max_send_fragment: Option<usize>,
+    /// If true, read and write buffers are released while connections are idle, reducing the
+    /// memory held by each connection at the cost of allocating them again. Enabled by default.
+    pub release_buffers: bool,
+}
 
-    fn accept(&self, conn: TcpStream) -> Self::AcceptFuture {
-        let mut acceptor = self.acceptor.clone();
-        Box::pin(async move {
-            let tls = acceptor.fetch_cert(&conn).await?;
-            tokio_boring::accept(&tls, conn)
-                .await
-                .map_err(TlsError::Handshake)
-        })
+impl Default for RecordOptions {
+    fn default() -> Self {
+        RecordOptions {
+            max_send_fragment: None,
+            release_buffers: true,
+        }
     }
 }
 
-const TEST_CERT: &[u8] = include_bytes!("cert-chain.pem");
-const TEST_PKEY: &[u8] = include_bytes!("key.pem");
-const TEST_ROOT: &[u8] = include_bytes!("root-cert.pem");
-const TEST_ROOT_KEY: &[u8] = include_bytes!("ca-key.pem");
+static RECORD_OPTIONS: Lazy<RwLock<RecordOptions>> = Lazy::new(Default::default);
 
-/// TestIdentity is an identity used for testing. This extends the Identity with test-only types
-#[derive(Debug)]
-pub enum TestIdentity {
-    Identity(Identity),
-    Ip(IpAddr),
+/// set_record_options sets the record options of every TLS context built afterwards.
+pub fn set_record_options(options: RecordOptions) {
+    *RECORD_OPTIONS.write().unwrap() = options;
 }
 
-impl From<Identity> for TestIdentity {
-    fn from(i: Identity) -> Self {
-        Self::Identity(i)
+fn record_options() -> RecordOptions {
+    *RECORD_OPTIONS.read().unwrap()
+}
+
+// Checks that key provides the bits of security the OpenSSL security level requires.
+fn check_security_level<T: pkey::HasPublic>(
+    key: &pkey::PKeyRef<T>,
+    level: u32,
+) -> Result<(), Error> {
+    const REQUIRED_BITS: [u32; 6] = [0, 80, 112, 128, 192, 256];
+    let required = REQUIRED_BITS[level.min(5) as usize];
+    // Finite field keys need many more bits than elliptic curve keys for the same security, see
+    // NIST SP 800-57.
+    let bits = match key.id() {
+        pkey::Id::RSA => match key.bits() {
+            b if b >= 15360 => 256,
+            b if b >= 7680 => 192,
+            b if b >= 3072 => 128,
+            b if b >= 2048 => 112,
+            b if b >= 1024 => 80,
+            _ => 0,
+        },
+        _ => key.bits() / 2,
+    };
+    if bits < required {
+        return Err(Error::SecurityLevel(bits, level, required));
     }
+    Ok(())
 }
 
-impl From<IpAddr> for TestIdentity {
-    fn from(i: IpAddr) -> Self {
-        Self::Ip(i)
+/// grpc_connector provides a client TLS channel for gRPC requests.
+/// `unix://` addresses are dialed as a plaintext unix domain socket instead, see uds_connector.
+pub fn grpc_connector(
+    uri: String,
+    root_cert: RootCert,
+    cfg: ConnectorConfig,
+) -> Result<TlsGrpcChannel, Error> {
+    if let Some(path) = uri.strip_prefix(UDS_SCHEME_PREFIX) {
+        return uds_connector(PathBuf::from(path), root_cert, cfg.limits);
     }
+    let uri = Uri::try_from(uri)?;
+    new_channel(uri, Transport::Tls(root_cert, cfg))
 }
 
-//
-// impl Display for TestIdentity {
-//     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-//         match self {
-//             TestIdentity::Identity(i) => std::fmt::Display::fmt(&i, f),
-//             TestIdentity::Ip(i) => std::fmt::Display::fmt(&i, f),
-//         }
-//     }
-// }
+/// uds_connector provides a plaintext h2 channel for gRPC requests over a unix domain socket.
+/// The socket is expected to be a local, already trusted, endpoint, so no root cert may be set.
+pub fn uds_connector(
+    path: PathBuf,
+    root_cert: RootCert,
+    limits: ChannelLimits,
+) -> Result<TlsGrpcChannel, Error> {
+    if root_cert != RootCert::Default {
+        return Err(Error::UdsRootCert(path));
+    }
+    new_channel(
+        Uri::from_static("http://localhost"),
+        Transport::Uds(path, limits),
+    )
+}
 
-// TODO: Move to the mock submodule.
+impl Transport {
+    fn limits(&self) -> &ChannelLimits {
+        match self {
+            Transport::Tls(_, cfg) => &cfg.limits,
+            Transport::Uds(_, limits) => limits,
+        }
+    }
+}
 
-// TODO: Move towards code that doesn't rely on SystemTime::now() for easier time control with
-// tokio. Ideally we'll be able to also get rid of the sub-second timestamps on certificates
-// (since right now they are there only for testing).
-fn generate_test_certs_at(
-    id: &TestIdentity,
-    not_before: SystemTime,
-    not_after: SystemTime,
-    rng: Option<&mut dyn rand::RngCore>,
-) -> Certs {
-    let key = pkey::PKey::private_key_from_pem(TEST_PKEY).unwrap();
-    let (ca_cert, ca_key) = test_ca().unwrap();
-    let mut builder = x509::X509::builder().unwrap();
-    let not_before_asn = system_time_to_asn1_time(not_before).unwrap();
-    builder.set_not_before(&not_before_asn).unwrap();
-    builder
-        .set_not_after(&system_time_to_asn1_time(not_after).unwrap())
-        .unwrap();
+fn new_channel(uri: Uri, transport: Transport) -> Result<TlsGrpcChannel, Error> {
+    let client = build_grpc_client(&uri, &transport)?;
+    let streams = transport.limits().max_concurrent_streams.max(1) as usize;
+    let root_cert_watch = match &transport {
+        Transport::Tls(RootCert::File(path) | RootCert::Directory(path), cfg) => Some(
+            RootCertWatch::new(path.clone(), cfg.root_cert_check_interval),
+        ),
+        _ => None,
+    };
+    Ok(TlsGrpcChannel {
+        uri,
+        transport,
+        inner: Arc::new(Mutex::new(ChannelInner {
+            client,
+            root_cert_watch,
+            failures: 0,
+            reconnect_at: None,
+            last_error: None,
+            last_success: None,
+        })),
+        reconnects: Default::default(),
+        backoff: None,
+        streams: Arc::new(tokio::sync::Semaphore::new(streams)),
+    })
+}
 
-    builder.set_pubkey(&key).unwrap();
-    builder.set_version(2).unwrap();
-    let serial_number = {
-        let mut data = [0u8; 20];
-        match rng {
-            None => rand::thread_rng().fill_bytes(&mut data),
-            Some(rng) => rng.fill_bytes(&mut data),
+fn build_grpc_client(uri: &Uri, transport: &Transport) -> Result<GrpcClient, Error> {
+    let (root_cert, cfg) = match transport {
+        Transport::Tls(root_cert, cfg) => (root_cert, cfg),
+        Transport::Uds(path, limits) => {
+            return Ok(GrpcClient::Uds(
+                grpc_client_builder(limits)
+                    .build(crate::hyper_util::UdsConnector::new(path.clone())),
+            ))
         }
-        // Clear the most significant bit to make the resulting bignum effectively 159 bit long.
-        data[0] &= 0x7f;
-        let serial = BigNum::from_slice(&data).unwrap();
-        serial.to_asn1_integer().unwrap()
     };
-    builder.set_serial_number(&serial_number).unwrap();
+    // Configure hyper's client to be h2 only and build with the
+    // correct https connector.
+    Ok(GrpcClient::Tls(
+        grpc_client_builder(&cfg.limits).build(control_plane_connector(uri, root_cert, cfg)?),
+    ))
+}
 
-    let mut names = boring::x509::X509NameBuilder::new().unwrap();
-    names.append_entry_by_text("O", "cluster.local").unwrap();
-    let names = names.build();
-    builder.set_issuer_name(&names).unwrap();
+/// control_plane_connector builds the connector establishing TLS connections to the control plane
+/// at uri, trusting root_cert.
+pub(super) fn control_plane_connector(
+    uri: &Uri,
+    root_cert: &RootCert,
+    cfg: &ConnectorConfig,
+) -> Result<AlpnCheckConnector<PinnedConnector<hyper_boring::HttpsConnector<ProxyConnector>>>, Error>
+{
+    let mut conn = ssl::SslConnector::builder(ssl::SslMethod::tls_client())?;
 
-    let basic_constraints = BasicConstraints::new().critical().build().unwrap();
-    let key_usage = KeyUsage::new()
-        .critical()
-        .digital_signature()
-        .key_encipherment()
-        .build()
-        .unwrap();
-    let ext_key_usage = ExtendedKeyUsage::new()
-        .client_auth()
-        .server_auth()
-        .build()
-        .unwrap();
-    let authority_key_identifier = AuthorityKeyIdentifier::new()
-        .keyid(false)
-        .issuer(false)
-        .build(&builder.x509v3_context(Some(&ca_cert), None))
-        .unwrap();
-    let mut san = SubjectAlternativeName::new();
-    let subject_alternative_name = match id {
-        TestIdentity::Identity(id) => san.uri(&id.to_string()),
-        TestIdentity::Ip(ip) => san.ip(&ip.to_string()),
-    };
-    let subject_alternative_name = subject_alternative_name
-        .critical()
-        .build(&builder.x509v3_context(Some(&ca_cert), None))
-        .unwrap();
-    builder.append_extension(key_usage).unwrap();
-    builder.append_extension(ext_key_usage).unwrap();
-    builder.append_extension(basic_constraints).unwrap();
-    builder.append_extension(authority_key_identifier).unwrap();
+    let is_localhost_call = uri.host() == Some("localhost");
+    let host_ip = uri_host_ip(uri);
+    let level = security_level();
+    let pins = ChainPins::new(&cfg.pins);
+    if level > 0 || pins.is_some() {
+        let pins = pins.clone();
+        conn.set_verify_callback(ssl::SslVerifyMode::PEER, move |verified, ctx| {
+            if !verified || ctx.error_depth() != 0 {
+                return verified;
+            }
+            // Reject servers whose key is too weak for the security level.
+            let strong_enough = level == 0
+                || ctx
+                    .current_cert()
+                    .and_then(|cert| cert.public_key().ok())
+                    .map(|key| check_security_level(&key, level).is_ok())
+                    .unwrap_or(false);
+            // Once the chain is verified, require a pinned key in it.
+            let pinned = match &pins {
+                Some(pins) => pins.check(ctx),
+                None => true,
+            };
+            if !(strong_enough && pinned) {
+                ctx.set_error(X509VerifyResult::APPLICATION_VERIFICATION);
+                return false;
+            }
+            true
+        });
+    } else {
+        conn.set_verify(ssl::SslVerifyMode::PEER);
+    }
+    match cfg.alpn {
+        ControlPlaneAlpn::H2 => conn.set_alpn_protos(Alpn::H2.encode())?,
+        ControlPlaneAlpn::H2AndHttp11 => conn.set_alpn_protos(Alpn::H2AndHttp11.encode())?,
+        ControlPlaneAlpn::Disabled => {}
+    }
+    conn.set_min_proto_version(Some(ssl::SslVersion::TLS1_2))?;
+    conn.set_max_proto_version(Some(ssl::SslVersion::TLS1_3))?;
+    match root_cert {
+        RootCert::File(f) => {
+            // Read the file here rather than with set_ca_file, which reports a missing, unreadable
+            // or unparsable file all the same way.
+            let pem = std::fs::read(f).map_err(|e| Error::root_cert_io(f, e))?;
+            for root in parse_root_certs(&pem)? {
+                conn.cert_store_mut().add_cert(root)?;
+            }
+        }
+        RootCert::Directory(dir) => {
+            for root in load_root_cert_dir(dir)? {
+                conn.cert_store_mut().add_cert(root)?;
+            }
+        }
+        RootCert::Static(b) => {
+            for root in parse_root_certs(b)? {
+                conn.cert_store_mut().add_cert(root)?;
+            }
+        }
+        RootCert::Default => {} // Already configured to use system root certs
+    }
+    let proxy = ProxyConnector::new(cfg.http_connector(), cfg.proxy_for(uri)?);
+    let mut https = hyper_boring::HttpsConnector::with_connector(proxy, conn)?;
+    let track_pins = pins.is_some();
+    https.set_callback(move |cc, _| {
+        if track_pins {
+            ChainPins::track(cc);
+        }
+        if let Some(ip) = host_ip {
+            // The host of an IPv6 uri is bracketed, which neither parses as an address nor is a
+            // valid server name. IP hosts are verified against the IP SANs, without SNI.
+            cc.set_use_server_name_indication(false);
+            cc.set_verify_hostname(false);
+            cc.param_mut().set_ip(ip)?;
+        }
+        if is_localhost_call {
+            // Follow Istio logic to allow localhost calls: https://github.com/istio/istio/blob/373fc89518c986c9f48ed3cd891930da6fdc8628/pkg/istio-agent/xds_proxy.go#L735
+            cc.set_verify_hostname(false);
+            let param = cc.param_mut();
+            param.set_hostflags(X509CheckFlags::NO_PARTIAL_WILDCARDS);
+            param.set_host("istiod.istio-system.svc").unwrap();
+        }
+        Ok(())
+    });
+    Ok(AlpnCheckConnector(PinnedConnector { inner: https, pins }))
+}
+
+/// uri_host_ip returns the host of uri if it is an IP address, such as 10.0.0.1 or [::1].
+fn uri_host_ip(uri: &Uri) -> Option<IpAddr> {
+    let host = uri.host()?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    host.parse().ok()
+}
+
+/// parse_root_certs parses every certificate in a PEM bundle. During root rotation, the bundle
+/// holds both the old and new roots, so all of them must be trusted.
+pub fn parse_root_certs(pem: &[u8]) -> Result<Vec<x509::X509>, Error> {
+    let roots = x509::X509::stack_from_pem(pem).map_err(Error::RootCertParse)?;
+    if roots.is_empty() {
+        return Err(Error::RootCertEmpty);
+    }
+    Ok(roots)
+}
+
+// Lists the *.pem and *.crt files in dir, sorted so the result is stable.
+fn root_cert_dir_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| Error::RootCertDirectory(dir.to_path_buf(), e.to_string()))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file()
+                && matches!(
+                    p.extension().and_then(|e| e.to_str()),
+                    Some("pem") | Some("crt")
+                )
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// load_root_cert_dir loads every root in the *.pem and *.crt files under dir. Files that cannot
+/// be parsed are skipped, but at least one root must be found.
+pub fn load_root_cert_dir(dir: &Path) -> Result<Vec<x509::X509>, Error> {
+    let mut roots = Vec::new();
+    for file in root_cert_dir_files(dir)? {
+        let parsed = std::fs::read(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| parse_root_certs(&pem).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(certs) => roots.extend(certs),
+            Err(e) => warn!("skipping root certificate {}: {e}", file.display()),
+        }
+    }
+    if roots.is_empty() {
+        return Err(Error::RootCertEmpty);
+    }
+    Ok(roots)
+}
+
+pub(super) fn grpc_client_builder(limits: &ChannelLimits) -> hyper_util::client::legacy::Builder {
+    let mut builder =
+        hyper_util::client::legacy::Client::builder(hyper_util::rt::TokioExecutor::new());
+    builder
+        .http2_only(true)
+        .http2_keep_alive_interval(Duration::from_secs(30))
+        .http2_keep_alive_timeout(Duration::from_secs(10))
+        .http2_initial_stream_window_size(limits.initial_stream_window_size)
+        .http2_initial_connection_window_size(limits.initial_connection_window_size)
+        .http2_max_frame_size(limits.max_frame_size)
+        .timer(crate::hyper_util::TokioTimer);
+    builder
+}
+
+type BoxBody1 = HttpBody04ToHttpBody1<BoxBody>;
+
+#[derive(Default)]
+pub enum DefaultIncoming {
+    Some(Incoming),
+    #[default]
+    Empty,
+}
+
+impl Body for DefaultIncoming {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        match self.get_mut() {
+            DefaultIncoming::Some(ref mut i) => Pin::new(i).poll_frame(cx),
+            DefaultIncoming::Empty => Pin::new(&mut http_body_util::Empty::<Bytes>::new())
+                .poll_frame(cx)
+                .map_err(|_| unreachable!()),
+        }
+    }
+}
+
+/// LimitedIncoming is a gRPC response body which fails once a message larger than the limit is
+/// announced, and releases its stream permit once dropped.
+pub struct LimitedIncoming {
+    inner: DefaultIncoming,
+    limit: usize,
+    // Length prefix of the next message; each message is preceded by a compression flag byte and
+    // a big endian u32 length.
+    header: [u8; 5],
+    header_read: usize,
+    // Bytes of the current message still to be received.
+    remaining: usize,
+    _permit: Option<tokio::sync::OwnedSemaphorePermit>,
+}
+
+impl LimitedIncoming {
+    fn new(
+        inner: DefaultIncoming,
+        limit: usize,
+        permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    ) -> Self {
+        LimitedIncoming {
+            inner,
+            limit,
+            header: [0; 5],
+            header_read: 0,
+            remaining: 0,
+            _permit: permit,
+        }
+    }
+
+    fn check(&mut self, mut data: &[u8]) -> Result<(), ChannelError> {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len());
+                self.remaining -= n;
+                data = &data[n..];
+                continue;
+            }
+            let n = (self.header.len() - self.header_read).min(data.len());
+            self.header[self.header_read..self.header_read + n].copy_from_slice(&data[..n]);
+            self.header_read += n;
+            data = &data[n..];
+            if self.header_read == self.header.len() {
+                self.header_read = 0;
+                let size = u32::from_be_bytes(self.header[1..].try_into().unwrap()) as usize;
+                if size > self.limit {
+                    return Err(ChannelError::MessageTooLarge {
+                        size,
+                        limit: self.limit,
+                    });
+                }
+                self.remaining = size;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Body for LimitedIncoming {
+    type Data = Bytes;
+    type Error = ChannelError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+            None => return Poll::Ready(None),
+            Some(Err(e)) => return Poll::Ready(Some(Err(ChannelError::ResponseBody(e)))),
+            Some(Ok(frame)) => frame,
+        };
+        if let Some(data) = frame.data_ref() {
+            if let Err(e) = this.check(data) {
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+        Poll::Ready(Some(Ok(frame)))
+    }
+}
+
+impl tower::Service<Request<BoxBody>> for TlsGrpcChannel {
+    type Response = Response<HttpBody1ToHttpBody04<LimitedIncoming>>;
+    type Error = ChannelError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.watch_root_cert();
+        let Some(reconnect_at) = self.inner.lock().unwrap().reconnect_at else {
+            self.backoff = None;
+            return Ok(()).into();
+        };
+        let backoff = self
+            .backoff
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(reconnect_at)));
+        if backoff.deadline() != reconnect_at {
+            backoff.as_mut().reset(reconnect_at);
+        }
+        ready!(backoff.as_mut().poll(cx));
+        self.backoff = None;
+        Poll::Ready(self.reconnect())
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        let mut req = req.map(HttpBody04ToHttpBody1::new);
+
+        let (Some(scheme), Some(authority)) = (self.uri.scheme(), self.uri.authority()) else {
+            let err = ChannelError::InvalidUri(self.uri.to_string());
+            return Box::pin(async move { Err(err) });
+        };
+        let uri = Uri::builder()
+            .scheme(scheme.to_owned())
+            .authority(authority.to_owned())
+            .path_and_query(
+                req.uri()
+                    .path_and_query()
+                    .map(|pq| pq.as_str())
+                    .unwrap_or("/"),
+            )
+            .build();
+        match uri {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(e) => {
+                let err = ChannelError::InvalidUri(e.to_string());
+                return Box::pin(async move { Err(err) });
+            }
+        }
+        let future = self.inner.lock().unwrap().client.request(req);
+        let inner = self.inner.clone();
+        let streams = self.streams.clone();
+        let limit = self.transport.limits().max_message_size;
+        Box::pin(async move {
+            // The semaphore is never closed.
+            let permit = streams.acquire_owned().await.ok();
+            match future.await {
+                Ok(res) => {
+                    Self::record_success(&inner);
+                    Ok(res.map(|body| {
+                        HttpBody1ToHttpBody04::new(LimitedIncoming::new(
+                            DefaultIncoming::Some(body),
+                            limit,
+                            permit,
+                        ))
+                    }))
+                }
+                Err(e) => {
+                    Self::record_failure(&inner, &e);
+                    Err(e.into())
+                }
+            }
+        })
+    }
+}
+
+impl Certs {
+    fn verify_mode() -> ssl::SslVerifyMode {
+        ssl::SslVerifyMode::PEER | ssl::SslVerifyMode::FAIL_IF_NO_PEER_CERT
+    }
+
+    /// builder returns a TlsContextBuilder to construct an acceptor or connector for these
+    /// certificates with non-default options.
+    pub fn builder(&self) -> TlsContextBuilder<'_> {
+        TlsContextBuilder::new(self)
+    }
+
+    pub fn mtls_acceptor(&self, dest_id: Option<&Identity>) -> Result<ssl::SslAcceptor, Error> {
+        let mut builder = self.builder();
+        if let Some(dest_id) = dest_id {
+            // Validate that the source cert shares the same trust domain
+            builder = builder.peer_trust_domain(dest_id);
+        }
+        builder.build_acceptor()
+    }
+
+    pub fn acceptor(&self) -> Result<ssl::SslAcceptor, Error> {
+        self.builder().require_client_cert(false).build_acceptor()
+    }
+
+    pub fn connector(&self, dest: impl Into<ExpectedPeer>) -> Result<ssl::SslConnector, Error> {
+        self.builder().build_connector(dest)
+    }
+
+    // Sets the certificate, chain and key of a single connection, overriding those of its context.
+    fn use_certificate(&self, ssl: &mut ssl::SslRef) -> Result<(), Error> {
+        self.key.apply_ssl(ssl)?;
+        ssl.set_certificate(&self.cert.x509)?;
+        for chain_cert in self.chain.iter().filter(|c| !is_self_signed(&c.x509)) {
+            ssl.add_chain_cert(chain_cert.x509.clone())?;
+        }
+        Ok(())
+    }
+
+    fn setup_ctx(
+        &self,
+        conn: &mut SslContextBuilder,
+        opts: &TlsContextBuilder,
+    ) -> Result<(), Error> {
+        // general TLS options
+        conn.set_alpn_protos(opts.alpn.unwrap_or(Alpn::H2).encode())?;
+        conn.set_min_proto_version(Some(opts.min_version))?;
+        conn.set_max_proto_version(Some(opts.max_version))?;
+        if !opts.session_cache {
+            conn.set_session_cache_mode(ssl::SslSessionCacheMode::OFF);
+        }
+        if let Some(keylog) = &opts.keylog {
+            let keylog = keylog.clone();
+            conn.set_keylog_callback(move |_, line| keylog(line));
+        }
+        if let Some(sigalgs) = &opts.sigalgs {
+            conn.set_sigalgs_list(sigalgs)
+                .map_err(|e| Error::InvalidSigalgs(sigalgs.clone(), e))?;
+        }
+        if let Some(max) = opts.records.max_send_fragment {
+            conn.set_max_send_fragment(max)?;
+        }
+        if opts.records.release_buffers {
+            conn.set_mode(ssl::SslMode::RELEASE_BUFFERS);
+        }
+        set_buffer_size(conn, &opts.records);
+        opts.runtime.apply(conn)?;
+
+        // key and certs
+        let policy = opts.policy();
+        check_security_level(&self.cert.x509.public_key()?, opts.security_level)?;
+        self.key.apply_ctx(conn)?;
+        conn.set_certificate(&self.cert.x509)?;
+        for chain_cert in self.chain.iter() {
+            // Only include intermediate certs in the chain.
+            // The root cert should already exist on the peer.
+            if !is_self_signed(&chain_cert.x509) {
+                // This is an intermediate cert that should be added to the cert chain
+                conn.add_extra_chain_cert(chain_cert.x509.clone())?;
+            }
+            if policy.root_store.is_none() {
+                conn.cert_store_mut().add_cert(chain_cert.x509.clone())?;
+            }
+        }
+        if let Some(store) = &policy.root_store {
+            for root in store.roots() {
+                conn.cert_store_mut().add_cert(root)?;
+            }
+        }
+        // Trust the union of federated roots; the verify callback then checks each peer chains to
+        // a root of its own trust domain.
+        if let Some(bundle) = &policy.trust_bundle {
+            for root in bundle.roots() {
+                conn.cert_store_mut().add_cert(root.clone())?;
+            }
+        }
+        // Engine keys cannot be checked here, they were when set by with_private_key.
+        if self.key.in_memory().is_some() {
+            conn.check_private_key()?;
+        }
+
+        // by default, allow boringssl to do standard validation
+        conn.set_verify_callback(
+            Self::verify_mode(),
+            Verifier::None.callback(policy, SanEnforcement::Enforce),
+        );
+
+        Ok(())
+    }
+}
+
+/// Number of connectors kept by a ConnectorCache by default.
+pub const DEFAULT_CONNECTOR_CACHE_CAPACITY: usize = 1024;
+
+/// ConnectorCache memoizes the connectors built by Certs::connector, which are costly to build.
+/// Connectors are keyed by destination identity, since their verify callback checks it, and by the
+/// generation of the local certificate, its key and the roots it trusts, so that a rotation of any
+/// of them builds new connectors. Clones share the cache.
+#[derive(Clone)]
+pub struct ConnectorCache {
+    inner: Arc<Mutex<ConnectorCacheInner>>,
+    capacity: usize,
+    builds: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct ConnectorCacheInner {
+    connectors: HashMap<ConnectorKey, ssl::SslConnector>,
+    // Insertion order, to evict the oldest connectors first. Connectors of a rotated certificate
+    // are never looked up again, and being older than those of its replacement go first.
+    order: VecDeque<ConnectorKey>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ConnectorKey {
+    generation: Generation,
+    dest: ExpectedPeer,
+}
+
+// Generation identifies what a connector was built from: the leaf certificate and its key, the
+// roots it trusts and the TlsRuntimeConfig. Certificates, keys and trust bundles are compared by
+// address, which is not reused while their connector is cached since the connector holds a
+// reference to them.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Generation {
+    leaf: usize,
+    key: (usize, Option<String>),
+    root_store: Option<(usize, u64)>,
+    trust_bundle: Option<usize>,
+    runtime: u64,
+}
+
+impl Generation {
+    fn of(certs: &Certs) -> Self {
+        use foreign_types::ForeignType;
+
+        Generation {
+            leaf: certs.cert.x509.as_ptr() as usize,
+            key: certs.key.identity(),
+            root_store: certs
+                .policy
+                .root_store
+                .as_ref()
+                .map(|s| (s.id(), s.version())),
+            trust_bundle: certs
+                .policy
+                .trust_bundle
+                .as_ref()
+                .map(|b| Arc::as_ptr(b) as usize),
+            runtime: TlsRuntime::global().generation(),
+        }
+    }
+}
+
+impl Default for ConnectorCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONNECTOR_CACHE_CAPACITY)
+    }
+}
+
+impl ConnectorCache {
+    pub fn new(capacity: usize) -> Self {
+        ConnectorCache {
+            inner: Default::default(),
+            capacity,
+            builds: Default::default(),
+        }
+    }
+
+    /// connect_config returns the configuration to connect to dest with certs, reusing the
+    /// connector built by an earlier call if the certificates and roots did not change since.
+    pub fn connect_config(
+        &self,
+        certs: &Certs,
+        dest: impl Into<ExpectedPeer>,
+    ) -> Result<ssl::ConnectConfiguration, Error> {
+        let key = ConnectorKey {
+            generation: Generation::of(certs),
+            dest: dest.into(),
+        };
+        let cached = self.inner.lock().unwrap().connectors.get(&key).cloned();
+        let connector = match cached {
+            Some(connector) => connector,
+            None => {
+                let connector = certs.connector(key.dest.clone())?;
+                self.builds.fetch_add(1, atomic::Ordering::Relaxed);
+                self.insert(key, connector.clone());
+                connector
+            }
+        };
+        Ok(connector.configure()?)
+    }
+
+    /// builds returns the number of connectors built, rather than served from the cache.
+    pub fn builds(&self) -> u64 {
+        self.builds.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().connectors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn insert(&self, key: ConnectorKey, connector: ssl::SslConnector) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.connectors.insert(key.clone(), connector).is_none() {
+            inner.order.push_back(key);
+        }
+        while inner.connectors.len() > self.capacity {
+            let Some(oldest) = inner.order.pop_front() else {
+                break;
+            };
+            inner.connectors.remove(&oldest);
+        }
+    }
+}
+
+type KeyLogCallback = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// TlsContextBuilder constructs acceptors and connectors for a Certs. The defaults match
+/// mtls_acceptor and connector: TLS 1.3 only, h2, client certificates required and peers verified
+/// against the roots and policy of the Certs.
+pub struct TlsContextBuilder<'a> {
+    certs: &'a Certs,
+    alpn: Option<Alpn>,
+    min_version: ssl::SslVersion,
+    max_version: ssl::SslVersion,
+    require_client_cert: bool,
+    peer_trust_domain: Option<Identity>,
+    session_cache: bool,
+    keylog: Option<KeyLogCallback>,
+    // If set, the certificate and key are taken from the current Certs on every handshake.
+    select_certs: Option<Arc<RwLock<Certs>>>,
+    security_level: u32,
+    sigalgs: Option<String>,
+    records: RecordOptions,
+    max_cert_list: Option<usize>,
+    san_enforcement: SanEnforcement,
+    runtime: Arc<TlsRuntimeConfig>,
+}
+
+impl<'a> TlsContextBuilder<'a> {
+    fn new(certs: &'a Certs) -> Self {
+        let runtime = TlsRuntime::global().load();
+        TlsContextBuilder {
+            certs,
+            alpn: runtime.alpn,
+            min_version: runtime.min_version(),
+            max_version: ssl::SslVersion::TLS1_3,
+            require_client_cert: true,
+            peer_trust_domain: None,
+            session_cache: true,
+            keylog: None,
+            select_certs: None,
+            security_level: security_level(),
+            sigalgs: sigalgs(),
+            records: record_options(),
+            max_cert_list: max_cert_list(),
+            san_enforcement: san_enforcement(),
+            runtime,
+        }
+    }
+
+    /// runtime_config builds the context with config rather than the global TlsRuntimeConfig. It
+    /// resets the minimum version and ALPN to those of config.
+    pub fn runtime_config(mut self, config: Arc<TlsRuntimeConfig>) -> Self {
+        self.min_version = config.min_version();
+        self.alpn = config.alpn;
+        self.runtime = config;
+        self
+    }
+
+    // The peer policy of the certificates, with the trust bundle of the runtime config if set.
+    fn policy(&self) -> PeerPolicy {
+        let mut policy = self.certs.policy.clone();
+        if let Some(bundle) = self.runtime.trust_bundle() {
+            policy.trust_bundle = Some(bundle);
+        }
+        policy
+    }
+
+    /// alpn sets the offered protocols. For acceptors, it also makes the server select the first
+    /// of its protocols offered by the client; by default, none is selected.
+    pub fn alpn(mut self, alpn: Alpn) -> Self {
+        self.alpn = Some(alpn);
+        self
+    }
+
+    pub fn min_version(mut self, version: ssl::SslVersion) -> Self {
+        self.min_version = version;
+        self
+    }
+
+    pub fn max_version(mut self, version: ssl::SslVersion) -> Self {
+        self.max_version = version;
+        self
+    }
+
+    /// require_client_cert sets whether acceptors require and verify a client certificate.
+    pub fn require_client_cert(mut self, require: bool) -> Self {
+        self.require_client_cert = require;
+        self
+    }
+
+    /// peer_trust_domain makes acceptors only accept clients of the trust domain of id.
+    pub fn peer_trust_domain(mut self, id: &Identity) -> Self {
+        self.peer_trust_domain = Some(id.clone());
+        self
+    }
+
+    pub fn session_cache(mut self, enabled: bool) -> Self {
+        self.session_cache = enabled;
+        self
+    }
+
+    /// keylog receives the TLS secrets of each connection in NSS key log format, for debugging.
+    pub fn keylog(mut self, keylog: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.keylog = Some(Arc::new(keylog));
+        self
+    }
+
+    /// security_level overrides the security level set with set_security_level for this context.
+    pub fn security_level(mut self, level: u32) -> Self {
+        self.security_level = level;
+        self
+    }
+
+    /// sigalgs overrides the signature algorithms set with set_sigalgs for this context. Invalid
+    /// lists fail the build of the context.
+    pub fn sigalgs(mut self, sigalgs: &str) -> Self {
+        self.sigalgs = Some(sigalgs.to_string());
+        self
+    }
+
+    /// max_send_fragment overrides the largest record set with set_record_options for this
+    /// context.
+    pub fn max_send_fragment(mut self, max: usize) -> Self {
+        self.records.max_send_fragment = Some(max);
+        self
+    }
+
+    /// release_buffers overrides whether idle connections release their buffers, as set with
+    /// set_record_options, for this context.
+    pub fn release_buffers(mut self, release: bool) -> Self {
+        self.records.release_buffers = release;
+        self
+    }
+
+    /// max_cert_list overrides the largest client certificate chain set with set_max_cert_list
+    /// for this context, in bytes.
+    pub fn max_cert_list(mut self, max: usize) -> Self {
+        self.max_cert_list = Some(max);
+        self
+    }
+
+    /// san_enforcement overrides how SAN mismatches are handled, as set with set_san_enforcement,
+    /// for this context.
+    pub fn san_enforcement(mut self, mode: SanEnforcement) -> Self {
+        self.san_enforcement = mode;
+        self
+    }
+
+    pub fn build_acceptor(self) -> Result<ssl::SslAcceptor, Error> {
+        // mozilla_intermediate_v5 is the only variant that enables TLSv1.3, so we use that.
+        let mut conn = ssl::SslAcceptor::mozilla_intermediate_v5(ssl::SslMethod::tls_server())?;
+        self.certs.setup_ctx(&mut conn, &self)?;
+        if let Some(max) = self.max_cert_list {
+            set_ctx_max_cert_list(&mut conn, max);
+        }
+
+        if let Some(alpn) = self.alpn {
+            conn.set_alpn_select_callback(move |_, client| {
+                ssl::select_next_proto(alpn.encode(), client).ok_or(ssl::AlpnError::NOACK)
+            });
+        }
+        if let Some(current) = self.select_certs.clone() {
+            conn.set_select_certificate_callback(move |mut hello| {
+                let certs = current.read().unwrap().clone();
+                certs.use_certificate(hello.ssl_mut()).map_err(|e| {
+                    warn!("failed to select certificate: {e}");
+                    ssl::SelectCertError::ERROR
+                })
+            });
+        }
+        if !self.require_client_cert {
+            conn.set_verify_callback(
+                ssl::SslVerifyMode::NONE,
+                Verifier::None.callback(PeerPolicy::default(), SanEnforcement::Enforce),
+            );
+        } else if let Some(id) = self.peer_trust_domain {
+            conn.set_verify_callback(
+                Certs::verify_mode(),
+                Verifier::san_trust_domain(id).callback(self.policy(), self.san_enforcement),
+            );
+        }
+        Ok(conn.build())
+    }
+
+    pub fn build_connector(
+        self,
+        dest: impl Into<ExpectedPeer>,
+    ) -> Result<ssl::SslConnector, Error> {
+        let mut conn = ssl::SslConnector::builder(ssl::SslMethod::tls_client())?;
+        self.certs.setup_ctx(&mut conn, &self)?;
+
+        // client verifies SAN
+        conn.set_verify_callback(
+            Certs::verify_mode(),
+            Verifier::peer(dest.into()).callback(self.policy(), self.san_enforcement),
+        );
+
+        Ok(conn.build())
+    }
+}
+
+enum Verifier {
+    // Does not verify an individual identity.
+    None,
+
+    // Allows exactly one identity, making sure at least one of the presented certs matches that identity
+    // The URI of the identity is rendered once, to compare it to the SANs of the peer.
+    San(Identity, String),
+
+    // Allows all identities that share the same trust domain
+    // Holds the "spiffe://<trust domain>/" prefix of identities of the trust domain.
+    SanTrustDomain(Identity, String),
+
+    // Allows peers with an IP SAN for the address.
+    IpSan(IpAddr),
+
+    // Allows peers with a DNS SAN matching the name.
+    DnsName(String),
+
+    // Allows peers accepted by any of the verifiers.
+    AnyOf(Vec<Verifier>),
+}
+
+impl fmt::Display for Verifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verifier::None => f.write_str("any peer"),
+            Verifier::San(_, uri) => f.write_str(uri),
+            Verifier::SanTrustDomain(_, prefix) => write!(f, "{prefix}*"),
+            Verifier::IpSan(ip) => write!(f, "{ip}"),
+            Verifier::DnsName(name) => f.write_str(name),
+            Verifier::AnyOf(verifiers) => {
+                for (i, verifier) in verifiers.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" or ")?;
+                    }
+                    write!(f, "{verifier}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Verifier {
+    fn base_verifier(verified: bool, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
+        if !verified {
+            return Err(TlsError::Verification(ctx.error()));
+        };
+        Ok(())
+    }
+
+    fn san(identity: Identity) -> Self {
+        let uri = identity.to_string();
+        Verifier::San(identity, uri)
+    }
+
+    fn peer(dest: ExpectedPeer) -> Self {
+        match dest {
+            ExpectedPeer::Identity(id) => Verifier::san(id),
+            ExpectedPeer::IpSan(ip) => Verifier::IpSan(ip),
+            ExpectedPeer::DnsName(name) => Verifier::DnsName(name),
+            ExpectedPeer::AnyOf(peers) => {
+                Verifier::AnyOf(peers.into_iter().map(Verifier::peer).collect())
+            }
+        }
+    }
+
+    fn san_trust_domain(identity: Identity) -> Self {
+        let prefix = match &identity {
+            Identity::Spiffe { trust_domain, .. } => format!("spiffe://{trust_domain}/"),
+        };
+        Verifier::SanTrustDomain(identity, prefix)
+    }
+
+    fn verifiy_san(
+        identity: &Identity,
+        uri: &str,
+        ctx: &mut X509StoreContextRef,
+    ) -> Result<(), TlsError> {
+        let cert = verified_leaf(ctx)?;
+
+        verify_san_uri(&cert, identity, uri)
+    }
+
+    fn verifiy_san_trust_domain(
+        identity: &Identity,
+        prefix: &str,
+        ctx: &mut X509StoreContextRef,
+    ) -> Result<(), TlsError> {
+        let cert = verified_leaf(ctx)?;
+
+        verify_san_trust_domain_prefix(&cert, identity, prefix)
+    }
+
+    fn verify_ip_san(ip: IpAddr, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
+        let cert = verified_leaf(ctx)?;
+
+        verify_ip_san(&cert, ip)
+    }
+
+    fn verify_dns_san(name: &str, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
+        let cert = verified_leaf(ctx)?;
+
+        verify_dns_san(&cert, name)
+    }
+
+    fn verify_not_denied(
+        deny_list: &DenyList,
+        ctx: &mut X509StoreContextRef,
+    ) -> Result<(), TlsError> {
+        // Only check once per handshake, when verifying the leaf.
+        if ctx.error_depth() != 0 {
+            return Ok(());
+        }
+        let cert = verified_leaf(ctx)?;
+
+        deny_list.verify(&cert)
+    }
+
+    fn verify(
+        &self,
+        verified: bool,
+        ctx: &mut X509StoreContextRef,
+        policy: &PeerPolicy,
+        enforcement: SanEnforcement,
+    ) -> Result<(), TlsError> {
+        Self::base_verifier(verified, ctx)?;
+        let peer = match &policy.trust_domain_aliases {
+            Some(aliases) => self.verify_peer_aliased(aliases, ctx),
+            None => self.verify_peer(ctx),
+        };
+        match (peer, enforcement) {
+            (Ok(()), _) => {}
+            (Err(e), SanEnforcement::Enforce) => return Err(e),
+            (Err(e), SanEnforcement::Monitor) => self.record_monitored(&e, ctx),
+        }
+        if let Some(deny_list) = &policy.deny_list {
+            Verifier::verify_not_denied(deny_list, ctx)?;
+        }
+        if let Some(bundle) = &policy.trust_bundle {
+            bundle.verify(ctx)?;
+        }
+        Ok(())
+    }
+
+    // Checks the peer certificate is for the expected peer.
+    fn verify_peer(&self, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
+        match self {
+            Self::San(identity, uri) => Verifier::verifiy_san(identity, uri, ctx),
+            Self::SanTrustDomain(identity, prefix) => {
+                Verifier::verifiy_san_trust_domain(identity, prefix, ctx)
+            }
+            Self::IpSan(ip) => Verifier::verify_ip_san(*ip, ctx),
+            Self::DnsName(name) => Verifier::verify_dns_san(name, ctx),
+            Self::AnyOf(verifiers) => {
+                let mut errors = Vec::with_capacity(verifiers.len());
+                for verifier in verifiers {
+                    match verifier.verify_peer(ctx) {
+                        Ok(()) => return Ok(()),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                Err(TlsError::NoExpectedPeer(errors))
+            }
+            Self::None => Ok(()),
+        }
+    }
+
+    // Returns verifiers accepting the expected peers under the aliases of their trust domain.
+    fn aliased(&self, aliases: &TrustDomainAliases) -> Vec<Verifier> {
+        match self {
+            Self::San(identity, _) => aliases
+                .alias_identities(identity)
+                .into_iter()
+                .map(Verifier::san)
+                .collect(),
+            Self::SanTrustDomain(identity, _) => aliases
+                .alias_identities(identity)
+                .into_iter()
+                .map(Verifier::san_trust_domain)
+                .collect(),
+            Self::AnyOf(verifiers) => verifiers.iter().flat_map(|v| v.aliased(aliases)).collect(),
+            Self::IpSan(_) | Self::DnsName(_) | Self::None => Vec::new(),
+        }
+    }
+
+    // Like verify_peer, but also accepts the expected peers under the aliases of their trust
+    // domain. The trust domain presented by accepted peers is recorded.
+    fn verify_peer_aliased(
+        &self,
+        aliases: &TrustDomainAliases,
+        ctx: &mut X509StoreContextRef,
+    ) -> Result<(), TlsError> {
+        if let Err(e) = self.verify_peer(ctx) {
+            let aliased = self.aliased(aliases);
+            if aliased.is_empty() || Verifier::AnyOf(aliased).verify_peer(ctx).is_err() {
+                return Err(e);
+            }
+        }
+        // Only record once per handshake, when verifying the leaf.
+        if ctx.error_depth() == 0 && !matches!(self, Self::None) {
+            if let Ok(cert) = verified_leaf(ctx) {
+                aliases.record_match(&extract_sans(&cert));
+            }
+        }
+        Ok(())
+    }
+
+    // Records a SAN mismatch let through by SanEnforcement::Monitor.
+    fn record_monitored(&self, e: &TlsError, ctx: &X509StoreContextRef) {
+        // Only record once per handshake, when verifying the leaf.
+        if ctx.error_depth() != 0 {
+            return;
+        }
+        let got = verified_leaf(ctx)
+            .map(|cert| extract_all_sans(&cert))
+            .unwrap_or_default()
+            .iter()
+            .map(San::to_string)
+            .collect::<Vec<_>>();
+        SAN_METRICS.monitored_san_mismatches.inc();
+        warn!(expected = %self, ?got, "allowing peer with unexpected SAN in monitor mode: {e}");
+    }
+
+    fn callback(
+        self,
+        policy: PeerPolicy,
+        enforcement: SanEnforcement,
+    ) -> impl Fn(bool, &mut X509StoreContextRef) -> bool {
+        move |verified, ctx| match self.verify(verified, ctx, &policy, enforcement) {
+            Ok(_) => true,
+            Err(e) => {
+                // TODO metrics/counters; info would be too noisy
+                info!("failed verifying TLS: {e}");
+                // Record SAN failures in the verify result, so they can be told apart once the
+                // handshake fails.
+                if matches!(
+                    e,
+                    TlsError::SanError(..)
+                        | TlsError::SanTrustDomainError(..)
+                        | TlsError::IpSanError(..)
+                        | TlsError::DnsSanError(..)
+                        | TlsError::NoExpectedPeer(_)
+                ) {
+                    ctx.set_error(X509VerifyResult::APPLICATION_VERIFICATION);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// DenyList is a set of peer identities that are rejected during the TLS handshake. It can be
+/// updated at runtime; clones share the same underlying set.
+#[derive(Clone, Debug, Default)]
+pub struct DenyList {
+    identities: Arc<RwLock<HashSet<Identity>>>,
+    denied: Arc<AtomicU64>,
+}
+
+impl DenyList {
+    pub fn deny(&self, id: Identity) {
+        self.identities.write().unwrap().insert(id);
+    }
+
+    pub fn allow(&self, id: &Identity) {
+        self.identities.write().unwrap().remove(id);
+    }
+
+    pub fn contains(&self, id: &Identity) -> bool {
+        self.identities.read().unwrap().contains(id)
+    }
+
+    /// denied returns the number of handshakes rejected due to a denied identity.
+    pub fn denied(&self) -> u64 {
+        self.denied.load(atomic::Ordering::Relaxed)
+    }
+
+    fn verify(&self, cert: &x509::X509) -> Result<(), TlsError> {
+        let identities = self.identities.read().unwrap();
+        if identities.is_empty() {
+            return Ok(());
+        }
+        if let Some(id) = extract_sans(cert)
+            .into_iter()
+            .find(|id| identities.contains(id))
+        {
+            self.denied.fetch_add(1, atomic::Ordering::Relaxed);
+            return Err(TlsError::IdentityDenied(id));
+        }
+        Ok(())
+    }
+}
+
+pub trait SanChecker {
+    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError>;
+    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError>;
+}
+
+impl SanChecker for Certs {
+    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError> {
+        self.cert.x509.verify_san(identity)
+    }
+
+    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError> {
+        self.cert.x509.verify_san_trust_domain(identity)
+    }
+}
+
+static SAN_METRICS: Lazy<SanMetrics> = Lazy::new(SanMetrics::default);
+
+/// SanMetrics counts the SANs of peer certificates that are not used as expected.
+#[derive(Default)]
+pub struct SanMetrics {
+    invalid_uri_sans: Counter,
+    monitored_san_mismatches: Counter,
+}
+
+impl SanMetrics {
+    /// global returns the counts of every certificate inspected by ztunnel.
+    pub fn global() -> &'static SanMetrics {
+        &SAN_METRICS
+    }
+
+    /// register_metrics exposes the counts in the registry.
+    pub fn register_metrics(&self, registry: &mut Registry) {
+        registry.register(
+            "tls_invalid_uri_sans",
+            "The total number of certificate URI SANs skipped because they are not valid SPIFFE \
+             identities",
+            self.invalid_uri_sans.clone(),
+        );
+        registry.register(
+            "tls_monitored_san_mismatches",
+            "The total number of handshakes allowed despite a peer SAN mismatch, as SAN \
+             verification is in monitor mode",
+            self.monitored_san_mismatches.clone(),
+        );
+    }
+}
+
+/// invalid_uri_sans returns the number of URI SANs skipped by extract_sans because they are not
+/// valid SPIFFE identities.
+pub fn invalid_uri_sans() -> u64 {
+    SAN_METRICS.invalid_uri_sans.get()
+}
+
+/// extract_sans returns the SPIFFE identities in the URI SANs of cert. URI SANs that are not
+/// SPIFFE identities are skipped, so that they don't hide the valid ones.
+pub fn extract_sans(cert: &x509::X509Ref) -> Vec<Identity> {
+    cert.subject_alt_names()
+        .iter()
+        .flat_map(|sans| sans.iter())
+        .filter_map(|s| s.uri())
+        .filter_map(|uri| match Identity::from_str(uri) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                debug!("skipping URI SAN: {e}");
+                SAN_METRICS.invalid_uri_sans.inc();
+                None
+            }
+        })
+        .collect()
+}
+
+/// peer_identities returns the SPIFFE identities of the certificate presented by the peer of a
+/// connection, client or server side.
+pub fn peer_identities(ssl: &ssl::SslRef) -> Vec<Identity> {
+    ssl.peer_certificate()
+        .map(|cert| extract_sans(&cert))
+        .unwrap_or_default()
+}
+
+// verified_leaf returns the peer certificate being verified: depth 0 of the chain built by the
+// verification, whatever order the peer presented its chain in. The first certificate the peer
+// presented is used before a chain was built.
+fn verified_leaf(ctx: &X509StoreContextRef) -> Result<x509::X509, TlsError> {
+    if let Some(leaf) = ctx.chain().and_then(|chain| chain.get(0)) {
+        return Ok(leaf.to_owned());
+    }
+    let ssl_idx = X509StoreContext::ssl_idx().map_err(Error::SslError)?;
+    ctx.ex_data(ssl_idx)
+        .ok_or(TlsError::ExDataError)?
+        .peer_certificate()
+        .ok_or(TlsError::PeerCertError)
+}
+
+/// verified_peer_chain returns the certificate chain of the peer of an established connection,
+/// from its leaf up to the root it was verified against, such as for audit logs. Certificates the
+/// peer presented that are not part of the chain are left out. BoringSSL does not keep the chain
+/// it verified during the handshake, so it is verified again against the roots of the connection.
+pub fn verified_peer_chain(ssl: &ssl::SslRef) -> Result<Vec<x509::X509>, TlsError> {
+    let leaf = ssl.peer_certificate().ok_or(TlsError::PeerCertError)?;
+    let mut presented = Stack::new().map_err(Error::SslError)?;
+    for cert in ssl.peer_cert_chain().into_iter().flatten() {
+        presented.push(cert.to_owned()).map_err(Error::SslError)?;
+    }
+    let store = ssl.ssl_context().cert_store();
+    let mut ctx = X509StoreContext::new().map_err(Error::SslError)?;
+    let chain = ctx
+        .init(store, &leaf, &presented, |ctx| {
+            if !ctx.verify_cert()? {
+                return Ok(Err(ctx.error()));
+            }
+            Ok(Ok(ctx
+                .chain()
+                .map(|chain| chain.iter().map(|cert| cert.to_owned()).collect())
+                .unwrap_or_default()))
+        })
+        .map_err(Error::SslError)?;
+    chain.map_err(TlsError::Verification)
+}
+
+/// San is a subject alternative name of a certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum San {
+    Uri(String),
+    Dns(String),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for San {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            San::Uri(uri) => f.write_str(uri),
+            San::Dns(name) => f.write_str(name),
+            San::Ip(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+/// extract_all_sans returns the URI, DNS and IP SANs of cert, in order. Other SAN types are
+/// skipped.
+pub fn extract_all_sans(cert: &x509::X509Ref) -> Vec<San> {
+    cert.subject_alt_names()
+        .iter()
+        .flat_map(|sans| sans.iter())
+        .filter_map(|san| {
+            if let Some(uri) = san.uri() {
+                Some(San::Uri(uri.to_string()))
+            } else if let Some(name) = san.dnsname() {
+                Some(San::Dns(name.to_string()))
+            } else {
+                san.ipaddress().and_then(ip_from_bytes).map(San::Ip)
+            }
+        })
+        .collect()
+}
+
+/// export_keying_material derives `len` bytes of keying material from an established TLS session,
+/// as described in RFC 5705. Both peers derive the same bytes for the same label and context.
+pub fn export_keying_material<S>(
+    stream: &tokio_boring::SslStream<S>,
+    label: &str,
+    context: Option<&[u8]>,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    let ssl = stream.ssl();
+    if !ssl.is_init_finished() {
+        return Err(Error::HandshakeIncomplete);
+    }
+    let mut out = vec![0; len];
+    ssl.export_keying_material(&mut out, label, context)?;
+    Ok(out)
+}
+
+// has_uri_san tells whether any URI SAN of cert matches, without allocating.
+fn has_uri_san(cert: &x509::X509Ref, matches: impl Fn(&str) -> bool) -> bool {
+    cert.subject_alt_names()
+        .map(|sans| sans.iter().any(|san| san.uri().map_or(false, &matches)))
+        .unwrap_or(false)
+}
+
+/// verify_san_uri is SanChecker::verify_san, with the URI of identity rendered by the caller. The
+/// SANs are compared to the URI first, so that the common case does not parse or allocate.
+fn verify_san_uri(cert: &x509::X509Ref, identity: &Identity, uri: &str) -> Result<(), TlsError> {
+    if has_uri_san(cert, |san| san == uri) {
+        return Ok(());
+    }
+    // The SAN may still be an equivalent spelling of the identity, or absent.
+    let sans = extract_sans(cert);
+    sans.iter()
+        .find(|id| id == &identity)
+        .ok_or_else(|| TlsError::SanError(identity.to_owned(), sans.clone()))
+        .map(|_| ())
+}
+
+// is_in_trust_domain tells whether uri is a SPIFFE identity starting with prefix, which is
+// "spiffe://<trust domain>/", without allocating.
+fn is_in_trust_domain(uri: &str, prefix: &str) -> bool {
+    let Some(path) = uri.strip_prefix(prefix) else {
+        return false;
+    };
+    let mut segments = path.split('/');
+    segments.next() == Some("ns")
+        && segments.next().is_some()
+        && segments.next() == Some("sa")
+        && segments.next().is_some()
+        && segments.next().is_none()
+}
+
+fn verify_san_trust_domain_prefix(
+    cert: &x509::X509Ref,
+    identity: &Identity,
+    prefix: &str,
+) -> Result<(), TlsError> {
+    if has_uri_san(cert, |san| is_in_trust_domain(san, prefix)) {
+        return Ok(());
+    }
+    verify_san_trust_domain_slow(cert, identity)
+}
+
+impl SanChecker for x509::X509 {
+    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError> {
+        verify_san_uri(self, identity, &identity.to_string())
+    }
+
+    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError> {
+        let prefix = match identity {
+            Identity::Spiffe { trust_domain, .. } => format!("spiffe://{trust_domain}/"),
+        };
+        verify_san_trust_domain_prefix(self, identity, &prefix)
+    }
+}
+
+fn verify_san_trust_domain_slow(cert: &x509::X509Ref, identity: &Identity) -> Result<(), TlsError> {
+    let source_trust_domain = match identity {
+        Identity::Spiffe { trust_domain, .. } => trust_domain,
+    };
+    let sans = extract_sans(cert);
+    sans.iter()
+        .find(|id| match id {
+            Identity::Spiffe { trust_domain, .. } => trust_domain == source_trust_domain,
+        })
+        .ok_or_else(|| TlsError::SanTrustDomainError(source_trust_domain.to_string(), sans.clone()))
+        .map(|_| ())
+}
+
+/// ExpectedPeer is what the certificate of a peer is verified against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ExpectedPeer {
+    /// The peer must have a URI SAN for the identity.
+    Identity(Identity),
+    /// The peer must have an IP SAN for the address, for workloads without an identity such as
+    /// VMs.
+    IpSan(IpAddr),
+    /// The peer must have a DNS SAN matching the name, for peers whose identity is not known but
+    /// whose name is, such as waypoints and east-west gateways.
+    DnsName(String),
+    /// The peer must match any of the expected peers, such as an identity or a DNS name while
+    /// migrating from one to the other.
+    AnyOf(Vec<ExpectedPeer>),
+}
+
+impl From<Identity> for ExpectedPeer {
+    fn from(id: Identity) -> Self {
+        ExpectedPeer::Identity(id)
+    }
+}
+
+impl From<&Identity> for ExpectedPeer {
+    fn from(id: &Identity) -> Self {
+        ExpectedPeer::Identity(id.clone())
+    }
+}
+
+impl From<IpAddr> for ExpectedPeer {
+    fn from(ip: IpAddr) -> Self {
+        ExpectedPeer::IpSan(ip)
+    }
+}
+
+impl fmt::Display for ExpectedPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpectedPeer::Identity(id) => write!(f, "{id}"),
+            ExpectedPeer::IpSan(ip) => write!(f, "{ip}"),
+            ExpectedPeer::DnsName(name) => f.write_str(name),
+            ExpectedPeer::AnyOf(peers) => {
+                for (i, peer) in peers.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" or ")?;
+                    }
+                    write!(f, "{peer}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// verify_ip_san checks cert has an IP SAN for ip. Addresses are compared parsed, so that
+/// different spellings of an IPv6 address match, and canonical, so that an IPv4-mapped IPv6
+/// address matches the IPv4 address.
+fn verify_ip_san(cert: &x509::X509Ref, ip: IpAddr) -> Result<(), TlsError> {
+    let ips: Vec<IpAddr> = extract_all_sans(cert)
+        .into_iter()
+        .filter_map(|san| match san {
+            San::Ip(ip) => Some(ip),
+            _ => None,
+        })
+        .collect();
+    let canonical = to_canonical_ip(ip);
+    if ips.iter().any(|san| to_canonical_ip(*san) == canonical) {
+        Ok(())
+    } else {
+        Err(TlsError::IpSanError(ip, ips))
+    }
+}
+
+/// verify_dns_san checks cert has a DNS SAN matching name.
+fn verify_dns_san(cert: &x509::X509Ref, name: &str) -> Result<(), TlsError> {
+    let names: Vec<String> = extract_all_sans(cert)
+        .into_iter()
+        .filter_map(|san| match san {
+            San::Dns(name) => Some(name),
+            _ => None,
+        })
+        .collect();
+    if names.iter().any(|san| dns_name_matches(san, name)) {
+        Ok(())
+    } else {
+        Err(TlsError::DnsSanError(name.to_string(), names))
+    }
+}
+
+/// dns_name_matches tells whether the DNS SAN san matches name, following
+/// X509VerifyParam::set_host with X509CheckFlags::NO_PARTIAL_WILDCARDS: names are compared case
+/// insensitively, and a wildcard must be the whole leftmost label of the SAN, matching exactly one
+/// label of name. The SAN is checked in the verify callback instead of with set_host, so that a
+/// mismatch can fall back to another expected peer.
+pub(super) fn dns_name_matches(san: &str, name: &str) -> bool {
+    let san = san.strip_suffix('.').unwrap_or(san);
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if san.eq_ignore_ascii_case(name) {
+        return true;
+    }
+    let suffix = match san.strip_prefix("*.") {
+        // Like OpenSSL, wildcards must be followed by at least two labels.
+        Some(suffix) if suffix.contains('.') => suffix,
+        _ => return false,
+    };
+    match name.split_once('.') {
+        Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(suffix),
+        None => false,
+    }
+}
+
+/// Alpn is a set of application protocols, in order of preference.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alpn {
+    #[serde(rename = "h2")]
+    H2,
+    #[serde(rename = "http/1.1")]
+    Http11,
+    #[serde(rename = "h2,http/1.1")]
+    H2AndHttp11,
+}
+
+impl Alpn {
+    pub(super) fn encode(&self) -> &'static [u8] {
+        match self {
+            Alpn::H2 => b"\x02h2",
+            Alpn::Http11 => b"\x08http/1.1",
+            Alpn::H2AndHttp11 => b"\x02h2\x08http/1.1",
+        }
+    }
+}
+
+/// Accept is how a CertProvider wants an inbound connection to be accepted.
+pub enum Accept {
+    /// Terminate TLS with the acceptor.
+    Tls(ssl::SslAcceptor),
+    /// Pass the connection through untouched, without a TLS handshake. Used for ports excluded
+    /// from mTLS, such as health checks or protocols that cannot be tunneled.
+    Passthrough,
+}
+
+impl From<ssl::SslAcceptor> for Accept {
+    fn from(acceptor: ssl::SslAcceptor) -> Self {
+        Accept::Tls(acceptor)
+    }
+}
+
+/// AcceptMeta describes an inbound connection, for the CertProvider to choose how to accept it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AcceptMeta {
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+    /// Where the connection was sent before being redirected to ztunnel, if it was redirected.
+    pub orig_dst: Option<SocketAddr>,
+}
+
+impl AcceptMeta {
+    pub fn from_stream(stream: &TcpStream) -> std::io::Result<Self> {
+        Ok(AcceptMeta {
+            local: crate::socket::to_canonical(stream.local_addr()?),
+            peer: crate::socket::to_canonical(stream.peer_addr()?),
+            orig_dst: crate::socket::orig_dst(stream).ok(),
+        })
+    }
+
+    /// destination is the original destination if known, or else the local address.
+    pub fn destination(&self) -> SocketAddr {
+        self.orig_dst.unwrap_or(self.local)
+    }
+}
+
+/// ConnMeta describes an accepted connection, including what its ClientHello asks for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnMeta {
+    pub addrs: AcceptMeta,
+    /// The server name the client asked for, if SNI routing is enabled and it sent one.
+    pub sni: Option<String>,
+}
+
+#[async_trait::async_trait]
+pub trait CertProvider: Send + Sync {
+    async fn fetch_cert(&mut self, fd: &TcpStream, meta: &AcceptMeta) -> Result<Accept, TlsError>;
+
+    /// fetch_cert_for is fetch_cert with the server name the client asked for, for providers
+    /// serving certificates by SNI rather than by destination address. Providers without SNI
+    /// routing need not implement it.
+    async fn fetch_cert_for(
+        &mut self,
+        fd: &TcpStream,
+        meta: &ConnMeta,
+    ) -> Result<Accept, TlsError> {
+        self.fetch_cert(fd, &meta.addrs).await
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ControlPlaneCertProvider(pub Certs);
+
+#[async_trait::async_trait]
+impl CertProvider for ControlPlaneCertProvider {
+    async fn fetch_cert(&mut self, _: &TcpStream, _: &AcceptMeta) -> Result<Accept, TlsError> {
+        let acc = self.0.acceptor()?;
+        Ok(acc.into())
+    }
+}
+
+/// WorkloadCertResolver resolves the identity whose certificate is presented on an inbound
+/// connection, from dst, the local address it was accepted on, and orig_dst, the address it was
+/// originally sent to when it was redirected. Destinations without a known identity are reported
+/// with TlsError::CertificateLookup.
+#[async_trait::async_trait]
+pub trait WorkloadCertResolver: Send + Sync {
+    async fn resolve(
+        &self,
+        dst: SocketAddr,
+        orig_dst: Option<SocketAddr>,
+    ) -> Result<Identity, TlsError>;
+}
+
+/// StaticCertResolver resolves identities from a fixed map of destination addresses, on a single
+/// network.
+#[derive(Clone, Debug, Default)]
+pub struct StaticCertResolver {
+    pub network: String,
+    pub identities: HashMap<IpAddr, Identity>,
+}
+
+#[async_trait::async_trait]
+impl WorkloadCertResolver for StaticCertResolver {
+    async fn resolve(
+        &self,
+        dst: SocketAddr,
+        orig_dst: Option<SocketAddr>,
+    ) -> Result<Identity, TlsError> {
+        let address = to_canonical_ip(orig_dst.unwrap_or(dst).ip());
+        self.identities.get(&address).cloned().ok_or_else(|| {
+            TlsError::CertificateLookup(NetworkAddress {
+                network: self.network.clone(),
+                address,
+            })
+        })
+    }
+}
+
+/// RotatingCertProvider serves the latest Certs it was updated with. The acceptor is rebuilt
+/// only when the Certs or the TlsRuntimeConfig change, so new handshakes get the new certificate
+/// while connections established earlier keep their context. Clones share the Certs.
+///
+/// Built with with_cert_callback, a single acceptor is kept instead, which picks the certificate
+/// of the current Certs during each handshake, so rotations build no context at all. Roots, peer
+/// policy and runtime config are then those the provider was built with.
+#[derive(Clone)]
+pub struct RotatingCertProvider {
+    certs: Arc<RwLock<Certs>>,
+    // The acceptor built last, with the Certs and the TlsRuntime generation it was built for.
+    acceptor: Arc<Mutex<Option<(Certs, u64, ssl::SslAcceptor)>>>,
+    // The acceptor used for every handshake, if certificates are selected in a callback.
+    hitless: Option<ssl::SslAcceptor>,
+}
+
+impl RotatingCertProvider {
+    pub fn new(certs: Certs) -> Self {
+        RotatingCertProvider {
+            certs: Arc::new(RwLock::new(certs)),
+            acceptor: Default::default(),
+            hitless: None,
+        }
+    }
+
+    /// with_cert_callback returns a provider keeping a single acceptor, which selects the
+    /// certificate of the current Certs during each handshake.
+    pub fn with_cert_callback(certs: Certs) -> Result<Self, Error> {
+        let current = Arc::new(RwLock::new(certs.clone()));
+        let mut builder = certs.builder().require_client_cert(false);
+        builder.select_certs = Some(current.clone());
+        Ok(RotatingCertProvider {
+            hitless: Some(builder.build_acceptor()?),
+            certs: current,
+            acceptor: Default::default(),
+        })
+    }
+
+    pub fn certs(&self) -> Certs {
+        self.certs.read().unwrap().clone()
+    }
+
+    pub fn update(&self, certs: Certs) {
+        *self.certs.write().unwrap() = certs;
+    }
+
+    /// follow updates the provider with each certificate published on certs, until it is closed.
+    pub fn follow(&self, mut certs: tokio::sync::watch::Receiver<identity::CertState>) {
+        let provider = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let identity::CertState::Available(latest) = &*certs.borrow_and_update() {
+                    provider.update(latest.clone());
+                }
+                if certs.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    fn acceptor(&self) -> Result<ssl::SslAcceptor, Error> {
+        if let Some(acceptor) = &self.hitless {
+            return Ok(acceptor.clone());
+        }
+        let certs = self.certs();
+        let generation = TlsRuntime::global().generation();
+        let mut cached = self.acceptor.lock().unwrap();
+        match &*cached {
+            Some((built_for, built_at, acceptor))
+                if *built_for == certs && *built_at == generation =>
+            {
+                Ok(acceptor.clone())
+            }
+            _ => {
+                let acceptor = certs.acceptor()?;
+                *cached = Some((certs, generation, acceptor.clone()));
+                Ok(acceptor)
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CertProvider for RotatingCertProvider {
+    async fn fetch_cert(&mut self, _: &TcpStream, _: &AcceptMeta) -> Result<Accept, TlsError> {
+        Ok(self.acceptor()?.into())
+    }
+}
+
+// Holds the IdentityPermit of an accepted connection, so it is dropped with the connection.
+static PERMIT_INDEX: Lazy<ex_data::Index<ssl::Ssl, IdentityPermit>> =
+    Lazy::new(|| ssl::Ssl::new_ex_index().expect("ex index must be allocated"));
+
+#[derive(Clone)]
+pub struct BoringTlsAcceptor<F: CertProvider> {
+    /// Acceptor is a function that determines the TLS context to use. As input, the FD of the client
+    /// connection is provided.
+    pub acceptor: F,
+    /// Records the latency and outcome of handshakes, if set.
+    pub metrics: Option<Arc<dyn TlsMetrics>>,
+    /// Switches the acceptor into draining.
+    pub drain: DrainSignal,
+    /// Decides whether connections may proceed once their handshake completed. All are allowed if
+    /// unset.
+    pub authorizer: Option<Arc<dyn AuthorizeConnection>>,
+    /// Caps the concurrent connections of each client identity, if set.
+    pub limiter: Option<IdentityLimiter>,
+    /// TCP options applied to connections before the handshake.
+    pub socket: SocketConfig,
+    /// Refuses new connections under resource pressure, if set.
+    pub shed: Option<LoadShedPolicy>,
+    /// Reads the SNI of clients before their certificate is fetched, and passes it to
+    /// CertProvider::fetch_cert_for.
+    pub sni_routing: bool,
+}
+
+/// DrainSignal switches acceptors into draining, for example during an upgrade: new connections
+/// are refused with TlsError::Draining, while handshakes in flight get a grace period to complete.
+/// Established connections are left alone. Clones share the same signal.
+#[derive(Clone, Debug)]
+pub struct DrainSignal(Arc<tokio::sync::watch::Sender<Option<tokio::time::Instant>>>);
+
+impl Default for DrainSignal {
+    fn default() -> Self {
+        DrainSignal(Arc::new(tokio::sync::watch::channel(None).0))
+    }
+}
+
+impl DrainSignal {
+    /// drain starts draining, giving handshakes in flight until grace from now to complete.
+    /// Draining again does not move the deadline.
+    pub fn drain(&self, grace: Duration) {
+        let deadline = tokio::time::Instant::now() + grace;
+        self.0.send_if_modified(|current| {
+            if current.is_some() {
+                return false;
+            }
+            *current = Some(deadline);
+            true
+        });
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.borrow().is_some()
+    }
+
+    /// expired completes once draining started and its grace period is over.
+    pub async fn expired(&self) {
+        let mut rx = self.0.subscribe();
+        loop {
+            if let Some(deadline) = *rx.borrow_and_update() {
+                return tokio::time::sleep_until(deadline).await;
+            }
+            // The sender is kept alive by self, so this only returns when drain is called.
+            let _ = rx.changed().await;
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsError {
+    #[error("tls handshake error: {}", explain_handshake(.0))]
+    Handshake(#[from] tokio_boring::HandshakeError<TcpStream>),
+    /// A handshake over a stream other than a TcpStream, such as the inner connection of double
+    /// HBONE. The error is kept without its stream, so it is not generic over the stream type.
+    #[error("tls handshake error: {message}")]
+    StreamHandshake {
+        failure: HandshakeFailure,
+        message: String,
+        io: Option<std::io::ErrorKind>,
+    },
+    #[error("tls verification error: {0}")]
+    Verification(X509VerifyResult),
+    #[error("certificate lookup error: {0} is not a known destination")]
+    CertificateLookup(NetworkAddress),
+    #[error("signing error: {0}")]
+    SigningError(#[from] identity::Error),
+    #[error("san verification error: remote did not present the expected SAN ({0}), got {1:?}")]
+    SanError(Identity, Vec<Identity>),
+    #[error(
+        "san verification error: remote did not present the expected trustdomain ({0}), got {1:?}"
+    )]
+    SanTrustDomainError(String, Vec<Identity>),
+    #[error("san verification error: remote did not present the expected IP SAN ({0}), got {1:?}")]
+    IpSanError(IpAddr, Vec<IpAddr>),
+    #[error("san verification error: remote did not present a DNS SAN matching {0}, got {1:?}")]
+    DnsSanError(String, Vec<String>),
+    #[error(
+        "san verification error: remote matched none of the expected peers: {}",
+        .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; ")
+    )]
+    NoExpectedPeer(Vec<TlsError>),
+    #[error("trust bundle verification error: {0:?} do not chain to a root of their trust domain")]
+    TrustBundleError(Vec<Identity>),
+    #[error("peer identity {0} is denied")]
+    IdentityDenied(Identity),
+    #[error("failed getting ex data")]
+    ExDataError,
+    #[error("failed getting peer cert")]
+    PeerCertError,
+    #[error("ssl error: {0}")]
+    SslError(#[from] Error),
+    #[error("connection is not tls")]
+    NotTls,
+    #[error("connection is passed through without tls")]
+    Passthrough,
+    #[error("acceptor is draining")]
+    Draining,
+    #[error("connection is not authorized: {0}")]
+    Unauthorized(String),
+    #[error("too many connections from {0}")]
+    ConnectionLimit(Identity),
+    #[error("client closed the connection before the handshake")]
+    ClientClosed,
+    #[error("connection shed under resource pressure: {0}")]
+    Shed(ShedReason),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl TlsError {
+    /// classification tells why a handshake failed, coarsely enough to be used as a log field or
+    /// metric label.
+    pub fn classification(&self) -> HandshakeFailureClass {
+        match self {
+            TlsError::Handshake(e) => HandshakeFailure::from(e).class,
+            TlsError::StreamHandshake { failure, .. } => failure.class,
+            TlsError::Verification(result) => HandshakeFailureClass::from_verify_result(*result),
+            TlsError::SanError(..)
+            | TlsError::SanTrustDomainError(..)
+            | TlsError::IpSanError(..)
+            | TlsError::DnsSanError(..)
+            | TlsError::NoExpectedPeer(_) => HandshakeFailureClass::SanMismatch,
+            TlsError::NotTls => HandshakeFailureClass::NotTls,
+            _ => HandshakeFailureClass::Other,
+        }
+    }
+
+    /// code returns a stable identifier of the kind of error, usable as a metric label or to
+    /// handle errors programmatically instead of matching on their message. Handshake and
+    /// verification failures use the code of their classification, errors wrapping an
+    /// identity::Error or tls::Error use its code, and I/O timeouts are HANDSHAKE_TIMEOUT.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TlsError::Handshake(e) => match e.as_io_error() {
+                Some(e) if e.kind() == std::io::ErrorKind::TimedOut => "HANDSHAKE_TIMEOUT",
+                _ => self.classification().code(),
+            },
+            TlsError::StreamHandshake {
+                io: Some(std::io::ErrorKind::TimedOut),
+                ..
+            } => "HANDSHAKE_TIMEOUT",
+            TlsError::StreamHandshake { .. } => self.classification().code(),
+            TlsError::Verification(_)
+            | TlsError::SanError(..)
+            | TlsError::SanTrustDomainError(..)
+            | TlsError::IpSanError(..)
+            | TlsError::DnsSanError(..)
+            | TlsError::NoExpectedPeer(_)
+            | TlsError::NotTls => self.classification().code(),
+            TlsError::CertificateLookup(_) => "CERTIFICATE_LOOKUP",
+            TlsError::SigningError(e) => e.code(),
+            TlsError::TrustBundleError(_) => HandshakeFailureClass::UnknownCa.code(),
+            TlsError::IdentityDenied(_) => "IDENTITY_DENIED",
+            TlsError::ExDataError | TlsError::PeerCertError => "INTERNAL",
+            TlsError::Passthrough => "PASSTHROUGH",
+            TlsError::Draining => "DRAINING",
+            TlsError::Unauthorized(_) => "UNAUTHORIZED",
+            TlsError::ConnectionLimit(_) => "CONNECTION_LIMIT",
+            TlsError::ClientClosed => "CLIENT_CLOSED",
+            TlsError::Shed(_) => "LOAD_SHED",
+            TlsError::SslError(e) => e.code(),
+            TlsError::Io(e) if e.kind() == std::io::ErrorKind::TimedOut => "HANDSHAKE_TIMEOUT",
+            TlsError::Io(_) => "IO",
+        }
+    }
+
+    /// is_retryable tells whether the connection may succeed if attempted again: the failure was
+    /// on the transport, or fetching our certificate failed transiently. Verification failures are
+    /// permanent.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TlsError::Handshake(e) => e.as_io_error().is_some(),
+            TlsError::StreamHandshake { io, .. } => io.is_some(),
+            TlsError::Io(_) | TlsError::Draining | TlsError::Shed(_) => true,
+            TlsError::SigningError(e) => e.is_retryable(),
+            TlsError::Verification(_)
+            | TlsError::CertificateLookup(_)
+            | TlsError::SanError(..)
+            | TlsError::SanTrustDomainError(..)
+            | TlsError::IpSanError(..)
+            | TlsError::DnsSanError(..)
+            | TlsError::NoExpectedPeer(_)
+            | TlsError::TrustBundleError(_)
+            | TlsError::IdentityDenied(_)
+            | TlsError::ExDataError
+            | TlsError::PeerCertError
+            | TlsError::SslError(_)
+            | TlsError::NotTls
+            | TlsError::Passthrough
+            | TlsError::Unauthorized(_)
+            | TlsError::ConnectionLimit(_)
+            | TlsError::ClientClosed => false,
+        }
+    }
+}
+
+/// HandshakeFailureClass is the reason a TLS handshake failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HandshakeFailureClass {
+    /// The peer did not speak TLS, most commonly plaintext sent to a TLS port.
+    NotTls,
+    /// The peer certificate does not chain to a trusted root.
+    UnknownCa,
+    /// The peer certificate has expired or is not valid yet.
+    Expired,
+    /// The peer certificate is not for the expected identity.
+    SanMismatch,
+    /// No TLS version is supported by both peers.
+    ProtocolVersion,
+    /// The peer sent a handshake message over the size limit, such as a certificate chain larger
+    /// than set with set_max_cert_list.
+    MessageTooLarge,
+    Other,
+}
+
+impl HandshakeFailureClass {
+    /// code returns the TlsError code of handshakes failing for this reason.
+    pub fn code(&self) -> &'static str {
+        match self {
+            HandshakeFailureClass::NotTls => "NOT_TLS",
+            HandshakeFailureClass::UnknownCa => "UNKNOWN_CA",
+            HandshakeFailureClass::Expired => "CERT_EXPIRED",
+            HandshakeFailureClass::SanMismatch => "SAN_MISMATCH",
+            HandshakeFailureClass::ProtocolVersion => "PROTOCOL_VERSION",
+            HandshakeFailureClass::MessageTooLarge => "MESSAGE_TOO_LARGE",
+            HandshakeFailureClass::Other => "HANDSHAKE_FAILED",
+        }
+    }
+}
+
+impl fmt::Display for HandshakeFailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let class = match self {
+            HandshakeFailureClass::NotTls => "not_tls",
+            HandshakeFailureClass::UnknownCa => "unknown_ca",
+            HandshakeFailureClass::Expired => "expired",
+            HandshakeFailureClass::SanMismatch => "san_mismatch",
+            HandshakeFailureClass::ProtocolVersion => "protocol_version",
+            HandshakeFailureClass::MessageTooLarge => "message_too_large",
+            HandshakeFailureClass::Other => "other",
+        };
+        f.write_str(class)
+    }
+}
+
+// X509 verify results, from x509.h.
+const X509_V_ERR_UNABLE_TO_GET_ISSUER_CERT: i32 = 2;
+const X509_V_ERR_CERT_NOT_YET_VALID: i32 = 9;
+const X509_V_ERR_CERT_HAS_EXPIRED: i32 = 10;
+const X509_V_ERR_DEPTH_ZERO_SELF_SIGNED_CERT: i32 = 18;
+const X509_V_ERR_SELF_SIGNED_CERT_IN_CHAIN: i32 = 19;
+const X509_V_ERR_UNABLE_TO_GET_ISSUER_CERT_LOCALLY: i32 = 20;
+const X509_V_ERR_UNABLE_TO_VERIFY_LEAF_SIGNATURE: i32 = 21;
+
+impl HandshakeFailureClass {
+    fn from_verify_result(result: X509VerifyResult) -> Self {
+        if result == X509VerifyResult::APPLICATION_VERIFICATION {
+            return HandshakeFailureClass::SanMismatch;
+        }
+        match result.as_raw() {
+            X509_V_ERR_CERT_NOT_YET_VALID | X509_V_ERR_CERT_HAS_EXPIRED => {
+                HandshakeFailureClass::Expired
+            }
+            X509_V_ERR_UNABLE_TO_GET_ISSUER_CERT
+            | X509_V_ERR_DEPTH_ZERO_SELF_SIGNED_CERT
+            | X509_V_ERR_SELF_SIGNED_CERT_IN_CHAIN
+            | X509_V_ERR_UNABLE_TO_GET_ISSUER_CERT_LOCALLY
+            | X509_V_ERR_UNABLE_TO_VERIFY_LEAF_SIGNATURE => HandshakeFailureClass::UnknownCa,
+            _ => HandshakeFailureClass::Other,
+        }
+    }
+
+    fn from_reason(reason: &str) -> Option<Self> {
+        match reason {
+            "HTTP_REQUEST" | "HTTPS_PROXY_REQUEST" | "WRONG_VERSION_NUMBER" => {
+                Some(HandshakeFailureClass::NotTls)
+            }
+            "UNSUPPORTED_PROTOCOL" | "TLSV1_ALERT_PROTOCOL_VERSION" => {
+                Some(HandshakeFailureClass::ProtocolVersion)
+            }
+            "EXCESSIVE_MESSAGE_SIZE" => Some(HandshakeFailureClass::MessageTooLarge),
+            "TLSV1_ALERT_UNKNOWN_CA" => Some(HandshakeFailureClass::UnknownCa),
+            "SSLV3_ALERT_CERTIFICATE_EXPIRED" => Some(HandshakeFailureClass::Expired),
+            _ => None,
+        }
+    }
+}
+
+/// HandshakeFailure holds the details of a failed handshake that are lost once the connection is
+/// dropped: the local verification result of the peer certificate and the alert received from the
+/// peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandshakeFailure {
+    pub class: HandshakeFailureClass,
+    /// Set if verifying the peer certificate failed.
+    pub verify_result: Option<X509VerifyResult>,
+    /// The alert sent by the peer, such as TLSV1_ALERT_UNKNOWN_CA.
+    pub alert: Option<String>,
+}
+
+impl<S> From<&tokio_boring::HandshakeError<S>> for HandshakeFailure {
+    fn from(e: &tokio_boring::HandshakeError<S>) -> Self {
+        let verify_result = e
+            .ssl()
+            .map(|ssl| ssl.verify_result())
+            .filter(|result| *result != X509VerifyResult::OK);
+        let reasons: Vec<String> = e
+            .as_ssl_error_stack()
+            .map(|stack| {
+                stack
+                    .errors()
+                    .iter()
+                    .filter_map(|e| e.reason())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let alert = reasons
+            .iter()
+            .find(|r| r.starts_with("SSLV3_ALERT_") || r.starts_with("TLSV1_ALERT_"))
+            .cloned();
+        let class = verify_result
+            .map(HandshakeFailureClass::from_verify_result)
+            .filter(|class| *class != HandshakeFailureClass::Other)
+            .or_else(|| {
+                reasons
+                    .iter()
+                    .find_map(|r| HandshakeFailureClass::from_reason(r))
+            })
+            .unwrap_or(HandshakeFailureClass::Other);
+        HandshakeFailure {
+            class,
+            verify_result,
+            alert,
+        }
+    }
+}
+
+impl TlsError {
+    /// stream_handshake converts the failure of a handshake over any stream type.
+    pub fn stream_handshake<S>(e: tokio_boring::HandshakeError<S>) -> TlsError {
+        let io = e.as_io_error().map(|e| e.kind());
+        let message = explain_handshake(&e);
+        TlsError::StreamHandshake {
+            failure: HandshakeFailure::from(&e),
+            message,
+            io,
+        }
+    }
+}
+
+/// accept_inner terminates TLS with acceptor over stream, which may itself be a TLS stream, such
+/// as the connection tunneled in an HBONE CONNECT when chaining gateways.
+pub async fn accept_inner<S>(
+    acceptor: &ssl::SslAcceptor,
+    stream: S,
+) -> Result<tokio_boring::SslStream<S>, TlsError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    tokio_boring::accept(acceptor, stream)
+        .await
+        .map_err(TlsError::stream_handshake)
+}
+
+/// InnerServerName is the server name sent by connect_over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InnerServerName {
+    /// No server name is sent, as on connections to workloads.
+    #[default]
+    None,
+    /// The name derived from the expected identity is sent, see identity_server_name, so that a
+    /// remote ztunnel serving several identities can select the certificate before the handshake.
+    Identity,
+}
+
+/// identity_server_name returns the server name standing for id, of the form
+/// `<service account>.<namespace>.sa.<trust domain>`. It is only a selector: peers are verified
+/// against their URI SAN, never against this name.
+pub fn identity_server_name(id: &Identity) -> String {
+    match id {
+        Identity::Spiffe {
+            trust_domain,
+            namespace,
+            service_account,
+        } => format!("{service_account}.{namespace}.sa.{trust_domain}"),
+    }
+}
+
+/// connect_over runs a TLS handshake as a client with expected over stream, which may itself be
+/// a tunnel such as the HBONE CONNECT to a remote ztunnel in double HBONE. config is to come from
+/// a connector for expected, such as one from ConnectorCache::connect_config: its Verifier checks
+/// the SANs of the peer, as there is no DNS hostname to verify.
+pub async fn connect_over<S>(
+    mut config: ssl::ConnectConfiguration,
+    expected: &Identity,
+    server_name: InnerServerName,
+    stream: S,
+) -> Result<tokio_boring::SslStream<S>, TlsError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    config.set_verify_hostname(false);
+    let domain = match server_name {
+        InnerServerName::None => {
+            config.set_use_server_name_indication(false);
+            String::new()
+        }
+        InnerServerName::Identity => identity_server_name(expected),
+    };
+    tokio_boring::connect(config, &domain, stream)
+        .instrument(debug_span!("tls_connect_over", identity = %expected))
+        .await
+        .map_err(TlsError::stream_handshake)
+}
+
+impl<F> tls_listener::AsyncTls<TcpStream> for BoringTlsAcceptor<F>
+where
+    F: CertProvider + Clone + 'static,
+{
+    type Stream = tokio_boring::SslStream<TcpStream>;
+    type Error = TlsError;
+    type AcceptFuture = Pin<Box<dyn Future<Output = Result<Self::Stream, Self::Error>> + Send>>;
+
+    fn accept(&self, conn: TcpStream) -> Self::AcceptFuture {
+        let acceptor = self.clone();
+        Box::pin(async move {
+            match acceptor.accept_maybe_tls(conn, false).await? {
+                MaybeTls::Tls(stream) => Ok(stream),
+                MaybeTls::Plain(_) => Err(TlsError::NotTls),
+                MaybeTls::Passthrough(_) => Err(TlsError::Passthrough),
+            }
+        })
+    }
+}
+
+// The first bytes of a ClientHello: a handshake record, followed by the major version of TLS.
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+const TLS_MAJOR_VERSION: u8 = 0x03;
+
+/// sniff_tls tells whether the client of conn starts with a TLS handshake, without consuming any
+/// data. It waits for the first bytes only, so it does not delay TLS handshakes.
+pub async fn sniff_tls(conn: &TcpStream) -> std::io::Result<bool> {
+    let mut buf = [0u8; 2];
+    let n = conn.peek(&mut buf).await?;
+    Ok(n > 0 && buf[0] == TLS_HANDSHAKE_RECORD && (n < 2 || buf[1] == TLS_MAJOR_VERSION))
+}
+
+/// record_negotiated records the TLS version and cipher of a completed handshake in the version
+/// and cipher fields of span.
+pub(super) fn record_negotiated(span: &tracing::Span, ssl: &ssl::SslRef) {
+    if span.is_disabled() {
+        return;
+    }
+    span.record("version", ssl.version_str());
+    if let Some(cipher) = ssl.current_cipher() {
+        span.record("cipher", cipher.name());
+    }
+}
+
+/// MaybeTls is a connection accepted by a listener that lets plaintext clients through.
+pub enum MaybeTls {
+    Tls(tokio_boring::SslStream<TcpStream>),
+    Plain(TcpStream),
+    /// A connection the certificate provider passed through with Accept::Passthrough. Nothing has
+    /// been read from it, so its bytes can be proxied untouched.
+    Passthrough(TcpStream),
+}
+
+impl<F: CertProvider + Clone + 'static> BoringTlsAcceptor<F> {
+    /// accept_maybe_tls terminates TLS on conn. Clients not starting with a TLS handshake are
+    /// rejected with TlsError::NotTls or, if permissive is set, returned untouched as
+    /// MaybeTls::Plain. Connections the certificate provider passes through are returned as
+    /// MaybeTls::Passthrough whatever they start with.
+    ///
+    /// Once draining, new connections are refused with TlsError::Draining, as are accepts still
+    /// in flight when the grace period is over. Accepts taking longer than the handshake timeout
+    /// of the TlsRuntimeConfig fail with a TimedOut I/O error.
+    ///
+    /// Connections the load shedding policy refuses are closed right away, before anything is
+    /// read, and fail with TlsError::Shed.
+    pub async fn accept_maybe_tls(
+        &self,
+        conn: TcpStream,
+        permissive: bool,
+    ) -> Result<MaybeTls, TlsError> {
+        if self.drain.is_draining() {
+            return Err(self.drained());
+        }
+        let guard = match &self.shed {
+            Some(policy) => match policy.admit() {
+                Ok(guard) => Some(guard),
+                Err(reason) => return Err(self.shed(policy, conn, reason)),
+            },
+            None => None,
+        };
+        let span = debug_span!(
+            "tls_accept",
+            peer = ?conn.peer_addr().ok(),
+            version = tracing::field::Empty,
+            cipher = tracing::field::Empty,
+        );
+        let accept = self
+            .accept_in_span(conn, permissive, guard, &span)
+            .instrument(span.clone());
+        let accept = async {
+            match TlsRuntime::global().load().handshake_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, accept)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into())
+                    }),
+                None => accept.await,
+            }
+        };
+        tokio::select! {
+            res = accept => res,
+            _ = self.drain.expired() => Err(self.drained()),
+        }
+    }
+
+    /// drain switches the acceptor, and all its clones, into draining. Handshakes in flight get
+    /// until grace from now to complete.
+    pub fn drain(&self, grace: Duration) {
+        self.drain.drain(grace)
+    }
+
+    /// with_authorizer has connections authorized by authorizer once their handshake completed.
+    /// Denied connections are closed with close_notify, and fail with TlsError::Unauthorized.
+    pub fn with_authorizer(mut self, authorizer: Arc<dyn AuthorizeConnection>) -> Self {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// with_identity_limits caps the concurrent connections of each client identity. Connections
+    /// over the cap are closed with close_notify once their handshake completed, and fail with
+    /// TlsError::ConnectionLimit.
+    pub fn with_identity_limits(mut self, limits: IdentityLimits) -> Self {
+        self.limiter = Some(IdentityLimiter::new(limits));
+        self
+    }
+
+    /// with_socket_config applies socket to accepted connections before their handshake.
+    pub fn with_socket_config(mut self, socket: SocketConfig) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    /// with_sni_routing has certificates fetched for the SNI of clients, which is read from their
+    /// ClientHello before the handshake. Clients without SNI are served by destination address as
+    /// usual. As the SNI is read before the provider is asked, clients that wait for the server
+    /// to speak first, such as those of passthrough ports, time out: SNI routing is not meant for
+    /// listeners with such ports.
+    pub fn with_sni_routing(mut self) -> Self {
+        self.sni_routing = true;
+        self
+    }
+
+    /// with_load_shedding has new connections refused while policy reports resource pressure.
+    /// TLS streams count against its open stream limit from their accept until they are dropped.
+    pub fn with_load_shedding(mut self, policy: LoadShedPolicy) -> Self {
+        self.shed = Some(policy);
+        self
+    }
+
+    // Asks the authorizer whether stream may proceed, closing it if not.
+    async fn authorize(
+        &self,
+        authorizer: &dyn AuthorizeConnection,
+        meta: &AcceptMeta,
+        stream: &mut tokio_boring::SslStream<TcpStream>,
+    ) -> Result<(), TlsError> {
+        let destination = meta.destination();
+        let conn = ConnectionInfo {
+            peer_identities: peer_identities(stream.ssl()),
+            source: meta.peer,
+            destination,
+            destination_port: destination.port(),
+        };
+        let Authorization::Deny(reason) = authorizer.authorize(&conn) else {
+            return Ok(());
+        };
+        debug!(peer=?conn.peer_identities, %reason, "connection is not authorized");
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_denied(HandshakeDirection::Inbound, &reason);
+        }
+        // The connection is dropped anyway, so a failure to say goodbye is ignored.
+        let _ = stream.shutdown().await;
+        Err(TlsError::Unauthorized(reason))
+    }
+
+    // Counts stream against the limit of its client identity, closing it if over. Clients
+    // without an identity are not limited.
+    async fn limit(
+        &self,
+        limiter: &IdentityLimiter,
+        stream: &mut tokio_boring::SslStream<TcpStream>,
+    ) -> Result<(), TlsError> {
+        let Some(identity) = peer_identities(stream.ssl()).into_iter().next() else {
+            return Ok(());
+        };
+        match limiter.acquire(&identity) {
+            Ok(permit) => {
+                // The permit is dropped with the connection, which uncounts it.
+                stream.ssl_mut().set_ex_data(*PERMIT_INDEX, permit);
+                Ok(())
+            }
+            Err(identity) => {
+                debug!(%identity, "too many connections");
+                if let Some(metrics) = &self.metrics {
+                    metrics.connection_limited(HandshakeDirection::Inbound, &identity);
+                }
+                let _ = stream.shutdown().await;
+                Err(TlsError::ConnectionLimit(identity))
+            }
+        }
+    }
+
+    // Refuses conn, shed by policy for reason.
+    fn shed(&self, policy: &LoadShedPolicy, conn: TcpStream, reason: ShedReason) -> TlsError {
+        debug!(%reason, "shedding connection");
+        if let Some(metrics) = &self.metrics {
+            metrics.accept_shed(HandshakeDirection::Inbound, reason);
+        }
+        policy.refuse(conn);
+        TlsError::Shed(reason)
+    }
+
+    // Records a connection refused because the acceptor is draining.
+    fn drained(&self) -> TlsError {
+        if let Some(metrics) = &self.metrics {
+            metrics.drain_rejected(HandshakeDirection::Inbound);
+        }
+        TlsError::Draining
+    }
+
+    /// accept_layered terminates two layers of TLS on conn: the outer one with outer, which
+    /// typically only presents a server certificate, and the inner one with the mutual TLS context
+    /// of the certificate provider.
+    pub async fn accept_layered(
+        &self,
+        conn: TcpStream,
+        outer: &ssl::SslAcceptor,
+    ) -> Result<tokio_boring::SslStream<tokio_boring::SslStream<TcpStream>>, TlsError> {
+        let meta = AcceptMeta::from_stream(&conn)?;
+        let Accept::Tls(inner) = self.acceptor.clone().fetch_cert(&conn, &meta).await? else {
+            return Err(TlsError::Passthrough);
+        };
+        let stream = tokio_boring::accept(outer, conn).await?;
+        accept_inner(&inner, stream).await
+    }
+
+    async fn accept_in_span(
+        &self,
+        conn: TcpStream,
+        permissive: bool,
+        guard: Option<StreamGuard>,
+        span: &tracing::Span,
+    ) -> Result<MaybeTls, TlsError> {
+        let start = std::time::Instant::now();
+        if let Err(e) = self.socket.apply(&conn) {
+            warn!("failed to apply socket options: {e}");
+        }
+        // The provider decides on passthrough before anything is read: the client of a passthrough
+        // port may well wait for the server to speak first.
+        let meta = AcceptMeta::from_stream(&conn)?;
+        let sni = match self.sni_routing {
+            true => peek_sni(&conn).await,
+            false => None,
+        };
+        let conn_meta = ConnMeta { addrs: meta, sni };
+        let mut acceptor = self.acceptor.clone();
+        let fetch = acceptor
+            .fetch_cert_for(&conn, &conn_meta)
+            .instrument(debug_span!("fetch_cert", identity = tracing::field::Empty));
+        // Fetching the certificate may take a CA round trip, which is wasted if the client gives
+        // up in the meantime. Dropping the fetch only drops this connection's interest in the
+        // certificate, the fetch itself completes for other connections waiting for it.
+        let fetched = tokio::select! {
+            res = fetch => res,
+            _ = client_closed(&conn) => {
+                debug!("client closed the connection while its certificate was fetched");
+                if let Some(metrics) = &self.metrics {
+                    metrics.accept_abandoned(HandshakeDirection::Inbound);
+                }
+                return Err(TlsError::ClientClosed);
+            }
+        };
+        let tls = match fetched {
+            Ok(Accept::Tls(tls)) => Ok(tls),
+            Ok(Accept::Passthrough) => {
+                debug!("passing connection through without tls");
+                return Ok(MaybeTls::Passthrough(conn));
+            }
+            Err(e) => Err(e),
+        };
+        if !sniff_tls(&conn).await? {
+            if permissive {
+                return Ok(MaybeTls::Plain(conn));
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics
+                    .handshake_failed(HandshakeDirection::Inbound, HandshakeFailureClass::NotTls);
+            }
+            return Err(TlsError::NotTls);
+        }
+        let res = match tls {
+            Ok(tls) => HandshakeOffload::global()
+                .run(async move { tokio_boring::accept(&tls, conn).await })
+                .await
+                .map_err(TlsError::Handshake),
+            Err(e) => Err(e),
+        };
+        if let Ok(stream) = &res {
+            record_negotiated(span, stream.ssl());
+        }
+        if let Some(metrics) = &self.metrics {
+            let outcome = match &res {
+                Ok(stream) => Ok(stream.ssl()),
+                Err(e) => Err(e.classification()),
+            };
+            record_handshake(
+                metrics.as_ref(),
+                HandshakeDirection::Inbound,
+                start,
+                outcome,
+            );
+        }
+        let mut stream = res?;
+        if let Some(authorizer) = &self.authorizer {
+            self.authorize(authorizer.as_ref(), &meta, &mut stream)
+                .await?;
+        }
+        if let Some(limiter) = &self.limiter {
+            self.limit(limiter, &mut stream).await?;
+        }
+        // Plaintext and passthrough connections dropped the guard already, only TLS streams stay
+        // counted as open.
+        TlsBuffers::global().track(stream.ssl_mut(), guard);
+        Ok(MaybeTls::Tls(stream))
+    }
+}
+
+// How often client_closed checks for a hangup once the client sent its ClientHello.
+const CLIENT_CLOSED_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// client_closed completes once the client hung up, or conn failed. The ClientHello is left unread
+// for the handshake, and a hangup is told apart from it by the read closed readiness (EPOLLRDHUP).
+// Since conn stays readable until the ClientHello is read, readiness is checked again periodically
+// rather than waited on once the ClientHello arrived.
+async fn client_closed(conn: &TcpStream) {
+    loop {
+        match conn.ready(tokio::io::Interest::READABLE).await {
+            Ok(ready) if ready.is_read_closed() => return,
+            Ok(_) => tokio::time::sleep(CLIENT_CLOSED_CHECK_INTERVAL).await,
+            Err(_) => return,
+        }
+    }
+}
+
+/// PassthroughTlsAcceptor accepts TLS clients, and returns the connections its certificate
+/// provider passes through as MaybeTls::Passthrough instead of rejecting them.
+#[derive(Clone)]
+pub struct PassthroughTlsAcceptor<F: CertProvider>(pub BoringTlsAcceptor<F>);
+
+impl<F> tls_listener::AsyncTls<TcpStream> for PassthroughTlsAcceptor<F>
+where
+    F: CertProvider + Clone + 'static,
+{
+    type Stream = MaybeTls;
+    type Error = TlsError;
+    type AcceptFuture = Pin<Box<dyn Future<Output = Result<Self::Stream, Self::Error>> + Send>>;
+
+    fn accept(&self, conn: TcpStream) -> Self::AcceptFuture {
+        let acceptor = self.0.clone();
+        Box::pin(async move { acceptor.accept_maybe_tls(conn, false).await })
+    }
+}
+
+/// PermissiveTlsAcceptor accepts both TLS and plaintext clients.
+#[derive(Clone)]
+pub struct PermissiveTlsAcceptor<F: CertProvider>(pub BoringTlsAcceptor<F>);
+
+impl<F> tls_listener::AsyncTls<TcpStream> for PermissiveTlsAcceptor<F>
+where
+    F: CertProvider + Clone + 'static,
+{
+    type Stream = MaybeTls;
+    type Error = TlsError;
+    type AcceptFuture = Pin<Box<dyn Future<Output = Result<Self::Stream, Self::Error>> + Send>>;
+
+    fn accept(&self, conn: TcpStream) -> Self::AcceptFuture {
+        let acceptor = self.0.clone();
+        Box::pin(async move { acceptor.accept_maybe_tls(conn, true).await })
+    }
+}
+
+/// LoopbackHandshake is the outcome of a handshake seen from both ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoopbackHandshake {
+    /// The identities of the server, as seen by the client.
+    pub server_identities: Vec<Identity>,
+    /// The identities of the client, as seen by the server.
+    pub client_identities: Vec<Identity>,
+    /// The protocol negotiated with ALPN, if any.
+    pub alpn: Option<Vec<u8>>,
+}
+
+/// loopback_handshake performs a mutual TLS handshake over the loopback interface between client,
+/// connecting to dest, and server. It is used to check a pair of certificates works together.
+pub async fn loopback_handshake(
+    client: &Certs,
+    server: &Certs,
+    dest: impl Into<ExpectedPeer>,
+) -> Result<LoopbackHandshake, TlsError> {
+    let listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let acceptor = server.mtls_acceptor(None)?;
+    let server = tokio::spawn(async move {
+        let (conn, _) = listener.accept().await?;
+        tokio_boring::accept(&acceptor, conn)
+            .await
+            .map_err(TlsError::Handshake)
+    });
+    let mut cfg = client.connector(dest)?.configure().map_err(Error::from)?;
+    cfg.set_verify_hostname(false);
+    cfg.set_use_server_name_indication(false);
+    let conn = TcpStream::connect(addr).await?;
+    let client = tokio_boring::connect(cfg, "", conn).await;
+    let server = server
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e).into()));
+    let client = client?;
+    let server = server?;
+    Ok(LoopbackHandshake {
+        server_identities: peer_identities(client.ssl()),
+        client_identities: peer_identities(server.ssl()),
+        alpn: client.ssl().selected_alpn_protocol().map(<[u8]>::to_vec),
+    })
+}
+
+const TEST_CERT: &[u8] = include_bytes!("cert-chain.pem");
+const TEST_PKEY: &[u8] = include_bytes!("key.pem");
+const TEST_ROOT: &[u8] = include_bytes!("root-cert.pem");
+const TEST_ROOT_KEY: &[u8] = include_bytes!("ca-key.pem");
+
+/// TestIdentity is an identity used for testing. This extends the Identity with test-only types
+#[derive(Debug)]
+pub enum TestIdentity {
+    Identity(Identity),
+    Ip(IpAddr),
+    /// Sans issues a certificate carrying all of the SANs, in order.
+    Sans(Vec<TestSan>),
+}
+
+/// TestSan is a single SAN of a test certificate.
+#[derive(Clone, Debug)]
+pub enum TestSan {
+    Uri(Identity),
+    Dns(String),
+    Ip(IpAddr),
+}
+
+impl From<Identity> for TestIdentity {
+    fn from(i: Identity) -> Self {
+        Self::Identity(i)
+    }
+}
+
+impl From<IpAddr> for TestIdentity {
+    fn from(i: IpAddr) -> Self {
+        Self::Ip(i)
+    }
+}
+
+//
+// impl Display for TestIdentity {
+//     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+//         match self {
+//             TestIdentity::Identity(i) => std::fmt::Display::fmt(&i, f),
+//             TestIdentity::Ip(i) => std::fmt::Display::fmt(&i, f),
+//         }
+//     }
+// }
+
+// TODO: Move to the mock submodule.
+
+fn generate_test_certs_at(
+    id: &TestIdentity,
+    not_before: SystemTime,
+    not_after: SystemTime,
+    rng: Option<&mut dyn rand::RngCore>,
+    key: Option<PKey<Private>>,
+) -> Certs {
+    let (ca_cert, ca_key) = test_ca().unwrap();
+    generate_test_certs_signed_by(id, not_before, not_after, rng, key, &ca_cert, &ca_key)
+}
+
+/// generate_test_certs_with_digest returns certificates for id signed by the test root with
+/// digest, to test weak signatures.
+pub fn generate_test_certs_with_digest(
+    id: &TestIdentity,
+    not_before: SystemTime,
+    not_after: SystemTime,
+    key: Option<PKey<Private>>,
+    digest: MessageDigest,
+) -> Certs {
+    let (ca_cert, ca_key) = test_ca().unwrap();
+    sign_test_certs(
+        id, not_before, not_after, None, key, &ca_cert, &ca_key, digest,
+    )
+}
+
+/// generate_test_certs_signed_by issues certificates for id to key, or to the shared test key if
+/// none is given.
+fn generate_test_certs_signed_by(
+    id: &TestIdentity,
+    not_before: SystemTime,
+    not_after: SystemTime,
+    rng: Option<&mut dyn rand::RngCore>,
+    key: Option<PKey<Private>>,
+    ca_cert: &x509::X509,
+    ca_key: &PKey<Private>,
+) -> Certs {
+    sign_test_certs(
+        id,
+        not_before,
+        not_after,
+        rng,
+        key,
+        ca_cert,
+        ca_key,
+        MessageDigest::sha256(),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sign_test_certs(
+    id: &TestIdentity,
+    not_before: SystemTime,
+    not_after: SystemTime,
+    rng: Option<&mut dyn rand::RngCore>,
+    key: Option<PKey<Private>>,
+    ca_cert: &x509::X509,
+    ca_key: &PKey<Private>,
+    digest: MessageDigest,
+) -> Certs {
+    let key = key.unwrap_or_else(|| pkey::PKey::private_key_from_pem(TEST_PKEY).unwrap());
+    let mut builder = x509::X509::builder().unwrap();
+    let not_before_asn = system_time_to_asn1_time(not_before).unwrap();
+    builder.set_not_before(&not_before_asn).unwrap();
+    builder
+        .set_not_after(&system_time_to_asn1_time(not_after).unwrap())
+        .unwrap();
+
+    builder.set_pubkey(&key).unwrap();
+    builder.set_version(2).unwrap();
+    let serial_number = {
+        let mut data = [0u8; 20];
+        match rng {
+            None => rand::thread_rng().fill_bytes(&mut data),
+            Some(rng) => rng.fill_bytes(&mut data),
+        }
+        // Clear the most significant bit to make the resulting bignum effectively 159 bit long.
+        data[0] &= 0x7f;
+        let serial = BigNum::from_slice(&data).unwrap();
+        serial.to_asn1_integer().unwrap()
+    };
+    builder.set_serial_number(&serial_number).unwrap();
+
+    let mut names = boring::x509::X509NameBuilder::new().unwrap();
+    for entry in ca_cert.subject_name().entries() {
+        names
+            .append_entry_by_nid(
+                entry.object().nid(),
+                &entry.data().as_utf8().unwrap().to_string(),
+            )
+            .unwrap();
+    }
+    let names = names.build();
+    builder.set_issuer_name(&names).unwrap();
+
+    let basic_constraints = BasicConstraints::new().critical().build().unwrap();
+    let key_usage = KeyUsage::new()
+        .critical()
+        .digital_signature()
+        .key_encipherment()
+        .build()
+        .unwrap();
+    let ext_key_usage = ExtendedKeyUsage::new()
+        .client_auth()
+        .server_auth()
+        .build()
+        .unwrap();
+    let authority_key_identifier = AuthorityKeyIdentifier::new()
+        .keyid(false)
+        .issuer(false)
+        .build(&builder.x509v3_context(Some(ca_cert), None))
+        .unwrap();
+    let mut san = SubjectAlternativeName::new();
+    match id {
+        TestIdentity::Identity(id) => {
+            san.uri(&id.to_string());
+        }
+        TestIdentity::Ip(ip) => {
+            san.ip(&ip.to_string());
+        }
+        TestIdentity::Sans(sans) => {
+            for entry in sans {
+                match entry {
+                    TestSan::Uri(id) => san.uri(&id.to_string()),
+                    TestSan::Dns(name) => san.dns(name),
+                    TestSan::Ip(ip) => san.ip(&ip.to_string()),
+                };
+            }
+        }
+    }
+    let subject_alternative_name = san
+        .critical()
+        .build(&builder.x509v3_context(Some(ca_cert), None))
+        .unwrap();
+    builder.append_extension(key_usage).unwrap();
+    builder.append_extension(ext_key_usage).unwrap();
+    builder.append_extension(basic_constraints).unwrap();
+    builder.append_extension(authority_key_identifier).unwrap();
     builder.append_extension(subject_alternative_name).unwrap();
 
-    builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+    builder.sign(ca_key, digest).unwrap();
+
+    let mut cert = ZtunnelCert::new(builder.build());
+    // For sub-second granularity
+    cert.not_before = not_before;
+    cert.not_after = not_after;
+    Certs {
+        cert,
+        key: key.into(),
+        chain: vec![ZtunnelCert::new(ca_cert.clone())],
+        policy: Default::default(),
+        lifetime_cap: None,
+    }
+}
+
+pub fn generate_test_certs(
+    id: &TestIdentity,
+    duration_until_valid: Duration,
+    duration_until_expiry: Duration,
+) -> Certs {
+    let not_before = SystemTime::now() + duration_until_valid;
+    generate_test_certs_at(
+        id,
+        not_before,
+        not_before + duration_until_expiry,
+        None,
+        None,
+    )
+}
+
+/// generate_test_certs_with_ca is like generate_test_certs, but signed by the provided CA instead of
+/// the default test root.
+pub fn generate_test_certs_with_ca(
+    id: &TestIdentity,
+    duration_until_valid: Duration,
+    duration_until_expiry: Duration,
+    ca_cert: &x509::X509,
+    ca_key: &PKey<Private>,
+) -> Certs {
+    let not_before = SystemTime::now() + duration_until_valid;
+    generate_test_certs_signed_by(
+        id,
+        not_before,
+        not_before + duration_until_expiry,
+        None,
+        None,
+        ca_cert,
+        ca_key,
+    )
+}
+
+/// sign_test_csr issues a certificate for id to the key of a PEM CSR, signed by the test root, the
+/// way a CA would. The identity is not taken from the CSR, so that tests can mimic a misbehaving
+/// CA. Returns the PEM leaf followed by the root.
+pub fn sign_test_csr(csr: &[u8], id: &Identity, duration_until_expiry: Duration) -> Vec<String> {
+    let req = x509::X509Req::from_pem(csr).unwrap();
+    let (ca_cert, ca_key) = test_ca().unwrap();
+    let mut builder = x509::X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = BigNum::from_u32(rand::random::<u32>() >> 1).unwrap();
+    builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_issuer_name(ca_cert.subject_name()).unwrap();
+    builder.set_pubkey(&req.public_key().unwrap()).unwrap();
+    let now = SystemTime::now();
+    builder
+        .set_not_before(&system_time_to_asn1_time(now).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&system_time_to_asn1_time(now + duration_until_expiry).unwrap())
+        .unwrap();
+    let san = SubjectAlternativeName::new()
+        .uri(&id.to_string())
+        .critical()
+        .build(&builder.x509v3_context(Some(&ca_cert), None))
+        .unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+    [builder.build(), ca_cert]
+        .iter()
+        .map(|cert| String::from_utf8(cert.to_pem().unwrap()).unwrap())
+        .collect()
+}
+
+/// generate_test_ca returns a new self-signed root, for tests that need more than one root.
+pub fn generate_test_ca(org: &str) -> (x509::X509, PKey<Private>) {
+    generate_test_ca_signed_by(org, None, KeyType::default())
+}
+
+/// generate_test_ca_with_key_type is like generate_test_ca, with a key of key_type.
+pub fn generate_test_ca_with_key_type(org: &str, key_type: KeyType) -> (x509::X509, PKey<Private>) {
+    generate_test_ca_signed_by(org, None, key_type)
+}
+
+/// generate_test_certs_with_key_type is like generate_test_certs_with_ca, with a new key of
+/// key_type. The CA is expected to have a key of the same type, such as one from
+/// generate_test_ca_with_key_type: the certificate is signed with the matching digest, such as
+/// SHA-384 for P-384.
+pub fn generate_test_certs_with_key_type(
+    id: &TestIdentity,
+    duration_until_valid: Duration,
+    duration_until_expiry: Duration,
+    key_type: KeyType,
+    ca_cert: &x509::X509,
+    ca_key: &PKey<Private>,
+) -> Certs {
+    let not_before = SystemTime::now() + duration_until_valid;
+    sign_test_certs(
+        id,
+        not_before,
+        not_before + duration_until_expiry,
+        None,
+        Some(key_type.generate().unwrap()),
+        ca_cert,
+        ca_key,
+        key_type.digest(),
+    )
+}
+
+/// generate_test_certs_with_chain returns certificates for id issued through depth intermediates,
+/// each signed by the previous one, under a root generated on the fly. The chain is ordered from
+/// the intermediate closest to the leaf up to the root.
+pub fn generate_test_certs_with_chain(
+    id: &TestIdentity,
+    depth: usize,
+    not_before: SystemTime,
+    not_after: SystemTime,
+) -> Certs {
+    let mut chain = vec![generate_test_ca("root")];
+    for i in 0..depth {
+        let (issuer_cert, issuer_key) = chain.last().unwrap();
+        let intermediate = generate_test_ca_signed_by(
+            &format!("intermediate-{i}"),
+            Some((issuer_cert, issuer_key)),
+            KeyType::default(),
+        );
+        chain.push(intermediate);
+    }
+    let (issuer_cert, issuer_key) = chain.last().unwrap();
+    let mut certs = generate_test_certs_signed_by(
+        id,
+        not_before,
+        not_after,
+        None,
+        None,
+        issuer_cert,
+        issuer_key,
+    );
+    certs.chain = chain
+        .into_iter()
+        .rev()
+        .map(|(cert, _)| ZtunnelCert::new(cert))
+        .collect();
+    certs
+}
+
+/// generate_test_ca_signed_by returns a new CA certificate with a key of key_type, signed by
+/// issuer or self-signed.
+fn generate_test_ca_signed_by(
+    org: &str,
+    issuer: Option<(&x509::X509, &PKey<Private>)>,
+    key_type: KeyType,
+) -> (x509::X509, PKey<Private>) {
+    let key = key_type.generate().unwrap();
+
+    let mut names = boring::x509::X509NameBuilder::new().unwrap();
+    names
+        .append_entry_by_nid(Nid::ORGANIZATIONNAME, org)
+        .unwrap();
+    let names = names.build();
+
+    let mut builder = x509::X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let serial_number = BigNum::from_u32(rand::random::<u32>() >> 1)
+        .unwrap()
+        .to_asn1_integer()
+        .unwrap();
+    builder.set_serial_number(&serial_number).unwrap();
+    builder.set_subject_name(&names).unwrap();
+    match issuer {
+        Some((issuer_cert, _)) => builder.set_issuer_name(issuer_cert.subject_name()).unwrap(),
+        None => builder.set_issuer_name(&names).unwrap(),
+    }
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(365).unwrap())
+        .unwrap();
+    builder
+        .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    builder
+        .append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+    let subject_key_identifier = SubjectKeyIdentifier::new()
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(subject_key_identifier).unwrap();
+    if let Some((issuer_cert, _)) = issuer {
+        let authority_key_identifier = AuthorityKeyIdentifier::new()
+            .keyid(false)
+            .build(&builder.x509v3_context(Some(issuer_cert), None))
+            .unwrap();
+        builder.append_extension(authority_key_identifier).unwrap();
+    }
+    let signing_key = issuer.map(|(_, key)| key).unwrap_or(&key);
+    builder.sign(signing_key, key_type.digest()).unwrap();
+    (builder.build(), key)
+}
+
+fn test_ca() -> Result<(x509::X509, PKey<Private>), Error> {
+    let cert = x509::X509::from_pem(TEST_ROOT)?;
+    let key = pkey::PKey::private_key_from_pem(TEST_ROOT_KEY)?;
+    Ok((cert, key))
+}
+
+pub fn test_certs() -> Certs {
+    let cert = ZtunnelCert::new(x509::X509::from_pem(TEST_CERT).unwrap());
+    let key = pkey::PKey::private_key_from_pem(TEST_PKEY).unwrap();
+    let chain = vec![cert.clone()];
+    Certs {
+        cert,
+        key: key.into(),
+        chain,
+        policy: Default::default(),
+        lifetime_cap: None,
+    }
+}
+
+pub mod mock {
+    use boring::bn::{BigNum, BigNumContext};
+    use boring::ec::{EcGroup, EcKey, EcPoint};
+    use boring::nid::Nid;
+    use boring::pkey::{PKey, Private};
+    use rand::{rngs::SmallRng, RngCore, SeedableRng};
+    use std::time::SystemTime;
+
+    use super::{generate_test_certs_at, Certs, TestIdentity};
+
+    /// Allows generating test certificates in a deterministic manner.
+    pub struct CertGenerator {
+        rng: SmallRng,
+        fresh_keys: bool,
+    }
+
+    impl CertGenerator {
+        /// Returns a new test certificate generator. The seed parameter sets the seed for any
+        /// randomized operations. Multiple CertGenerator instances created with the same seed will
+        /// return the same successive certificates, if same arguments to new_certs are given.
+        pub fn new(seed: u64) -> Self {
+            Self {
+                rng: SmallRng::seed_from_u64(seed),
+                fresh_keys: false,
+            }
+        }
+
+        /// with_fresh_keys makes new_certs issue each certificate to a new P-256 key derived from
+        /// the seed, instead of the key shared by all test certificates.
+        pub fn with_fresh_keys(mut self) -> Self {
+            self.fresh_keys = true;
+            self
+        }
+
+        pub fn new_certs(
+            &mut self,
+            id: &TestIdentity,
+            not_before: SystemTime,
+            not_after: SystemTime,
+        ) -> Certs {
+            let key = self.fresh_keys.then(|| self.new_key());
+            generate_test_certs_at(id, not_before, not_after, Some(&mut self.rng), key)
+        }
+
+        fn new_key(&mut self) -> PKey<Private> {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+            let mut data = [0u8; 32];
+            self.rng.fill_bytes(&mut data);
+            // Keep the scalar non-zero and below the group order.
+            data[0] &= 0x7f;
+            data[31] |= 1;
+            let private = BigNum::from_slice(&data).unwrap();
+            let ctx = BigNumContext::new().unwrap();
+            let mut public = EcPoint::new(&group).unwrap();
+            public.mul_generator(&group, &private, &ctx).unwrap();
+            let key = EcKey::from_private_components(&group, &private, &public).unwrap();
+            PKey::from_ec_key(key).unwrap()
+        }
+    }
+
+    impl Default for CertGenerator {
+        fn default() -> Self {
+            // Use arbitrary seed.
+            Self::new(427)
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::time::Duration;
+
+    use hyper::Request;
+    use tower::{Service, ServiceExt};
+
+    use crate::config::RootCert;
+    use crate::identity::Identity;
+    use crate::tls::TestIdentity;
+
+    use super::{generate_test_certs, grpc_connector, ChannelError, ChannelState, ConnectorConfig};
+
+    #[test]
+    #[cfg(feature = "fips")]
+    fn is_fips_enabled() {
+        assert!(boring::fips::enabled());
+        assert!(super::is_fips());
+        assert!(super::require_fips(true).is_ok());
+    }
+
+    #[test]
+    #[cfg(not(feature = "fips"))]
+    fn is_fips_disabled() {
+        assert!(!boring::fips::enabled());
+        assert!(!super::is_fips());
+        assert!(matches!(
+            super::require_fips(true),
+            Err(super::Error::FipsUnavailable)
+        ));
+        assert!(super::require_fips(false).is_ok());
+    }
+
+    #[test]
+    fn weak_certificates() {
+        use boring::hash::MessageDigest;
+        use boring::pkey::PKey;
+        use boring::rsa::Rsa;
+
+        use super::{CertPolicy, Error};
+
+        let id = Identity::default();
+        let now = std::time::SystemTime::now();
+        let not_after = now + Duration::from_secs(100);
+        let strong = super::generate_test_certs_at(&id.clone().into(), now, not_after, None, None);
+        let sha1 = super::generate_test_certs_with_digest(
+            &id.clone().into(),
+            now,
+            not_after,
+            None,
+            MessageDigest::sha1(),
+        );
+        let small_key = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+        let small = super::generate_test_certs_at(
+            &id.clone().into(),
+            now,
+            not_after,
+            None,
+            Some(small_key),
+        );
+
+        let policy = CertPolicy::default();
+        strong.check_policy(&policy).unwrap();
+        assert!(matches!(
+            sha1.check_policy(&policy),
+            Err(Error::WeakCertificate { reason }) if reason.contains("sha1WithRSAEncryption")
+        ));
+        assert!(matches!(
+            small.check_policy(&policy),
+            Err(Error::WeakCertificate { reason }) if reason.contains("1024 bit RSA key")
+        ));
+
+        // The permissive policy only warns.
+        let permissive = CertPolicy {
+            enforce: false,
+            ..Default::default()
+        };
+        sha1.check_policy(&permissive).unwrap();
+        small.check_policy(&permissive).unwrap();
+
+        // Weak certificates are rejected when loaded and validated.
+        let pem = |certs: &super::Certs| {
+            (
+                certs
+                    .private_key()
+                    .in_memory()
+                    .unwrap()
+                    .private_key_to_pem_pkcs8()
+                    .unwrap(),
+                certs.x509().to_pem().unwrap(),
+            )
+        };
+        let (key, cert) = pem(&small);
+        assert!(matches!(
+            super::cert_from(&key, &cert, vec![]),
+            Err(Error::WeakCertificate { .. })
+        ));
+        let (key, cert) = pem(&strong);
+        super::cert_from(&key, &cert, vec![]).unwrap();
+        let roots: Vec<_> = sha1.iter_chain().cloned().collect();
+        assert!(matches!(
+            sha1.validate(&id, &roots, &crate::time::SystemClock),
+            Err(Error::WeakCertificate { .. })
+        ));
+    }
+
+    #[test]
+    fn security_level() {
+        use boring::pkey::PKey;
+        use boring::rsa::Rsa;
+
+        use super::Error;
+
+        let id: TestIdentity = Identity::default().into();
+        let now = std::time::SystemTime::now();
+        // The test key is a P-256 key, providing 128 bits of security.
+        let certs =
+            super::generate_test_certs_at(&id, now, now + Duration::from_secs(100), None, None);
+        assert!(certs.builder().security_level(3).build_acceptor().is_ok());
+        assert!(matches!(
+            certs.builder().security_level(4).build_acceptor(),
+            Err(Error::SecurityLevel(128, 4, 192))
+        ));
+
+        let weak = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+        let certs = super::generate_test_certs_at(
+            &id,
+            now,
+            now + Duration::from_secs(100),
+            None,
+            Some(weak),
+        );
+        assert!(certs.builder().security_level(1).build_acceptor().is_ok());
+        assert!(matches!(
+            certs
+                .builder()
+                .security_level(2)
+                .build_connector(&Identity::default()),
+            Err(Error::SecurityLevel(80, 2, 112))
+        ));
+    }
+
+    #[test]
+    fn validate() {
+        use super::{generate_test_ca, generate_test_certs_with_ca, Error};
+        use crate::time::{Converter, SystemClock};
+
+        let id = Identity::default();
+        let (root, root_key) = generate_test_ca("root");
+        let roots = [root.clone()];
+        let issue = |until_valid, until_expiry| {
+            generate_test_certs_with_ca(
+                &id.clone().into(),
+                Duration::from_secs(until_valid),
+                Duration::from_secs(until_expiry),
+                &root,
+                &root_key,
+            )
+        };
+        let certs = issue(0, 100);
+        certs.validate(&id, &roots, &SystemClock).unwrap();
+
+        // Key does not match the leaf.
+        let (_, other_key) = generate_test_ca("other");
+        let mismatched = issue(0, 100).with_private_key(other_key.into());
+        assert!(matches!(
+            mismatched.validate(&id, &roots, &SystemClock),
+            Err(Error::KeyCertMismatch)
+        ));
+
+        // Certificate for another identity.
+        let other = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "other".to_string(),
+            service_account: "default".to_string(),
+        };
+        assert!(matches!(
+            certs.validate(&other, &roots, &SystemClock),
+            Err(Error::SanMismatch(expected, got)) if expected == other && got == vec![id.clone()]
+        ));
+
+        // Chain does not lead to the roots.
+        let (other_root, _) = generate_test_ca("other");
+        assert!(matches!(
+            certs.validate(&id, &[other_root], &SystemClock),
+            Err(Error::UntrustedChain(_))
+        ));
+
+        // Expired, or valid only far in the future.
+        assert!(matches!(
+            issue(0, 0).validate(&id, &roots, &SystemClock),
+            Err(Error::CertificateValidity(_))
+        ));
+        assert!(matches!(
+            issue(24 * 60 * 60, 100).validate(&id, &roots, &SystemClock),
+            Err(Error::CertificateValidity(_))
+        ));
+        // Slight clock skew with the CA is tolerated.
+        issue(60, 100).validate(&id, &roots, &SystemClock).unwrap();
+
+        // Validity, of the leaf and of the chain, is checked at the time of the clock.
+        let later = Converter::new_at(certs.not_after() + Duration::from_secs(60));
+        assert!(matches!(
+            certs.validate(&id, &roots, &later),
+            Err(Error::CertificateValidity(_))
+        ));
+        let tomorrow = issue(24 * 60 * 60, 100);
+        let clock = Converter::new_at(tomorrow.not_after() - Duration::from_secs(60));
+        tomorrow.validate(&id, &roots, &clock).unwrap();
+    }
+
+    #[test]
+    fn extract_sans_skips_invalid_uris() {
+        use boring::x509::extension::SubjectAlternativeName;
+
+        let id = Identity::default();
+        let key = boring::pkey::PKey::private_key_from_pem(super::TEST_PKEY).unwrap();
+        let mut builder = boring::x509::X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        let san = SubjectAlternativeName::new()
+            .uri("https://example.com/not/a/spiffe/id")
+            .uri(&id.to_string())
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder
+            .sign(&key, boring::hash::MessageDigest::sha256())
+            .unwrap();
+        let cert = builder.build();
+
+        let skipped = super::invalid_uri_sans();
+        assert_eq!(super::extract_sans(&cert), vec![id.clone()]);
+        assert!(super::invalid_uri_sans() > skipped);
+        super::SanChecker::verify_san(&cert, &id).unwrap();
+    }
+
+    #[test]
+    fn verify_san_equivalent_uri() {
+        use boring::x509::extension::SubjectAlternativeName;
+
+        use super::{SanChecker, TlsError};
+
+        let id = Identity::default();
+        let key = boring::pkey::PKey::private_key_from_pem(super::TEST_PKEY).unwrap();
+        let mut builder = boring::x509::X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        // Not the canonical rendering of the identity, so only the parsing path matches it.
+        let san = SubjectAlternativeName::new()
+            .uri(&format!("{id}/"))
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder
+            .sign(&key, boring::hash::MessageDigest::sha256())
+            .unwrap();
+        let cert = builder.build();
+
+        cert.verify_san(&id).unwrap();
+        cert.verify_san_trust_domain(&id).unwrap();
+        let other = Identity::Spiffe {
+            trust_domain: "other.local".to_string(),
+            namespace: "ns".to_string(),
+            service_account: "sa".to_string(),
+        };
+        assert!(matches!(
+            cert.verify_san(&other),
+            Err(TlsError::SanError(expected, sans)) if expected == other && sans == vec![id.clone()]
+        ));
+        assert!(matches!(
+            cert.verify_san_trust_domain(&other),
+            Err(TlsError::SanTrustDomainError(td, _)) if td == "other.local"
+        ));
+    }
+
+    #[test]
+    fn trust_domain_prefix() {
+        let prefix = "spiffe://cluster.local/";
+        assert!(super::is_in_trust_domain(
+            "spiffe://cluster.local/ns/default/sa/default",
+            prefix
+        ));
+        assert!(!super::is_in_trust_domain(
+            "spiffe://cluster.local.evil/ns/default/sa/default",
+            prefix
+        ));
+        assert!(!super::is_in_trust_domain(
+            "spiffe://cluster.local/ns/default/sa/default/extra",
+            prefix
+        ));
+        assert!(!super::is_in_trust_domain(
+            "spiffe://cluster.local/foo/default/sa/default",
+            prefix
+        ));
+    }
+
+    #[test]
+    fn multiple_sans() {
+        use super::{San, TestSan};
+        use std::net::IpAddr;
+
+        let a = Identity::default();
+        let b = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "other".to_string(),
+            service_account: "other".to_string(),
+        };
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let certs = generate_test_certs(
+            &TestIdentity::Sans(vec![
+                TestSan::Uri(a.clone()),
+                TestSan::Dns("example.com".to_string()),
+                TestSan::Uri(b.clone()),
+                TestSan::Ip(ip),
+            ]),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let cert = certs.x509();
+        assert_eq!(super::extract_sans(cert), vec![a.clone(), b.clone()]);
+        assert_eq!(
+            super::extract_all_sans(cert),
+            vec![
+                San::Uri(a.to_string()),
+                San::Dns("example.com".to_string()),
+                San::Uri(b.to_string()),
+                San::Ip(ip),
+            ]
+        );
+        super::SanChecker::verify_san(cert, &b).unwrap();
+    }
+
+    #[test]
+    fn deterministic_keys() {
+        use super::mock::CertGenerator;
+
+        let id: TestIdentity = Identity::default().into();
+        let now = std::time::SystemTime::now();
+        let certs = |seed| {
+            CertGenerator::new(seed).with_fresh_keys().new_certs(
+                &id,
+                now,
+                now + Duration::from_secs(100),
+            )
+        };
+        let der = |certs: &super::Certs| {
+            let key = certs.private_key().in_memory().unwrap().clone();
+            (
+                certs.x509().to_der().unwrap(),
+                key.private_key_to_der().unwrap(),
+            )
+        };
+        let (a, b, c) = (certs(1), certs(1), certs(2));
+        assert_eq!(der(&a), der(&b));
+        assert_ne!(der(&a).1, der(&c).1);
+
+        // By default, all certificates share one key.
+        let mut shared = CertGenerator::new(1);
+        let (d, e) = (
+            shared.new_certs(&id, now, now + Duration::from_secs(100)),
+            shared.new_certs(&id, now, now + Duration::from_secs(100)),
+        );
+        assert_eq!(der(&d).1, der(&e).1);
+        assert_ne!(der(&a).1, der(&d).1);
+    }
+
+    #[test]
+    fn cert_expiration() {
+        let id: TestIdentity = Identity::default().into();
+        let expiry = Duration::from_secs(1000);
+        let now = std::time::SystemTime::now();
+        let certs = super::generate_test_certs_at(&id, now, now + expiry, None, None);
+        assert!(!certs.is_expired_at(now));
+        assert_eq!(certs.get_duration_until_refresh_at(now), expiry / 2);
+        let later = now + Duration::from_secs(100);
+        assert_eq!(
+            certs.get_duration_until_refresh_at(later),
+            expiry / 2 - Duration::from_secs(100)
+        );
+        assert_eq!(
+            certs.get_duration_until_refresh_at(now + expiry / 2),
+            Duration::ZERO
+        );
+        assert!(!certs.is_expired_at(now + expiry));
+        assert!(certs.is_expired_at(now + expiry + Duration::from_secs(1)));
+        assert_eq!(
+            certs.get_duration_until_refresh_at(now + expiry + Duration::from_secs(1)),
+            Duration::ZERO
+        );
+
+        // Certificates that are not valid yet are refreshed right away.
+        let future = now + Duration::from_secs(1000);
+        let future_certs = super::generate_test_certs_at(&id, future, future + expiry, None, None);
+        assert!(!future_certs.is_expired_at(now));
+        assert_eq!(
+            future_certs.get_duration_until_refresh_at(now),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn cert_lifetime_cap() {
+        let id: TestIdentity = Identity::default().into();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = std::time::SystemTime::now();
+        let certs = super::generate_test_certs_at(&id, now, now + 365 * day, None, None);
+        assert_eq!(certs.lifetime(), 365 * day);
+        assert_eq!(certs.refresh_at(), now + 365 * day / 2);
+
+        let capped = certs.with_lifetime_cap(day);
+        assert_eq!(capped.lifetime(), 365 * day);
+        assert_eq!(capped.refresh_at(), now + day / 2);
+        assert_eq!(capped.get_duration_until_refresh_at(now), day / 2);
+        assert_eq!(
+            capped.get_duration_until_refresh_at(now + day / 2),
+            Duration::ZERO
+        );
+
+        // A cap longer than the lifetime has no effect.
+        let short = super::generate_test_certs_at(&id, now, now + day, None, None)
+            .with_lifetime_cap(365 * day);
+        assert_eq!(short.refresh_at(), now + day / 2);
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_invalid_uri() {
+        let mut channel = grpc_connector(
+            "/no/authority".to_string(),
+            RootCert::Default,
+            ConnectorConfig::default(),
+        )
+        .unwrap();
+        let req = Request::builder()
+            .uri("/test.Service/Method")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        let res = channel.ready().await.unwrap().call(req).await;
+        assert!(matches!(res, Err(ChannelError::InvalidUri(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn grpc_channel_reconnects_after_connect_failure() {
+        // Grab a free port, then close it so connections are refused.
+        let addr = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let mut channel = grpc_connector(
+            format!("https://{addr}"),
+            RootCert::Default,
+            ConnectorConfig::default(),
+        )
+        .unwrap();
+        let req = Request::builder()
+            .uri("/test.Service/Method")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        let res = channel.ready().await.unwrap().call(req).await;
+        assert!(matches!(res, Err(ChannelError::Request(_))));
+        assert_eq!(channel.reconnects(), 0);
+
+        // The channel is not ready until the backoff elapses, at which point it reconnects.
+        let start = tokio::time::Instant::now();
+        channel.ready().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(80));
+        assert_eq!(channel.reconnects(), 1);
+
+        // Clones share the connection state, so there is nothing left to rebuild.
+        channel.clone().ready().await.unwrap();
+        assert_eq!(channel.reconnects(), 1);
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_reconnects_after_connection_closed() {
+        let path = std::env::temp_dir().join(format!("ztunnel-uds-{}.sock", rand::random::<u64>()));
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            // Read the start of the request, then drop the established connection.
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut buf).await;
+        });
+        let mut channel = grpc_connector(
+            format!("unix://{}", path.display()),
+            RootCert::Default,
+            ConnectorConfig::default(),
+        )
+        .unwrap();
+        let req = Request::builder()
+            .uri("/test.Service/Method")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        let res = channel.ready().await.unwrap().call(req).await;
+        assert!(matches!(res, Err(ChannelError::Request(_))));
+        assert_eq!(channel.health().state, ChannelState::Failing);
+
+        // The closed connection is rebuilt once the backoff elapses.
+        channel.ready().await.unwrap();
+        assert_eq!(channel.reconnects(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn uds_connector_rejects_root_cert() {
+        let res = grpc_connector(
+            "unix:///tmp/ztunnel.sock".to_string(),
+            RootCert::File("/etc/root-cert.pem".into()),
+            ConnectorConfig::default(),
+        );
+        assert!(matches!(res, Err(crate::tls::Error::UdsRootCert(_))));
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_uds_round_trip() {
+        let path = std::env::temp_dir().join(format!("ztunnel-uds-{}.sock", rand::random::<u64>()));
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            crate::hyper_util::http2_server()
+                .serve_connection(
+                    socket,
+                    hyper::service::service_fn(|req: Request<hyper::body::Incoming>| async move {
+                        assert_eq!(req.uri().path(), "/test.Service/Method");
+                        Ok::<_, std::convert::Infallible>(
+                            hyper::Response::builder()
+                                .header("content-type", "application/grpc")
+                                .body(http_body_util::Empty::<bytes::Bytes>::new())
+                                .unwrap(),
+                        )
+                    }),
+                )
+                .await
+                .unwrap();
+        });
+
+        let mut channel = grpc_connector(
+            format!("unix://{}", path.display()),
+            RootCert::Default,
+            ConnectorConfig::default(),
+        )
+        .unwrap();
+        let req = Request::builder()
+            .uri("/test.Service/Method")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        let res = channel.ready().await.unwrap().call(req).await.unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_health() {
+        let path = std::env::temp_dir().join(format!("ztunnel-uds-{}.sock", rand::random::<u64>()));
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let _ = crate::hyper_util::http2_server()
+                    .serve_connection(
+                        socket,
+                        hyper::service::service_fn(|_| async move {
+                            Ok::<_, std::convert::Infallible>(
+                                hyper::Response::builder()
+                                    .header("content-type", "application/grpc")
+                                    .body(http_body_util::Empty::<bytes::Bytes>::new())
+                                    .unwrap(),
+                            )
+                        }),
+                    )
+                    .await;
+            }
+        });
+        let mut channel = grpc_connector(
+            format!("unix://{}", path.display()),
+            RootCert::Default,
+            ConnectorConfig::default(),
+        )
+        .unwrap();
+        let request = || {
+            Request::builder()
+                .uri("/test.Service/Method")
+                .body(tonic::body::empty_body())
+                .unwrap()
+        };
+
+        let health = channel.health();
+        assert_eq!(health.state, ChannelState::Connecting);
+        assert_eq!(health.last_success, None);
+        assert!(health.check().is_ok());
+
+        channel
+            .ready()
+            .await
+            .unwrap()
+            .call(request())
+            .await
+            .unwrap();
+        let health = channel.health();
+        assert_eq!(health.state, ChannelState::Ready);
+        assert_eq!(health.consecutive_failures, 0);
+        assert!(health.last_success.is_some());
+
+        // The server goes away. The pooled connection may not be known to be closed yet, so the
+        // failure may only be detected by a later request.
+        server.abort();
+        let _ = server.await;
+        std::fs::remove_file(&path).unwrap();
+        for _ in 0..10 {
+            let _ = channel.ready().await.unwrap().call(request()).await;
+            if channel.health().state == ChannelState::Failing {
+                break;
+            }
+        }
+        let health = channel.health();
+        assert_eq!(health.state, ChannelState::Failing);
+        assert!(health.consecutive_failures >= 1);
+        assert!(health.last_error.is_some());
+        assert_eq!(health.check(), Err(health.last_error.clone().unwrap()));
+        // The time of the last success is kept.
+        assert!(health.last_success.is_some());
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_message_too_large() {
+        use http_body_04::Body as _;
+
+        let path = std::env::temp_dir().join(format!("ztunnel-uds-{}.sock", rand::random::<u64>()));
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = crate::hyper_util::http2_server()
+                .serve_connection(
+                    socket,
+                    hyper::service::service_fn(|_| async move {
+                        // A single uncompressed message of 2048 bytes.
+                        let mut message = vec![0, 0, 0, 8, 0];
+                        message.extend_from_slice(&[1; 2048]);
+                        Ok::<_, std::convert::Infallible>(
+                            hyper::Response::builder()
+                                .header("content-type", "application/grpc")
+                                .body(http_body_util::Full::new(bytes::Bytes::from(message)))
+                                .unwrap(),
+                        )
+                    }),
+                )
+                .await;
+        });
+
+        let mut channel = grpc_connector(
+            format!("unix://{}", path.display()),
+            RootCert::Default,
+            ConnectorConfig {
+                limits: super::ChannelLimits {
+                    max_message_size: 1024,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let req = Request::builder()
+            .uri("/test.Service/Method")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        let mut body = channel
+            .ready()
+            .await
+            .unwrap()
+            .call(req)
+            .await
+            .unwrap()
+            .into_body();
+        let res = body.data().await.unwrap();
+        assert!(
+            matches!(
+                res,
+                Err(ChannelError::MessageTooLarge {
+                    size: 2048,
+                    limit: 1024
+                })
+            ),
+            "{res:?}"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_h2_settings() {
+        use tokio::io::AsyncReadExt;
+
+        const SETTINGS: u8 = 0x4;
+        const WINDOW_UPDATE: u8 = 0x8;
+        const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+        const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+        let path = std::env::temp_dir().join(format!("ztunnel-uds-{}.sock", rand::random::<u64>()));
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let limits = super::ChannelLimits {
+            initial_stream_window_size: 1024 * 1024,
+            initial_connection_window_size: 2 * 1024 * 1024,
+            max_frame_size: 32 * 1024,
+            ..Default::default()
+        };
+        let mut channel = grpc_connector(
+            format!("unix://{}", path.display()),
+            RootCert::Default,
+            ConnectorConfig {
+                limits: limits.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // The server never answers, the request only serves to open the connection.
+        let client = tokio::spawn(async move {
+            let req = Request::builder()
+                .uri("/test.Service/Method")
+                .body(tonic::body::empty_body())
+                .unwrap();
+            let _ = channel.ready().await.unwrap().call(req).await;
+        });
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut preface = [0u8; 24];
+        socket.read_exact(&mut preface).await.unwrap();
+        assert_eq!(&preface, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+        let mut settings = std::collections::HashMap::new();
+        let mut connection_window_increment = None;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while connection_window_increment.is_none() {
+                let mut header = [0u8; 9];
+                socket.read_exact(&mut header).await.unwrap();
+                let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+                let stream = u32::from_be_bytes(header[5..9].try_into().unwrap()) & 0x7fff_ffff;
+                let mut payload = vec![0u8; len];
+                socket.read_exact(&mut payload).await.unwrap();
+                match header[3] {
+                    SETTINGS => {
+                        for setting in payload.chunks(6) {
+                            let id = u16::from_be_bytes([setting[0], setting[1]]);
+                            let value = u32::from_be_bytes(setting[2..6].try_into().unwrap());
+                            settings.insert(id, value);
+                        }
+                    }
+                    WINDOW_UPDATE if stream == 0 => {
+                        connection_window_increment =
+                            Some(u32::from_be_bytes(payload[..4].try_into().unwrap()));
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("no connection window update received");
+
+        assert_eq!(
+            settings.get(&SETTINGS_INITIAL_WINDOW_SIZE),
+            Some(&limits.initial_stream_window_size)
+        );
+        assert_eq!(
+            settings.get(&SETTINGS_MAX_FRAME_SIZE),
+            Some(&limits.max_frame_size)
+        );
+        // The connection window starts at the h2 default of 65535 bytes.
+        assert_eq!(
+            connection_window_increment,
+            Some(limits.initial_connection_window_size - 65535)
+        );
+        client.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_http11_negotiated() {
+        // A load balancer that only speaks http/1.1.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let certs = generate_test_certs(
+            &addr.ip().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let acceptor = certs
+            .builder()
+            .require_client_cert(false)
+            .alpn(super::Alpn::Http11)
+            .build_acceptor()
+            .unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    // Hold the connection open, so the failure comes from the negotiated protocol.
+                    let _stream = tokio_boring::accept(&acceptor, socket).await;
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                });
+            }
+        });
+
+        let mut channel = grpc_connector(
+            format!("https://{addr}"),
+            RootCert::Static(certs.chain().unwrap()),
+            ConnectorConfig {
+                alpn: super::ControlPlaneAlpn::H2AndHttp11,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let req = Request::builder()
+            .uri("/test.Service/Method")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        let err = channel.ready().await.unwrap().call(req).await.unwrap_err();
+        let msg = err.to_string();
+        assert!(
+            msg.contains("127.0.0.1 negotiated http/1.1 instead of h2"),
+            "{msg}"
+        );
+        assert!(msg.contains("load balancers"), "{msg}");
+    }
+
+    // Spawns an h2 server over TLS on bind, using the provided certs, that responds OK to every
+    // request.
+    async fn spawn_tls_server(bind: &str, certs: super::Certs) -> std::net::SocketAddr {
+        use tokio_stream::StreamExt;
+
+        let listener = tokio::net::TcpListener::bind(bind).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut tls_stream =
+            crate::hyper_util::tls_server(super::ControlPlaneCertProvider(certs), listener, None);
+        tokio::spawn(async move {
+            while let Some(socket) = tls_stream.next().await {
+                tokio::spawn(crate::hyper_util::http2_server().serve_connection(
+                    socket,
+                    hyper::service::service_fn(|_req: Request<hyper::body::Incoming>| async {
+                        Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                            http_body_util::Empty::<bytes::Bytes>::new(),
+                        ))
+                    }),
+                ));
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn rotating_cert_provider() {
+        let id = Identity::default();
+        let issue = || {
+            generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            )
+        };
+        let provider = super::RotatingCertProvider::new(issue());
+        assert_rotates(provider, issue).await;
+    }
+
+    #[tokio::test]
+    async fn rotating_cert_provider_cert_callback() {
+        let id = Identity::default();
+        let issue = || {
+            generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            )
+        };
+        let provider = super::RotatingCertProvider::with_cert_callback(issue()).unwrap();
+        let before = provider.acceptor().unwrap();
+        assert_rotates(provider.clone(), issue).await;
+        // The certificate changed without building another context.
+        let after = provider.acceptor().unwrap();
+        assert!(std::ptr::eq(before.context(), after.context()));
+    }
+
+    // Checks that handshakes with provider present a new certificate once it is updated.
+    async fn assert_rotates(
+        provider: super::RotatingCertProvider,
+        issue: impl Fn() -> super::Certs,
+    ) {
+        use tokio_stream::StreamExt;
+
+        let id = Identity::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut tls_stream = crate::hyper_util::tls_server(provider.clone(), listener, None);
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Some(socket) = tls_stream.next().await {
+                conns.push(socket);
+            }
+        });
+
+        async fn peer_serial(
+            addr: std::net::SocketAddr,
+            client: &super::Certs,
+            id: &Identity,
+        ) -> boring::bn::BigNum {
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut cfg = client.connector(id).unwrap().configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            let tls = tokio_boring::connect(cfg, "", tcp).await.unwrap();
+            let cert = tls.ssl().peer_certificate().unwrap();
+            cert.serial_number().to_bn().unwrap()
+        }
+
+        let client = issue();
+        let first = peer_serial(addr, &client, &id).await;
+        assert_eq!(first, peer_serial(addr, &client, &id).await);
+
+        let rotated = issue();
+        let expected = rotated.x509().serial_number().to_bn().unwrap();
+        provider.update(rotated);
+        let second = peer_serial(addr, &client, &id).await;
+        assert_ne!(first, second);
+        assert_eq!(second, expected);
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_reloads_root_cert_file() {
+        let addr = spawn_tls_server(
+            "127.0.0.1:0",
+            generate_test_certs(
+                &std::net::IpAddr::from([127, 0, 0, 1]).into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            ),
+        )
+        .await;
+        // Start out trusting an unrelated certificate, so the handshake fails.
+        let path = std::env::temp_dir().join(format!("ztunnel-root-{}.pem", rand::random::<u64>()));
+        std::fs::write(&path, super::TEST_CERT).unwrap();
+        let mut channel = grpc_connector(
+            format!("https://{addr}"),
+            RootCert::File(path.clone()),
+            ConnectorConfig {
+                root_cert_check_interval: Duration::from_millis(10),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let req = || {
+            Request::builder()
+                .uri("/test.Service/Method")
+                .body(tonic::body::empty_body())
+                .unwrap()
+        };
+        assert!(channel.ready().await.unwrap().call(req()).await.is_err());
+
+        // Rotate in the root that signed the server certificate. It is picked up in the
+        // background, at the next check.
+        std::fs::write(&path, super::TEST_ROOT).unwrap();
+        let mut res = None;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if let Ok(r) = channel.ready().await.unwrap().call(req()).await {
+                res = Some(r);
+                break;
+            }
+        }
+        assert_eq!(res.unwrap().status(), hyper::StatusCode::OK);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_static_root_bundle() {
+        // The server chains to the second root in the bundle.
+        let (ca_cert, ca_key) = super::generate_test_ca("new-root.local");
+        let addr = spawn_tls_server(
+            "127.0.0.1:0",
+            super::generate_test_certs_with_ca(
+                &std::net::IpAddr::from([127, 0, 0, 1]).into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+                &ca_cert,
+                &ca_key,
+            ),
+        )
+        .await;
+        let mut bundle = super::TEST_ROOT.to_vec();
+        bundle.extend_from_slice(&ca_cert.to_pem().unwrap());
+        assert_eq!(super::parse_root_certs(&bundle).unwrap().len(), 2);
+
+        let mut channel = grpc_connector(
+            format!("https://{addr}"),
+            RootCert::Static(bundle.into()),
+            ConnectorConfig::default(),
+        )
+        .unwrap();
+        let req = Request::builder()
+            .uri("/test.Service/Method")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        let res = channel.ready().await.unwrap().call(req).await.unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_ipv6() {
+        let addr = spawn_tls_server(
+            "[::1]:0",
+            generate_test_certs(
+                &std::net::IpAddr::from(std::net::Ipv6Addr::LOCALHOST).into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            ),
+        )
+        .await;
+
+        // Dials https://[::1]:port, verifying the IPv6 SAN.
+        let mut channel = grpc_connector(
+            format!("https://{addr}"),
+            RootCert::Static(super::TEST_ROOT.into()),
+            ConnectorConfig::default(),
+        )
+        .unwrap();
+        let req = Request::builder()
+            .uri("/test.Service/Method")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        let res = channel.ready().await.unwrap().call(req).await.unwrap();
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn grpc_channel_pins() {
+        let certs = generate_test_certs(
+            &std::net::IpAddr::from(std::net::Ipv4Addr::LOCALHOST).into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let leaf = super::spki_hash(certs.x509()).unwrap();
+        let root = super::spki_hash(certs.iter_chain().last().unwrap()).unwrap();
+        let addr = spawn_tls_server("127.0.0.1:0", certs).await;
+        let call = |pins: Vec<String>| async move {
+            let mut channel = grpc_connector(
+                format!("https://{addr}"),
+                RootCert::Static(super::TEST_ROOT.into()),
+                ConnectorConfig {
+                    pins,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let req = Request::builder()
+                .uri("/test.Service/Method")
+                .body(tonic::body::empty_body())
+                .unwrap();
+            channel.ready().await.unwrap().call(req).await
+        };
+
+        // Either the leaf or the root may be pinned.
+        let other = base64::encode([0u8; 32]);
+        for pins in [vec![leaf.clone()], vec![other.clone(), root.clone()]] {
+            assert_eq!(call(pins).await.unwrap().status(), hyper::StatusCode::OK);
+        }
+
+        let msg = call(vec![other]).await.unwrap_err().to_string();
+        assert!(msg.contains("presented no pinned public key"), "{msg}");
+        assert!(msg.contains(&leaf), "{msg}");
+    }
+
+    #[test]
+    fn static_root_without_certs() {
+        let res = grpc_connector(
+            "https://istiod:15012".to_string(),
+            RootCert::Static("not a cert".into()),
+            ConnectorConfig::default(),
+        );
+        assert!(matches!(res, Err(crate::tls::Error::RootCertEmpty)));
+    }
+
+    #[test]
+    fn root_cert_file_errors() {
+        use crate::tls::Error;
+
+        let path = std::env::temp_dir().join(format!("ztunnel-root-{}.pem", rand::random::<u64>()));
+        let connect = || {
+            grpc_connector(
+                "https://istiod:15012".to_string(),
+                RootCert::File(path.clone()),
+                ConnectorConfig::default(),
+            )
+            .err()
+            .unwrap()
+        };
+
+        let err = connect();
+        assert!(
+            matches!(&err, Error::RootCertIo(p, e) if p == &path && e.kind() == std::io::ErrorKind::NotFound),
+            "{err}"
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::write(&path, super::TEST_ROOT).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o000)).unwrap();
+            // Permissions are not enforced when running as root.
+            if std::fs::read(&path).is_err() {
+                let err = connect();
+                assert!(
+                    matches!(&err, Error::RootCertIo(_, e) if e.kind() == std::io::ErrorKind::PermissionDenied),
+                    "{err}"
+                );
+            }
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+
+        std::fs::write(&path, "not a cert").unwrap();
+        assert!(matches!(connect(), Error::RootCertEmpty));
+
+        std::fs::write(
+            &path,
+            "-----BEGIN CERTIFICATE-----\nbm90IGEgY2VydA==\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let err = connect();
+        assert!(matches!(err, Error::RootCertParse(_)), "{err}");
+        // The BoringSSL reason is kept as the source, for {:#} to print it.
+        assert_eq!(anyhow::Error::from(err).chain().count(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn root_cert_directory() {
+        let dir = std::env::temp_dir().join(format!("ztunnel-roots-{}", rand::random::<u64>()));
+        assert!(super::load_root_cert_dir(&dir).is_err());
+
+        std::fs::create_dir(&dir).unwrap();
+        assert!(super::load_root_cert_dir(&dir).is_err());
+
+        let (ca_cert, _) = super::generate_test_ca("other.local");
+        std::fs::write(dir.join("a.crt"), ca_cert.to_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("b.pem"), super::TEST_ROOT).unwrap();
+        std::fs::write(dir.join("garbage.pem"), "not a cert").unwrap();
+        std::fs::write(dir.join("ignored.txt"), super::TEST_ROOT).unwrap();
+        let roots = super::load_root_cert_dir(&dir).unwrap();
+        assert_eq!(roots.len(), 2);
+
+        assert!(grpc_connector(
+            "https://istiod:15012".to_string(),
+            RootCert::Directory(dir.clone()),
+            ConnectorConfig::default(),
+        )
+        .is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Performs a handshake between a client using `client` to connect to `dest`, and a server
+    // presenting `server`.
+    async fn handshake(
+        client: &super::Certs,
+        dest: &Identity,
+        server: &super::Certs,
+    ) -> Result<(), ()> {
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = server.acceptor().unwrap();
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let mut cfg = client.connector(dest).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let res = tokio_boring::connect(cfg, "", client_io).await;
+        let _ = server.await;
+        res.map(|_| ()).map_err(|_| ())
+    }
+
+    async fn connect(
+        acceptor: boring::ssl::SslAcceptor,
+        connector: boring::ssl::SslConnector,
+    ) -> Result<tokio_boring::SslStream<tokio::io::DuplexStream>, ()> {
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let mut cfg = connector.configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let res = tokio_boring::connect(cfg, "", client_io).await;
+        let server = server.await.unwrap();
+        match (res, server) {
+            (Ok(client), Ok(_)) => Ok(client),
+            _ => Err(()),
+        }
+    }
+
+    // Handshakes client and server, returning the classifications of the client and server
+    // failures.
+    async fn classify(
+        acceptor: boring::ssl::SslAcceptor,
+        connector: boring::ssl::SslConnector,
+    ) -> (
+        Option<super::HandshakeFailureClass>,
+        Option<super::HandshakeFailureClass>,
+    ) {
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let mut cfg = connector.configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let class = |e: &tokio_boring::HandshakeError<_>| super::HandshakeFailure::from(e).class;
+        let client = tokio_boring::connect(cfg, "", client_io).await;
+        let client = client.as_ref().err().map(class);
+        let server = server.await.unwrap();
+        (client, server.as_ref().err().map(class))
+    }
+
+    #[test]
+    fn error_codes() {
+        use boring::x509::X509VerifyResult;
+
+        use super::{ShedReason, TlsError};
+        use crate::identity;
+
+        let id = Identity::default();
+        let io = |kind| std::io::Error::new(kind, "test");
+        let status = |code| tonic::Status::new(code, "test");
+        let cases = [
+            (
+                TlsError::Verification(X509VerifyResult::APPLICATION_VERIFICATION),
+                "SAN_MISMATCH",
+                false,
+            ),
+            (
+                TlsError::CertificateLookup(crate::workload::NetworkAddress {
+                    network: String::new(),
+                    address: [127, 0, 0, 1].into(),
+                }),
+                "CERTIFICATE_LOOKUP",
+                false,
+            ),
+            (
+                TlsError::SigningError(identity::Error::SigningRequest(status(
+                    tonic::Code::Unavailable,
+                ))),
+                "SIGNING_REQUEST",
+                true,
+            ),
+            (
+                TlsError::SigningError(identity::Error::SigningRequest(status(
+                    tonic::Code::PermissionDenied,
+                ))),
+                "SIGNING_REQUEST",
+                false,
+            ),
+            (
+                TlsError::SigningError(identity::Error::InvalidCertificate(
+                    super::Error::KeyCertMismatch,
+                )),
+                "KEY_MISMATCH",
+                false,
+            ),
+            (
+                TlsError::SigningError(identity::Error::CertificateExpired(id.clone())),
+                "CERT_EXPIRED",
+                false,
+            ),
+            (
+                TlsError::SanError(id.clone(), vec![]),
+                "SAN_MISMATCH",
+                false,
+            ),
+            (
+                TlsError::SanTrustDomainError("td".to_string(), vec![]),
+                "SAN_MISMATCH",
+                false,
+            ),
+            (
+                TlsError::IpSanError([127, 0, 0, 1].into(), vec![]),
+                "SAN_MISMATCH",
+                false,
+            ),
+            (TlsError::TrustBundleError(vec![]), "UNKNOWN_CA", false),
+            (
+                TlsError::IdentityDenied(id.clone()),
+                "IDENTITY_DENIED",
+                false,
+            ),
+            (TlsError::ExDataError, "INTERNAL", false),
+            (TlsError::PeerCertError, "INTERNAL", false),
+            (
+                TlsError::SslError(super::Error::UntrustedChain(String::new())),
+                "UNKNOWN_CA",
+                false,
+            ),
+            (TlsError::NotTls, "NOT_TLS", false),
+            (TlsError::ClientClosed, "CLIENT_CLOSED", false),
+            (TlsError::Shed(ShedReason::MaxStreams), "LOAD_SHED", true),
+            (
+                TlsError::Io(io(std::io::ErrorKind::TimedOut)),
+                "HANDSHAKE_TIMEOUT",
+                true,
+            ),
+            (
+                TlsError::Io(io(std::io::ErrorKind::ConnectionReset)),
+                "IO",
+                true,
+            ),
+        ];
+        for (err, code, retryable) in cases {
+            assert_eq!(err.code(), code, "{err}");
+            assert_eq!(err.is_retryable(), retryable, "{err}");
+        }
+
+        // Codes are used as metric labels.
+        use super::HandshakeFailureClass::*;
+        for class in [
+            NotTls,
+            UnknownCa,
+            Expired,
+            SanMismatch,
+            ProtocolVersion,
+            Other,
+        ] {
+            let code = class.code();
+            assert!(code.chars().all(|c| c.is_ascii_uppercase() || c == '_'));
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_failure_classification() {
+        use boring::ssl::SslVersion;
+        use tokio::io::AsyncWriteExt;
+
+        use super::HandshakeFailureClass::*;
+
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+
+        // Plaintext HTTP sent to a TLS port.
+        let (mut client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = certs.acceptor().unwrap();
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        client_io
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let err = server.await.unwrap().err().unwrap();
+        assert_eq!(super::HandshakeFailure::from(&err).class, NotTls);
+
+        // A server certificate from another root.
+        let (other_ca, other_key) = super::generate_test_ca("other");
+        let other = super::generate_test_certs_with_ca(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+            &other_ca,
+            &other_key,
+        );
+        let (client, _) = classify(other.acceptor().unwrap(), certs.connector(&id).unwrap()).await;
+        assert_eq!(client, Some(UnknownCa));
+
+        // An expired server certificate.
+        let now = std::time::SystemTime::now();
+        let expired = super::generate_test_certs_at(
+            &id.clone().into(),
+            now - Duration::from_secs(200),
+            now - Duration::from_secs(100),
+            None,
+            None,
+        );
+        let (client, _) =
+            classify(expired.acceptor().unwrap(), certs.connector(&id).unwrap()).await;
+        assert_eq!(client, Some(Expired));
+
+        // A server certificate for another identity.
+        let other_id = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "other".to_string(),
+            service_account: "other".to_string(),
+        };
+        let (client, _) = classify(
+            certs.acceptor().unwrap(),
+            certs.connector(&other_id).unwrap(),
+        )
+        .await;
+        assert_eq!(client, Some(SanMismatch));
+
+        // A TLS 1.2 client connecting to a TLS 1.3 server.
+        let tls12 = certs
+            .builder()
+            .min_version(SslVersion::TLS1_2)
+            .max_version(SslVersion::TLS1_2)
+            .build_connector(&id)
+            .unwrap();
+        let (client, server) = classify(certs.acceptor().unwrap(), tls12).await;
+        assert_eq!(client, Some(ProtocolVersion));
+        assert_eq!(server, Some(ProtocolVersion));
+
+        // The client going away mid-handshake.
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = certs.acceptor().unwrap();
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        drop(client_io);
+        let err = server.await.unwrap().err().unwrap();
+        assert_eq!(super::HandshakeFailure::from(&err).class, Other);
+    }
+
+    #[tokio::test]
+    async fn sniff_plaintext() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use super::{BoringTlsAcceptor, ControlPlaneCertProvider, MaybeTls, TlsError};
+
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let acceptor = BoringTlsAcceptor {
+            acceptor: ControlPlaneCertProvider(certs.clone()),
+            metrics: None,
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+        for permissive in [false, true] {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(REQUEST).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            match acceptor.accept_maybe_tls(conn, permissive).await {
+                Ok(MaybeTls::Plain(mut conn)) => {
+                    assert!(permissive);
+                    // Sniffing does not consume the request.
+                    let mut buf = vec![0; REQUEST.len()];
+                    conn.read_exact(&mut buf).await.unwrap();
+                    assert_eq!(buf, REQUEST);
+                }
+                Err(TlsError::NotTls) => assert!(!permissive),
+                _ => panic!("plaintext was not detected"),
+            }
+        }
+
+        let client = tokio::spawn(async move {
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut cfg = certs.connector(&id).unwrap().configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            tokio_boring::connect(cfg, "", tcp).await.map(|_| ())
+        });
+        let (conn, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            acceptor.accept_maybe_tls(conn, true).await,
+            Ok(MaybeTls::Tls(_))
+        ));
+        assert!(client.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn drain_acceptor() {
+        use tokio::net::TcpStream;
+
+        use super::{BoringTlsAcceptor, ControlPlaneCertProvider, MaybeTls, TlsError};
+
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let acceptor = BoringTlsAcceptor {
+            acceptor: ControlPlaneCertProvider(certs.clone()),
+            metrics: None,
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = |conn| {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move { acceptor.accept_maybe_tls(conn, false).await })
+        };
+
+        // One client about to handshake, and one never starting its handshake.
+        let in_flight_tcp = TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        let in_flight = accept(conn);
+        let _stalled_tcp = TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        let stalled = accept(conn);
+        tokio::task::yield_now().await;
+
+        acceptor.drain(Duration::from_secs(1));
+        assert!(acceptor.drain.is_draining());
+
+        // Connections arriving after the drain started are refused right away.
+        let _late_tcp = TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            acceptor.accept_maybe_tls(conn, false).await,
+            Err(TlsError::Draining)
+        ));
+
+        // The handshake in flight completes during the grace period.
+        let mut cfg = certs.connector(&id).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        assert!(tokio_boring::connect(cfg, "", in_flight_tcp).await.is_ok());
+        assert!(matches!(in_flight.await.unwrap(), Ok(MaybeTls::Tls(_))));
+
+        // The stalled one is given up on once the grace period is over.
+        assert!(matches!(stalled.await.unwrap(), Err(TlsError::Draining)));
+    }
+
+    #[tokio::test]
+    async fn handshake_spans() {
+        use std::collections::HashMap;
+        use std::fmt;
+        use std::sync::{Arc, Mutex};
+
+        use tracing::{info_span, Instrument};
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+        use tracing_subscriber::registry::LookupSpan;
+
+        use super::{BoringTlsAcceptor, ControlPlaneCertProvider};
+
+        #[derive(Debug, Default)]
+        struct RecordedSpan {
+            parent: Option<String>,
+            fields: HashMap<String, String>,
+        }
+
+        struct Fields<'a>(&'a mut HashMap<String, String>);
+
+        impl tracing::field::Visit for Fields<'_> {
+            fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                self.0.insert(field.name().to_string(), value.to_string());
+            }
+
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+                self.0
+                    .insert(field.name().to_string(), format!("{value:?}"));
+            }
+        }
+
+        // Records the parent and fields of spans, by name.
+        #[derive(Clone, Default)]
+        struct SpanRecorder(Arc<Mutex<HashMap<String, RecordedSpan>>>);
+
+        impl<S> tracing_subscriber::Layer<S> for SpanRecorder
+        where
+            S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+        {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                id: &tracing::span::Id,
+                ctx: Context<'_, S>,
+            ) {
+                let parent = ctx
+                    .span(id)
+                    .and_then(|span| span.parent())
+                    .map(|parent| parent.name().to_string());
+                let mut span = RecordedSpan {
+                    parent,
+                    ..Default::default()
+                };
+                attrs.record(&mut Fields(&mut span.fields));
+                let name = attrs.metadata().name().to_string();
+                self.0.lock().unwrap().insert(name, span);
+            }
+
+            fn on_record(
+                &self,
+                id: &tracing::span::Id,
+                values: &tracing::span::Record<'_>,
+                ctx: Context<'_, S>,
+            ) {
+                let name = ctx.span(id).unwrap().name().to_string();
+                if let Some(span) = self.0.lock().unwrap().get_mut(&name) {
+                    values.record(&mut Fields(&mut span.fields));
+                }
+            }
+        }
+
+        let recorder = SpanRecorder::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(recorder.clone()));
+
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let acceptor = BoringTlsAcceptor {
+            acceptor: ControlPlaneCertProvider(certs.clone()),
+            metrics: None,
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        let server = tokio::spawn(
+            async move { acceptor.accept_maybe_tls(conn, false).await.map(|_| ()) }
+                .instrument(info_span!("inbound")),
+        );
+        let mut cfg = certs.connector(&id).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let client = crate::tls::connect(cfg, tcp, &(&id).into(), &Default::default(), None)
+            .instrument(info_span!("outbound"))
+            .await;
+        assert!(client.is_ok());
+        assert!(server.await.unwrap().is_ok());
+
+        let spans = recorder.0.lock().unwrap();
+        let accept = &spans["tls_accept"];
+        assert_eq!(accept.parent.as_deref(), Some("inbound"));
+        assert!(accept.fields["peer"].contains("127.0.0.1"));
+        assert_eq!(accept.fields["version"], "TLSv1.3");
+        assert!(!accept.fields["cipher"].is_empty());
+        assert_eq!(spans["fetch_cert"].parent.as_deref(), Some("tls_accept"));
+        let connect = &spans["tls_connect"];
+        assert_eq!(connect.parent.as_deref(), Some("outbound"));
+        assert_eq!(connect.fields["peer"], format!("Some({addr})"));
+        assert_eq!(connect.fields["identity"], id.to_string());
+        assert_eq!(connect.fields["version"], "TLSv1.3");
+        assert_eq!(connect.fields["cipher"], accept.fields["cipher"]);
+    }
+
+    #[tokio::test]
+    async fn ip_san_peers() {
+        use std::net::IpAddr;
+
+        use super::{ExpectedPeer, TestSan};
+
+        let id = Identity::default();
+        let v4: IpAddr = "10.0.0.5".parse().unwrap();
+        let v6: IpAddr = "::1".parse().unwrap();
+        let server = generate_test_certs(
+            &TestIdentity::Sans(vec![TestSan::Ip(v4), TestSan::Ip(v6)]),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let client =
+            generate_test_certs(&id.into(), Duration::from_secs(0), Duration::from_secs(100));
+        for (expected, ok) in [
+            ("10.0.0.5", true),
+            ("10.0.0.6", false),
+            ("0:0:0:0:0:0:0:1", true),
+            ("::2", false),
+            // IPv4-mapped addresses, as seen on dual-stack sockets, match the IPv4 SAN.
+            ("::ffff:10.0.0.5", true),
+            ("::ffff:10.0.0.6", false),
+        ] {
+            let ip: IpAddr = expected.parse().unwrap();
+            let res = connect(
+                server.acceptor().unwrap(),
+                client.connector(ExpectedPeer::IpSan(ip)).unwrap(),
+            )
+            .await;
+            assert_eq!(res.is_ok(), ok, "{expected}");
+        }
+        // An identity is not matched against IP SANs.
+        assert!(connect(
+            server.acceptor().unwrap(),
+            client.connector(&Identity::default()).unwrap()
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn double_hbone_handshake() {
+        use super::{accept_inner, peer_identities, HandshakeFailureClass, TlsError};
+
+        let gateway = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "istio-system".to_string(),
+            service_account: "gateway".to_string(),
+        };
+        let client_id = Identity::default();
+        let server_id = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "default".to_string(),
+            service_account: "server".to_string(),
+        };
+        let certs = |id: &Identity| {
+            generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            )
+        };
+        let (gateway_certs, client_certs, server_certs) =
+            (certs(&gateway), certs(&client_id), certs(&server_id));
+        let connect_config = |dest: &Identity| {
+            let mut cfg = client_certs.connector(dest).unwrap().configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            cfg
+        };
+
+        // The outer connection goes to the gateway, the inner one to `inner_dest` through it.
+        let chain = |inner_dest: &Identity| {
+            let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+            let outer = gateway_certs.acceptor().unwrap();
+            let inner = server_certs.mtls_acceptor(None).unwrap();
+            let server = tokio::spawn(async move {
+                let stream = accept_inner(&outer, server_io).await?;
+                accept_inner(&inner, stream).await
+            });
+            let (outer_cfg, inner_cfg) = (connect_config(&gateway), connect_config(inner_dest));
+            async move {
+                let outer = tokio_boring::connect(outer_cfg, "", client_io)
+                    .await
+                    .unwrap();
+                let client = tokio_boring::connect(inner_cfg, "", outer).await;
+                (client.is_ok(), server.await.unwrap())
+            }
+        };
+
+        let (client_ok, server) = chain(&server_id).await;
+        assert!(client_ok);
+        let server = server.unwrap();
+        assert_eq!(peer_identities(server.ssl()), vec![client_id.clone()]);
+        assert_eq!(
+            peer_identities(server.get_ref().ssl()),
+            Vec::<Identity>::new()
+        );
+
+        // The client rejects the inner server, so the inner handshake fails.
+        let (client_ok, server) = chain(&gateway).await;
+        assert!(!client_ok);
+        let err = server.err().unwrap();
+        assert!(matches!(err, TlsError::StreamHandshake { .. }), "{err}");
+        assert_ne!(err.classification(), HandshakeFailureClass::NotTls);
+    }
+
+    #[tokio::test]
+    async fn connect_over_hbone() {
+        use std::sync::{Arc, Mutex};
+
+        use http_body_util::{Empty, Full};
+        use hyper::service::service_fn;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use super::{
+            accept_inner, connect_over, identity_server_name, peer_identities, InnerServerName,
+            TlsError,
+        };
+        use crate::hyper_util::{http2_client, http2_server};
+
+        let client_id = Identity::default();
+        let server_id = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "default".to_string(),
+            service_account: "remote".to_string(),
+        };
+        let certs = |id: &Identity| {
+            generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            )
+        };
+        let (client_certs, server_certs) = (certs(&client_id), certs(&server_id));
+
+        // A CONNECT is tunneled over an in-memory h2 connection, and the inner handshake runs
+        // over the upgraded stream. The server reports the SNI and peer of the inner handshake.
+        let run = |expected: Identity, server_name: InnerServerName| {
+            let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+            let acceptor = server_certs.mtls_acceptor(None).unwrap();
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            let tx = Arc::new(Mutex::new(Some(tx)));
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                    let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                    tokio::spawn(async move {
+                        let upgraded = hyper::upgrade::on(req).await.unwrap();
+                        let res = match accept_inner(&acceptor, upgraded).await {
+                            Ok(mut stream) => {
+                                stream.write_all(b"hello").await.unwrap();
+                                let sni = crate::tls::sni::sni(stream.ssl()).map(str::to_string);
+                                Ok((sni, peer_identities(stream.ssl())))
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let _ = tx.lock().unwrap().take().unwrap().send(res);
+                    });
+                    async {
+                        Ok::<_, std::convert::Infallible>(hyper::Response::new(
+                            Full::<bytes::Bytes>::default(),
+                        ))
+                    }
+                });
+                let _ = http2_server().serve_connection(server_io, service).await;
+            });
+            let cfg = client_certs
+                .connector(&expected)
+                .unwrap()
+                .configure()
+                .unwrap();
+            async move {
+                let (mut sender, conn) = http2_client().handshake(client_io).await.unwrap();
+                tokio::spawn(conn);
+                let req = Request::builder()
+                    .uri("10.0.0.1:8080")
+                    .method(hyper::Method::CONNECT)
+                    .version(hyper::Version::HTTP_2)
+                    .body(Empty::<bytes::Bytes>::new())
+                    .unwrap();
+                let resp = sender.send_request(req).await.unwrap();
+                assert_eq!(resp.status(), 200);
+                let upgraded = hyper::upgrade::on(resp).await.unwrap();
+                let client = match connect_over(cfg, &expected, server_name, upgraded).await {
+                    Ok(mut stream) => {
+                        let mut buf = [0; 5];
+                        stream.read_exact(&mut buf).await.unwrap();
+                        assert_eq!(&buf, b"hello");
+                        Ok(peer_identities(stream.ssl()))
+                    }
+                    Err(e) => Err(e),
+                };
+                (client, rx.await.unwrap())
+            }
+        };
+
+        let (client, server) = run(server_id.clone(), InnerServerName::Identity).await;
+        assert_eq!(client.unwrap(), vec![server_id.clone()]);
+        let (sni, peers) = server.unwrap();
+        assert_eq!(sni.as_deref(), Some("remote.default.sa.cluster.local"));
+        assert_eq!(sni.unwrap(), identity_server_name(&server_id));
+        assert_eq!(peers, vec![client_id.clone()]);
+
+        let (client, server) = run(server_id.clone(), InnerServerName::None).await;
+        assert_eq!(client.unwrap(), vec![server_id.clone()]);
+        assert_eq!(server.unwrap(), (None, vec![client_id.clone()]));
+
+        // The SANs are still verified without a hostname, so another peer is rejected.
+        let (client, server) = run(client_id.clone(), InnerServerName::Identity).await;
+        let err = client.unwrap_err();
+        assert!(matches!(err, TlsError::StreamHandshake { .. }), "{err}");
+        assert!(server.is_err());
+    }
+
+    #[tokio::test]
+    async fn san_enforcement() {
+        use super::{
+            generate_test_ca, generate_test_certs_with_key_type, monitored_san_mismatches, KeyType,
+            SanEnforcement,
+        };
+
+        let expected = Identity::default();
+        let other = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "default".to_string(),
+            service_account: "other".to_string(),
+        };
+        let certs = |id: &Identity| {
+            generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            )
+        };
+        let (client, server) = (certs(&expected), certs(&other));
+        let connector = |mode| {
+            client
+                .builder()
+                .san_enforcement(mode)
+                .build_connector(&expected)
+                .unwrap()
+        };
+
+        // The peer does not have the expected SAN.
+        assert!(connect(
+            server.acceptor().unwrap(),
+            connector(SanEnforcement::Enforce)
+        )
+        .await
+        .is_err());
+        let before = monitored_san_mismatches();
+        assert!(connect(
+            server.acceptor().unwrap(),
+            connector(SanEnforcement::Monitor)
+        )
+        .await
+        .is_ok());
+        assert!(monitored_san_mismatches() > before);
+
+        // Monitoring does not let through peers whose chain does not verify.
+        let (ca_cert, ca_key) = generate_test_ca("untrusted");
+        let untrusted = generate_test_certs_with_key_type(
+            &expected.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+            KeyType::default(),
+            &ca_cert,
+            &ca_key,
+        );
+        assert!(connect(
+            untrusted.acceptor().unwrap(),
+            connector(SanEnforcement::Monitor)
+        )
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn dns_name_peers() {
+        use super::{dns_name_matches, verify_dns_san, TestSan};
+
+        for (san, name, ok) in [
+            ("waypoint.ns.svc", "waypoint.ns.svc", true),
+            ("waypoint.ns.svc", "WAYPOINT.ns.svc.", true),
+            ("*.ns.svc", "waypoint.ns.svc", true),
+            ("*.ns.svc", "ns.svc", false),
+            ("*.ns.svc", "a.waypoint.ns.svc", false),
+            ("*.ns.svc", ".ns.svc", false),
+            ("*.svc", "ns.svc", false),
+            ("way*.ns.svc", "waypoint.ns.svc", false),
+            ("waypoint.*.svc", "waypoint.ns.svc", false),
+        ] {
+            assert_eq!(dns_name_matches(san, name), ok, "{san} {name}");
+        }
+
+        let cert = generate_test_certs(
+            &TestIdentity::Sans(vec![TestSan::Dns("gateway.ns.svc".to_string())]),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let err = verify_dns_san(cert.x509(), "waypoint.ns.svc").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "san verification error: remote did not present a DNS SAN matching waypoint.ns.svc, \
+             got [\"gateway.ns.svc\"]"
+        );
+        assert_eq!(err.code(), "SAN_MISMATCH");
+    }
+
+    #[test]
+    fn csr_ip_sans() {
+        use std::net::IpAddr;
+
+        let v4: IpAddr = "10.0.0.5".parse().unwrap();
+        let v6: IpAddr = "::1".parse().unwrap();
+        let cs = super::CsrOptions {
+            san: Identity::default().to_string(),
+            ip_sans: vec![v4, v6],
+            ..Default::default()
+        }
+        .generate()
+        .unwrap();
+        let der = boring::x509::X509Req::from_pem(&cs.csr)
+            .unwrap()
+            .to_der()
+            .unwrap();
+        // An IP SAN is encoded as an implicit [7] octet string of the address bytes.
+        let contains = |ip: IpAddr| {
+            let mut want = vec![0x87];
+            match ip {
+                IpAddr::V4(ip) => want.extend([4].iter().chain(ip.octets().iter())),
+                IpAddr::V6(ip) => want.extend([16].iter().chain(ip.octets().iter())),
+            }
+            der.windows(want.len()).any(|w| w == want.as_slice())
+        };
+        assert!(contains(v4));
+        assert!(contains(v6));
+        assert!(!contains("10.0.0.6".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn connector_cache() {
+        use super::ConnectorCache;
+
+        let id = Identity::default();
+        let other_id = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "other".to_string(),
+            service_account: "other".to_string(),
+        };
+        let issue = || {
+            generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            )
+        };
+        let certs = issue();
+        let cache = ConnectorCache::new(2);
+
+        // The second connection to a destination reuses the connector.
+        for _ in 0..2 {
+            let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+            let acceptor = certs.acceptor().unwrap();
+            let server =
+                tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+            let mut cfg = cache.connect_config(&certs, &id).unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            tokio_boring::connect(cfg, "", client_io).await.unwrap();
+            server.await.unwrap().unwrap();
+        }
+        assert_eq!(cache.builds(), 1);
 
-    let mut cert = ZtunnelCert::new(builder.build());
-    // For sub-second granularity
-    cert.not_before = not_before;
-    cert.not_after = not_after;
-    Certs {
-        cert,
-        key,
-        chain: vec![ZtunnelCert::new(ca_cert)],
+        // Connectors are not shared across destinations: the cached connector for id must not
+        // accept a peer expecting other_id.
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = certs.acceptor().unwrap();
+        tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let mut cfg = cache.connect_config(&certs, &other_id).unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        assert!(tokio_boring::connect(cfg, "", client_io).await.is_err());
+        assert_eq!(cache.builds(), 2);
+        assert_eq!(cache.len(), 2);
+
+        // A rotated certificate builds new connectors and drops the stale ones.
+        let rotated = issue();
+        cache.connect_config(&rotated, &id).unwrap();
+        assert_eq!(cache.builds(), 3);
+        assert_eq!(cache.len(), 2);
+        cache.connect_config(&rotated, &id).unwrap();
+        assert_eq!(cache.builds(), 3);
+        // The stale connectors are the oldest, so they are evicted before the current ones.
+        cache.connect_config(&rotated, &other_id).unwrap();
+        cache.connect_config(&rotated, &id).unwrap();
+        assert_eq!(cache.builds(), 4);
+
+        // Root updates build new connectors too.
+        let store = super::RootCertStore::new(rotated.iter_chain().cloned().collect());
+        let rotated = rotated.with_root_store(&store);
+        cache.connect_config(&rotated, &id).unwrap();
+        assert_eq!(cache.builds(), 5);
+        let (other_root, _) = super::generate_test_ca("other");
+        store.add(other_root);
+        cache.connect_config(&rotated, &id).unwrap();
+        assert_eq!(cache.builds(), 6);
+
+        // The cache is bounded.
+        for i in 0..4 {
+            let dest = Identity::Spiffe {
+                trust_domain: "cluster.local".to_string(),
+                namespace: "ns".to_string(),
+                service_account: format!("sa-{i}"),
+            };
+            cache.connect_config(&rotated, &dest).unwrap();
+        }
+        assert_eq!(cache.len(), 2);
     }
-}
 
-pub fn generate_test_certs(
-    id: &TestIdentity,
-    duration_until_valid: Duration,
-    duration_until_expiry: Duration,
-) -> Certs {
-    let not_before = SystemTime::now() + duration_until_valid;
-    generate_test_certs_at(id, not_before, not_before + duration_until_expiry, None)
-}
+    #[tokio::test]
+    async fn context_builder() {
+        use boring::ssl::SslVersion;
 
-fn test_ca() -> Result<(x509::X509, PKey<Private>), Error> {
-    let cert = x509::X509::from_pem(TEST_ROOT)?;
-    let key = pkey::PKey::private_key_from_pem(TEST_ROOT_KEY)?;
-    Ok((cert, key))
-}
+        use super::Alpn;
 
-pub fn test_certs() -> Certs {
-    let cert = ZtunnelCert::new(x509::X509::from_pem(TEST_CERT).unwrap());
-    let key = pkey::PKey::private_key_from_pem(TEST_PKEY).unwrap();
-    let chain = vec![cert.clone()];
-    Certs { cert, key, chain }
-}
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
 
-pub mod mock {
-    use rand::{rngs::SmallRng, SeedableRng};
-    use std::time::SystemTime;
+        // By default, no protocol is selected by the server.
+        let client = connect(certs.acceptor().unwrap(), certs.connector(&id).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(client.ssl().selected_alpn_protocol(), None);
+        let client = connect(
+            certs
+                .builder()
+                .alpn(Alpn::H2AndHttp11)
+                .build_acceptor()
+                .unwrap(),
+            certs
+                .builder()
+                .alpn(Alpn::Http11)
+                .build_connector(&id)
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            client.ssl().selected_alpn_protocol(),
+            Some(&b"http/1.1"[..])
+        );
 
-    use super::{generate_test_certs_at, Certs, TestIdentity};
+        // Versions
+        let tls12 = || {
+            certs
+                .builder()
+                .min_version(SslVersion::TLS1_2)
+                .max_version(SslVersion::TLS1_2)
+        };
+        assert!(connect(
+            tls12().build_acceptor().unwrap(),
+            certs.connector(&id).unwrap()
+        )
+        .await
+        .is_err());
+        let client = connect(
+            tls12().build_acceptor().unwrap(),
+            tls12().build_connector(&id).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(client.ssl().version_str(), "TLSv1.2");
 
-    /// Allows generating test certificates in a deterministic manner.
-    pub struct CertGenerator {
-        rng: SmallRng,
+        // Client certificates
+        let anonymous = || {
+            let mut conn =
+                boring::ssl::SslConnector::builder(boring::ssl::SslMethod::tls_client()).unwrap();
+            conn.set_verify(boring::ssl::SslVerifyMode::NONE);
+            conn.build()
+        };
+        assert!(
+            connect(certs.builder().build_acceptor().unwrap(), anonymous())
+                .await
+                .is_err()
+        );
+        assert!(connect(
+            certs
+                .builder()
+                .require_client_cert(false)
+                .build_acceptor()
+                .unwrap(),
+            anonymous()
+        )
+        .await
+        .is_ok());
+
+        // Key log
+        let lines = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let keylog = {
+            let lines = lines.clone();
+            move |line: &str| lines.lock().unwrap().push(line.to_string())
+        };
+        connect(
+            certs.acceptor().unwrap(),
+            certs
+                .builder()
+                .keylog(keylog)
+                .session_cache(false)
+                .build_connector(&id)
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(lines
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|l| l.starts_with("CLIENT_HANDSHAKE_TRAFFIC_SECRET")));
     }
 
-    impl CertGenerator {
-        /// Returns a new test certificate generator. The seed parameter sets the seed for any
-        /// randomized operations. Multiple CertGenerator instances created with the same seed will
-        /// return the same successive certificates, if same arguments to new_certs are given.
-        pub fn new(seed: u64) -> Self {
-            Self {
-                rng: SmallRng::seed_from_u64(seed),
+    #[test]
+    fn release_buffers() {
+        use boring::ssl::{SslContext, SslMethod, SslMode};
+
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        // Setting no mode returns the current mode flags of the context.
+        let mode = |builder: TlsContextBuilder| {
+            let mut ctx = SslContext::builder(SslMethod::tls()).unwrap();
+            certs.setup_ctx(&mut ctx, &builder).unwrap();
+            ctx.set_mode(SslMode::empty())
+        };
+        assert!(mode(certs.builder()).contains(SslMode::RELEASE_BUFFERS));
+        assert!(!mode(certs.builder().release_buffers(false)).contains(SslMode::RELEASE_BUFFERS));
+    }
+
+    #[tokio::test]
+    async fn max_cert_list() {
+        use super::{generate_test_ca_signed_by, HandshakeFailureClass, KeyType, ZtunnelCert};
+
+        let id = Identity::default();
+        let now = std::time::SystemTime::now();
+        let (root, root_key) = super::generate_test_ca("root");
+        let (issuer, issuer_key) = generate_test_ca_signed_by(
+            "intermediate",
+            Some((&root, &root_key)),
+            KeyType::default(),
+        );
+        let mut certs = super::generate_test_certs_signed_by(
+            &id.clone().into(),
+            now,
+            now + Duration::from_secs(100),
+            None,
+            None,
+            &issuer,
+            &issuer_key,
+        );
+        // Pad the chain with intermediates the peer does not need, until it is over 64 KiB but
+        // still under the BoringSSL default of 100 KiB.
+        let mut chain = vec![issuer];
+        let mut size = 0;
+        while size < 80 * 1024 {
+            let (padding, _) = generate_test_ca_signed_by(
+                &format!("padding-{}", chain.len()),
+                Some((&root, &root_key)),
+                KeyType::default(),
+            );
+            size += padding.to_der().unwrap().len();
+            chain.push(padding);
+        }
+        chain.push(root.clone());
+        certs.chain = chain.into_iter().map(ZtunnelCert::new).collect();
+        let server = certs
+            .clone()
+            .with_root_store(&super::RootCertStore::new(vec![root]));
+
+        let limited = server
+            .builder()
+            .max_cert_list(64 * 1024)
+            .build_acceptor()
+            .unwrap();
+        let (_, class) = classify(limited, certs.connector(&id).unwrap()).await;
+        assert_eq!(class, Some(HandshakeFailureClass::MessageTooLarge));
+
+        let unlimited = server.builder().build_acceptor().unwrap();
+        assert_eq!(
+            classify(unlimited, certs.connector(&id).unwrap()).await,
+            (None, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn verified_peer_chain() {
+        use super::{peer_identities, verified_peer_chain};
+
+        let id = Identity::default();
+        let now = std::time::SystemTime::now();
+        let mut client = super::generate_test_certs_with_chain(
+            &id.clone().into(),
+            2,
+            now,
+            now + Duration::from_secs(100),
+        );
+        // From the leaf up to the root.
+        let expected: Vec<_> = std::iter::once(client.x509())
+            .chain(client.iter_chain())
+            .map(|cert| cert.to_der().unwrap())
+            .collect();
+        let root = client.iter_chain().last().unwrap().clone();
+        let server = client
+            .clone()
+            .with_root_store(&super::RootCertStore::new(vec![root]));
+        // Present the intermediates in the wrong order.
+        client.chain.swap(0, 1);
+
+        let acceptor = server.mtls_acceptor(Some(&id)).unwrap();
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let accepted =
+            tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let mut cfg = client.connector(&id).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let _client = tokio_boring::connect(cfg, "", client_io).await.unwrap();
+        let accepted = accepted.await.unwrap().unwrap();
+
+        assert_eq!(peer_identities(accepted.ssl()), vec![id]);
+        let chain: Vec<_> = verified_peer_chain(accepted.ssl())
+            .unwrap()
+            .iter()
+            .map(|cert| cert.to_der().unwrap())
+            .collect();
+        assert_eq!(chain, expected);
+    }
+
+    #[tokio::test]
+    async fn intermediate_chain() {
+        let id = Identity::default();
+        let now = std::time::SystemTime::now();
+        let chained = super::generate_test_certs_with_chain(
+            &id.clone().into(),
+            2,
+            now,
+            now + Duration::from_secs(100),
+        );
+        let chain: Vec<_> = chained.iter_chain().collect();
+        assert_eq!(chain.len(), 3);
+        for (child, issuer) in std::iter::once(chained.x509())
+            .chain(chain.clone())
+            .zip(&chain)
+        {
+            assert_eq!(
+                child.issuer_name().to_der().unwrap(),
+                issuer.subject_name().to_der().unwrap()
+            );
+            assert!(child.verify(&issuer.public_key().unwrap()).unwrap());
+        }
+        // chain() serializes the issuer of the leaf.
+        assert_eq!(
+            chained.chain().unwrap(),
+            chain[0].to_pem().unwrap().as_slice()
+        );
+
+        // The peer only knows the root; the intermediates are sent during the handshake.
+        let root = (*chain.last().unwrap()).clone();
+        let peer = chained
+            .clone()
+            .with_root_store(&super::RootCertStore::new(vec![root]));
+        assert!(handshake(&peer, &id, &chained).await.is_ok());
+        assert!(handshake(&chained, &id, &peer).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn chain_normalization() {
+        use super::{certs_from_pem, CertPolicy, Error};
+
+        let id = Identity::default();
+        let now = std::time::SystemTime::now();
+        let chained = super::generate_test_certs_with_chain(
+            &id.clone().into(),
+            2,
+            now,
+            now + Duration::from_secs(100),
+        );
+        // Ordered from the issuer of the leaf up to the root.
+        let chain: Vec<_> = chained.iter_chain().cloned().collect();
+        let der = |certs: &super::Certs| -> Vec<_> {
+            certs.iter_chain().map(|c| c.to_der().unwrap()).collect()
+        };
+        let leaf = chained.x509().to_pem().unwrap();
+        let load = |order: &[usize], policy: &CertPolicy| {
+            let pems: Vec<_> = order.iter().map(|i| chain[*i].to_pem().unwrap()).collect();
+            let key = chained.private_key().in_memory().unwrap().clone();
+            certs_from_pem(key, &leaf, pems.iter().map(Vec::as_slice).collect(), policy)
+        };
+        let policy = CertPolicy::default();
+
+        // The peer only knows the root, so the intermediates must be sent in order.
+        let root = chain.last().unwrap().clone();
+        let peer = chained
+            .clone()
+            .with_root_store(&super::RootCertStore::new(vec![root]));
+        for order in [[0, 1, 2], [2, 1, 0], [1, 2, 0]] {
+            let loaded = load(&order, &policy).unwrap();
+            assert_eq!(der(&loaded), der(&chained), "{order:?}");
+            assert!(loaded.has_root());
+            assert!(handshake(&peer, &id, &loaded).await.is_ok(), "{order:?}");
+        }
+
+        // Without the root, every intermediate is still sent.
+        let loaded = load(&[1, 0], &policy).unwrap();
+        assert_eq!(der(&loaded), der(&chained)[..2]);
+        assert!(!loaded.has_root());
+        assert!(handshake(&peer, &id, &loaded).await.is_ok());
+
+        // A missing intermediate cannot be repaired.
+        let res = load(&[2, 0], &policy);
+        assert!(
+            matches!(&res, Err(Error::BrokenChain { missing_issuer }) if missing_issuer == "O=intermediate-0"),
+            "{res:?}"
+        );
+
+        // Without normalization, the chain is used as given.
+        let skip = CertPolicy {
+            normalize_chain: false,
+            ..Default::default()
+        };
+        let loaded = load(&[2, 1, 0], &skip).unwrap();
+        let reversed: Vec<_> = der(&chained).into_iter().rev().collect();
+        assert_eq!(der(&loaded), reversed);
+        assert!(load(&[2, 0], &skip).is_ok());
+    }
+
+    #[tokio::test]
+    async fn root_rotation() {
+        let id = Identity::default();
+        let (root_a, key_a) = super::generate_test_ca("root-a");
+        let (root_b, key_b) = super::generate_test_ca("root-b");
+        let issue = |root, key| {
+            super::generate_test_certs_with_ca(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+                root,
+                key,
+            )
+        };
+        let (peer_a, peer_b) = (issue(&root_a, &key_a), issue(&root_b, &key_b));
+
+        let store = super::RootCertStore::new(vec![root_a.clone()]);
+        let local = issue(&root_a, &key_a).with_root_store(&store);
+        assert!(handshake(&local, &id, &peer_a).await.is_ok());
+        assert!(handshake(&local, &id, &peer_b).await.is_err());
+
+        // During the rotation, peers of either root are trusted.
+        assert!(store.add(root_b.clone()));
+        assert!(handshake(&local, &id, &peer_a).await.is_ok());
+        assert!(handshake(&local, &id, &peer_b).await.is_ok());
+
+        // Once the old root is removed, its peers are rejected, even though our own certificate
+        // still chains to it.
+        assert!(store.remove(&root_a));
+        assert!(handshake(&local, &id, &peer_a).await.is_err());
+        assert!(handshake(&local, &id, &peer_b).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn trust_bundle_per_trust_domain() {
+        let local_id = Identity::Spiffe {
+            trust_domain: "td-a".to_string(),
+            namespace: "ns".to_string(),
+            service_account: "sa".to_string(),
+        };
+        let foreign_id = Identity::Spiffe {
+            trust_domain: "td-b".to_string(),
+            namespace: "ns".to_string(),
+            service_account: "sa".to_string(),
+        };
+        let (foreign_ca, foreign_key) = super::generate_test_ca("td-b");
+        let mut bundle = super::TrustBundle::new();
+        bundle.add("td-b", vec![foreign_ca.clone()]);
+        let local = generate_test_certs(
+            &local_id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        )
+        .with_trust_bundle(&bundle);
+
+        // Foreign identity signed by the foreign root is accepted.
+        let foreign = super::generate_test_certs_with_ca(
+            &foreign_id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+            &foreign_ca,
+            &foreign_key,
+        );
+        assert!(handshake(&local, &foreign_id, &foreign).await.is_ok());
+
+        // Foreign identity signed by our own root is rejected.
+        let forged = generate_test_certs(
+            &foreign_id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        assert!(handshake(&local, &foreign_id, &forged).await.is_err());
+
+        // A foreign root cannot vouch for identities of another trust domain, even alongside one
+        // of its own.
+        let smuggled = super::generate_test_certs_with_ca(
+            &TestIdentity::Sans(vec![
+                super::TestSan::Uri(foreign_id.clone()),
+                super::TestSan::Uri(local_id.clone()),
+            ]),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+            &foreign_ca,
+            &foreign_key,
+        );
+        assert!(handshake(&local, &local_id, &smuggled).await.is_err());
+        assert!(handshake(&local, &foreign_id, &smuggled).await.is_err());
+
+        // Local identities still chain to our own root.
+        let peer = generate_test_certs(
+            &local_id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        assert!(handshake(&local, &local_id, &peer).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn deny_list() {
+        let denied_id = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "ns".to_string(),
+            service_account: "denied".to_string(),
+        };
+        let other_id = Identity::default();
+        let deny_list = super::DenyList::default();
+        let client = generate_test_certs(
+            &other_id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        )
+        .with_deny_list(&deny_list);
+        let denied = generate_test_certs(
+            &denied_id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let other = generate_test_certs(
+            &other_id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        assert!(handshake(&client, &denied_id, &denied).await.is_ok());
+
+        // The deny list is shared, so the change applies without rebuilding the certs.
+        deny_list.deny(denied_id.clone());
+        assert!(handshake(&client, &denied_id, &denied).await.is_err());
+        assert!(handshake(&client, &other_id, &other).await.is_ok());
+        assert_eq!(deny_list.denied(), 1);
+
+        deny_list.allow(&denied_id);
+        assert!(handshake(&client, &denied_id, &denied).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn trust_domain_aliases() {
+        let id = |trust_domain: &str| Identity::Spiffe {
+            trust_domain: trust_domain.to_string(),
+            namespace: "ns".to_string(),
+            service_account: "sa".to_string(),
+        };
+        let certs = |id: &Identity| {
+            generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            )
+        };
+        let (old_id, new_id) = (id("cluster.local"), id("corp.example"));
+        let aliases = super::TrustDomainAliases::default();
+        let old = certs(&old_id).with_trust_domain_aliases(&aliases);
+        let new = certs(&new_id).with_trust_domain_aliases(&aliases);
+
+        // Without aliases, each trust domain only accepts its own identities.
+        assert!(handshake(&new, &new_id, &new).await.is_ok());
+        assert!(handshake(&new, &new_id, &old).await.is_err());
+        assert!(connect(
+            new.mtls_acceptor(Some(&new_id)).unwrap(),
+            old.connector(&old_id).unwrap()
+        )
+        .await
+        .is_err());
+
+        // The aliases are read during each handshake, so the change applies without rebuilding
+        // the certs.
+        aliases.set(vec![
+            "corp.example".to_string(),
+            "cluster.local".to_string(),
+        ]);
+
+        // Outbound, the destination is accepted under either trust domain.
+        assert!(handshake(&new, &new_id, &old).await.is_ok());
+        assert!(handshake(&old, &old_id, &new).await.is_ok());
+        assert!(handshake(&new, &id("other.example"), &old).await.is_err());
+
+        // Inbound, clients of either trust domain are accepted.
+        for client in [&old, &new] {
+            for server_id in [&old_id, &new_id] {
+                let server = certs(server_id).with_trust_domain_aliases(&aliases);
+                assert!(connect(
+                    server.mtls_acceptor(Some(server_id)).unwrap(),
+                    client.connector(server_id).unwrap()
+                )
+                .await
+                .is_ok());
             }
         }
+        let other = certs(&id("other.example"));
+        assert!(connect(
+            new.mtls_acceptor(Some(&new_id)).unwrap(),
+            other.connector(&new_id).unwrap()
+        )
+        .await
+        .is_err());
 
-        pub fn new_certs(
-            &mut self,
-            id: &TestIdentity,
-            not_before: SystemTime,
-            not_after: SystemTime,
-        ) -> Certs {
-            generate_test_certs_at(id, not_before, not_after, Some(&mut self.rng))
+        // The trust domain presented by accepted peers is recorded.
+        let matched = aliases.matched();
+        assert!(matched["cluster.local"] > 0, "{matched:?}");
+        assert!(matched["corp.example"] > 0, "{matched:?}");
+        assert!(!matched.contains_key("other.example"));
+
+        aliases.set(Vec::new());
+        assert!(handshake(&new, &new_id, &old).await.is_err());
+    }
+
+    #[test]
+    fn csr_key_types() {
+        use super::{CsrOptions, EcCurve, KeyType};
+
+        for (key_type, bits) in [
+            (KeyType::Ec(EcCurve::P256), 256),
+            (KeyType::Ec(EcCurve::P384), 384),
+            (KeyType::Rsa(2048), 2048),
+            (KeyType::Rsa(3072), 3072),
+        ] {
+            let cs = CsrOptions {
+                san: Identity::default().to_string(),
+                key_type,
+                ..Default::default()
+            }
+            .generate()
+            .unwrap();
+            let key = boring::pkey::PKey::private_key_from_pem(&cs.pkey).unwrap();
+            assert_eq!(key.bits(), bits);
+            let req = boring::x509::X509Req::from_pem(&cs.csr).unwrap();
+            assert!(req.verify(&req.public_key().unwrap()).unwrap());
+        }
+
+        for key_type in [KeyType::Rsa(1024), KeyType::Rsa(2047)] {
+            let res = CsrOptions {
+                san: Identity::default().to_string(),
+                key_type,
+                ..Default::default()
+            }
+            .generate();
+            assert!(matches!(res, Err(crate::tls::Error::UnsupportedKeyType(_))));
         }
     }
 
-    impl Default for CertGenerator {
-        fn default() -> Self {
-            // Use arbitrary seed.
-            Self::new(427)
+    #[tokio::test(flavor = "current_thread")]
+    async fn csr_generate_async() {
+        use super::{CsrOptions, KeyType};
+
+        let start = std::time::Instant::now();
+        let generate = futures::future::try_join_all((0..4).map(|_| async {
+            let cs = CsrOptions {
+                san: Identity::default().to_string(),
+                key_type: KeyType::Rsa(4096),
+                ..Default::default()
+            }
+            .generate_async()
+            .await?;
+            Ok::<_, crate::tls::Error>((cs, std::time::Instant::now()))
+        }));
+        let timer = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            std::time::Instant::now()
+        };
+        let (generated, fired) = tokio::join!(generate, timer);
+
+        // The single runtime thread is free while the keys are generated, so the timer fires
+        // before any of them is done.
+        let generated = generated.unwrap();
+        assert!(fired.duration_since(start) < Duration::from_millis(500));
+        for (cs, done) in generated {
+            assert!(done > fired);
+            let key = boring::pkey::PKey::private_key_from_pem(&cs.pkey).unwrap();
+            assert_eq!(key.bits(), 4096);
         }
     }
-}
 
-#[cfg(test)]
-pub mod tests {
-    use std::time::Duration;
+    #[tokio::test]
+    async fn rsa_mtls_handshake() {
+        let id = Identity::default();
+        let rsa_certs = || {
+            let cs = super::CsrOptions {
+                san: id.to_string(),
+                key_type: super::KeyType::Rsa(2048),
+                ..Default::default()
+            }
+            .generate()
+            .unwrap();
+            let chain = super::sign_test_csr(&cs.csr, &id, Duration::from_secs(24 * 60 * 60));
+            let leaf = chain[0].as_bytes();
+            super::cert_from(&cs.pkey, leaf, vec![super::TEST_ROOT]).unwrap()
+        };
+        let (client, server) = (rsa_certs(), rsa_certs());
 
-    use crate::identity::Identity;
-    use crate::tls::TestIdentity;
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = server.mtls_acceptor(Some(&id)).unwrap();
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let mut cfg = client.connector(&id).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        tokio_boring::connect(cfg, "", client_io).await.unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn p384_sigalgs() {
+        use super::{EcCurve, KeyType};
+
+        let id = Identity::default();
+        let p384 = KeyType::Ec(EcCurve::P384);
+        let (ca_cert, ca_key) = super::generate_test_ca_with_key_type("cluster.local", p384);
+        let certs = || {
+            super::generate_test_certs_with_key_type(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+                p384,
+                &ca_cert,
+                &ca_key,
+            )
+        };
+        let (client, server) = (certs(), certs());
+        assert_eq!(client.cert.x509.public_key().unwrap().bits(), 384);
+
+        // Both sides only sign and accept P-384 signatures.
+        const SIGALGS: &str = "ECDSA+SHA384";
+        let acceptor = |server: &super::Certs| {
+            server
+                .builder()
+                .peer_trust_domain(&id)
+                .sigalgs(SIGALGS)
+                .build_acceptor()
+                .unwrap()
+        };
+        let connector = client
+            .builder()
+            .sigalgs(SIGALGS)
+            .build_connector(&id)
+            .unwrap();
+        let conn = connect(acceptor(&server), connector).await.unwrap();
+        let peer = conn.ssl().peer_certificate().unwrap();
+        assert_eq!(peer.public_key().unwrap().bits(), 384);
+
+        // A P-256 client cannot sign with the remaining algorithms, although it is trusted.
+        let p256 = super::generate_test_certs_with_key_type(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+            KeyType::Ec(EcCurve::P256),
+            &ca_cert,
+            &ca_key,
+        );
+        let connector = p256.builder().build_connector(&id).unwrap();
+        assert!(connect(acceptor(&server), connector).await.is_err());
+        let connector = p256.builder().build_connector(&id).unwrap();
+        let unrestricted = server.mtls_acceptor(Some(&id)).unwrap();
+        assert!(connect(unrestricted, connector).await.is_ok());
 
-    use super::generate_test_certs;
+        // Invalid lists fail when building the context, not during handshakes.
+        let res = server.builder().sigalgs("ECDSA+NOPE").build_acceptor();
+        assert!(matches!(res, Err(crate::tls::Error::InvalidSigalgs(..))));
+
+        assert_eq!("EC_P384".parse::<KeyType>().unwrap(), p384);
+        assert!("EC_P521".parse::<KeyType>().is_err());
+    }
 
     #[test]
-    #[cfg(feature = "fips")]
-    fn is_fips_enabled() {
-        assert!(boring::fips::enabled());
+    fn csr_subject_and_digest() {
+        use boring::nid::Nid;
+
+        use super::{CsrOptions, SignatureDigest};
+
+        // DER encoded OIDs of ecdsa-with-SHA256 and ecdsa-with-SHA384.
+        const ECDSA_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+        const ECDSA_SHA384: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+        let contains = |der: &[u8], oid: &[u8]| der.windows(oid.len()).any(|w| w == oid);
+
+        let cs = CsrOptions {
+            san: Identity::default().to_string(),
+            ..Default::default()
+        }
+        .generate()
+        .unwrap();
+        let req = boring::x509::X509Req::from_pem(&cs.csr).unwrap();
+        assert_eq!(req.subject_name().entries().count(), 0);
+        assert!(contains(&req.to_der().unwrap(), ECDSA_SHA256));
+
+        let cs = CsrOptions {
+            san: Identity::default().to_string(),
+            organization: Some("cluster.local".to_string()),
+            common_name: Some("ztunnel".to_string()),
+            signature_digest: Some(SignatureDigest::Sha384),
+            ..Default::default()
+        }
+        .generate()
+        .unwrap();
+        let req = boring::x509::X509Req::from_pem(&cs.csr).unwrap();
+        let entry = |nid| {
+            req.subject_name()
+                .entries_by_nid(nid)
+                .next()
+                .unwrap()
+                .data()
+                .as_utf8()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(entry(Nid::ORGANIZATIONNAME), "cluster.local");
+        assert_eq!(entry(Nid::COMMONNAME), "ztunnel");
+        let der = req.to_der().unwrap();
+        assert!(contains(&der, ECDSA_SHA384));
+        assert!(!contains(&der, ECDSA_SHA256));
     }
 
     #[test]
-    #[cfg(not(feature = "fips"))]
-    fn is_fips_disabled() {
-        assert!(!boring::fips::enabled());
+    fn private_key_redacted() {
+        let cs = super::CsrOptions {
+            san: Identity::default().to_string(),
+            ..Default::default()
+        }
+        .generate()
+        .unwrap();
+        let debug = format!("{cs:?}");
+        assert!(debug.contains("<redacted>"), "{debug}");
+        assert!(!debug.contains("PRIVATE KEY"), "{debug}");
+
+        let certs = generate_test_certs(
+            &Identity::default().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let debug = format!("{certs:?}");
+        assert!(debug.contains("<redacted>"), "{debug}");
+        assert!(!debug.contains("PRIVATE KEY"), "{debug}");
     }
 
     #[test]
-    fn cert_expiration() {
-        let expiry_seconds = 1000;
-        let id: TestIdentity = Identity::default().into();
-        let zero_dur = Duration::from_secs(0);
-        let certs_not_expired = generate_test_certs(
-            &id,
+    fn encrypted_private_key() {
+        use boring::symm::Cipher;
+
+        use super::{cert_from_encrypted, Error};
+
+        let certs = generate_test_certs(
+            &Identity::default().into(),
             Duration::from_secs(0),
-            Duration::from_secs(expiry_seconds),
+            Duration::from_secs(100),
         );
-        assert!(!certs_not_expired.is_expired());
-        let seconds_until_refresh = certs_not_expired.get_duration_until_refresh().as_secs();
-        // Give a couple second window to avoid flakiness in the test.
-        assert!(
-            seconds_until_refresh <= expiry_seconds / 2
-                && seconds_until_refresh >= expiry_seconds / 2 - 1
+        let key = certs
+            .key
+            .in_memory()
+            .unwrap()
+            .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), b"secret")
+            .unwrap();
+        let cert = certs.x509().to_pem().unwrap();
+
+        let decrypted = cert_from_encrypted(&key, b"secret", &cert, vec![]).unwrap();
+        assert_eq!(decrypted, certs);
+
+        let res = cert_from_encrypted(&key, b"wrong", &cert, vec![]);
+        assert!(matches!(res, Err(Error::KeyPassphrase)), "{res:?}");
+
+        let res = cert_from_encrypted(b"not a key", b"secret", &cert, vec![]);
+        assert!(matches!(res, Err(Error::InvalidPrivateKey(_))), "{res:?}");
+
+        // unencrypted keys are accepted as well; the passphrase is unused
+        let key = certs
+            .key
+            .in_memory()
+            .unwrap()
+            .private_key_to_pem_pkcs8()
+            .unwrap();
+        let plain = cert_from_encrypted(&key, b"secret", &cert, vec![]).unwrap();
+        assert_eq!(plain, certs);
+    }
+
+    #[tokio::test]
+    async fn pkcs12_round_trip() {
+        use boring::pkcs12::Pkcs12;
+        use boring::stack::Stack;
+
+        use super::{Certs, Error};
+
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
         );
+        let (other_ca, _) = super::generate_test_ca("other");
+        let build = |ca: &[&boring::x509::X509]| {
+            let mut stack = Stack::new().unwrap();
+            for c in ca {
+                stack.push((*c).clone()).unwrap();
+            }
+            let mut builder = Pkcs12::builder();
+            if !ca.is_empty() {
+                builder.ca(stack);
+            }
+            builder
+                .build(
+                    "secret",
+                    "ztunnel",
+                    certs.key.in_memory().unwrap(),
+                    certs.x509(),
+                )
+                .unwrap()
+                .to_der()
+                .unwrap()
+        };
+        let root = certs.iter_chain().last().unwrap().clone();
 
-        let certs_expired = generate_test_certs(&id, zero_dur, zero_dur);
-        assert!(certs_expired.is_expired());
-        assert_eq!(certs_expired.get_duration_until_refresh(), zero_dur);
+        // certs outside of the leaf's chain are dropped
+        let der = build(&[&other_ca, &root]);
+        let loaded = Certs::from_pkcs12(&der, "secret").unwrap();
+        assert_eq!(loaded, certs);
+        let chain: Vec<_> = loaded.iter_chain().map(|c| c.to_der().unwrap()).collect();
+        assert_eq!(chain, vec![root.to_der().unwrap()]);
 
-        let future_certs = generate_test_certs(
-            &id,
-            Duration::from_secs(1000),
-            Duration::from_secs(expiry_seconds),
+        let res = Certs::from_pkcs12(&der, "wrong");
+        assert!(matches!(res, Err(Error::InvalidPkcs12(_))), "{res:?}");
+        let res = Certs::from_pkcs12(&build(&[]), "secret");
+        assert!(matches!(res, Err(Error::Pkcs12MissingChain)), "{res:?}");
+
+        let der = build(&[&root]);
+        let loaded = Certs::from_pkcs12(&der, "secret").unwrap();
+        handshake(&loaded, &id, &certs).await.unwrap();
+        handshake(&certs, &id, &loaded).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn engine_private_key() {
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        use boring::pkey::{PKey, Private};
+
+        use super::{sign_with_key, Error, KeyEngine, PrivateKeyProvider};
+
+        // MockEngine stands in for an HSM, signing with keys it holds by id.
+        #[derive(Default)]
+        struct MockEngine {
+            keys: HashMap<String, PKey<Private>>,
+            signatures: AtomicUsize,
+        }
+
+        impl KeyEngine for MockEngine {
+            fn sign(&self, key_id: &str, algorithm: u16, input: &[u8]) -> Result<Vec<u8>, Error> {
+                let key = self
+                    .keys
+                    .get(key_id)
+                    .ok_or_else(|| Error::KeyEngine(format!("unknown key {key_id:?}")))?;
+                self.signatures.fetch_add(1, Ordering::SeqCst);
+                sign_with_key(key, algorithm, input)
+            }
+        }
+
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let (_, other_key) = super::generate_test_ca("other");
+        let engine = Arc::new(MockEngine {
+            keys: HashMap::from([
+                (
+                    "workload".to_string(),
+                    certs.key.in_memory().unwrap().clone(),
+                ),
+                ("other".to_string(), other_key),
+            ]),
+            ..Default::default()
+        });
+        let engine_key = |key_id: &str| {
+            certs.clone().with_private_key(PrivateKeyProvider::Engine {
+                engine_id: "mock".to_string(),
+                key_id: key_id.to_string(),
+                engine: engine.clone(),
+            })
+        };
+
+        let engine_certs = engine_key("workload").unwrap();
+        assert!(engine_certs.private_key().in_memory().is_none());
+        assert!(!format!("{engine_certs:?}").contains("<redacted>"));
+        // Checking the key took a signature; each handshake takes one more.
+        let signatures = engine.signatures.load(Ordering::SeqCst);
+        handshake(&engine_certs, &id, &certs).await.unwrap();
+        handshake(&certs, &id, &engine_certs).await.unwrap();
+        assert_eq!(engine.signatures.load(Ordering::SeqCst), signatures + 2);
+
+        // Swapping the key of a leaf builds a new connector rather than reusing that of the old
+        // key, whether the new key is held by an engine or in memory.
+        let cache = super::ConnectorCache::new(4);
+        cache.connect_config(&certs, &id).unwrap();
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = certs.acceptor().unwrap();
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let mut cfg = cache.connect_config(&engine_certs, &id).unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        tokio_boring::connect(cfg, "", client_io).await.unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(cache.builds(), 2);
+        assert_eq!(engine.signatures.load(Ordering::SeqCst), signatures + 3);
+        let der = certs.key.in_memory().unwrap().private_key_to_der().unwrap();
+        let reloaded = certs
+            .clone()
+            .with_private_key(PKey::private_key_from_der(&der).unwrap().into())
+            .unwrap();
+        cache.connect_config(&reloaded, &id).unwrap();
+        assert_eq!(cache.builds(), 3);
+        cache.connect_config(&certs, &id).unwrap();
+        assert_eq!(cache.builds(), 3);
+
+        let res = engine_key("other").err();
+        assert!(matches!(res, Some(Error::EngineKeyMismatch(..))), "{res:?}");
+        let res = engine_key("missing").err();
+        assert!(matches!(res, Some(Error::EngineKeyMismatch(..))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn export_keying_material() {
+        use super::export_keying_material;
+
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let acceptor = certs.acceptor().unwrap();
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let mut cfg = certs.connector(&id).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let client = tokio_boring::connect(cfg, "", client_io).await.unwrap();
+        let server = server.await.unwrap().unwrap();
+
+        let ekm = |s, label| export_keying_material(s, label, Some(b"ctx"), 32).unwrap();
+        let client_ekm = ekm(&client, "EXPORTER-ztunnel");
+        assert_eq!(client_ekm.len(), 32);
+        assert_eq!(client_ekm, ekm(&server, "EXPORTER-ztunnel"));
+        assert_ne!(client_ekm, ekm(&client, "EXPORTER-other"));
+        assert_ne!(
+            client_ekm,
+            export_keying_material(&client, "EXPORTER-ztunnel", None, 32).unwrap()
         );
-        assert!(!future_certs.is_expired());
-        assert_eq!(future_certs.get_duration_until_refresh(), zero_dur);
     }
 }
@@ -0,0 +1,167 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use boring::ex_data;
+use boring::ssl::{self, SslContextBuilder, SslRef};
+use once_cell::sync::Lazy;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+
+use super::{RecordOptions, StreamGuard, MAX_RECORD_SIZE};
+
+// Most a record adds to its plaintext: the header, padding and tag of the cipher.
+const RECORD_OVERHEAD: usize = 5 + 320;
+
+static GLOBAL: Lazy<TlsBuffers> = Lazy::new(TlsBuffers::default);
+
+// The buffer size of the connections of a context, set by set_buffer_size.
+static BUFFER_SIZE_INDEX: Lazy<ex_data::Index<ssl::SslContext, usize>> =
+    Lazy::new(|| ssl::SslContext::new_ex_index().expect("ex index must be allocated"));
+
+// Holds the StreamGuard of a stream and its share of the estimate, until its Ssl is freed.
+static OPEN_STREAM_INDEX: Lazy<ex_data::Index<ssl::Ssl, OpenStream>> =
+    Lazy::new(|| ssl::Ssl::new_ex_index().expect("ex index must be allocated"));
+
+/// buffer_size returns the size of the read and write buffers of a connection sending records
+/// per options, in bytes. It is about 34 KB with the default record size.
+pub fn buffer_size(options: &RecordOptions) -> usize {
+    let write = options.max_send_fragment.unwrap_or(MAX_RECORD_SIZE);
+    MAX_RECORD_SIZE + write + 2 * RECORD_OVERHEAD
+}
+
+// set_buffer_size records the buffer size of the connections of ctx, for TlsBuffers::track.
+pub(super) fn set_buffer_size(ctx: &mut SslContextBuilder, options: &RecordOptions) {
+    ctx.set_ex_data(*BUFFER_SIZE_INDEX, buffer_size(options));
+}
+
+/// TlsBuffers estimates the memory held by the buffers of open TLS streams, as the sum of their
+/// configured buffer sizes. It is an upper bound: streams that release their buffers while idle,
+/// as they do by default, hold less. Streams are not counted here: inbound streams are counted by
+/// the StreamGuard of the LoadShedPolicy that admitted them.
+#[derive(Default)]
+pub struct TlsBuffers {
+    bytes: Gauge,
+}
+
+impl TlsBuffers {
+    /// global returns the estimate of every stream established by acceptors and connectors.
+    pub fn global() -> &'static TlsBuffers {
+        &GLOBAL
+    }
+
+    /// register_metrics exposes the estimate in the registry.
+    pub fn register_metrics(&self, registry: &mut Registry) {
+        registry.register(
+            "tls_buffer_bytes",
+            "An estimate of the memory held by the buffers of open TLS streams, in bytes",
+            self.bytes.clone(),
+        );
+    }
+
+    /// track adds the buffer size of the context of ssl to the estimate until ssl is freed, along
+    /// with guard, if any, so that the stream stays counted as open as long. Streams of contexts
+    /// not built by a TlsContextBuilder are assumed to use the default record size.
+    pub fn track(&self, ssl: &mut SslRef, guard: Option<StreamGuard>) {
+        let size = ssl
+            .ssl_context()
+            .ex_data(*BUFFER_SIZE_INDEX)
+            .copied()
+            .unwrap_or_else(|| buffer_size(&RecordOptions::default()));
+        self.bytes.inc_by(size as i64);
+        ssl.set_ex_data(
+            *OPEN_STREAM_INDEX,
+            OpenStream {
+                _guard: guard,
+                bytes: self.bytes.clone(),
+                size,
+            },
+        );
+    }
+
+    /// buffer_bytes returns the estimated memory held by the buffers of open streams, in bytes.
+    pub fn buffer_bytes(&self) -> u64 {
+        self.bytes.get().max(0) as u64
+    }
+}
+
+struct OpenStream {
+    _guard: Option<StreamGuard>,
+    bytes: Gauge,
+    size: usize,
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.bytes.dec_by(self.size as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::identity::Identity;
+    use crate::tls::{generate_test_certs, LoadShedConfig, LoadShedPolicy, RecordOptions};
+
+    use super::{buffer_size, TlsBuffers};
+
+    #[tokio::test]
+    async fn tracks_open_streams() {
+        const STREAMS: usize = 8;
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let acceptor = certs
+            .builder()
+            .max_send_fragment(4096)
+            .build_acceptor()
+            .unwrap();
+        let connector = certs.connector(&id).unwrap();
+        let size = buffer_size(&RecordOptions {
+            max_send_fragment: Some(4096),
+            ..Default::default()
+        });
+        assert!(size < buffer_size(&RecordOptions::default()));
+
+        let buffers = TlsBuffers::default();
+        let policy = LoadShedPolicy::new(LoadShedConfig::default());
+        let mut open = Vec::new();
+        for _ in 0..STREAMS {
+            let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+            let mut cfg = connector.configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            let (server, client) = tokio::join!(
+                tokio_boring::accept(&acceptor, server_io),
+                tokio_boring::connect(cfg, "", client_io),
+            );
+            let mut server = server.unwrap();
+            buffers.track(server.ssl_mut(), Some(policy.admit().unwrap()));
+            // The connections are left idle.
+            open.push((server, client.unwrap()));
+        }
+        assert_eq!(policy.open_streams(), STREAMS);
+        assert_eq!(buffers.buffer_bytes(), (STREAMS * size) as u64);
+
+        open.truncate(STREAMS / 2);
+        assert_eq!(policy.open_streams(), STREAMS / 2);
+        assert_eq!(buffers.buffer_bytes(), (STREAMS / 2 * size) as u64);
+        drop(open);
+        assert_eq!(policy.open_streams(), 0);
+        assert_eq!(buffers.buffer_bytes(), 0);
+    }
+}
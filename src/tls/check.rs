@@ -0,0 +1,229 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use boring::hash::MessageDigest;
+use boring::x509;
+use hyper::Uri;
+use hyper_boring::MaybeHttpsStream;
+use tower::{Service, ServiceExt};
+
+use crate::config::RootCert;
+
+use super::boring::{control_plane_connector, error_chain};
+use super::{extract_all_sans, name_to_string, CertInfo, ConnectorConfig, San, UDS_SCHEME_PREFIX};
+
+/// ControlPlaneCheck is the outcome of a TLS handshake with a control plane endpoint.
+#[derive(serde::Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ControlPlaneCheck {
+    pub address: String,
+    pub success: bool,
+    /// Why the connection or handshake failed, including certificate verification errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpn: Option<String>,
+    /// The certificates presented by the server, leaf first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub peer_chain: Vec<PeerCertInfo>,
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct PeerCertInfo {
+    pub subject: String,
+    /// Hex encoded SHA-256 digest of the DER certificate.
+    pub sha256: String,
+    #[serde(flatten)]
+    pub cert: CertInfo,
+    /// The SANs of the certificate, by type.
+    #[serde(skip)]
+    pub typed_sans: Vec<San>,
+    /// The certificate in PEM format.
+    #[serde(skip)]
+    pub pem: String,
+}
+
+impl PeerCertInfo {
+    /// from_x509 describes a certificate presented by a peer.
+    pub fn from_x509(cert: &x509::X509Ref) -> PeerCertInfo {
+        PeerCertInfo {
+            subject: name_to_string(cert.subject_name()),
+            sha256: cert
+                .digest(MessageDigest::sha256())
+                .map(|digest| digest.iter().map(|b| format!("{b:02x}")).collect())
+                .unwrap_or_default(),
+            cert: CertInfo::from_x509(cert),
+            typed_sans: extract_all_sans(cert),
+            pem: cert
+                .to_pem()
+                .ok()
+                .and_then(|pem| String::from_utf8(pem).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// check_control_plane performs a TLS handshake with the control plane at address, verified
+/// against root_cert, without sending any request. Connections are established exactly like the
+/// CA and XDS clients do, so the check fails when they would.
+pub async fn check_control_plane(
+    address: &str,
+    root_cert: &RootCert,
+    cfg: &ConnectorConfig,
+    timeout: Duration,
+) -> ControlPlaneCheck {
+    let mut check = ControlPlaneCheck {
+        address: address.to_string(),
+        ..Default::default()
+    };
+    match tokio::time::timeout(timeout, handshake(address, root_cert, cfg, &mut check)).await {
+        Ok(Ok(())) => check.success = true,
+        Ok(Err(e)) => check.error = Some(e),
+        Err(_) => check.error = Some(format!("timed out after {timeout:?}")),
+    }
+    check
+}
+
+async fn handshake(
+    address: &str,
+    root_cert: &RootCert,
+    cfg: &ConnectorConfig,
+    check: &mut ControlPlaneCheck,
+) -> Result<(), String> {
+    if address.starts_with(UDS_SCHEME_PREFIX) {
+        return Err("unix domain socket endpoints do not use TLS".to_string());
+    }
+    let uri = Uri::try_from(address).map_err(|e| e.to_string())?;
+    let mut connector =
+        control_plane_connector(&uri, root_cert, cfg).map_err(|e| error_chain(&e))?;
+    let stream = connector
+        .ready()
+        .await
+        .map_err(|e| error_chain(e.as_ref()))?
+        .call(uri)
+        .await
+        .map_err(|e| error_chain(e.as_ref()))?;
+    let tls = match &stream {
+        MaybeHttpsStream::Https(tls) => tls,
+        MaybeHttpsStream::Http(_) => return Err(format!("{address} is not a https address")),
+    };
+    let ssl = tls.ssl();
+    check.version = Some(ssl.version_str().to_string());
+    check.alpn = ssl
+        .selected_alpn_protocol()
+        .map(|p| String::from_utf8_lossy(p).into_owned());
+    check.peer_chain = ssl
+        .peer_cert_chain()
+        .map(|chain| chain.iter().map(PeerCertInfo::from_x509).collect())
+        .unwrap_or_default();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use crate::config::RootCert;
+    use crate::tls::{generate_test_ca, generate_test_certs, Certs, ControlPlaneCertProvider};
+
+    use super::check_control_plane;
+
+    // Spawns a TLS server presenting certs, which completes handshakes and nothing more.
+    async fn spawn_tls_server(certs: Certs) -> SocketAddr {
+        use tokio_stream::StreamExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut tls_stream =
+            crate::hyper_util::tls_server(ControlPlaneCertProvider(certs), listener, None);
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Some(conn) = tls_stream.next().await {
+                conns.push(conn);
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn check() {
+        let certs = generate_test_certs(
+            &IpAddr::from([127, 0, 0, 1]).into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let root = RootCert::Static(certs.chain().unwrap());
+        let addr = spawn_tls_server(certs).await;
+        let address = format!("https://{addr}");
+
+        let check =
+            check_control_plane(&address, &root, &Default::default(), Duration::from_secs(5)).await;
+        assert!(check.success, "{:?}", check.error);
+        assert_eq!(check.version.as_deref(), Some("TLSv1.3"));
+        assert_eq!(check.alpn.as_deref(), Some("h2"));
+        // The root is not sent.
+        assert_eq!(check.peer_chain.len(), 1);
+        assert_eq!(check.peer_chain[0].cert.sans, vec!["127.0.0.1".to_string()]);
+        assert_eq!(check.peer_chain[0].cert.issuer, "O=cluster.local");
+
+        // The server is not trusted by another root.
+        let (ca_cert, _) = generate_test_ca("other.local");
+        let other = RootCert::Static(ca_cert.to_pem().unwrap().into());
+        let check = check_control_plane(
+            &address,
+            &other,
+            &Default::default(),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(!check.success);
+        let error = check.error.unwrap();
+        assert!(
+            error
+                .to_lowercase()
+                .replace('_', " ")
+                .contains("certificate verify failed"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn check_timeout() {
+        // Accepts connections, but never answers the handshake.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+
+        let check = check_control_plane(
+            &format!("https://{addr}"),
+            &RootCert::Default,
+            &Default::default(),
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(!check.success);
+        assert!(check.error.unwrap().starts_with("timed out"));
+    }
+}
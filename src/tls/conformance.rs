@@ -0,0 +1,167 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Tests of the public API of the TLS backends, run against each of the compiled in backends so
+// that they behave identically. Certificates are issued with the boring test helpers and loaded
+// through the API of the backend under test.
+
+macro_rules! conformance_suite {
+    ($name:ident, $($backend:tt)+) => {
+        mod $name {
+            use std::time::{Duration, SystemTime};
+
+            use $($backend)+ as backend;
+
+            use crate::identity::Identity;
+            use crate::tls::san_cases::san_cases;
+            use crate::tls::{
+                generate_test_ca, generate_test_certs, generate_test_certs_with_ca,
+                sign_test_csr, CsrOptions, TestIdentity,
+            };
+
+            const TEST_PKEY: &[u8] = include_bytes!("key.pem");
+
+            // Loads certificates issued by the boring test helpers with the backend under test.
+            fn load(certs: &crate::tls::Certs) -> backend::Certs {
+                let leaf = certs.x509().to_pem().unwrap();
+                let chain = certs.chain().unwrap();
+                backend::cert_from(TEST_PKEY, &leaf, vec![&chain[..]]).unwrap()
+            }
+
+            fn certs(id: &TestIdentity) -> backend::Certs {
+                load(&generate_test_certs(
+                    id,
+                    Duration::from_secs(0),
+                    Duration::from_secs(100),
+                ))
+            }
+
+            fn other_identity() -> Identity {
+                Identity::Spiffe {
+                    trust_domain: "cluster.local".to_string(),
+                    namespace: "default".to_string(),
+                    service_account: "other".to_string(),
+                }
+            }
+
+            #[tokio::test]
+            async fn generated_certificate() {
+                let id = Identity::default();
+                let csr = backend::generate_csr(&CsrOptions {
+                    san: id.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+                let chain = sign_test_csr(&csr.csr, &id, Duration::from_secs(100));
+                let chain: Vec<&[u8]> = chain.iter().map(|pem| pem.as_bytes()).collect();
+                let generated = backend::cert_from(&csr.pkey, chain[0], chain[1..].to_vec())
+                    .unwrap();
+                assert_eq!(generated.identities(), vec![id.clone()]);
+
+                let peer = certs(&id.clone().into());
+                assert!(backend::loopback_handshake(&generated, &peer, &id)
+                    .await
+                    .is_ok());
+                assert!(backend::loopback_handshake(&peer, &generated, &id)
+                    .await
+                    .is_ok());
+            }
+
+            #[test]
+            fn expiry() {
+                let now = SystemTime::now();
+                let certs = certs(&Identity::default().into());
+                assert!(!certs.is_expired(&crate::time::SystemClock));
+                assert!(!certs.is_expired_at(now));
+                assert!(certs.is_expired_at(certs.not_after() + Duration::from_secs(1)));
+
+                // Certificates are refreshed halfway through their lifetime.
+                let refresh = certs.get_duration_until_refresh_at(now);
+                assert!(refresh <= Duration::from_secs(50), "{refresh:?}");
+                assert!(refresh >= Duration::from_secs(48), "{refresh:?}");
+                assert!(certs.refresh_at() < certs.not_after());
+                assert_eq!(
+                    certs.get_duration_until_refresh_at(certs.not_after()),
+                    Duration::ZERO
+                );
+            }
+
+            #[tokio::test]
+            async fn san_verification() {
+                let client = certs(&Identity::default().into());
+                for case in san_cases() {
+                    let server = certs(&case.server);
+                    let res = backend::loopback_handshake(&client, &server, case.expected).await;
+                    assert_eq!(res.is_ok(), case.ok, "{}", case.name);
+                }
+            }
+
+            #[tokio::test]
+            async fn mtls_loopback() {
+                let client_id = Identity::default();
+                let server_id = other_identity();
+                let client = certs(&client_id.clone().into());
+                let server = certs(&server_id.clone().into());
+                let handshake = backend::loopback_handshake(&client, &server, &server_id)
+                    .await
+                    .unwrap();
+                assert_eq!(handshake.server_identities, vec![server_id]);
+                assert_eq!(handshake.client_identities, vec![client_id]);
+                assert_eq!(handshake.alpn.as_deref(), Some(&b"h2"[..]));
+            }
+
+            #[tokio::test]
+            async fn untrusted_root() {
+                let id = Identity::default();
+                let (ca_cert, ca_key) = generate_test_ca("other");
+                let server = load(&generate_test_certs_with_ca(
+                    &id.clone().into(),
+                    Duration::from_secs(0),
+                    Duration::from_secs(100),
+                    &ca_cert,
+                    &ca_key,
+                ));
+                let client = certs(&id.clone().into());
+                assert!(backend::loopback_handshake(&client, &server, &id)
+                    .await
+                    .is_err());
+            }
+
+            #[tokio::test]
+            async fn chain_serialization() {
+                let id = Identity::default();
+                let fixture = generate_test_certs(
+                    &id.clone().into(),
+                    Duration::from_secs(0),
+                    Duration::from_secs(100),
+                );
+                let certs = load(&fixture);
+                let chain = certs.chain().unwrap();
+                assert_eq!(chain, fixture.chain().unwrap());
+
+                // The serialized chain can be loaded back.
+                let leaf = fixture.x509().to_pem().unwrap();
+                let reloaded = backend::cert_from(TEST_PKEY, &leaf, vec![&chain[..]]).unwrap();
+                assert_eq!(reloaded.identities(), certs.identities());
+                assert!(backend::loopback_handshake(&reloaded, &certs, &id)
+                    .await
+                    .is_ok());
+            }
+        }
+    };
+}
+
+conformance_suite!(boring, crate::tls::boring);
+#[cfg(feature = "tls-rustls")]
+conformance_suite!(rustls, crate::tls::rustls);
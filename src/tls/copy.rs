@@ -0,0 +1,329 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use boring::ssl::{self, ShutdownState};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_boring::SslStream;
+use tracing::trace;
+
+use super::MAX_RECORD_SIZE;
+
+/// CopyOptions configures copy_bidirectional_tls.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CopyOptions {
+    /// Fail the copy if the TLS peer closes the connection without sending close_notify. Such a
+    /// close cannot be told apart from a truncation by an attacker, but many peers do it.
+    pub require_close_notify: bool,
+    /// Size of the buffer of each direction.
+    pub buffer_size: usize,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            require_close_notify: false,
+            buffer_size: MAX_RECORD_SIZE,
+        }
+    }
+}
+
+/// TlsTransferred reports what copy_bidirectional_tls transferred.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TlsTransferred {
+    /// Bytes read from the plaintext stream and written to the TLS stream.
+    pub to_tls: u64,
+    /// Bytes read from the TLS stream and written to the plaintext stream.
+    pub from_tls: u64,
+    /// Whether the TLS peer closed its side with close_notify.
+    pub close_notify: bool,
+}
+
+/// copy_bidirectional_tls copies data between plain and tls until both directions are closed.
+/// Each direction is shut down on its own once its source reaches EOF: EOF on plain sends
+/// close_notify on tls, and close_notify from the TLS peer shuts down the write side of plain,
+/// while the other direction keeps flowing. A TLS peer that closes without close_notify is
+/// treated as EOF, unless opts require close_notify.
+pub async fn copy_bidirectional_tls<P, S>(
+    plain: &mut P,
+    tls: &mut SslStream<S>,
+    opts: CopyOptions,
+) -> io::Result<TlsTransferred>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut to_tls = Direction::new(opts.buffer_size);
+    let mut from_tls = Direction::new(opts.buffer_size);
+    std::future::poll_fn(|cx| {
+        if !to_tls.done {
+            let res = to_tls.poll_copy(
+                cx,
+                |cx, buf| Pin::new(&mut *plain).poll_read(cx, buf),
+                Pin::new(&mut *tls),
+            );
+            if let Poll::Ready(res) = res {
+                trace!(?res, transferred = to_tls.transferred, "plain -> tls");
+                res?;
+            }
+        }
+        if !from_tls.done {
+            let res = from_tls.poll_copy(
+                cx,
+                |cx, buf| poll_read_tls(tls, cx, buf, opts.require_close_notify),
+                Pin::new(&mut *plain),
+            );
+            if let Poll::Ready(res) = res {
+                trace!(?res, transferred = from_tls.transferred, "tls -> plain");
+                res?;
+            }
+        }
+        if to_tls.done && from_tls.done {
+            Poll::Ready(Ok::<_, io::Error>(()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await?;
+
+    let transferred = TlsTransferred {
+        to_tls: to_tls.transferred,
+        from_tls: from_tls.transferred,
+        close_notify: received_close_notify(tls),
+    };
+    trace!(?transferred, "copy tls complete");
+    Ok(transferred)
+}
+
+fn received_close_notify<S>(tls: &mut SslStream<S>) -> bool {
+    tls.ssl_mut()
+        .get_shutdown()
+        .contains(ShutdownState::RECEIVED)
+}
+
+// Reads from tls, reporting EOF without close_notify as an error if required, and as EOF
+// otherwise.
+fn poll_read_tls<S: AsyncRead + AsyncWrite + Unpin>(
+    tls: &mut SslStream<S>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+    require_close_notify: bool,
+) -> Poll<io::Result<()>> {
+    let filled = buf.filled().len();
+    let eof = match ready!(Pin::new(&mut *tls).poll_read(cx, buf)) {
+        Ok(()) => buf.filled().len() == filled,
+        Err(e) if is_unclean_eof(&e) => true,
+        Err(e) => return Poll::Ready(Err(e)),
+    };
+    if eof && require_close_notify && !received_close_notify(tls) {
+        return Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "tls peer closed the connection without close_notify",
+        )));
+    }
+    Poll::Ready(Ok(()))
+}
+
+// is_unclean_eof returns whether e reports the transport closing in the middle of the TLS
+// stream, rather than a failure.
+fn is_unclean_eof(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        return true;
+    }
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<ssl::Error>())
+        .map(|e| e.code() == ssl::ErrorCode::SYSCALL && e.io_error().is_none())
+        .unwrap_or_default()
+}
+
+// Direction copies one way, shutting down the writer once the reader reached EOF.
+struct Direction {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    read_done: bool,
+    done: bool,
+    transferred: u64,
+}
+
+impl Direction {
+    fn new(size: usize) -> Self {
+        Direction {
+            buf: vec![0; size.max(1)].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            read_done: false,
+            done: false,
+            transferred: 0,
+        }
+    }
+
+    fn poll_copy<W: AsyncWrite>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut read: impl FnMut(&mut Context<'_>, &mut ReadBuf<'_>) -> Poll<io::Result<()>>,
+        mut writer: Pin<&mut W>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.pos == self.cap && !self.read_done {
+                let mut buf = ReadBuf::new(&mut self.buf);
+                match read(cx, &mut buf) {
+                    Poll::Ready(res) => res?,
+                    Poll::Pending => {
+                        // Write out what was copied so far while waiting for more.
+                        if let Poll::Ready(Err(e)) = writer.as_mut().poll_flush(cx) {
+                            return Poll::Ready(Err(e));
+                        }
+                        return Poll::Pending;
+                    }
+                }
+                let n = buf.filled().len();
+                if n == 0 {
+                    self.read_done = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                }
+            }
+
+            while self.pos < self.cap {
+                let n = ready!(writer
+                    .as_mut()
+                    .poll_write(cx, &self.buf[self.pos..self.cap]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                self.pos += n;
+                self.transferred += n as u64;
+            }
+
+            if self.read_done {
+                ready!(writer.as_mut().poll_flush(cx))?;
+                ready!(writer.as_mut().poll_shutdown(cx))?;
+                self.done = true;
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_boring::SslStream;
+
+    use crate::identity::Identity;
+    use crate::tls::generate_test_certs;
+
+    use super::{copy_bidirectional_tls, CopyOptions, TlsTransferred};
+
+    // Returns the client and server ends of a TLS connection.
+    async fn tls_pair() -> (SslStream<DuplexStream>, SslStream<DuplexStream>) {
+        let certs = generate_test_certs(
+            &Identity::default().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let acceptor = certs.acceptor().unwrap();
+        let mut cfg = certs
+            .connector(&Identity::default())
+            .unwrap()
+            .configure()
+            .unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let ssl = cfg.into_ssl("").unwrap();
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let (server, client) = tokio::join!(
+            tokio_boring::accept(&acceptor, server_io),
+            tokio_boring::SslStreamBuilder::new(ssl, client_io).connect()
+        );
+        (client.unwrap(), server.unwrap())
+    }
+
+    #[tokio::test]
+    async fn server_half_close() {
+        let (mut tls, mut server) = tls_pair().await;
+        let (mut app, mut plain) = tokio::io::duplex(4096);
+
+        let server = tokio::spawn(async move {
+            // Respond, then close the write side while the client is still sending.
+            server.write_all(b"response").await.unwrap();
+            server.shutdown().await.unwrap();
+            let mut request = Vec::new();
+            server.read_to_end(&mut request).await.unwrap();
+            request
+        });
+        let copy = tokio::spawn(async move {
+            copy_bidirectional_tls(&mut plain, &mut tls, CopyOptions::default()).await
+        });
+
+        app.write_all(b"request").await.unwrap();
+        let mut response = Vec::new();
+        app.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+        // The client can keep sending after the response was closed.
+        app.write_all(b" and more").await.unwrap();
+        app.shutdown().await.unwrap();
+
+        assert_eq!(server.await.unwrap(), b"request and more");
+        assert_eq!(
+            copy.await.unwrap().unwrap(),
+            TlsTransferred {
+                to_tls: 16,
+                from_tls: 8,
+                close_notify: true,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_close_notify() {
+        for require_close_notify in [false, true] {
+            let (mut tls, mut server) = tls_pair().await;
+            let (mut app, mut plain) = tokio::io::duplex(4096);
+
+            // Close the transport without close_notify.
+            server.write_all(b"response").await.unwrap();
+            server.get_mut().shutdown().await.unwrap();
+
+            let opts = CopyOptions {
+                require_close_notify,
+                ..Default::default()
+            };
+            let copy =
+                tokio::spawn(
+                    async move { copy_bidirectional_tls(&mut plain, &mut tls, opts).await },
+                );
+            let mut response = Vec::new();
+            app.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"response");
+
+            if require_close_notify {
+                let err = copy.await.unwrap().unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            } else {
+                app.shutdown().await.unwrap();
+                let transferred = copy.await.unwrap().unwrap();
+                assert_eq!(transferred.from_tls, 8);
+                assert!(!transferred.close_notify);
+            }
+        }
+    }
+}
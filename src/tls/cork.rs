@@ -0,0 +1,332 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Largest amount of plaintext in a TLS record.
+pub const MAX_RECORD_SIZE: usize = 16 * 1024;
+
+/// CorkedStream wraps a stream, typically a tokio_boring::SslStream, and coalesces small writes so
+/// that they are sent in full TLS records rather than one record per write. Buffered data is
+/// written out once capacity is reached, on flush and shutdown, or once it was held for the
+/// delay. Writes of at least capacity bytes are passed through.
+///
+/// Coalescing adds up to delay of latency to small writes, so it is only worth it for bulk
+/// transfers, and is enabled per stream. The delay is enforced while the stream is polled for
+/// reading or writing; callers that stop polling must flush.
+pub struct CorkedStream<S> {
+    inner: S,
+    buf: Vec<u8>,
+    capacity: usize,
+    delay: Duration,
+    // Set while data is buffered, to when it must be written out.
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> CorkedStream<S> {
+    /// new wraps inner, buffering up to capacity bytes for at most delay. A capacity of
+    /// MAX_RECORD_SIZE fills records entirely.
+    pub fn new(inner: S, capacity: usize, delay: Duration) -> Self {
+        CorkedStream {
+            inner,
+            buf: Vec::with_capacity(capacity),
+            capacity,
+            delay,
+            deadline: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// buffered returns the number of bytes written to the stream, but not to inner yet.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CorkedStream<S> {
+    // Writes out all buffered data.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.buf.drain(..n);
+        }
+        self.deadline = None;
+        Poll::Ready(Ok(()))
+    }
+
+    // Writes out buffered data once it was held for the delay. Until then, the task is woken at
+    // the deadline.
+    fn poll_deadline(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(deadline) = &mut self.deadline else {
+            return Poll::Ready(Ok(()));
+        };
+        ready!(deadline.as_mut().poll(cx));
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for CorkedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // A failure to write is reported to the reader, since the connection is broken anyway.
+        if let Poll::Ready(Err(e)) = this.poll_deadline(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CorkedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buf.len() + data.len() > this.capacity {
+            ready!(this.poll_drain(cx))?;
+        }
+        if data.len() >= this.capacity {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        }
+        this.buf.extend_from_slice(data);
+        if this.deadline.is_none() {
+            this.deadline = Some(Box::pin(tokio::time::sleep(this.delay)));
+        }
+        // Failures are reported by the next write or flush, which retries the buffered data.
+        let _ = this.poll_deadline(cx);
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+    use crate::identity::Identity;
+    use crate::tls::{generate_test_certs, TlsContextBuilder};
+
+    use super::{CorkedStream, MAX_RECORD_SIZE};
+
+    const PAYLOAD: usize = 64 * 1024;
+
+    // RecordCounter counts the application data records written to the stream.
+    struct RecordCounter {
+        inner: DuplexStream,
+        records: Arc<AtomicUsize>,
+        header: Vec<u8>,
+        // Bytes left in the body of the current record.
+        remaining: usize,
+    }
+
+    impl RecordCounter {
+        fn scan(&mut self, mut data: &[u8]) {
+            while !data.is_empty() {
+                if self.remaining > 0 {
+                    let n = self.remaining.min(data.len());
+                    self.remaining -= n;
+                    data = &data[n..];
+                    continue;
+                }
+                let n = (5 - self.header.len()).min(data.len());
+                self.header.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                if self.header.len() == 5 {
+                    // Application data
+                    if self.header[0] == 23 {
+                        self.records.fetch_add(1, Ordering::SeqCst);
+                    }
+                    self.remaining = u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+                    self.header.clear();
+                }
+            }
+        }
+    }
+
+    impl AsyncRead for RecordCounter {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for RecordCounter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = res {
+                self.scan(&buf[..n]);
+            }
+            res
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    // Sends PAYLOAD bytes from a server built by configure to a client in writes of chunk bytes,
+    // corked if set. Returns the number of application data records the payload took.
+    async fn records(
+        configure: impl FnOnce(TlsContextBuilder<'_>) -> TlsContextBuilder<'_>,
+        chunk: usize,
+        cork: bool,
+    ) -> usize {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let acceptor = configure(certs.builder().require_client_cert(false))
+            .build_acceptor()
+            .unwrap();
+        let (client_io, server_io) = tokio::io::duplex(PAYLOAD * 2);
+        let records = Arc::new(AtomicUsize::new(0));
+        let counter = RecordCounter {
+            inner: server_io,
+            records: records.clone(),
+            header: Vec::new(),
+            remaining: 0,
+        };
+        let mut cfg = certs.connector(&id).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let (server, client) = tokio::join!(
+            tokio_boring::accept(&acceptor, counter),
+            tokio_boring::connect(cfg, "", client_io)
+        );
+        let (server, mut client) = (server.unwrap(), client.unwrap());
+
+        let reader = tokio::spawn(async move {
+            let mut buf = vec![0; PAYLOAD];
+            client.read_exact(&mut buf).await.unwrap();
+        });
+        // Records sent so far carry the handshake, and possibly session tickets.
+        let before = records.load(Ordering::SeqCst);
+        let mut server: Box<dyn AsyncWrite + Unpin + Send> = if cork {
+            Box::new(CorkedStream::new(
+                server,
+                MAX_RECORD_SIZE,
+                Duration::from_secs(1),
+            ))
+        } else {
+            Box::new(server)
+        };
+        let payload = vec![1; chunk];
+        for _ in 0..PAYLOAD / chunk {
+            server.write_all(&payload).await.unwrap();
+        }
+        server.flush().await.unwrap();
+        reader.await.unwrap();
+        records.load(Ordering::SeqCst) - before
+    }
+
+    #[tokio::test]
+    async fn bytes_per_record() {
+        // Large writes are split into full records.
+        let full = records(|b| b, PAYLOAD, false).await;
+        assert!(full <= PAYLOAD / MAX_RECORD_SIZE + 3, "{full}");
+
+        // Smaller records trade throughput for latency.
+        let small = records(|b| b.max_send_fragment(4096), PAYLOAD, false).await;
+        assert!(small >= PAYLOAD / 4096, "{small}");
+
+        // Each small write takes its own record, unless corked.
+        let uncorked = records(|b| b, 64, false).await;
+        assert!(uncorked >= PAYLOAD / 64, "{uncorked}");
+        let corked = records(|b| b, 64, true).await;
+        assert!(corked <= PAYLOAD / MAX_RECORD_SIZE + 3, "{corked}");
+
+        // Releasing buffers does not change what is sent.
+        let released = records(|b| b.release_buffers(true), PAYLOAD, false).await;
+        assert!(released <= PAYLOAD / MAX_RECORD_SIZE + 3, "{released}");
+    }
+
+    #[tokio::test]
+    async fn cork_delay() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = CorkedStream::new(server, 1024, Duration::from_millis(10));
+
+        // Small writes are held back, and written out once they were held for the delay, as long
+        // as the stream is polled.
+        server.write_all(b"hello").await.unwrap();
+        assert_eq!(server.buffered(), 5);
+        let serve = tokio::spawn(async move {
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            (server, buf)
+        });
+        let mut buf = [0; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        client.write_all(b"done").await.unwrap();
+        let (mut server, buf) = serve.await.unwrap();
+        assert_eq!(&buf, b"done");
+        assert_eq!(server.buffered(), 0);
+
+        // Writes filling the buffer are passed through.
+        server.write_all(&[1; 1024]).await.unwrap();
+        assert_eq!(server.buffered(), 0);
+        let mut buf = [0; 1024];
+        client.read_exact(&mut buf).await.unwrap();
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use hyper_util::client::connect::dns::Name;
+use once_cell::sync::Lazy;
+use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::registry::Registry;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// DnsCacheConfig controls how long resolutions are reused. The system resolver does not report
+/// record TTLs, so fixed TTLs are used instead.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DnsCacheConfig {
+    /// How long successful resolutions are reused.
+    pub ttl: Duration,
+    /// How long failed resolutions are reused.
+    pub negative_ttl: Duration,
+    /// How long after its TTL a successful resolution is still used when resolving again fails.
+    pub max_stale: Duration,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        DnsCacheConfig {
+            ttl: Duration::from_secs(30),
+            negative_ttl: Duration::from_secs(5),
+            max_stale: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// AddressFamily restricts the addresses a host resolves to.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn matches(self, ip: &IpAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => ip.is_ipv4(),
+            AddressFamily::Ipv6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// ResolveFn resolves a host name to its addresses, replacing the system resolver.
+pub type ResolveFn =
+    Arc<dyn Fn(String) -> BoxFuture<'static, io::Result<Vec<IpAddr>>> + Send + Sync>;
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+struct DnsLookup {
+    result: DnsLookupResult,
+}
+
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, EncodeLabelValue)]
+enum DnsLookupResult {
+    // Served from the cache, including cached failures.
+    Hit,
+    // Resolved, as nothing usable was cached.
+    Miss,
+    // Served from an expired entry, as resolving again failed.
+    Stale,
+}
+
+struct Entry {
+    result: Result<Vec<IpAddr>, String>,
+    resolved_at: Instant,
+}
+
+/// DnsCache caches the resolutions of the host names connections are dialed to, so that repeated
+/// dials neither wait on the resolver nor fail when it blips. Clones share the cache.
+#[derive(Clone)]
+pub struct DnsCache {
+    inner: Arc<DnsCacheInner>,
+}
+
+struct DnsCacheInner {
+    config: RwLock<DnsCacheConfig>,
+    entries: Mutex<HashMap<(String, AddressFamily), Entry>>,
+    resolve: Option<ResolveFn>,
+    lookups: Family<DnsLookup, Counter>,
+}
+
+impl fmt::Debug for DnsCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsCache")
+            .field("config", &*self.inner.config.read().unwrap())
+            .finish_non_exhaustive()
+    }
+}
+
+static GLOBAL: Lazy<DnsCache> = Lazy::new(|| DnsCache::new(DnsCacheConfig::default()));
+
+impl DnsCache {
+    pub fn new(config: DnsCacheConfig) -> Self {
+        Self::build(config, None)
+    }
+
+    /// with_resolve_override returns a cache resolving with resolve instead of the system
+    /// resolver, for tests.
+    pub fn with_resolve_override(config: DnsCacheConfig, resolve: ResolveFn) -> Self {
+        Self::build(config, Some(resolve))
+    }
+
+    fn build(config: DnsCacheConfig, resolve: Option<ResolveFn>) -> Self {
+        DnsCache {
+            inner: Arc::new(DnsCacheInner {
+                config: RwLock::new(config),
+                entries: Default::default(),
+                resolve,
+                lookups: Default::default(),
+            }),
+        }
+    }
+
+    /// global returns the cache used by control plane connectors, unless overridden on the
+    /// ConnectorConfig.
+    pub fn global() -> &'static DnsCache {
+        &GLOBAL
+    }
+
+    /// set_config changes the TTLs, applying to entries already cached as well.
+    pub fn set_config(&self, config: DnsCacheConfig) {
+        *self.inner.config.write().unwrap() = config;
+    }
+
+    /// register_metrics exposes the number of lookups by result in the registry.
+    pub fn register_metrics(&self, registry: &mut Registry) {
+        registry.register(
+            "dns_cache_lookups",
+            "The total number of host name lookups, by whether the cache was hit",
+            self.inner.lookups.clone(),
+        );
+    }
+
+    /// resolver returns a resolver for HttpConnector backed by the cache.
+    pub fn resolver(&self, family: AddressFamily) -> CachingResolver {
+        CachingResolver {
+            cache: self.clone(),
+            family,
+        }
+    }
+
+    /// resolve returns the addresses of host from family. IP addresses are returned as is.
+    pub async fn resolve(&self, host: &str, family: AddressFamily) -> io::Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let config = *self.inner.config.read().unwrap();
+        let key = (host.to_string(), family);
+        let stale = {
+            let entries = self.inner.entries.lock().unwrap();
+            match entries.get(&key) {
+                Some(entry) => {
+                    let age = entry.resolved_at.elapsed();
+                    match &entry.result {
+                        Ok(ips) if age < config.ttl => {
+                            self.record(DnsLookupResult::Hit);
+                            return Ok(ips.clone());
+                        }
+                        Err(err) if age < config.negative_ttl => {
+                            self.record(DnsLookupResult::Hit);
+                            return Err(io::Error::new(io::ErrorKind::NotFound, err.clone()));
+                        }
+                        Ok(ips) if age < config.ttl + config.max_stale => Some(ips.clone()),
+                        _ => None,
+                    }
+                }
+                None => None,
+            }
+        };
+
+        let result = match self.lookup(host).await {
+            Ok(ips) => {
+                let ips: Vec<IpAddr> = ips.into_iter().filter(|ip| family.matches(ip)).collect();
+                if ips.is_empty() {
+                    Err(format!("no {family:?} address found for {host}"))
+                } else {
+                    Ok(ips)
+                }
+            }
+            Err(err) => Err(err.to_string()),
+        };
+        match (&result, stale) {
+            (Err(err), Some(ips)) => {
+                // Keep the expired entry, so that resolving is retried on the next dial.
+                warn!(host, "failed to resolve, using stale addresses: {err}");
+                self.record(DnsLookupResult::Stale);
+                return Ok(ips);
+            }
+            _ => self.record(DnsLookupResult::Miss),
+        }
+        debug!(host, ?result, "resolved");
+        self.inner.entries.lock().unwrap().insert(
+            key,
+            Entry {
+                result: result.clone(),
+                resolved_at: Instant::now(),
+            },
+        );
+        result.map_err(|err| io::Error::new(io::ErrorKind::NotFound, err))
+    }
+
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        match &self.inner.resolve {
+            Some(resolve) => resolve(host.to_string()).await,
+            None => Ok(tokio::net::lookup_host((host, 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect()),
+        }
+    }
+
+    fn record(&self, result: DnsLookupResult) {
+        self.inner
+            .lookups
+            .get_or_create(&DnsLookup { result })
+            .inc();
+    }
+}
+
+/// CachingResolver resolves the host names dialed by an HttpConnector through a DnsCache.
+#[derive(Clone, Debug)]
+pub struct CachingResolver {
+    cache: DnsCache,
+    family: AddressFamily,
+}
+
+impl tower::Service<Name> for CachingResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let cache = self.cache.clone();
+        let family = self.family;
+        Box::pin(async move {
+            // The connector sets the port of the destination on the returned addresses.
+            let ips = cache.resolve(name.as_str(), family).await?;
+            Ok(ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect::<Vec<_>>()
+                .into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::IpAddr;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{AddressFamily, DnsCache, DnsCacheConfig};
+
+    // Returns a cache resolving every host to 10.0.0.1 and fd00::1, or failing while fail is set,
+    // along with the number of resolutions.
+    fn counting_cache(fail: Arc<AtomicBool>) -> (DnsCache, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let cache = DnsCache::with_resolve_override(
+            DnsCacheConfig {
+                ttl: Duration::from_secs(30),
+                negative_ttl: Duration::from_secs(5),
+                max_stale: Duration::from_secs(60),
+            },
+            Arc::new({
+                let count = count.clone();
+                move |_| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    let failing = fail.load(Ordering::SeqCst);
+                    Box::pin(async move {
+                        if failing {
+                            return Err(io::Error::new(io::ErrorKind::Other, "resolver down"));
+                        }
+                        Ok(vec![
+                            "10.0.0.1".parse::<IpAddr>().unwrap(),
+                            "fd00::1".parse().unwrap(),
+                        ])
+                    })
+                }
+            }),
+        );
+        (cache, count)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caches_resolutions() {
+        let fail = Arc::new(AtomicBool::new(false));
+        let (cache, count) = counting_cache(fail);
+        let v4: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap()];
+
+        assert_eq!(
+            cache.resolve("istiod", AddressFamily::Ipv4).await.unwrap(),
+            v4
+        );
+        // Within the TTL, the second dial does not resolve again.
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(
+            cache.resolve("istiod", AddressFamily::Ipv4).await.unwrap(),
+            v4
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        // The family is part of the key.
+        assert_eq!(
+            cache
+                .resolve("istiod", AddressFamily::Any)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        // Addresses are not resolved.
+        cache.resolve("10.0.0.2", AddressFamily::Any).await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // Once expired, the host is resolved again.
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        cache.resolve("istiod", AddressFamily::Ipv4).await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_if_error() {
+        let fail = Arc::new(AtomicBool::new(false));
+        let (cache, count) = counting_cache(fail.clone());
+        cache.resolve("istiod", AddressFamily::Ipv4).await.unwrap();
+
+        // The expired addresses are used while the resolver fails, resolving on every dial.
+        fail.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        for _ in 0..2 {
+            assert_eq!(
+                cache.resolve("istiod", AddressFamily::Ipv4).await.unwrap(),
+                vec!["10.0.0.1".parse::<IpAddr>().unwrap()]
+            );
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // Past max_stale, the failure is returned, and cached for the negative TTL.
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(cache.resolve("istiod", AddressFamily::Ipv4).await.is_err());
+        assert!(cache.resolve("istiod", AddressFamily::Ipv4).await.is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+        fail.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        cache.resolve("istiod", AddressFamily::Ipv4).await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 5);
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use boring::error::ErrorStack;
+
+// Hints for the reason codes users commonly run into, by reason. Reasons are the BoringSSL
+// reason strings, alerts received from the peer being prefixed with SSLV3_ALERT_ or TLSV1_ALERT_.
+const HINTS: &[(&str, &str)] = &[
+    ("WRONG_VERSION_NUMBER", "the peer likely sent plaintext"),
+    ("HTTP_REQUEST", "the peer likely sent plaintext"),
+    (
+        "HTTPS_PROXY_REQUEST",
+        "the peer sent an HTTP CONNECT request, it may be configured to use us as a proxy",
+    ),
+    (
+        "CERTIFICATE_VERIFY_FAILED",
+        "the peer certificate does not chain to a root we trust, or is expired",
+    ),
+    ("TLSV1_ALERT_UNKNOWN_CA", "the peer does not trust our root"),
+    (
+        "SSLV3_ALERT_CERTIFICATE_EXPIRED",
+        "the peer considers our certificate expired, check the clocks of both hosts",
+    ),
+    (
+        "SSLV3_ALERT_BAD_CERTIFICATE",
+        "the peer rejected our certificate",
+    ),
+    (
+        "TLSV13_ALERT_CERTIFICATE_REQUIRED",
+        "the peer requires a client certificate, which we did not send",
+    ),
+    (
+        "PEER_DID_NOT_RETURN_A_CERTIFICATE",
+        "the peer did not send a client certificate, it may not be part of the mesh",
+    ),
+    (
+        "DECRYPTION_FAILED_OR_BAD_RECORD_MAC",
+        "the data was corrupted in transit, or something between the peers modified it",
+    ),
+    (
+        "SSLV3_ALERT_BAD_RECORD_MAC",
+        "the data was corrupted in transit, or something between the peers modified it",
+    ),
+    (
+        "UNSUPPORTED_PROTOCOL",
+        "no TLS version is enabled on both peers",
+    ),
+    (
+        "TLSV1_ALERT_PROTOCOL_VERSION",
+        "no TLS version is enabled on both peers",
+    ),
+    ("NO_SHARED_CIPHER", "no cipher is enabled on both peers"),
+    (
+        "EXCESSIVE_MESSAGE_SIZE",
+        "the peer sent a certificate chain or handshake message over the size limit",
+    ),
+    (
+        "SSLV3_ALERT_HANDSHAKE_FAILURE",
+        "the peer could not agree on the handshake parameters, such as ciphers or ALPN",
+    ),
+];
+
+/// reason_hint returns a human explanation of the reason code of an SSL error, if it is a common one.
+pub fn reason_hint(reason: &str) -> Option<&'static str> {
+    HINTS
+        .iter()
+        .find(|(r, _)| *r == reason)
+        .map(|(_, hint)| *hint)
+}
+
+/// SslErrorDetail is an entry of an SSL error queue, with a hint of what it likely means.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SslErrorDetail {
+    pub lib: Option<&'static str>,
+    pub func: Option<&'static str>,
+    pub reason: Option<&'static str>,
+    pub hint: Option<&'static str>,
+}
+
+impl fmt::Display for SslErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let field = |v: Option<&'static str>| v.unwrap_or("unknown");
+        write!(
+            f,
+            "{}:{}:{}",
+            field(self.lib),
+            field(self.func),
+            field(self.reason)
+        )?;
+        if let Some(hint) = self.hint {
+            write!(f, " ({hint})")?;
+        }
+        Ok(())
+    }
+}
+
+/// explain returns the details of each error of e, oldest first.
+pub fn explain(e: &ErrorStack) -> Vec<SslErrorDetail> {
+    e.errors()
+        .iter()
+        .map(|e| SslErrorDetail {
+            lib: e.library(),
+            func: e.function(),
+            reason: e.reason(),
+            hint: e.reason().and_then(reason_hint),
+        })
+        .collect()
+}
+
+/// Explained displays an ErrorStack as its explained details.
+pub struct Explained<'a>(pub &'a ErrorStack);
+
+impl fmt::Display for Explained<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let details = explain(self.0);
+        if details.is_empty() {
+            return f.write_str("no error details");
+        }
+        for (i, detail) in details.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{detail}")?;
+        }
+        Ok(())
+    }
+}
+
+/// explain_handshake describes why a handshake failed: the I/O error that interrupted it, or the
+/// explained SSL errors.
+pub fn explain_handshake<S>(e: &tokio_boring::HandshakeError<S>) -> String {
+    match (e.as_io_error(), e.as_ssl_error_stack()) {
+        (Some(io), _) => io.to_string(),
+        (None, Some(stack)) => Explained(&stack).to_string(),
+        (None, None) => "handshake failed".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::identity::Identity;
+    use crate::tls::{generate_test_ca, generate_test_certs, generate_test_certs_with_ca};
+
+    use super::{explain_handshake, reason_hint};
+
+    #[test]
+    fn hints() {
+        assert_eq!(
+            reason_hint("TLSV1_ALERT_UNKNOWN_CA"),
+            Some("the peer does not trust our root")
+        );
+        assert_eq!(reason_hint("NOT_A_REASON"), None);
+    }
+
+    #[tokio::test]
+    async fn plaintext_to_tls() {
+        let certs = generate_test_certs(
+            &Identity::default().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let acceptor = certs.acceptor().unwrap();
+        let (mut client_io, server_io) = tokio::io::duplex(16 * 1024);
+        client_io
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let err = tokio_boring::accept(&acceptor, server_io)
+            .await
+            .err()
+            .unwrap();
+        let explained = explain_handshake(&err);
+        assert!(
+            explained.contains("the peer likely sent plaintext"),
+            "{explained}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_ca() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let (other_ca, other_key) = generate_test_ca("other");
+        let other = generate_test_certs_with_ca(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+            &other_ca,
+            &other_key,
+        );
+        let acceptor = other.acceptor().unwrap();
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(async move { tokio_boring::accept(&acceptor, server_io).await });
+        let mut cfg = certs.connector(&id).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let err = tokio_boring::connect(cfg, "", client_io)
+            .await
+            .err()
+            .unwrap();
+        let explained = explain_handshake(&err);
+        assert!(
+            explained.contains("CERTIFICATE_VERIFY_FAILED (the peer certificate does not chain"),
+            "{explained}"
+        );
+
+        // The server is told why by the alert the client sends.
+        let err = server.await.unwrap().err().unwrap();
+        let explained = explain_handshake(&err);
+        assert!(
+            explained.contains("the peer does not trust our root"),
+            "{explained}"
+        );
+    }
+}
@@ -0,0 +1,388 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use hyper::{Request, Uri};
+use tokio::time::Instant;
+use tonic::body::BoxBody;
+use tower::{Service, ServiceExt};
+use tracing::{info, warn};
+
+use super::retry::GRPC_STATUS_UNAVAILABLE;
+use super::{
+    grpc_connector, ChannelError, ChannelHealth, ConnectorConfig, Error, ProxyConnector,
+    TlsGrpcChannel,
+};
+use crate::config::RootCert;
+
+// Consecutive failures of the active endpoint after which the next one is used.
+const FAILOVER_THRESHOLD: u32 = 3;
+const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Endpoint is a control plane address, along with the root used to verify it.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: String,
+    pub root_cert: RootCert,
+}
+
+#[derive(Debug)]
+struct FailoverState {
+    // Index of the endpoint requests are sent to.
+    active: usize,
+    // Consecutive failures of the active endpoint.
+    failures: u32,
+    // Set while a failover endpoint is active, to check whether the primary is back.
+    next_probe: Option<Instant>,
+    probing: bool,
+    probe_interval: Duration,
+}
+
+// Shared across clones, and rebuilt channels.
+#[derive(Debug)]
+struct Shared {
+    endpoints: Vec<Endpoint>,
+    state: Mutex<FailoverState>,
+    failovers: AtomicU64,
+}
+
+/// FailoverChannel sends gRPC requests to the first of an ordered list of control plane
+/// endpoints that is reachable. Once the active endpoint fails to connect, or answers UNAVAILABLE,
+/// several times in a row, the next endpoint is used. While a failover endpoint is active, the
+/// primary is periodically probed, and used again once a connection to it can be established.
+#[derive(Debug)]
+pub struct FailoverChannel {
+    shared: Arc<Shared>,
+    cfg: ConnectorConfig,
+    channels: Vec<TlsGrpcChannel>,
+    // Endpoint whose channel poll_ready was called on, which the next call is sent to.
+    ready: Option<usize>,
+}
+
+impl Clone for FailoverChannel {
+    fn clone(&self) -> Self {
+        FailoverChannel {
+            shared: self.shared.clone(),
+            cfg: self.cfg.clone(),
+            channels: self.channels.clone(),
+            ready: None,
+        }
+    }
+}
+
+/// grpc_failover_connector provides a channel sending gRPC requests to the first reachable of
+/// endpoints, which must not be empty.
+pub fn grpc_failover_connector(
+    endpoints: Vec<Endpoint>,
+    cfg: ConnectorConfig,
+) -> Result<FailoverChannel, Error> {
+    assert!(!endpoints.is_empty(), "no control plane endpoint");
+    Ok(FailoverChannel {
+        channels: build_channels(&endpoints, &cfg)?,
+        shared: Arc::new(Shared {
+            endpoints,
+            state: Mutex::new(FailoverState {
+                active: 0,
+                failures: 0,
+                next_probe: None,
+                probing: false,
+                probe_interval: DEFAULT_PROBE_INTERVAL,
+            }),
+            failovers: Default::default(),
+        }),
+        cfg,
+        ready: None,
+    })
+}
+
+fn build_channels(
+    endpoints: &[Endpoint],
+    cfg: &ConnectorConfig,
+) -> Result<Vec<TlsGrpcChannel>, Error> {
+    endpoints
+        .iter()
+        .map(|e| grpc_connector(e.address.clone(), e.root_cert.clone(), cfg.clone()))
+        .collect()
+}
+
+impl FailoverChannel {
+    /// with_probe_interval sets how often the primary endpoint is probed while failed over.
+    pub fn with_probe_interval(self, interval: Duration) -> Self {
+        self.shared.state.lock().unwrap().probe_interval = interval;
+        self
+    }
+
+    /// rebuild returns a channel with new connections, which keeps using the active endpoint.
+    pub fn rebuild(&self) -> Result<FailoverChannel, Error> {
+        Ok(FailoverChannel {
+            channels: build_channels(&self.shared.endpoints, &self.cfg)?,
+            ..self.clone()
+        })
+    }
+
+    /// failovers returns the number of times the active endpoint was changed after failures.
+    pub fn failovers(&self) -> u64 {
+        self.shared.failovers.load(Ordering::Relaxed)
+    }
+
+    /// active_endpoint returns the address requests are currently sent to.
+    pub fn active_endpoint(&self) -> &str {
+        &self.shared.endpoints[self.shared.active()].address
+    }
+
+    /// health returns the connectivity of the active endpoint.
+    pub fn health(&self) -> ChannelHealth {
+        self.channels[self.shared.active()].health()
+    }
+
+    // Starts a probe of the primary endpoint, if one is due.
+    fn maybe_probe(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.next_probe {
+            Some(at) if !state.probing && at <= Instant::now() => state.probing = true,
+            _ => return,
+        }
+        drop(state);
+        let shared = self.shared.clone();
+        let cfg = self.cfg.clone();
+        tokio::spawn(async move {
+            let primary = &shared.endpoints[0];
+            let res = probe(primary, &cfg).await;
+            let mut state = shared.state.lock().unwrap();
+            state.probing = false;
+            match res {
+                Ok(()) => {
+                    info!(endpoint = %primary.address, "primary control plane endpoint is back");
+                    state.active = 0;
+                    state.failures = 0;
+                    state.next_probe = None;
+                }
+                Err(e) => {
+                    warn!(
+                        endpoint = %primary.address,
+                        "primary control plane endpoint still failing: {e}"
+                    );
+                    state.next_probe = Some(Instant::now() + state.probe_interval);
+                }
+            }
+        });
+    }
+}
+
+impl Shared {
+    fn active(&self) -> usize {
+        self.state.lock().unwrap().active
+    }
+
+    fn record_success(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        if state.active == index {
+            state.failures = 0;
+        }
+    }
+
+    fn record_failure(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        // Failures of requests sent before a failover don't count against the new endpoint.
+        if state.active != index || self.endpoints.len() < 2 {
+            return;
+        }
+        state.failures += 1;
+        if state.failures < FAILOVER_THRESHOLD {
+            return;
+        }
+        state.active = (index + 1) % self.endpoints.len();
+        state.failures = 0;
+        state.next_probe = (state.active != 0).then(|| Instant::now() + state.probe_interval);
+        self.failovers.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            from = %self.endpoints[index].address,
+            to = %self.endpoints[state.active].address,
+            "control plane endpoint failing, failing over"
+        );
+    }
+}
+
+// Checks that a connection to the endpoint can be established. Only the transport is checked,
+// so the primary is used again as soon as it accepts connections.
+async fn probe(endpoint: &Endpoint, cfg: &ConnectorConfig) -> Result<(), String> {
+    if let Some(path) = endpoint.address.strip_prefix("unix://") {
+        return tokio::net::UnixStream::connect(PathBuf::from(path))
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string());
+    }
+    let uri = Uri::try_from(&endpoint.address).map_err(|e| e.to_string())?;
+    let proxy = cfg.proxy_for(&uri).map_err(|e| e.to_string())?;
+    ProxyConnector::new(cfg.http_connector(), proxy)
+        .oneshot(uri)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+impl Service<Request<BoxBody>> for FailoverChannel {
+    type Response = <TlsGrpcChannel as Service<Request<BoxBody>>>::Response;
+    type Error = ChannelError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.maybe_probe();
+        let index = *self.ready.get_or_insert_with(|| self.shared.active());
+        let res = ready!(self.channels[index].poll_ready(cx));
+        if res.is_err() {
+            self.ready = None;
+            self.shared.record_failure(index);
+        }
+        Poll::Ready(res)
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        let index = self.ready.take().unwrap_or_else(|| self.shared.active());
+        let future = self.channels[index].call(req);
+        let shared = self.shared.clone();
+        Box::pin(async move {
+            let res = future.await;
+            let failed = match &res {
+                Ok(res) => res
+                    .headers()
+                    .get("grpc-status")
+                    .map(|s| s == GRPC_STATUS_UNAVAILABLE)
+                    .unwrap_or(false),
+                Err(e) => e.is_retryable(),
+            };
+            if failed {
+                shared.record_failure(index);
+            } else {
+                shared.record_success(index);
+            }
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::Request;
+    use tower::{Service, ServiceExt};
+
+    use super::*;
+
+    // Serves h2 on a unix socket, one connection at a time, so aborting the task closes the
+    // connection. Every request is answered OK and counted in hits.
+    fn spawn_server(path: &Path, hits: Arc<AtomicU64>) -> tokio::task::JoinHandle<()> {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let hits = hits.clone();
+                let _ = crate::hyper_util::http2_server()
+                    .serve_connection(
+                        socket,
+                        hyper::service::service_fn(move |_| {
+                            hits.fetch_add(1, Ordering::SeqCst);
+                            async move {
+                                Ok::<_, std::convert::Infallible>(
+                                    hyper::Response::builder()
+                                        .header("content-type", "application/grpc")
+                                        .header("grpc-status", "0")
+                                        .body(http_body_util::Empty::<bytes::Bytes>::new())
+                                        .unwrap(),
+                                )
+                            }
+                        }),
+                    )
+                    .await;
+            }
+        })
+    }
+
+    async fn send(channel: &mut FailoverChannel) -> Result<(), ChannelError> {
+        let req = Request::builder()
+            .uri("/test.Service/Method")
+            .body(tonic::body::empty_body())
+            .unwrap();
+        channel.ready().await?.call(req).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn fails_over_and_back() {
+        let dir = std::env::temp_dir();
+        let id = rand::random::<u64>();
+        let primary = dir.join(format!("ztunnel-primary-{id}.sock"));
+        let secondary = dir.join(format!("ztunnel-secondary-{id}.sock"));
+        let (primary_hits, secondary_hits) =
+            (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        let primary_server = spawn_server(&primary, primary_hits.clone());
+        let _secondary_server = spawn_server(&secondary, secondary_hits.clone());
+
+        let endpoint = |path: &Path| Endpoint {
+            address: format!("unix://{}", path.display()),
+            root_cert: RootCert::Default,
+        };
+        let mut channel = grpc_failover_connector(
+            vec![endpoint(&primary), endpoint(&secondary)],
+            ConnectorConfig::default(),
+        )
+        .unwrap()
+        .with_probe_interval(Duration::from_millis(100));
+
+        send(&mut channel).await.unwrap();
+        assert_eq!(primary_hits.load(Ordering::SeqCst), 1);
+        assert_eq!(channel.failovers(), 0);
+
+        // The primary goes away mid-run; requests fail until the channel fails over.
+        primary_server.abort();
+        let _ = primary_server.await;
+        std::fs::remove_file(&primary).unwrap();
+        for _ in 0..10 {
+            if send(&mut channel).await.is_ok() {
+                break;
+            }
+        }
+        assert_eq!(channel.failovers(), 1);
+        assert_eq!(channel.active_endpoint(), endpoint(&secondary).address);
+        send(&mut channel).await.unwrap();
+        assert!(secondary_hits.load(Ordering::SeqCst) >= 1);
+
+        // Once the primary is back, a probe switches back to it.
+        let _primary_server = spawn_server(&primary, primary_hits.clone());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while channel.active_endpoint() != endpoint(&primary).address {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                // Requests start the probes.
+                send(&mut channel).await.unwrap();
+            }
+        })
+        .await
+        .expect("did not fail back to the primary");
+        send(&mut channel).await.unwrap();
+        assert_eq!(primary_hits.load(Ordering::SeqCst), 2);
+        assert_eq!(channel.failovers(), 1);
+
+        std::fs::remove_file(&primary).unwrap();
+        std::fs::remove_file(&secondary).unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+use tracing::debug;
+
+use super::{HandshakeDirection, TlsMetrics};
+
+/// IdleTimeoutStream wraps an accepted stream, typically a tokio_boring::SslStream, and shuts it
+/// down once nothing was read or written for the idle timeout: for TLS streams, close_notify is
+/// sent before the TCP connection is shut down. Reads and writes then fail with
+/// io::ErrorKind::TimedOut, so the stream is dropped by its owner.
+///
+/// The timeout is enforced while the stream is polled for reading, which servers do for the whole
+/// life of a connection. It never fires while a write is in flight, such as one waiting for a slow
+/// peer to read.
+pub struct IdleTimeoutStream<S> {
+    inner: S,
+    idle: Option<Idle>,
+    metrics: Option<Arc<dyn TlsMetrics>>,
+}
+
+struct Idle {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    last_activity: Instant,
+    write_pending: bool,
+    state: State,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Open,
+    ShuttingDown,
+    Closed,
+}
+
+impl<S> IdleTimeoutStream<S> {
+    /// new wraps inner, closing it after timeout of inactivity. Without a timeout, the stream is
+    /// never closed. Idle closes are recorded in metrics, if set.
+    pub fn new(inner: S, timeout: Option<Duration>, metrics: Option<Arc<dyn TlsMetrics>>) -> Self {
+        let idle = timeout.map(|timeout| {
+            let now = Instant::now();
+            Idle {
+                timeout,
+                sleep: Box::pin(tokio::time::sleep_until(now + timeout)),
+                last_activity: now,
+                write_pending: false,
+                state: State::Open,
+            }
+        });
+        IdleTimeoutStream {
+            inner,
+            idle,
+            metrics,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn activity(&mut self) {
+        if let Some(idle) = &mut self.idle {
+            idle.last_activity = Instant::now();
+        }
+    }
+
+    fn set_write_pending(&mut self, pending: bool) {
+        if let Some(idle) = &mut self.idle {
+            idle.write_pending = pending;
+        }
+    }
+
+    fn closed(&self) -> bool {
+        matches!(&self.idle, Some(idle) if idle.state != State::Open)
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "connection was idle for too long")
+}
+
+impl<S: AsyncWrite + Unpin> IdleTimeoutStream<S> {
+    // Completes with an error once the stream was idle for the timeout and has been shut down.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        let Some(idle) = &mut self.idle else {
+            return Poll::Pending;
+        };
+        loop {
+            match idle.state {
+                State::Open => {
+                    ready!(idle.sleep.as_mut().poll(cx));
+                    let now = Instant::now();
+                    if idle.write_pending {
+                        idle.last_activity = now;
+                    }
+                    let deadline = idle.last_activity + idle.timeout;
+                    if deadline > now {
+                        idle.sleep.as_mut().reset(deadline);
+                        continue;
+                    }
+                    debug!(timeout=?idle.timeout, "closing idle connection");
+                    if let Some(metrics) = &self.metrics {
+                        metrics.idle_closed(HandshakeDirection::Inbound);
+                    }
+                    idle.state = State::ShuttingDown;
+                }
+                State::ShuttingDown => {
+                    // The connection is closed anyway, so a failure to say goodbye is ignored.
+                    let _ = ready!(Pin::new(&mut self.inner).poll_shutdown(cx));
+                    idle.state = State::Closed;
+                }
+                State::Closed => return Poll::Ready(timed_out()),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for IdleTimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closed() {
+            let filled = buf.filled().len();
+            if let Poll::Ready(res) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+                if buf.filled().len() > filled {
+                    this.activity();
+                }
+                return Poll::Ready(res);
+            }
+        }
+        this.poll_idle(cx).map(Err)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.closed() {
+            return this.poll_idle(cx).map(Err);
+        }
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.set_write_pending(res.is_pending());
+        if res.is_ready() {
+            this.activity();
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.closed() {
+            return this.poll_idle(cx).map(Err);
+        }
+        let res = Pin::new(&mut this.inner).poll_flush(cx);
+        this.set_write_pending(res.is_pending());
+        if res.is_ready() {
+            this.activity();
+        }
+        res
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.closed() {
+            return this.poll_idle(cx).map(Err);
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::identity::Identity;
+    use crate::tls::{HandshakeDirection, HandshakeFailureClass, ShedReason, TlsMetrics};
+
+    use super::IdleTimeoutStream;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[derive(Default)]
+    struct IdleMetrics(AtomicUsize);
+
+    impl TlsMetrics for IdleMetrics {
+        fn handshake_duration(&self, _: HandshakeDirection, _: Duration) {}
+        fn handshake_succeeded(&self, _: HandshakeDirection, _: &str, _: &str) {}
+        fn handshake_failed(&self, _: HandshakeDirection, _: HandshakeFailureClass) {}
+        fn drain_rejected(&self, _: HandshakeDirection) {}
+        fn idle_closed(&self, _: HandshakeDirection) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+        fn connection_denied(&self, _: HandshakeDirection, _: &str) {}
+        fn connection_limited(&self, _: HandshakeDirection, _: &Identity) {}
+        fn accept_abandoned(&self, _: HandshakeDirection) {}
+        fn accept_shed(&self, _: HandshakeDirection, _: ShedReason) {}
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_stream_closes() {
+        let metrics = Arc::new(IdleMetrics::default());
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = IdleTimeoutStream::new(server, Some(TIMEOUT), Some(metrics.clone()));
+
+        let start = tokio::time::Instant::now();
+        let mut buf = [0; 16];
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), TIMEOUT);
+        assert_eq!(metrics.0.load(Ordering::SeqCst), 1);
+
+        // The stream was shut down, and stays closed.
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert!(server.write_all(b"late").await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn active_stream_survives() {
+        let metrics = Arc::new(IdleMetrics::default());
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = IdleTimeoutStream::new(server, Some(TIMEOUT), Some(metrics.clone()));
+
+        // Activity in either direction pushes the deadline back.
+        let mut buf = [0; 4];
+        for i in 0..5 {
+            tokio::time::sleep(TIMEOUT / 2).await;
+            if i % 2 == 0 {
+                client.write_all(b"ping").await.unwrap();
+                server.read_exact(&mut buf).await.unwrap();
+            } else {
+                server.write_all(b"pong").await.unwrap();
+                client.read_exact(&mut buf).await.unwrap();
+            }
+        }
+        assert_eq!(metrics.0.load(Ordering::SeqCst), 0);
+
+        // Without a timeout, streams are never closed.
+        let (_client, server) = tokio::io::duplex(1024);
+        let mut server = IdleTimeoutStream::new(server, None, None);
+        let mut buf = [0; 4];
+        assert!(tokio::time::timeout(TIMEOUT * 10, server.read(&mut buf))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_flight_write() {
+        let metrics = Arc::new(IdleMetrics::default());
+        let (mut client, server) = tokio::io::duplex(4);
+        let server = IdleTimeoutStream::new(server, Some(TIMEOUT), Some(metrics.clone()));
+        let (mut read_half, mut write_half) = tokio::io::split(server);
+        let read = tokio::spawn(async move {
+            let start = tokio::time::Instant::now();
+            let mut buf = [0; 1];
+            let res = read_half.read(&mut buf).await;
+            (res, start.elapsed())
+        });
+        let write = tokio::spawn(async move { write_half.write_all(b"larger than 4 bytes").await });
+
+        // The write is stuck on a peer that does not read, but the stream is not closed.
+        tokio::time::sleep(TIMEOUT * 3).await;
+        assert!(!read.is_finished());
+        assert!(!write.is_finished());
+        assert_eq!(metrics.0.load(Ordering::SeqCst), 0);
+
+        // Once the write completes, the stream is closed after the timeout.
+        let mut buf = vec![0; 19];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(write.await.unwrap().is_ok());
+        let (res, elapsed) = read.await.unwrap();
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        assert!(elapsed > TIMEOUT * 3, "{elapsed:?}");
+        assert_eq!(metrics.0.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+
+use boring::ex_data;
+use boring::hash::MessageDigest;
+use boring::pkey::{self, PKey, PKeyRef, Private};
+use boring::rsa::Padding;
+use boring::sign::{RsaPssSaltlen, Signer, Verifier};
+use boring::ssl::{self, SslContextBuilder, SslRef};
+use boring::x509::X509Ref;
+use boring_sys::ssl_private_key_result_t;
+use foreign_types::ForeignTypeRef;
+use once_cell::sync::Lazy;
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use super::Error;
+
+/// KeyEngine performs the operations of private keys held outside of ztunnel's memory, such as in
+/// an HSM or TPM. The keys never leave the engine: handshakes hand it the data to sign through
+/// BoringSSL's private key method.
+pub trait KeyEngine: Send + Sync {
+    /// sign signs input, the handshake data before hashing, with the key key_id. algorithm is the
+    /// TLS SignatureScheme to use, such as 0x0403 for ecdsa_secp256r1_sha256 or 0x0804 for
+    /// rsa_pss_rsae_sha256, see sign_with_key.
+    fn sign(&self, key_id: &str, algorithm: u16, input: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// decrypt decrypts input with the RSA key key_id. It is only needed for the RSA key exchange
+    /// of TLS 1.2, which ztunnel does not negotiate, so engines may leave it unsupported.
+    fn decrypt(&self, key_id: &str, _input: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::KeyEngine(format!(
+            "decrypt is not supported for key {key_id:?}"
+        )))
+    }
+}
+
+/// PrivateKeyProvider supplies the private key of a Certs. InMemory keys are handed to BoringSSL,
+/// while handshakes using Engine keys call into the engine for each signature, so the key material
+/// is never held by ztunnel.
+#[derive(Clone)]
+pub enum PrivateKeyProvider {
+    InMemory(PKey<Private>),
+    Engine {
+        engine_id: String,
+        key_id: String,
+        engine: Arc<dyn KeyEngine>,
+    },
+}
+
+impl PrivateKeyProvider {
+    /// in_memory returns the key if it is held in memory, and None if it is held by an engine.
+    pub fn in_memory(&self) -> Option<&PKey<Private>> {
+        match self {
+            PrivateKeyProvider::InMemory(key) => Some(key),
+            PrivateKeyProvider::Engine { .. } => None,
+        }
+    }
+
+    // Identifies the key by address: that of the key, or of the engine along with the id of the
+    // key within it. A context built with the key holds a reference to it, so the address is not
+    // reused while the context is alive.
+    pub(super) fn identity(&self) -> (usize, Option<String>) {
+        match self {
+            PrivateKeyProvider::InMemory(key) => (key.as_ptr() as usize, None),
+            PrivateKeyProvider::Engine { key_id, engine, .. } => (
+                Arc::as_ptr(engine) as *const () as usize,
+                Some(key_id.clone()),
+            ),
+        }
+    }
+
+    // Checks that the key matches the public key of cert. Engine keys are asked to sign a
+    // challenge, which is verified against cert.
+    pub(super) fn check(&self, cert: &X509Ref) -> Result<(), Error> {
+        let public = cert.public_key()?;
+        let (engine_id, key_id, engine) = match self {
+            PrivateKeyProvider::InMemory(key) if public.public_eq(key) => return Ok(()),
+            PrivateKeyProvider::InMemory(_) => return Err(Error::KeyCertMismatch),
+            PrivateKeyProvider::Engine {
+                engine_id,
+                key_id,
+                engine,
+            } => (engine_id, key_id, engine),
+        };
+        let mismatch =
+            |reason: String| Error::EngineKeyMismatch(engine_id.clone(), key_id.clone(), reason);
+        let algorithm = check_algorithm(&public)
+            .ok_or_else(|| mismatch(format!("unsupported key type {}", public.id().as_raw())))?;
+        let mut challenge = [0u8; 32];
+        boring::rand::rand_bytes(&mut challenge)?;
+        let signature = engine
+            .sign(key_id, algorithm, &challenge)
+            .map_err(|e| mismatch(e.to_string()))?;
+        let (digest, pss) = signature_digest(algorithm).expect("check algorithms are known");
+        let mut verifier = Verifier::new(digest, &public)?;
+        if pss {
+            verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+            verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+        }
+        verifier.update(&challenge)?;
+        match verifier.verify(&signature) {
+            Ok(true) => Ok(()),
+            _ => Err(mismatch("signature does not verify".to_string())),
+        }
+    }
+
+    // Installs the key on a context. Engine keys are installed as a private key method.
+    #[allow(unsafe_code)]
+    pub(super) fn apply_ctx(&self, ctx: &mut SslContextBuilder) -> Result<(), Error> {
+        match self {
+            PrivateKeyProvider::InMemory(key) => ctx.set_private_key(key)?,
+            PrivateKeyProvider::Engine { key_id, engine, .. } => {
+                ctx.set_ex_data(*CTX_ENGINE_KEY_INDEX, EngineKey::new(engine, key_id));
+                // SAFETY: the method is static, and the key it reads is owned by ctx.
+                unsafe {
+                    boring_sys::SSL_CTX_set_private_key_method(ctx.as_ptr(), &ENGINE_KEY_METHOD)
+                };
+            }
+        }
+        Ok(())
+    }
+
+    // Installs the key on a single connection, overriding that of its context.
+    #[allow(unsafe_code)]
+    pub(super) fn apply_ssl(&self, ssl: &mut SslRef) -> Result<(), Error> {
+        match self {
+            PrivateKeyProvider::InMemory(key) => {
+                // Drop any engine of the context, which would take precedence over the key.
+                // SAFETY: a null method only clears that of ssl.
+                unsafe { boring_sys::SSL_set_private_key_method(ssl.as_ptr(), std::ptr::null()) };
+                ssl.set_private_key(key)?;
+            }
+            PrivateKeyProvider::Engine { key_id, engine, .. } => {
+                ssl.set_ex_data(*SSL_ENGINE_KEY_INDEX, EngineKey::new(engine, key_id));
+                // SAFETY: the method is static, and the key it reads is owned by ssl.
+                unsafe { boring_sys::SSL_set_private_key_method(ssl.as_ptr(), &ENGINE_KEY_METHOD) };
+            }
+        }
+        Ok(())
+    }
+}
+
+/// sign_with_key signs input with an in-memory key using the TLS SignatureScheme algorithm, as
+/// KeyEngine::sign does. It is meant for engines wrapping software keys, such as in tests.
+pub fn sign_with_key(
+    key: &PKeyRef<Private>,
+    algorithm: u16,
+    input: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let (digest, pss) = signature_digest(algorithm).ok_or_else(|| {
+        Error::KeyEngine(format!("unsupported signature algorithm {algorithm:#06x}"))
+    })?;
+    let mut signer = Signer::new(digest, key)?;
+    if pss {
+        signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+        signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+    }
+    signer.update(input)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+// Returns the digest of a TLS SignatureScheme, and whether it uses RSA-PSS padding.
+fn signature_digest(algorithm: u16) -> Option<(MessageDigest, bool)> {
+    Some(match algorithm {
+        0x0401 | 0x0403 => (MessageDigest::sha256(), false),
+        0x0501 | 0x0503 => (MessageDigest::sha384(), false),
+        0x0601 | 0x0603 => (MessageDigest::sha512(), false),
+        0x0804 => (MessageDigest::sha256(), true),
+        0x0805 => (MessageDigest::sha384(), true),
+        0x0806 => (MessageDigest::sha512(), true),
+        _ => return None,
+    })
+}
+
+// Returns the SignatureScheme used to check an engine key against the public key of its cert.
+fn check_algorithm(public: &PKeyRef<pkey::Public>) -> Option<u16> {
+    match (public.id(), public.bits()) {
+        (pkey::Id::RSA, _) => Some(0x0804),
+        (pkey::Id::EC, 256) => Some(0x0403),
+        (pkey::Id::EC, 384) => Some(0x0503),
+        (pkey::Id::EC, 521) => Some(0x0603),
+        _ => None,
+    }
+}
+
+// The engine and key of the handshakes of a context or connection.
+struct EngineKey {
+    engine: Arc<dyn KeyEngine>,
+    key_id: String,
+}
+
+impl EngineKey {
+    fn new(engine: &Arc<dyn KeyEngine>, key_id: &str) -> Self {
+        EngineKey {
+            engine: engine.clone(),
+            key_id: key_id.to_string(),
+        }
+    }
+}
+
+static CTX_ENGINE_KEY_INDEX: Lazy<ex_data::Index<ssl::SslContext, EngineKey>> =
+    Lazy::new(|| ssl::SslContext::new_ex_index().expect("ex index must be allocated"));
+static SSL_ENGINE_KEY_INDEX: Lazy<ex_data::Index<ssl::Ssl, EngineKey>> =
+    Lazy::new(|| ssl::Ssl::new_ex_index().expect("ex index must be allocated"));
+
+static ENGINE_KEY_METHOD: boring_sys::SSL_PRIVATE_KEY_METHOD = boring_sys::SSL_PRIVATE_KEY_METHOD {
+    sign: Some(engine_sign),
+    decrypt: Some(engine_decrypt),
+    complete: Some(engine_complete),
+};
+
+// Returns the engine key of ssl, set on the connection or else on its context.
+#[allow(unsafe_code)]
+unsafe fn engine_key<'a>(ssl: *mut boring_sys::SSL) -> Option<&'a EngineKey> {
+    let key = boring_sys::SSL_get_ex_data(ssl, SSL_ENGINE_KEY_INDEX.as_raw());
+    let key = if key.is_null() {
+        let ctx = boring_sys::SSL_get_SSL_CTX(ssl);
+        boring_sys::SSL_CTX_get_ex_data(ctx, CTX_ENGINE_KEY_INDEX.as_raw())
+    } else {
+        key
+    };
+    (key as *const EngineKey).as_ref()
+}
+
+// Runs an operation of the engine key of ssl, copying its output to out.
+#[allow(unsafe_code)]
+unsafe fn run_engine_op(
+    ssl: *mut boring_sys::SSL,
+    out: *mut u8,
+    out_len: *mut usize,
+    max_out: usize,
+    op: impl FnOnce(&EngineKey) -> Result<Vec<u8>, Error>,
+) -> ssl_private_key_result_t {
+    let Some(key) = engine_key(ssl) else {
+        return ssl_private_key_result_t::ssl_private_key_failure;
+    };
+    // Panics must not unwind into BoringSSL.
+    let output = match catch_unwind(AssertUnwindSafe(|| op(key))) {
+        Ok(Ok(output)) => Zeroizing::new(output),
+        Ok(Err(e)) => {
+            warn!(key_id = %key.key_id, "key engine operation failed: {e}");
+            return ssl_private_key_result_t::ssl_private_key_failure;
+        }
+        Err(_) => {
+            warn!(key_id = %key.key_id, "key engine operation panicked");
+            return ssl_private_key_result_t::ssl_private_key_failure;
+        }
+    };
+    if output.len() > max_out {
+        warn!(key_id = %key.key_id, "key engine output is too large");
+        return ssl_private_key_result_t::ssl_private_key_failure;
+    }
+    std::ptr::copy_nonoverlapping(output.as_ptr(), out, output.len());
+    *out_len = output.len();
+    ssl_private_key_result_t::ssl_private_key_success
+}
+
+#[allow(unsafe_code)]
+unsafe extern "C" fn engine_sign(
+    ssl: *mut boring_sys::SSL,
+    out: *mut u8,
+    out_len: *mut usize,
+    max_out: usize,
+    algorithm: u16,
+    input: *const u8,
+    in_len: usize,
+) -> ssl_private_key_result_t {
+    let input = std::slice::from_raw_parts(input, in_len);
+    run_engine_op(ssl, out, out_len, max_out, |key| {
+        key.engine.sign(&key.key_id, algorithm, input)
+    })
+}
+
+#[allow(unsafe_code)]
+unsafe extern "C" fn engine_decrypt(
+    ssl: *mut boring_sys::SSL,
+    out: *mut u8,
+    out_len: *mut usize,
+    max_out: usize,
+    input: *const u8,
+    in_len: usize,
+) -> ssl_private_key_result_t {
+    let input = std::slice::from_raw_parts(input, in_len);
+    run_engine_op(ssl, out, out_len, max_out, |key| {
+        key.engine.decrypt(&key.key_id, input)
+    })
+}
+
+// Operations complete within sign and decrypt, so there is never one pending.
+#[allow(unsafe_code)]
+unsafe extern "C" fn engine_complete(
+    _ssl: *mut boring_sys::SSL,
+    _out: *mut u8,
+    _out_len: *mut usize,
+    _max_out: usize,
+) -> ssl_private_key_result_t {
+    ssl_private_key_result_t::ssl_private_key_failure
+}
+
+impl From<PKey<Private>> for PrivateKeyProvider {
+    fn from(key: PKey<Private>) -> Self {
+        PrivateKeyProvider::InMemory(key)
+    }
+}
+
+impl PartialEq for PrivateKeyProvider {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (PrivateKeyProvider::InMemory(a), PrivateKeyProvider::InMemory(b)) => {
+                match (a.private_key_to_der(), b.private_key_to_der()) {
+                    (Ok(a), Ok(b)) => Zeroizing::new(a) == Zeroizing::new(b),
+                    _ => false,
+                }
+            }
+            (
+                PrivateKeyProvider::Engine {
+                    engine_id: a_engine,
+                    key_id: a_key,
+                    ..
+                },
+                PrivateKeyProvider::Engine {
+                    engine_id: b_engine,
+                    key_id: b_key,
+                    ..
+                },
+            ) => a_engine == b_engine && a_key == b_key,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for PrivateKeyProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrivateKeyProvider::InMemory(_) => f.write_str("InMemory(<redacted>)"),
+            PrivateKeyProvider::Engine {
+                engine_id, key_id, ..
+            } => f
+                .debug_struct("Engine")
+                .field("engine_id", engine_id)
+                .field("key_id", key_id)
+                .finish(),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::identity::Identity;
+
+/// IdentityLimits caps the concurrent inbound connections of each client identity, so that a
+/// single misbehaving client cannot exhaust file descriptors.
+#[derive(serde::Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdentityLimits {
+    pub max_connections: usize,
+    /// Identities whose connections are not limited, such as gateways.
+    #[serde(serialize_with = "serialize_identities")]
+    pub exempt: HashSet<Identity>,
+}
+
+fn serialize_identities<S: serde::Serializer>(
+    identities: &HashSet<Identity>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    let mut identities: Vec<String> = identities.iter().map(Identity::to_string).collect();
+    identities.sort();
+    s.collect_seq(identities)
+}
+
+/// IdentityLimiter counts the open connections of each client identity against IdentityLimits.
+/// Clones share the counts.
+#[derive(Clone, Debug)]
+pub struct IdentityLimiter {
+    limits: Arc<IdentityLimits>,
+    open: Arc<Mutex<HashMap<Identity, usize>>>,
+}
+
+impl IdentityLimiter {
+    pub fn new(limits: IdentityLimits) -> Self {
+        IdentityLimiter {
+            limits: Arc::new(limits),
+            open: Default::default(),
+        }
+    }
+
+    /// acquire counts a connection of identity, until the returned permit is dropped. It fails if
+    /// identity already has as many connections open as allowed. Exempt identities get a permit
+    /// that counts nothing.
+    pub fn acquire(&self, identity: &Identity) -> Result<IdentityPermit, Identity> {
+        if self.limits.exempt.contains(identity) {
+            return Ok(IdentityPermit(None));
+        }
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(identity.clone()).or_default();
+        if *count >= self.limits.max_connections {
+            return Err(identity.clone());
+        }
+        *count += 1;
+        Ok(IdentityPermit(Some((self.clone(), identity.clone()))))
+    }
+
+    /// open returns the number of connections of identity counted.
+    pub fn open(&self, identity: &Identity) -> usize {
+        self.open
+            .lock()
+            .unwrap()
+            .get(identity)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn release(&self, identity: &Identity) {
+        let mut open = self.open.lock().unwrap();
+        if let Some(count) = open.get_mut(identity) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(identity);
+            }
+        }
+    }
+}
+
+/// IdentityPermit keeps a connection counted by an IdentityLimiter while it is alive.
+#[derive(Debug)]
+pub struct IdentityPermit(Option<(IdentityLimiter, Identity)>);
+
+impl Drop for IdentityPermit {
+    fn drop(&mut self) {
+        if let Some((limiter, identity)) = &self.0 {
+            limiter.release(identity);
+        }
+    }
+}
@@ -0,0 +1,419 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{debug, warn};
+
+use crate::socket::SocketConfig;
+
+use super::{
+    BoringTlsAcceptor, CertProvider, DrainSignal, IdleTimeoutStream, LoadShedPolicy, StreamGuard,
+    TlsError, TlsMetrics,
+};
+
+type AcceptStream = Pin<Box<dyn Stream<Item = (PlainOrTls, Option<StreamGuard>)> + Send>>;
+
+/// PortPolicy is how a port of a PlainOrTlsListener serves its connections.
+pub enum PortPolicy<F> {
+    /// TLS is terminated with the certificates of the provider.
+    Tls(F),
+    /// Connections are served without TLS, such as those of the admin and metrics ports.
+    Plain,
+}
+
+/// PlainOrTls is a connection accepted on a TLS or a plaintext port of a PlainOrTlsListener.
+pub enum PlainOrTls {
+    Tls(tokio_boring::SslStream<TcpStream>),
+    Plain(TcpStream),
+}
+
+impl PlainOrTls {
+    /// tcp returns the TCP connection, under TLS if any.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            PlainOrTls::Tls(stream) => stream.get_ref(),
+            PlainOrTls::Plain(stream) => stream,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, PlainOrTls::Tls(_))
+    }
+}
+
+impl AsyncRead for PlainOrTls {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PlainOrTls::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            PlainOrTls::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PlainOrTls {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PlainOrTls::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            PlainOrTls::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PlainOrTls::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            PlainOrTls::Plain(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PlainOrTls::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            PlainOrTls::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// ConnectionCounter counts the connections of a PlainOrTlsListener that are open. Clones share
+/// the count.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionCounter(Arc<AtomicUsize>);
+
+impl ConnectionCounter {
+    pub fn open(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+
+    fn count(&self) -> OpenConnection {
+        self.0.fetch_add(1, Ordering::AcqRel);
+        OpenConnection(self.0.clone())
+    }
+}
+
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// ListenedStream is a connection accepted by a PlainOrTlsListener. It is counted as open until it
+/// is dropped, and is shut down once idle for the idle timeout of the listener.
+pub struct ListenedStream {
+    inner: IdleTimeoutStream<PlainOrTls>,
+    // Plaintext connections have no Ssl to hold their guard, so they hold it here.
+    _guard: Option<StreamGuard>,
+    _open: OpenConnection,
+}
+
+impl ListenedStream {
+    pub fn get_ref(&self) -> &PlainOrTls {
+        self.inner.get_ref()
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.get_ref().is_tls()
+    }
+}
+
+impl AsyncRead for ListenedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ListenedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// PlainOrTlsListener serves TLS ports, such as the mTLS ports, and plaintext ports, such as the
+/// admin and metrics ports, from the same accept loop, so that they share draining, connection
+/// counting, idle timeouts, socket options and load shedding.
+///
+/// Once drain is signaled, new connections are refused on every port and the stream of accepted
+/// connections ends when the grace period of handshakes in flight is over. Established
+/// connections are left alone.
+pub struct PlainOrTlsListener<F> {
+    ports: Vec<(TcpListener, PortPolicy<F>)>,
+    drain: DrainSignal,
+    metrics: Option<Arc<dyn TlsMetrics>>,
+    socket: SocketConfig,
+    idle_timeout: Option<Duration>,
+    shed: Option<LoadShedPolicy>,
+    open: ConnectionCounter,
+}
+
+impl<F: CertProvider + Clone + 'static> PlainOrTlsListener<F> {
+    pub fn new(drain: DrainSignal) -> Self {
+        PlainOrTlsListener {
+            ports: Vec::new(),
+            drain,
+            metrics: None,
+            socket: Default::default(),
+            idle_timeout: None,
+            shed: None,
+            open: Default::default(),
+        }
+    }
+
+    /// port has the connections of listener served per policy.
+    pub fn port(mut self, listener: TcpListener, policy: PortPolicy<F>) -> Self {
+        self.ports.push((listener, policy));
+        self
+    }
+
+    /// with_metrics records the handshakes of the TLS ports, and the idle closes of every port.
+    pub fn with_metrics(mut self, metrics: Arc<dyn TlsMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// with_socket_config applies socket to every accepted connection.
+    pub fn with_socket_config(mut self, socket: SocketConfig) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    /// with_idle_timeout shuts connections down once nothing was read or written for timeout.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// with_load_shedding has new connections of every port refused while policy reports
+    /// resource pressure. Connections count against its open stream limit until dropped.
+    pub fn with_load_shedding(mut self, policy: LoadShedPolicy) -> Self {
+        self.shed = Some(policy);
+        self
+    }
+
+    /// connections returns the counter of the open connections of every port.
+    pub fn connections(&self) -> ConnectionCounter {
+        self.open.clone()
+    }
+
+    /// serve accepts the connections of every port, until draining is over. Connections that fail
+    /// to be accepted, such as those with a failed handshake, are logged and skipped.
+    pub fn serve(self) -> impl Stream<Item = ListenedStream> {
+        let PlainOrTlsListener {
+            ports,
+            drain,
+            metrics,
+            socket,
+            idle_timeout,
+            shed,
+            open,
+        } = self;
+        let accepted = ports.into_iter().map(|(listener, policy)| match policy {
+            PortPolicy::Tls(provider) => {
+                let acceptor = BoringTlsAcceptor {
+                    acceptor: provider,
+                    metrics: metrics.clone(),
+                    drain: drain.clone(),
+                    authorizer: None,
+                    limiter: None,
+                    socket,
+                    shed: shed.clone(),
+                    sni_routing: false,
+                };
+                tls_accepted(tls_listener::builder(acceptor).listen(listener))
+            }
+            PortPolicy::Plain => plain_accepted(listener, drain.clone(), socket, shed.clone()),
+        });
+        let accepted = futures_util::stream::select_all(accepted);
+        futures_util::StreamExt::take_until(
+            accepted,
+            Box::pin(async move { drain.expired().await }),
+        )
+        .map(move |(stream, guard)| ListenedStream {
+            inner: IdleTimeoutStream::new(stream, idle_timeout, metrics.clone()),
+            _guard: guard,
+            _open: open.count(),
+        })
+    }
+}
+
+// tls_accepted yields the streams of the TLS port of accepted. Their load shedding guard is held
+// by their Ssl.
+fn tls_accepted<F: CertProvider + Clone + 'static>(
+    accepted: tls_listener::TlsListener<TcpListener, BoringTlsAcceptor<F>>,
+) -> AcceptStream {
+    Box::pin(accepted.filter_map(|conn| match conn {
+        Err(tls_listener::Error::TlsAcceptError(TlsError::Draining)) => {
+            debug!("refused connection while draining");
+            None
+        }
+        Err(tls_listener::Error::TlsAcceptError(TlsError::Shed(reason))) => {
+            debug!(%reason, "refused connection under resource pressure");
+            None
+        }
+        Err(tls_listener::Error::TlsAcceptError(err)) => {
+            warn!(reason = %err.classification(), "TLS handshake error: {}", err);
+            None
+        }
+        Err(err) => {
+            warn!("TLS handshake error: {}", err);
+            None
+        }
+        Ok(s) => Some((PlainOrTls::Tls(s), None)),
+    }))
+}
+
+// plain_accepted yields the connections of the plaintext port listener, refusing them like the
+// TLS ports do while draining or shed.
+fn plain_accepted(
+    listener: TcpListener,
+    drain: DrainSignal,
+    socket: SocketConfig,
+    shed: Option<LoadShedPolicy>,
+) -> AcceptStream {
+    Box::pin(TcpListenerStream::new(listener).filter_map(move |conn| {
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("accept error: {}", err);
+                return None;
+            }
+        };
+        if drain.is_draining() {
+            debug!("refused connection while draining");
+            return None;
+        }
+        let guard = match &shed {
+            Some(policy) => match policy.admit() {
+                Ok(guard) => Some(guard),
+                Err(reason) => {
+                    debug!(%reason, "refused connection under resource pressure");
+                    return None;
+                }
+            },
+            None => None,
+        };
+        if let Err(e) = socket.apply(&conn) {
+            warn!("failed to apply socket options: {e}");
+        }
+        Some((PlainOrTls::Plain(conn), guard))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::StreamExt;
+
+    use crate::identity::Identity;
+    use crate::tls::{generate_test_certs, ControlPlaneCertProvider, DrainSignal};
+
+    use super::{PlainOrTlsListener, PortPolicy};
+
+    #[tokio::test]
+    async fn tls_and_plain_ports() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let tls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plain = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (tls_addr, plain_addr) = (tls.local_addr().unwrap(), plain.local_addr().unwrap());
+        let drain = DrainSignal::default();
+        let listener = PlainOrTlsListener::new(drain.clone())
+            .port(
+                tls,
+                PortPolicy::Tls(ControlPlaneCertProvider(certs.clone())),
+            )
+            .port(plain, PortPolicy::Plain);
+        let connections = listener.connections();
+        let mut accepted = Box::pin(listener.serve());
+
+        let mut cfg = certs.connector(&id).unwrap().configure().unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let tcp = TcpStream::connect(tls_addr).await.unwrap();
+        let (client, server) = tokio::join!(tokio_boring::connect(cfg, "", tcp), accepted.next());
+        let (mut tls_client, mut tls_server) = (client.unwrap(), server.unwrap());
+        assert!(tls_server.is_tls());
+
+        let mut plain_client = TcpStream::connect(plain_addr).await.unwrap();
+        let mut plain_server = accepted.next().await.unwrap();
+        assert!(!plain_server.is_tls());
+        assert_eq!(
+            plain_server.get_ref().tcp().local_addr().unwrap(),
+            plain_addr
+        );
+        assert_eq!(connections.open(), 2);
+
+        // Both are served through the same stream type.
+        let mut buf = [0; 4];
+        tls_client.write_all(b"ping").await.unwrap();
+        tls_server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        plain_client.write_all(b"pong").await.unwrap();
+        plain_server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // Draining refuses new connections, and ends the stream once the grace
+        // period is over. Established connections keep working.
+        drain.drain(Duration::from_millis(50));
+        let mut refused = TcpStream::connect(plain_addr).await.unwrap();
+        assert!(accepted.next().await.is_none());
+        assert_eq!(refused.read(&mut buf).await.unwrap(), 0);
+        plain_server.write_all(b"done").await.unwrap();
+        plain_client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"done");
+
+        drop((tls_server, plain_server));
+        assert_eq!(connections.open(), 0);
+    }
+}
@@ -0,0 +1,584 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+use boring::ssl;
+use tokio::net::TcpStream;
+use tracing::{debug_span, warn, Instrument};
+
+use crate::identity::Identity;
+use crate::socket::SocketConfig;
+
+use super::boring::record_negotiated;
+use super::{
+    ExpectedPeer, HandshakeFailure, HandshakeFailureClass, HandshakeOffload, ShedReason, TlsBuffers,
+};
+
+/// HandshakeDirection tells whether a TLS handshake was accepted or initiated by ztunnel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HandshakeDirection {
+    Inbound,
+    Outbound,
+}
+
+/// TlsMetrics records the latency and outcome of TLS handshakes. It is handed to the acceptors and
+/// to connect, so the tls module does not depend on the metrics registry.
+pub trait TlsMetrics: Send + Sync {
+    /// handshake_duration records the time a handshake took, whether it succeeded or not.
+    fn handshake_duration(&self, direction: HandshakeDirection, duration: Duration);
+    /// handshake_succeeded records a completed handshake, and the version and cipher negotiated.
+    fn handshake_succeeded(&self, direction: HandshakeDirection, version: &str, cipher: &str);
+    /// handshake_failed records a failed handshake, and why it failed.
+    fn handshake_failed(&self, direction: HandshakeDirection, class: HandshakeFailureClass);
+    /// drain_rejected records a connection refused, or a handshake cut short, because the acceptor
+    /// is draining.
+    fn drain_rejected(&self, direction: HandshakeDirection);
+    /// idle_closed records a connection closed after being idle for too long.
+    fn idle_closed(&self, direction: HandshakeDirection);
+    /// connection_denied records a connection closed after its handshake, because it was not
+    /// authorized for the reason given.
+    fn connection_denied(&self, direction: HandshakeDirection, reason: &str);
+    /// connection_limited records a connection closed after its handshake, because its client
+    /// identity had too many connections open.
+    fn connection_limited(&self, direction: HandshakeDirection, identity: &Identity);
+    /// accept_abandoned records a connection closed by its client before the handshake started,
+    /// such as while its certificate was fetched.
+    fn accept_abandoned(&self, direction: HandshakeDirection);
+    /// accept_shed records a connection refused before its handshake, because of resource
+    /// pressure.
+    fn accept_shed(&self, direction: HandshakeDirection, reason: ShedReason);
+}
+
+/// record_handshake records in metrics a handshake started at start, negotiating ssl or failing
+/// for the reason given.
+pub(super) fn record_handshake(
+    metrics: &dyn TlsMetrics,
+    direction: HandshakeDirection,
+    start: Instant,
+    outcome: Result<&ssl::SslRef, HandshakeFailureClass>,
+) {
+    metrics.handshake_duration(direction, start.elapsed());
+    match outcome {
+        Ok(ssl) => {
+            let cipher = ssl.current_cipher().map(|c| c.name()).unwrap_or("unknown");
+            metrics.handshake_succeeded(direction, ssl.version_str(), cipher);
+        }
+        Err(class) => metrics.handshake_failed(direction, class),
+    }
+}
+
+/// connect performs a TLS handshake as a client over stream with dest, recording its outcome in
+/// metrics if set. Socket options are applied to stream first; failing to is only logged.
+pub async fn connect(
+    cfg: ssl::ConnectConfiguration,
+    stream: TcpStream,
+    dest: &ExpectedPeer,
+    socket: &SocketConfig,
+    metrics: Option<&dyn TlsMetrics>,
+) -> Result<tokio_boring::SslStream<TcpStream>, tokio_boring::HandshakeError<TcpStream>> {
+    if let Err(e) = socket.apply(&stream) {
+        warn!(identity = %dest, "failed to apply socket options: {e}");
+    }
+    let span = debug_span!(
+        "tls_connect",
+        peer = ?stream.peer_addr().ok(),
+        identity = %dest,
+        version = tracing::field::Empty,
+        cipher = tracing::field::Empty,
+    );
+    let start = Instant::now();
+    let mut res = HandshakeOffload::global()
+        .run(tokio_boring::connect(cfg, "", stream).instrument(span.clone()))
+        .await;
+    if let Ok(stream) = &mut res {
+        record_negotiated(&span, stream.ssl());
+        TlsBuffers::global().track(stream.ssl_mut(), None);
+    }
+    if let Some(metrics) = metrics {
+        let outcome = match &res {
+            Ok(stream) => Ok(stream.ssl()),
+            Err(e) => Err(HandshakeFailure::from(e).class),
+        };
+        record_handshake(metrics, HandshakeDirection::Outbound, start, outcome);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::identity::Identity;
+    use crate::tls::{
+        generate_test_certs, Accept, AcceptMeta, AllowAll, Authorization, AuthorizeConnection,
+        BoringTlsAcceptor, CertProvider, Certs, ConnectionInfo, ControlPlaneCertProvider,
+        HandshakeFailureClass, IdentityLimits, LoadShedConfig, LoadShedPolicy, MaybeTls,
+        ShedReason, TlsError,
+    };
+
+    use super::{connect, HandshakeDirection, TlsMetrics};
+
+    #[derive(Default)]
+    struct FakeMetrics {
+        durations: Mutex<Vec<HandshakeDirection>>,
+        succeeded: Mutex<Vec<(HandshakeDirection, String, String)>>,
+        failed: Mutex<Vec<(HandshakeDirection, HandshakeFailureClass)>>,
+        drain_rejected: Mutex<Vec<HandshakeDirection>>,
+        denied: Mutex<Vec<(HandshakeDirection, String)>>,
+        limited: Mutex<Vec<Identity>>,
+        abandoned: Mutex<Vec<HandshakeDirection>>,
+        shed: Mutex<Vec<ShedReason>>,
+    }
+
+    impl TlsMetrics for FakeMetrics {
+        fn handshake_duration(&self, direction: HandshakeDirection, _: Duration) {
+            self.durations.lock().unwrap().push(direction);
+        }
+
+        fn handshake_succeeded(&self, direction: HandshakeDirection, version: &str, cipher: &str) {
+            self.succeeded.lock().unwrap().push((
+                direction,
+                version.to_string(),
+                cipher.to_string(),
+            ));
+        }
+
+        fn handshake_failed(&self, direction: HandshakeDirection, class: HandshakeFailureClass) {
+            self.failed.lock().unwrap().push((direction, class));
+        }
+
+        fn drain_rejected(&self, direction: HandshakeDirection) {
+            self.drain_rejected.lock().unwrap().push(direction);
+        }
+
+        fn idle_closed(&self, _: HandshakeDirection) {}
+
+        fn connection_denied(&self, direction: HandshakeDirection, reason: &str) {
+            self.denied
+                .lock()
+                .unwrap()
+                .push((direction, reason.to_string()));
+        }
+
+        fn connection_limited(&self, _: HandshakeDirection, identity: &Identity) {
+            self.limited.lock().unwrap().push(identity.clone());
+        }
+
+        fn accept_abandoned(&self, direction: HandshakeDirection) {
+            self.abandoned.lock().unwrap().push(direction);
+        }
+
+        fn accept_shed(&self, _: HandshakeDirection, reason: ShedReason) {
+            self.shed.lock().unwrap().push(reason);
+        }
+    }
+
+    // DenyIdentity denies connections from one identity.
+    struct DenyIdentity(Identity);
+
+    impl AuthorizeConnection for DenyIdentity {
+        fn authorize(&self, conn: &ConnectionInfo) -> Authorization {
+            if conn.peer_identities.contains(&self.0) {
+                Authorization::Deny("denied-identity".to_string())
+            } else {
+                Authorization::Allow
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn records_handshakes() {
+        let id = Identity::default();
+        let other = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "other".to_string(),
+            service_account: "other".to_string(),
+        };
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let server_metrics = Arc::new(FakeMetrics::default());
+        let acceptor = BoringTlsAcceptor {
+            acceptor: ControlPlaneCertProvider(certs.clone()),
+            metrics: Some(server_metrics.clone()),
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        };
+        let client_metrics = FakeMetrics::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The first handshake succeeds, the second fails as the server is not the expected peer.
+        for dest in [&id, &other] {
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            let server = {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move { acceptor.accept_maybe_tls(conn, false).await })
+            };
+            let mut cfg = certs.connector(dest).unwrap().configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            let res = connect(
+                cfg,
+                tcp,
+                &dest.into(),
+                &Default::default(),
+                Some(&client_metrics),
+            )
+            .await;
+            assert_eq!(res.is_ok(), dest == &id);
+            drop(res);
+            let _ = server.await.unwrap();
+        }
+
+        for (metrics, direction) in [
+            (&client_metrics, HandshakeDirection::Outbound),
+            (server_metrics.as_ref(), HandshakeDirection::Inbound),
+        ] {
+            assert_eq!(*metrics.durations.lock().unwrap(), vec![direction; 2]);
+            let succeeded = metrics.succeeded.lock().unwrap();
+            assert_eq!(succeeded.len(), 1);
+            assert_eq!(succeeded[0].0, direction);
+            assert_eq!(succeeded[0].1, "TLSv1.3");
+            assert!(!succeeded[0].2.is_empty());
+            let failed = metrics.failed.lock().unwrap();
+            assert_eq!(failed.len(), 1);
+            assert_eq!(failed[0].0, direction);
+        }
+    }
+    #[tokio::test]
+    async fn authorizes_connections() {
+        let id = Identity::default();
+        let denied = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "other".to_string(),
+            service_account: "other".to_string(),
+        };
+        let server_certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let metrics = Arc::new(FakeMetrics::default());
+        let acceptor = BoringTlsAcceptor {
+            acceptor: ControlPlaneCertProvider(server_certs),
+            metrics: Some(metrics.clone()),
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let cases: [(Arc<dyn AuthorizeConnection>, &Identity, bool); 3] = [
+            (Arc::new(DenyIdentity(denied.clone())), &denied, false),
+            (Arc::new(DenyIdentity(denied.clone())), &id, true),
+            (Arc::new(AllowAll), &denied, true),
+        ];
+        for (authorizer, client, allowed) in cases {
+            let client_certs = generate_test_certs(
+                &client.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            );
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            let server = {
+                let acceptor = acceptor.clone().with_authorizer(authorizer);
+                tokio::spawn(async move { acceptor.accept_maybe_tls(conn, false).await })
+            };
+            let mut cfg = client_certs.connector(&id).unwrap().configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            // The handshake completes either way, authorization happens after it.
+            let mut stream = connect(cfg, tcp, &(&id).into(), &Default::default(), None)
+                .await
+                .unwrap();
+            let res = server.await.unwrap();
+            if allowed {
+                assert!(res.is_ok());
+            } else {
+                assert!(
+                    matches!(res, Err(TlsError::Unauthorized(ref reason)) if reason == "denied-identity")
+                );
+                // Denied clients see a clean close.
+                let mut buf = [0; 1];
+                assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+            }
+        }
+
+        assert_eq!(
+            *metrics.denied.lock().unwrap(),
+            vec![(HandshakeDirection::Inbound, "denied-identity".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_connections_per_identity() {
+        let id = Identity::default();
+        let other = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "other".to_string(),
+            service_account: "other".to_string(),
+        };
+        let server_certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let metrics = Arc::new(FakeMetrics::default());
+        let acceptor = BoringTlsAcceptor {
+            acceptor: ControlPlaneCertProvider(server_certs),
+            metrics: Some(metrics.clone()),
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        }
+        .with_identity_limits(IdentityLimits {
+            max_connections: 2,
+            ..Default::default()
+        });
+        let limiter = acceptor.limiter.clone().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut open = Vec::new();
+        for (client, allowed) in [(&id, true), (&id, true), (&id, false), (&other, true)] {
+            let client_certs = generate_test_certs(
+                &client.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            );
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            let server = {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move { acceptor.accept_maybe_tls(conn, false).await })
+            };
+            let mut cfg = client_certs.connector(&id).unwrap().configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            let mut stream = connect(cfg, tcp, &(&id).into(), &Default::default(), None)
+                .await
+                .unwrap();
+            match server.await.unwrap() {
+                Ok(server) => {
+                    assert!(allowed);
+                    open.push((server, stream));
+                }
+                Err(err) => {
+                    assert!(!allowed);
+                    assert!(
+                        matches!(err, TlsError::ConnectionLimit(ref limited) if limited == &id)
+                    );
+                    // Refused clients see a clean close.
+                    let mut buf = [0; 1];
+                    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+                }
+            }
+        }
+        assert_eq!(limiter.open(&id), 2);
+        assert_eq!(limiter.open(&other), 1);
+        assert_eq!(*metrics.limited.lock().unwrap(), vec![id.clone()]);
+
+        // Closing a connection frees its slot.
+        open.remove(0);
+        assert_eq!(limiter.open(&id), 1);
+    }
+
+    #[tokio::test]
+    async fn sheds_over_max_streams() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let metrics = Arc::new(FakeMetrics::default());
+        let policy = LoadShedPolicy::new(LoadShedConfig {
+            max_streams: Some(2),
+            send_alert: true,
+            ..Default::default()
+        });
+        let acceptor = BoringTlsAcceptor {
+            acceptor: ControlPlaneCertProvider(certs.clone()),
+            metrics: Some(metrics.clone()),
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        }
+        .with_load_shedding(policy.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut open = Vec::new();
+        for allowed in [true, true, false] {
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            let server = {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move { acceptor.accept_maybe_tls(conn, false).await })
+            };
+            let mut cfg = certs.connector(&id).unwrap().configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            let client = connect(cfg, tcp, &(&id).into(), &Default::default(), None).await;
+            match server.await.unwrap() {
+                Ok(MaybeTls::Tls(server)) => {
+                    assert!(allowed);
+                    open.push((server, client.unwrap()));
+                }
+                Ok(_) => panic!("expected a TLS stream"),
+                Err(err) => {
+                    assert!(!allowed);
+                    assert!(matches!(err, TlsError::Shed(ShedReason::MaxStreams)));
+                    // The refused client is told with an alert.
+                    assert!(client.is_err());
+                }
+            }
+        }
+        assert_eq!(policy.open_streams(), 2);
+        assert_eq!(*metrics.shed.lock().unwrap(), vec![ShedReason::MaxStreams]);
+        // The handshake of the refused client never started.
+        assert_eq!(metrics.durations.lock().unwrap().len(), 2);
+
+        // The admitted streams keep working.
+        for (server, client) in &mut open {
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+
+        // Closing a stream frees its slot.
+        open.remove(0);
+        assert_eq!(policy.open_streams(), 1);
+    }
+
+    // SlowCertProvider takes a while to provide its certificate, and records whether it did.
+    #[derive(Clone)]
+    struct SlowCertProvider {
+        certs: Certs,
+        fetched: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl CertProvider for SlowCertProvider {
+        async fn fetch_cert(
+            &mut self,
+            _: &tokio::net::TcpStream,
+            _: &AcceptMeta,
+        ) -> Result<Accept, TlsError> {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            self.fetched.store(true, Ordering::SeqCst);
+            Ok(self.certs.acceptor()?.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn abandons_closed_clients() {
+        let certs = generate_test_certs(
+            &Identity::default().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let metrics = Arc::new(FakeMetrics::default());
+        let fetched = Arc::new(AtomicBool::new(false));
+        let acceptor = BoringTlsAcceptor {
+            acceptor: SlowCertProvider {
+                certs: certs.clone(),
+                fetched: fetched.clone(),
+            },
+            metrics: Some(metrics.clone()),
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The client gives up while its certificate is being fetched, either right away or after
+        // sending its ClientHello.
+        for send_hello in [false, true] {
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            let server = {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    tokio::time::timeout(
+                        Duration::from_secs(1),
+                        acceptor.accept_maybe_tls(conn, false),
+                    )
+                    .await
+                })
+            };
+            if send_hello {
+                let mut cfg = certs
+                    .connector(&Identity::default())
+                    .unwrap()
+                    .configure()
+                    .unwrap();
+                cfg.set_verify_hostname(false);
+                cfg.set_use_server_name_indication(false);
+                // No ServerHello comes while the certificate is fetched, so the client times out
+                // and closes the connection.
+                let res = tokio::time::timeout(
+                    Duration::from_millis(100),
+                    tokio_boring::connect(cfg, "", tcp),
+                )
+                .await;
+                assert!(res.is_err());
+            } else {
+                drop(tcp);
+            }
+            let res = server
+                .await
+                .unwrap()
+                .expect("accept should be abandoned before the certificate is fetched");
+            assert!(matches!(res, Err(TlsError::ClientClosed)));
+        }
+        assert!(!fetched.load(Ordering::SeqCst));
+        assert_eq!(
+            *metrics.abandoned.lock().unwrap(),
+            vec![HandshakeDirection::Inbound; 2]
+        );
+        // No handshake was attempted.
+        assert!(metrics.durations.lock().unwrap().is_empty());
+        assert!(metrics.failed.lock().unwrap().is_empty());
+    }
+}
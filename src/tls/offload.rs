@@ -0,0 +1,161 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::io;
+
+use once_cell::sync::{Lazy, OnceCell};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::registry::Registry;
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+static GLOBAL: Lazy<HandshakeOffload> = Lazy::new(HandshakeOffload::default);
+
+/// HandshakeOffload runs TLS handshakes on a dedicated pool of threads, once enabled, so that
+/// their crypto does not hold up the tokio workers serving established connections. BoringSSL
+/// cannot suspend a handshake in the middle of a private key operation, so the whole handshake
+/// runs on the pool; its socket stays registered with the runtime it was created on, which keeps
+/// reporting readiness. Handshakes run inline until enable is called.
+#[derive(Default)]
+pub struct HandshakeOffload {
+    pool: OnceCell<Runtime>,
+    offloaded: Counter,
+}
+
+impl HandshakeOffload {
+    /// global returns the offload used by acceptors and connectors.
+    pub fn global() -> &'static HandshakeOffload {
+        &GLOBAL
+    }
+
+    /// enable starts the pool, with the given number of threads. The pool can only be started
+    /// once; later calls keep the first pool.
+    pub fn enable(&self, threads: usize) -> io::Result<()> {
+        self.pool.get_or_try_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(threads.max(1))
+                .thread_name("tls-handshake")
+                .enable_all()
+                .build()
+        })?;
+        Ok(())
+    }
+
+    /// enabled tells whether handshakes are offloaded.
+    pub fn enabled(&self) -> bool {
+        self.pool.get().is_some()
+    }
+
+    /// register_metrics exposes the number of offloaded handshakes in the registry.
+    pub fn register_metrics(&self, registry: &mut Registry) {
+        registry.register(
+            "tls_offloaded_handshakes",
+            "The total number of TLS handshakes run on the dedicated handshake threads",
+            self.offloaded.clone(),
+        );
+    }
+
+    /// offloaded returns the number of handshakes run on the pool.
+    pub fn offloaded(&self) -> u64 {
+        self.offloaded.get()
+    }
+
+    /// run completes handshake on the pool if enabled, or inline otherwise. Dropping the returned
+    /// future, such as when the handshake times out, cancels the handshake.
+    pub async fn run<F>(&self, handshake: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let Some(pool) = self.pool.get() else {
+            return handshake.await;
+        };
+        self.offloaded.inc();
+        let mut task = AbortOnDrop(pool.spawn(handshake.in_current_span()));
+        match (&mut task.0).await {
+            Ok(output) => output,
+            // The pool is never shut down, so the task can only have panicked.
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+impl Drop for HandshakeOffload {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which is not allowed from within another runtime.
+        if let Some(pool) = self.pool.take() {
+            pool.shutdown_background();
+        }
+    }
+}
+
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::identity::Identity;
+    use crate::tls::generate_test_certs;
+
+    use super::HandshakeOffload;
+
+    #[tokio::test]
+    async fn offload() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let handshake = || {
+            let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+            let acceptor = certs.mtls_acceptor(None).unwrap();
+            let mut cfg = certs.connector(&id).unwrap().configure().unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(false);
+            let server = async move { tokio_boring::accept(&acceptor, server_io).await.is_ok() };
+            let client = async move { tokio_boring::connect(cfg, "", client_io).await.is_ok() };
+            (server, client)
+        };
+
+        // Handshakes run inline until enabled.
+        let offload = HandshakeOffload::default();
+        let (server, client) = handshake();
+        let (server, client) = tokio::join!(offload.run(server), client);
+        assert!(server && client);
+        assert!(!offload.enabled());
+        assert_eq!(offload.offloaded(), 0);
+
+        offload.enable(2).unwrap();
+        let (server, client) = handshake();
+        let (server, client) = tokio::join!(offload.run(server), offload.run(client));
+        assert!(server && client);
+        assert_eq!(offload.offloaded(), 2);
+
+        // A cancelled handshake is aborted on the pool, rather than left waiting for its peer.
+        let (server, _client) = handshake();
+        let res = tokio::time::timeout(Duration::from_millis(10), offload.run(server)).await;
+        assert!(res.is_err());
+        assert_eq!(offload.offloaded(), 3);
+    }
+}
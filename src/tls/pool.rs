@@ -0,0 +1,568 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_boring::SslStream;
+use tracing::debug;
+
+use crate::identity::{CertEvent, CertEventReceiver, Identity};
+
+use super::RootCertStore;
+
+/// PoolKey identifies the connections that can be used in place of each other: to the same
+/// address and peer identity, presenting the same local identity.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct PoolKey {
+    pub dst: SocketAddr,
+    pub dst_id: Identity,
+    pub src_id: Identity,
+}
+
+/// PoolConfig bounds the idle connections kept by a TlsConnectionPool.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Idle connections are closed once unused for this long.
+    pub max_idle_time: Duration,
+    /// Maximum number of idle connections, across keys.
+    pub max_size: usize,
+    /// Maximum number of idle connections of each key.
+    pub max_per_key: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_idle_time: Duration::from_secs(90),
+            max_size: 1024,
+            max_per_key: 8,
+        }
+    }
+}
+
+struct Idle {
+    stream: SslStream<TcpStream>,
+    since: Instant,
+    generation: u64,
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: HashMap<PoolKey, VecDeque<Idle>>,
+    size: usize,
+    // Bumped whenever the certificate of a local identity is rotated, so that connections
+    // established with the previous certificate are not reused.
+    generations: HashMap<Identity, u64>,
+}
+
+impl PoolState {
+    fn generation(&self, id: &Identity) -> u64 {
+        self.generations.get(id).copied().unwrap_or_default()
+    }
+}
+
+/// TlsConnectionPool keeps established outbound TLS connections once they are released, so that
+/// later requests to the same PoolKey skip the TCP and TLS handshakes. Clones share the pool.
+#[derive(Clone)]
+pub struct TlsConnectionPool {
+    config: PoolConfig,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl TlsConnectionPool {
+    pub fn new(config: PoolConfig) -> Self {
+        TlsConnectionPool {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// get_or_connect returns an idle connection for key if one is still alive, or establishes a
+    /// new one with connect otherwise. The connection is returned to the pool when the returned
+    /// PooledStream is dropped.
+    pub async fn get_or_connect<F, Fut, E>(
+        &self,
+        key: PoolKey,
+        connect: F,
+    ) -> Result<PooledStream, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<SslStream<TcpStream>, E>>,
+    {
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            let generation = state.generation(&key.src_id);
+            if let Some(stream) = self.checkout(&mut state, &key, generation) {
+                return Ok(PooledStream {
+                    stream: Some(stream),
+                    key,
+                    generation,
+                    pool: self.clone(),
+                    reused: true,
+                });
+            }
+            generation
+        };
+        let stream = connect().await?;
+        Ok(PooledStream {
+            stream: Some(stream),
+            key,
+            generation,
+            pool: self.clone(),
+            reused: false,
+        })
+    }
+
+    // Pops the most recently released connection of key that is still usable, dropping the
+    // unusable ones found on the way.
+    fn checkout(
+        &self,
+        state: &mut PoolState,
+        key: &PoolKey,
+        generation: u64,
+    ) -> Option<SslStream<TcpStream>> {
+        let idle = state.idle.get_mut(key)?;
+        let mut found = None;
+        let mut removed = 0;
+        while let Some(conn) = idle.pop_back() {
+            removed += 1;
+            if conn.generation != generation {
+                debug!(
+                    ?key,
+                    "dropping connection established with a rotated certificate"
+                );
+            } else if conn.since.elapsed() >= self.config.max_idle_time {
+                debug!(?key, "dropping idle connection");
+            } else if !is_reusable(&conn.stream) {
+                debug!(?key, "dropping closed connection, or one with pending data");
+            } else {
+                found = Some(conn.stream);
+                break;
+            }
+        }
+        if idle.is_empty() {
+            state.idle.remove(key);
+        }
+        state.size -= removed;
+        found
+    }
+
+    // Adds a released connection to the pool, unless it is over its bounds or was established
+    // before the local certificate was rotated.
+    fn release(&self, key: PoolKey, stream: SslStream<TcpStream>, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.generation(&key.src_id) != generation {
+            return;
+        }
+        self.evict_expired(&mut state);
+        if state.size >= self.config.max_size {
+            return;
+        }
+        let idle = state.idle.entry(key).or_default();
+        if idle.len() >= self.config.max_per_key {
+            return;
+        }
+        idle.push_back(Idle {
+            stream,
+            since: Instant::now(),
+            generation,
+        });
+        state.size += 1;
+    }
+
+    // Drops the connections idle for longer than max_idle_time.
+    fn evict_expired(&self, state: &mut PoolState) {
+        let max_idle_time = self.config.max_idle_time;
+        let mut removed = 0;
+        state.idle.retain(|_, idle| {
+            let before = idle.len();
+            idle.retain(|conn| conn.since.elapsed() < max_idle_time);
+            removed += before - idle.len();
+            !idle.is_empty()
+        });
+        state.size -= removed;
+    }
+
+    /// invalidate drops the idle connections presenting the certificate of src_id, and keeps the
+    /// connections in use from being pooled again, once its certificate was rotated.
+    pub fn invalidate(&self, src_id: &Identity) {
+        let mut state = self.state.lock().unwrap();
+        *state.generations.entry(src_id.to_owned()).or_default() += 1;
+        let mut removed = 0;
+        state.idle.retain(|key, idle| {
+            if &key.src_id == src_id {
+                removed += idle.len();
+                false
+            } else {
+                true
+            }
+        });
+        state.size -= removed;
+    }
+
+    /// invalidate_on_rotation invalidates the connections of each local identity whose
+    /// certificate is rotated, or no longer chains to a trusted root, until events is closed.
+    pub fn invalidate_on_rotation(&self, mut events: CertEventReceiver) {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                match event {
+                    CertEvent::CertRotated { identity, .. }
+                    | CertEvent::CertInvalidated { identity } => pool.invalidate(&identity),
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    /// revalidate drops the idle connections whose peer no longer chains to a root of store, such
+    /// as after the root or an intermediate the peer was verified with was removed.
+    pub fn revalidate(&self, store: &RootCertStore) {
+        let mut state = self.state.lock().unwrap();
+        let mut removed = 0;
+        state.idle.retain(|key, idle| {
+            let before = idle.len();
+            idle.retain(|conn| {
+                let ssl = conn.stream.ssl();
+                let Some(leaf) = ssl.peer_certificate() else {
+                    return false;
+                };
+                let chain = ssl.peer_cert_chain().into_iter().flatten();
+                match store.verify(&leaf, chain) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        debug!(?key, "dropping connection to a peer no longer trusted: {e}");
+                        false
+                    }
+                }
+            });
+            removed += before - idle.len();
+            !idle.is_empty()
+        });
+        state.size -= removed;
+    }
+
+    /// revalidate_on_root_change calls revalidate whenever store is updated, until the pool is
+    /// dropped.
+    pub fn revalidate_on_root_change(&self, store: RootCertStore) {
+        let state = Arc::downgrade(&self.state);
+        let config = self.config;
+        let mut updates = store.subscribe();
+        tokio::spawn(async move {
+            // The store is held by this task, so updates are never closed.
+            while updates.changed().await.is_ok() {
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+                TlsConnectionPool { config, state }.revalidate(&store);
+            }
+        });
+    }
+
+    /// idle returns the number of idle connections.
+    pub fn idle(&self) -> usize {
+        self.state.lock().unwrap().size
+    }
+}
+
+// Tells whether the connection can be handed out again: the peer has not closed it, and it has no
+// pending data, neither buffered by the TLS stream nor unread on the socket. A connection with
+// pending data is not between exchanges, so the next caller would read the end of an earlier one.
+// This includes a session ticket not read yet, as on a connection released unused.
+fn is_reusable(stream: &SslStream<TcpStream>) -> bool {
+    if stream.ssl().pending() > 0 {
+        return false;
+    }
+    let mut buf = [MaybeUninit::<u8>::uninit(); 1];
+    // Sockets are non-blocking, so peeking an open connection without pending data fails with
+    // WouldBlock. Anything else means that it was closed, failed or has data to read.
+    matches!(
+        socket2::SockRef::from(stream.get_ref()).peek(&mut buf),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+    )
+}
+
+/// PooledStream is a connection checked out of a TlsConnectionPool. It is returned to the pool
+/// when dropped, unless discarded.
+pub struct PooledStream {
+    stream: Option<SslStream<TcpStream>>,
+    key: PoolKey,
+    generation: u64,
+    pool: TlsConnectionPool,
+    reused: bool,
+}
+
+impl PooledStream {
+    /// reused tells whether the connection was taken from the pool.
+    pub fn reused(&self) -> bool {
+        self.reused
+    }
+
+    /// discard closes the connection instead of returning it to the pool, such as after an error
+    /// left it in an unknown state.
+    pub fn discard(mut self) {
+        self.stream = None;
+    }
+}
+
+impl Deref for PooledStream {
+    type Target = SslStream<TcpStream>;
+
+    fn deref(&self) -> &Self::Target {
+        self.stream.as_ref().expect("stream is only taken on drop")
+    }
+}
+
+impl DerefMut for PooledStream {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.stream.as_mut().expect("stream is only taken on drop")
+    }
+}
+
+impl Drop for PooledStream {
+    fn drop(&mut self) {
+        let Some(stream) = self.stream.take() else {
+            return;
+        };
+        if is_reusable(&stream) {
+            self.pool.release(self.key.clone(), stream, self.generation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_boring::SslStream;
+
+    use crate::identity::Identity;
+    use crate::tls::{connect, generate_test_ca, generate_test_certs, Certs, RootCertStore};
+
+    use super::{PoolConfig, PoolKey, TlsConnectionPool};
+
+    // Spawns a TLS echo server, returning its address.
+    async fn spawn_server(certs: &Certs) -> SocketAddr {
+        let acceptor = certs.builder().build_acceptor().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = tokio_boring::accept(&acceptor, socket).await.unwrap();
+                    let mut buf = [0; 1024];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    struct Test {
+        pool: TlsConnectionPool,
+        key: PoolKey,
+        certs: Certs,
+        connects: AtomicUsize,
+    }
+
+    impl Test {
+        async fn new(config: PoolConfig) -> Test {
+            let id = Identity::default();
+            let certs = generate_test_certs(
+                &id.clone().into(),
+                Duration::from_secs(0),
+                Duration::from_secs(100),
+            );
+            let dst = spawn_server(&certs).await;
+            Test {
+                pool: TlsConnectionPool::new(config),
+                key: PoolKey {
+                    dst,
+                    dst_id: id.clone(),
+                    src_id: id,
+                },
+                certs,
+                connects: AtomicUsize::new(0),
+            }
+        }
+
+        async fn connect(&self) -> Result<SslStream<TcpStream>, Infallible> {
+            self.connects.fetch_add(1, Ordering::SeqCst);
+            let tcp = TcpStream::connect(self.key.dst).await.unwrap();
+            let cfg = self
+                .certs
+                .connector(&self.key.dst_id)
+                .unwrap()
+                .configure()
+                .unwrap();
+            Ok(connect(
+                cfg,
+                tcp,
+                &(&self.key.dst_id).into(),
+                &Default::default(),
+                None,
+            )
+            .await
+            .unwrap())
+        }
+
+        // Checks out a connection and echoes through it, returning whether it was reused.
+        async fn echo(&self) -> bool {
+            let mut stream = self
+                .pool
+                .get_or_connect(self.key.clone(), || self.connect())
+                .await
+                .unwrap();
+            ping(&mut stream).await;
+            stream.reused()
+        }
+    }
+
+    async fn ping(stream: &mut SslStream<TcpStream>) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn reuse() {
+        let test = Test::new(PoolConfig::default()).await;
+        assert!(!test.echo().await);
+        assert_eq!(test.pool.idle(), 1);
+        assert!(test.echo().await);
+        assert_eq!(test.connects.load(Ordering::SeqCst), 1);
+
+        // Connections checked out at the same time are distinct, and both pooled once released.
+        let mut first = test
+            .pool
+            .get_or_connect(test.key.clone(), || test.connect())
+            .await
+            .unwrap();
+        let mut second = test
+            .pool
+            .get_or_connect(test.key.clone(), || test.connect())
+            .await
+            .unwrap();
+        assert!(first.reused() && !second.reused());
+        ping(&mut first).await;
+        ping(&mut second).await;
+        drop((first, second));
+        assert_eq!(test.pool.idle(), 2);
+
+        // Discarded connections are not pooled.
+        let stream = test
+            .pool
+            .get_or_connect(test.key.clone(), || test.connect())
+            .await
+            .unwrap();
+        stream.discard();
+        assert_eq!(test.pool.idle(), 1);
+
+        // Neither are connections with data left to read, which the next caller would get.
+        let mut stream = test
+            .pool
+            .get_or_connect(test.key.clone(), || test.connect())
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.get_ref().readable().await.unwrap();
+        drop(stream);
+        assert_eq!(test.pool.idle(), 0);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let test = Test::new(PoolConfig {
+            max_idle_time: Duration::from_secs(10),
+            max_per_key: 1,
+            ..Default::default()
+        })
+        .await;
+        assert!(!test.echo().await);
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(11)).await;
+        tokio::time::resume();
+        assert!(!test.echo().await);
+        assert_eq!(test.connects.load(Ordering::SeqCst), 2);
+        assert_eq!(test.pool.idle(), 1);
+    }
+
+    #[tokio::test]
+    async fn invalidate_on_rotation() {
+        let test = Test::new(PoolConfig::default()).await;
+        assert!(!test.echo().await);
+        let in_use = test
+            .pool
+            .get_or_connect(test.key.clone(), || test.connect())
+            .await
+            .unwrap();
+        assert!(in_use.reused());
+
+        // Neither idle connections nor those in use when the certificate was rotated are reused.
+        assert!(!test.echo().await);
+        test.pool.invalidate(&test.key.src_id);
+        assert_eq!(test.pool.idle(), 0);
+        drop(in_use);
+        assert_eq!(test.pool.idle(), 0);
+        assert!(!test.echo().await);
+        assert!(test.echo().await);
+        assert_eq!(test.connects.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn revalidate_on_root_change() {
+        let test = Test::new(PoolConfig::default()).await;
+        let root_a = test.certs.iter_chain().last().unwrap().clone();
+        let store = RootCertStore::new(vec![root_a]);
+        test.pool.revalidate_on_root_change(store.clone());
+        assert!(!test.echo().await);
+
+        // Connections to peers that are still trusted are kept.
+        let (root_b, _) = generate_test_ca("root-b");
+        test.pool.revalidate(&store);
+        assert_eq!(test.pool.idle(), 1);
+
+        // Once the root of the peer is removed, its connections are dropped.
+        store.set(vec![root_b]);
+        for _ in 0..100 {
+            if test.pool.idle() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(test.pool.idle(), 0);
+        assert!(!test.echo().await);
+        assert_eq!(test.connects.load(Ordering::SeqCst), 2);
+    }
+}
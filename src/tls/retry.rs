@@ -0,0 +1,288 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http_body_04::Body;
+use hyper::{Request, Response};
+use once_cell::sync::Lazy;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::registry::Registry;
+use rand::Rng;
+use tonic::body::BoxBody;
+use tower::{Service, ServiceExt};
+use tracing::debug;
+
+use super::ChannelError;
+
+/// GrpcRetryPolicy controls which control plane RPCs are retried, and how.
+///
+/// XDS is not retried by this layer: its RPCs are bidirectional streams, which cannot be replayed,
+/// and the XDS client already reconnects with backoff when its stream fails, including before the
+/// initial fetch completes.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct GrpcRetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Timeout of each attempt, if set.
+    pub per_try_timeout: Option<Duration>,
+    /// Backoff before the first retry, doubled for each following one up to max_backoff.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time after which no attempt is started or kept running, across all attempts, if set.
+    pub deadline: Option<Duration>,
+    /// Full paths of the idempotent unary methods that are retried, such as
+    /// "/istio.v1.auth.IstioCertificateService/CreateCertificate". Other calls, in particular
+    /// streaming ones, are passed through untouched.
+    #[serde(skip_serializing)]
+    pub methods: Vec<String>,
+}
+
+impl Default for GrpcRetryPolicy {
+    fn default() -> Self {
+        GrpcRetryPolicy {
+            max_attempts: 3,
+            per_try_timeout: Some(Duration::from_secs(10)),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            deadline: Some(Duration::from_secs(30)),
+            methods: Vec::new(),
+        }
+    }
+}
+
+impl GrpcRetryPolicy {
+    fn backoff(&self, retry: u32) -> Duration {
+        let exp = retry.saturating_sub(1).min(16);
+        let base = self
+            .initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff);
+        // Add jitter so many ztunnels don't retry against a recovering istiod in lockstep.
+        base.mul_f64(rand::thread_rng().gen_range(0.8..1.2))
+            .min(self.max_backoff)
+    }
+}
+
+static RETRY_METRICS: Lazy<RetryMetrics> = Lazy::new(RetryMetrics::default);
+
+/// RetryMetrics counts the control plane RPC attempts made after a first one failed.
+#[derive(Default)]
+pub struct RetryMetrics {
+    retries: Family<RetryLabels, Counter>,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+struct RetryLabels {
+    method: String,
+}
+
+impl RetryMetrics {
+    /// global returns the counts of every GrpcRetry.
+    pub fn global() -> &'static RetryMetrics {
+        &RETRY_METRICS
+    }
+
+    /// register_metrics exposes the counts in the registry.
+    pub fn register_metrics(&self, registry: &mut Registry) {
+        registry.register(
+            "control_plane_rpc_retries",
+            "The total number of control plane RPC attempts made after a first one failed",
+            self.retries.clone(),
+        );
+    }
+
+    fn record_retry(&self, method: &str) {
+        self.retries
+            .get_or_create(&RetryLabels {
+                method: method.to_string(),
+            })
+            .inc();
+    }
+}
+
+// gRPC status codes that are worth retrying. Other failures are not expected to go away.
+const GRPC_STATUS_DEADLINE_EXCEEDED: &str = "4";
+pub(super) const GRPC_STATUS_UNAVAILABLE: &str = "14";
+
+fn is_retryable_response<B>(res: &Response<B>) -> bool {
+    // Failed unary calls are answered with a trailers-only response, so the status is found in
+    // the headers.
+    matches!(
+        res.headers()
+            .get("grpc-status")
+            .and_then(|s| s.to_str().ok()),
+        Some(GRPC_STATUS_UNAVAILABLE | GRPC_STATUS_DEADLINE_EXCEEDED)
+    )
+}
+
+impl ChannelError {
+    /// is_retryable tells whether the request may succeed if sent again: the connection failed
+    /// or the attempt timed out.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ChannelError::Request(e) => e.is_connect(),
+            ChannelError::Reconnect(_) | ChannelError::Timeout(_) => true,
+            ChannelError::InvalidUri(_)
+            | ChannelError::RequestBody(_)
+            | ChannelError::ResponseBody(_)
+            | ChannelError::MessageTooLarge { .. } => false,
+        }
+    }
+}
+
+/// GrpcRetry retries the idempotent unary RPCs of its policy when they fail transiently.
+#[derive(Clone)]
+pub struct GrpcRetry<S> {
+    inner: S,
+    policy: Arc<GrpcRetryPolicy>,
+    retries: Arc<AtomicU64>,
+}
+
+impl<S> GrpcRetry<S> {
+    pub fn new(inner: S, policy: GrpcRetryPolicy) -> Self {
+        GrpcRetry {
+            inner,
+            policy: Arc::new(policy),
+            retries: Default::default(),
+        }
+    }
+
+    /// get_ref returns the wrapped service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// retries returns the number of attempts made after a first one failed.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+}
+
+impl<S, B> Service<Request<BoxBody>> for GrpcRetry<S>
+where
+    S: Service<Request<BoxBody>, Response = Response<B>, Error = ChannelError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = ChannelError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        if !self.policy.methods.iter().any(|m| m == req.uri().path()) {
+            return Box::pin(self.inner.call(req));
+        }
+        // The clone may not be ready, so keep the one poll_ready was called on for the first
+        // attempt.
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+        let policy = self.policy.clone();
+        let retries = self.retries.clone();
+        Box::pin(retry(inner, req, policy, retries))
+    }
+}
+
+async fn retry<S, B>(
+    mut inner: S,
+    req: Request<BoxBody>,
+    policy: Arc<GrpcRetryPolicy>,
+    retries: Arc<AtomicU64>,
+) -> Result<Response<B>, ChannelError>
+where
+    S: Service<Request<BoxBody>, Response = Response<B>, Error = ChannelError>,
+{
+    let deadline = policy.deadline.map(|d| now() + d);
+    let (parts, body) = req.into_parts();
+    let body = read_body(body).await?;
+    let mut attempt = 1;
+    loop {
+        let mut req = Request::new(tonic::body::boxed(http_body_04::Full::new(body.clone())));
+        *req.method_mut() = parts.method.clone();
+        *req.uri_mut() = parts.uri.clone();
+        *req.version_mut() = parts.version;
+        *req.headers_mut() = parts.headers.clone();
+
+        let remaining = deadline.map(|d| d.saturating_duration_since(now()));
+        let timeout = match (policy.per_try_timeout, remaining) {
+            (Some(t), Some(r)) => Some(t.min(r)),
+            (t, r) => t.or(r),
+        };
+        let res = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, attempt_once(&mut inner, req))
+                .await
+                .unwrap_or(Err(ChannelError::Timeout(timeout))),
+            None => attempt_once(&mut inner, req).await,
+        };
+        let retryable = match &res {
+            Ok(res) => is_retryable_response(res),
+            Err(e) => e.is_retryable(),
+        };
+        if !retryable || attempt >= policy.max_attempts {
+            return res;
+        }
+        let backoff = policy.backoff(attempt);
+        if deadline.map(|d| now() + backoff >= d).unwrap_or(false) {
+            return res;
+        }
+        debug!(
+            path = parts.uri.path(),
+            attempt,
+            ?backoff,
+            "retrying request"
+        );
+        retries.fetch_add(1, Ordering::Relaxed);
+        RETRY_METRICS.record_retry(parts.uri.path());
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+fn now() -> tokio::time::Instant {
+    tokio::time::Instant::now()
+}
+
+async fn attempt_once<S, B>(
+    inner: &mut S,
+    req: Request<BoxBody>,
+) -> Result<Response<B>, ChannelError>
+where
+    S: Service<Request<BoxBody>, Response = Response<B>, Error = ChannelError>,
+{
+    inner.ready().await?.call(req).await
+}
+
+// Buffers the request, so it can be sent again. Unary requests hold a single message.
+async fn read_body(mut body: BoxBody) -> Result<Bytes, ChannelError> {
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        buf.extend_from_slice(&chunk.map_err(ChannelError::RequestBody)?);
+    }
+    Ok(buf.freeze())
+}
@@ -0,0 +1,199 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use boring::stack::Stack;
+use boring::x509::store::X509StoreBuilder;
+use boring::x509::{self, X509Ref, X509StoreContext};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use super::{parse_root_certs, Error, TlsError};
+
+/// RootCertStore holds the roots peers are verified against. It is shared by every Certs it is
+/// attached to and can be updated at runtime, so that during a root rotation peers chained to
+/// either the old or the new root are trusted, until the old root is removed. TLS contexts read
+/// the roots when they are built, so updates apply to subsequent handshakes.
+#[derive(Clone, Debug)]
+pub struct RootCertStore {
+    roots: Arc<RwLock<Vec<x509::X509>>>,
+    // Bumped on every update, for subscribers to re-check what was verified against the roots.
+    updates: Arc<watch::Sender<u64>>,
+}
+
+impl Default for RootCertStore {
+    fn default() -> Self {
+        RootCertStore {
+            roots: Default::default(),
+            updates: Arc::new(watch::channel(0).0),
+        }
+    }
+}
+
+impl RootCertStore {
+    pub fn new(roots: Vec<x509::X509>) -> Self {
+        let store = Self::default();
+        store.set(roots);
+        store
+    }
+
+    /// from_file builds a RootCertStore from a PEM bundle.
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        Ok(Self::new(read_roots(path)?))
+    }
+
+    /// roots returns the currently trusted roots.
+    pub fn roots(&self) -> Vec<x509::X509> {
+        self.roots.read().unwrap().clone()
+    }
+
+    /// set replaces the trusted roots.
+    pub fn set(&self, roots: Vec<x509::X509>) {
+        let mut deduped: Vec<x509::X509> = Vec::with_capacity(roots.len());
+        for root in roots {
+            if !contains(&deduped, &root) {
+                deduped.push(root);
+            }
+        }
+        *self.roots.write().unwrap() = deduped;
+        self.updated();
+    }
+
+    /// add trusts root in addition to the current roots. Returns false if it was already trusted.
+    pub fn add(&self, root: x509::X509) -> bool {
+        let mut roots = self.roots.write().unwrap();
+        if contains(&roots, &root) {
+            return false;
+        }
+        roots.push(root);
+        drop(roots);
+        self.updated();
+        true
+    }
+
+    /// remove stops trusting root. Returns false if it was not trusted.
+    pub fn remove(&self, root: &X509Ref) -> bool {
+        let mut roots = self.roots.write().unwrap();
+        let len = roots.len();
+        roots.retain(|r| !same_cert(r, root));
+        let removed = roots.len() != len;
+        drop(roots);
+        if removed {
+            self.updated();
+        }
+        removed
+    }
+
+    pub fn contains(&self, root: &X509Ref) -> bool {
+        contains(&self.roots.read().unwrap(), root)
+    }
+
+    /// subscribe returns a receiver notified whenever the roots are updated.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.updates.subscribe()
+    }
+
+    /// id identifies the store, shared by its clones.
+    pub fn id(&self) -> usize {
+        Arc::as_ptr(&self.roots) as usize
+    }
+
+    /// version returns the number of times the roots were updated.
+    pub fn version(&self) -> u64 {
+        *self.updates.borrow()
+    }
+
+    fn updated(&self) {
+        self.updates.send_modify(|n| *n += 1);
+    }
+
+    /// verify checks that leaf, along with the intermediates in chain, chains to one of the
+    /// current roots. Certificates verified when the roots were different, such as our own
+    /// certificates or the peers of pooled connections, are checked again this way once a root is
+    /// removed.
+    pub fn verify<'a>(
+        &self,
+        leaf: &X509Ref,
+        chain: impl IntoIterator<Item = &'a X509Ref>,
+    ) -> Result<(), TlsError> {
+        let mut builder = X509StoreBuilder::new().map_err(Error::SslError)?;
+        for root in self.roots() {
+            builder.add_cert(root).map_err(Error::SslError)?;
+        }
+        let store = builder.build();
+        let mut untrusted = Stack::new().map_err(Error::SslError)?;
+        for cert in chain {
+            untrusted.push(cert.to_owned()).map_err(Error::SslError)?;
+        }
+        let mut ctx = X509StoreContext::new().map_err(Error::SslError)?;
+        let res = ctx
+            .init(&store, leaf, &untrusted, |ctx| {
+                Ok(if ctx.verify_cert()? {
+                    Ok(())
+                } else {
+                    Err(ctx.error())
+                })
+            })
+            .map_err(Error::SslError)?;
+        res.map_err(TlsError::Verification)
+    }
+
+    /// watch_file replaces the roots with the contents of the PEM bundle whenever it changes. The
+    /// file is compared every interval, which also detects atomic symlink swaps. Unreadable or
+    /// invalid bundles are ignored, keeping the current roots.
+    pub fn watch_file(&self, path: PathBuf, interval: Duration) {
+        let (roots, updates) = (Arc::downgrade(&self.roots), Arc::downgrade(&self.updates));
+        tokio::spawn(async move {
+            let mut contents = std::fs::read(&path).ok();
+            loop {
+                tokio::time::sleep(interval).await;
+                // Stop once the store is dropped.
+                let (Some(roots), Some(updates)) = (roots.upgrade(), updates.upgrade()) else {
+                    return;
+                };
+                let latest = std::fs::read(&path).ok();
+                if latest == contents {
+                    continue;
+                }
+                contents = latest;
+                match read_roots(&path) {
+                    Ok(latest) => {
+                        info!("root certificates in {path:?} changed, reloading them");
+                        RootCertStore { roots, updates }.set(latest);
+                    }
+                    Err(e) => warn!("keeping the current root certificates: {e}"),
+                }
+            }
+        });
+    }
+}
+
+fn read_roots(path: &Path) -> Result<Vec<x509::X509>, Error> {
+    let pem = std::fs::read(path).map_err(|e| Error::root_cert_io(path, e))?;
+    parse_root_certs(&pem)
+}
+
+fn contains(roots: &[x509::X509], root: &X509Ref) -> bool {
+    roots.iter().any(|r| same_cert(r, root))
+}
+
+fn same_cert(a: &X509Ref, b: &X509Ref) -> bool {
+    match (a.to_der(), b.to_der()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
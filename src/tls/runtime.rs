@@ -0,0 +1,387 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use boring::ssl;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Deserializer, Serializer};
+use tracing::info;
+
+use super::{Alpn, Error, TrustBundle};
+
+/// TlsVersion is a TLS protocol version that can be configured.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[serde(rename = "TLSv1.2")]
+    Tls12,
+    #[serde(rename = "TLSv1.3")]
+    Tls13,
+}
+
+impl From<TlsVersion> for ssl::SslVersion {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls12 => ssl::SslVersion::TLS1_2,
+            TlsVersion::Tls13 => ssl::SslVersion::TLS1_3,
+        }
+    }
+}
+
+/// TlsRuntimeConfig holds the TLS settings that can be changed without a restart. They are read
+/// when acceptors and connectors are built, so a change applies to new handshakes while
+/// established connections keep the settings they were made with.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TlsRuntimeConfig {
+    /// Lowest TLS version negotiated. TLS 1.3 if unset.
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    /// Ciphers allowed with TLS 1.2, in OpenSSL list format. BoringSSL defaults are used if unset;
+    /// TLS 1.3 ciphers are not configurable.
+    #[serde(default)]
+    pub cipher_list: Option<String>,
+    /// Time allowed for inbound handshakes to complete, such as "10s". Unbounded if unset.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub handshake_timeout: Option<Duration>,
+    /// Application protocols offered, and selected by acceptors, unless set by the code building
+    /// the context: "h2", "http/1.1" or "h2,http/1.1". Only h2 is offered, and none selected, if
+    /// unset.
+    #[serde(default)]
+    pub alpn: Option<Alpn>,
+    /// PEM root bundles of federated trust domains, keyed by trust domain. If set, they replace the
+    /// trust bundle configured at startup.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub trust_bundles: HashMap<String, PathBuf>,
+    // The roots read from trust_bundles, once validated.
+    #[serde(skip)]
+    trust_bundle: OnceCell<Arc<TrustBundle>>,
+}
+
+// The roots read from the trust bundles are derived from their paths, and not compared.
+impl PartialEq for TlsRuntimeConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.min_version == other.min_version
+            && self.cipher_list == other.cipher_list
+            && self.handshake_timeout == other.handshake_timeout
+            && self.alpn == other.alpn
+            && self.trust_bundles == other.trust_bundles
+    }
+}
+
+impl Eq for TlsRuntimeConfig {}
+
+impl TlsRuntimeConfig {
+    pub fn min_version(&self) -> ssl::SslVersion {
+        self.min_version
+            .map(Into::into)
+            .unwrap_or(ssl::SslVersion::TLS1_3)
+    }
+
+    /// apply sets the settings of the config on ctx.
+    pub(super) fn apply(&self, ctx: &mut ssl::SslContextBuilder) -> Result<(), Error> {
+        if let Some(ciphers) = &self.cipher_list {
+            ctx.set_cipher_list(ciphers)
+                .map_err(|e| Error::InvalidCipherList(ciphers.clone(), e))?;
+        }
+        Ok(())
+    }
+
+    /// trust_bundle returns the roots of trust_bundles, if set. They are read by validate, or
+    /// on first use if the config was not validated, in which case roots that cannot be read are
+    /// ignored.
+    pub fn trust_bundle(&self) -> Option<Arc<TrustBundle>> {
+        if self.trust_bundles.is_empty() {
+            return None;
+        }
+        if let Some(bundle) = self.trust_bundle.get() {
+            return Some(bundle.clone());
+        }
+        match TrustBundle::from_files(&self.trust_bundles) {
+            Ok(bundle) => Some(self.trust_bundle.get_or_init(|| Arc::new(bundle)).clone()),
+            Err(e) => {
+                tracing::warn!("ignoring runtime trust bundles: {e}");
+                None
+            }
+        }
+    }
+
+    /// validate checks the config by building a throwaway context with it, and reads the trust
+    /// bundles.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut ctx = ssl::SslContextBuilder::new(ssl::SslMethod::tls())?;
+        ctx.set_min_proto_version(Some(self.min_version()))?;
+        ctx.set_max_proto_version(Some(ssl::SslVersion::TLS1_3))?;
+        if let Some(alpn) = self.alpn {
+            ctx.set_alpn_protos(alpn.encode())?;
+        }
+        self.apply(&mut ctx)?;
+        if !self.trust_bundles.is_empty() && self.trust_bundle.get().is_none() {
+            let bundle = TrustBundle::from_files(&self.trust_bundles)?;
+            let _ = self.trust_bundle.set(Arc::new(bundle));
+        }
+        Ok(())
+    }
+
+    /// read parses and validates the YAML or JSON config in path.
+    pub fn read(path: &Path) -> Result<TlsRuntimeConfig, Error> {
+        let data =
+            std::fs::read(path).map_err(|e| Error::RuntimeConfig(path.into(), e.to_string()))?;
+        let config: TlsRuntimeConfig = serde_yaml::from_slice(&data)
+            .map_err(|e| Error::RuntimeConfig(path.into(), e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let Some(duration) = Option::<String>::deserialize(d)? else {
+        return Ok(None);
+    };
+    go_parse_duration::parse_duration(&duration)
+        .ok()
+        .and_then(|ns| u64::try_from(ns).ok())
+        .map(|ns| Some(Duration::from_nanos(ns)))
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid duration {duration}")))
+}
+
+fn serialize_duration<S: Serializer>(d: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => s.serialize_str(&format!("{d:?}")),
+        None => s.serialize_none(),
+    }
+}
+
+static GLOBAL: Lazy<TlsRuntime> = Lazy::new(Default::default);
+
+/// TlsRuntime holds the current TlsRuntimeConfig, and counts how often it was replaced. Clones
+/// share the config.
+#[derive(Clone, Default)]
+pub struct TlsRuntime {
+    inner: Arc<TlsRuntimeInner>,
+}
+
+#[derive(Default)]
+struct TlsRuntimeInner {
+    config: RwLock<Arc<TlsRuntimeConfig>>,
+    generation: AtomicU64,
+}
+
+impl TlsRuntime {
+    /// global returns the runtime read by every TLS context built, unless overridden on the
+    /// TlsContextBuilder.
+    pub fn global() -> &'static TlsRuntime {
+        &GLOBAL
+    }
+
+    pub fn load(&self) -> Arc<TlsRuntimeConfig> {
+        self.inner.config.read().unwrap().clone()
+    }
+
+    /// generation is the number of times the config was replaced.
+    pub fn generation(&self) -> u64 {
+        self.inner.generation.load(Ordering::Acquire)
+    }
+
+    /// reload replaces the config with config, once it is validated, and returns the new
+    /// generation. An invalid config is rejected, and the current one stays active.
+    pub fn reload(&self, config: TlsRuntimeConfig) -> Result<u64, Error> {
+        config.validate()?;
+        let mut current = self.inner.config.write().unwrap();
+        *current = Arc::new(config);
+        let generation = self.inner.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        info!(generation, config=?current, "reloaded tls runtime config");
+        Ok(generation)
+    }
+
+    /// reload_file reloads the config from path, as TlsRuntimeConfig::read.
+    pub fn reload_file(&self, path: &Path) -> Result<u64, Error> {
+        self.reload(TlsRuntimeConfig::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use boring::ssl;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_boring::SslStream;
+
+    use crate::identity::Identity;
+    use crate::tls::{generate_test_certs, Certs, Error};
+
+    use super::{Alpn, TlsRuntime, TlsRuntimeConfig, TlsVersion};
+
+    #[test]
+    fn parse() {
+        let config: TlsRuntimeConfig = serde_yaml::from_str(
+            "minVersion: TLSv1.2\ncipherList: ECDHE-ECDSA-AES128-GCM-SHA256\nhandshakeTimeout: 10s\n\
+             alpn: h2,http/1.1\ntrustBundles:\n  example.com: /etc/roots.pem\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            TlsRuntimeConfig {
+                min_version: Some(TlsVersion::Tls12),
+                cipher_list: Some("ECDHE-ECDSA-AES128-GCM-SHA256".to_string()),
+                handshake_timeout: Some(Duration::from_secs(10)),
+                alpn: Some(Alpn::H2AndHttp11),
+                trust_bundles: [("example.com".to_string(), "/etc/roots.pem".into())].into(),
+                ..Default::default()
+            }
+        );
+        assert!(serde_yaml::from_str::<TlsRuntimeConfig>("minVersion: TLSv1.0").is_err());
+        assert!(serde_yaml::from_str::<TlsRuntimeConfig>("handshakeTimeout: soon").is_err());
+    }
+
+    #[test]
+    fn invalid_reload() {
+        let runtime = TlsRuntime::default();
+        let valid = TlsRuntimeConfig {
+            min_version: Some(TlsVersion::Tls12),
+            ..Default::default()
+        };
+        assert_eq!(runtime.reload(valid.clone()).unwrap(), 1);
+
+        // The invalid config is rejected as a whole, the previous one staying active.
+        let res = runtime.reload(TlsRuntimeConfig {
+            min_version: Some(TlsVersion::Tls13),
+            cipher_list: Some("NOT-A-CIPHER".to_string()),
+            ..Default::default()
+        });
+        assert!(matches!(res, Err(Error::InvalidCipherList(..))));
+        assert_eq!(runtime.generation(), 1);
+        assert_eq!(*runtime.load(), valid);
+
+        // So are trust bundles that cannot be read.
+        let res = runtime.reload(TlsRuntimeConfig {
+            trust_bundles: [("example.com".to_string(), "/nonexistent/roots.pem".into())].into(),
+            ..Default::default()
+        });
+        assert!(res.is_err());
+        assert_eq!(runtime.generation(), 1);
+        assert_eq!(*runtime.load(), valid);
+    }
+
+    // Connects a client limited to TLS 1.2 to acceptor, returning both ends if the handshake
+    // succeeds.
+    async fn connect_tls12(
+        certs: &Certs,
+        acceptor: &ssl::SslAcceptor,
+    ) -> Option<(SslStream<DuplexStream>, SslStream<DuplexStream>)> {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let mut cfg = certs
+            .connector(&Identity::default())
+            .unwrap()
+            .configure()
+            .unwrap();
+        cfg.set_verify_hostname(false);
+        cfg.set_use_server_name_indication(false);
+        let mut ssl = cfg.into_ssl("").unwrap();
+        ssl.set_min_proto_version(Some(ssl::SslVersion::TLS1_2))
+            .unwrap();
+        ssl.set_max_proto_version(Some(ssl::SslVersion::TLS1_2))
+            .unwrap();
+        let (server, client) = tokio::join!(
+            tokio_boring::accept(acceptor, server_io),
+            tokio_boring::SslStreamBuilder::new(ssl, client_io).connect()
+        );
+        server.ok().zip(client.ok())
+    }
+
+    #[tokio::test]
+    async fn min_version_reload() {
+        let certs = generate_test_certs(
+            &Identity::default().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let runtime = TlsRuntime::default();
+        let acceptor = || {
+            certs
+                .builder()
+                .require_client_cert(false)
+                .runtime_config(runtime.load())
+                .build_acceptor()
+                .unwrap()
+        };
+
+        // Only TLS 1.3 is accepted by default.
+        assert!(connect_tls12(&certs, &acceptor()).await.is_none());
+
+        runtime
+            .reload(TlsRuntimeConfig {
+                min_version: Some(TlsVersion::Tls12),
+                ..Default::default()
+            })
+            .unwrap();
+        let (mut server, mut client) = connect_tls12(&certs, &acceptor()).await.unwrap();
+        assert_eq!(client.ssl().version_str(), "TLSv1.2");
+
+        // Raising the minimum again rejects new TLS 1.2 clients, but established connections
+        // survive.
+        runtime.reload(TlsRuntimeConfig::default()).unwrap();
+        assert_eq!(runtime.generation(), 2);
+        assert!(connect_tls12(&certs, &acceptor()).await.is_none());
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn alpn_reload() {
+        let certs = generate_test_certs(
+            &Identity::default().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let runtime = TlsRuntime::default();
+        let acceptor = || {
+            certs
+                .builder()
+                .require_client_cert(false)
+                .runtime_config(runtime.load())
+                .build_acceptor()
+                .unwrap()
+        };
+        let config = TlsRuntimeConfig {
+            min_version: Some(TlsVersion::Tls12),
+            ..Default::default()
+        };
+
+        // No protocol is selected by default.
+        runtime.reload(config.clone()).unwrap();
+        let (_, client) = connect_tls12(&certs, &acceptor()).await.unwrap();
+        assert_eq!(client.ssl().selected_alpn_protocol(), None);
+
+        runtime
+            .reload(TlsRuntimeConfig {
+                alpn: Some(Alpn::H2AndHttp11),
+                ..config
+            })
+            .unwrap();
+        let (_, client) = connect_tls12(&certs, &acceptor()).await.unwrap();
+        assert_eq!(client.ssl().selected_alpn_protocol(), Some(&b"h2"[..]));
+    }
+}
@@ -0,0 +1,1039 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A rustls implementation of certificate handling and the control plane client, next to the boring
+// backend rather than in place of it: the proxy and identity layers use the boring types, and
+// BoringSSL is linked either way. Peers are verified exactly as by the boring backend: the chain
+// must lead to one of our roots and the leaf must carry the expected SPIFFE URI SAN, or IP SAN.
+// Features that depend on BoringSSL, such as keys held by an HSM, are reported as unsupported.
+
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use hyper::body::Incoming;
+use hyper::{Request, Response, Uri};
+use hyper_util::client::connect::{Connected, Connection, HttpConnector};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{Certificate, CertificateError, DistinguishedName, PrivateKey, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tonic::body::BoxBody;
+use tower_hyper_http_body_compat::{HttpBody04ToHttpBody1, HttpBody1ToHttpBody04};
+use tracing::debug;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+use zeroize::Zeroizing;
+
+use crate::config::RootCert;
+use crate::identity::Identity;
+use crate::time::Clock;
+
+use super::boring::dns_name_matches;
+use super::{
+    Alpn, CachingResolver, CertSign, ChannelError, ConnectorConfig, ControlPlaneAlpn, CsrOptions,
+    EcCurve, ExpectedPeer, KeyType,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("invalid private key: {0}")]
+    InvalidPrivateKey(String),
+
+    #[error("invalid certificate: {0}")]
+    InvalidCertificate(String),
+
+    #[error("private key does not match the certificate")]
+    KeyCertMismatch,
+
+    #[error("tls error: {0}")]
+    Tls(#[from] rustls::Error),
+
+    #[error("failed to generate CSR: {0}")]
+    Csr(#[from] rcgen::RcgenError),
+
+    #[error("invalid uri: {0}")]
+    InvalidUri(#[from] hyper::http::uri::InvalidUri),
+
+    #[error("failed to read root certificate: {0}")]
+    ReadRootCert(#[from] std::io::Error),
+
+    #[error("no root certificates found")]
+    NoRootCerts,
+
+    #[error("{0} is not supported by the rustls backend")]
+    Unsupported(&'static str),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsError {
+    #[error("tls handshake error: {0}")]
+    Handshake(std::io::Error),
+    #[error("san verification error: remote did not present the expected SAN ({0}), got {1:?}")]
+    SanError(Identity, Vec<Identity>),
+    #[error(
+        "san verification error: remote did not present the expected trustdomain ({0}), got {1:?}"
+    )]
+    SanTrustDomainError(String, Vec<Identity>),
+    #[error("san verification error: remote did not present the expected IP SAN ({0}), got {1:?}")]
+    IpSanError(IpAddr, Vec<IpAddr>),
+    #[error("san verification error: remote did not present a DNS SAN matching {0}, got {1:?}")]
+    DnsSanError(String, Vec<String>),
+    #[error(
+        "san verification error: remote matched none of the expected peers: {}",
+        .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; ")
+    )]
+    NoExpectedPeer(Vec<TlsError>),
+    #[error("configuration error: {0}")]
+    Config(#[from] Error),
+}
+
+/// Certs holds a workload certificate, the remainder of its chain and its private key. The last
+/// certificate of the chain is the root peers are verified against.
+#[derive(Clone)]
+pub struct Certs {
+    cert: Certificate,
+    chain: Vec<Certificate>,
+    key: PrivateKey,
+    not_before: SystemTime,
+    not_after: SystemTime,
+}
+
+impl Debug for Certs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certs")
+            .field("sans", &extract_sans(&self.cert))
+            .field("chain", &self.chain.len())
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// cert_from builds Certs from a PEM key, leaf and chain. The leaf PEM may be followed by its chain,
+/// and each chain PEM may hold several certificates.
+pub fn cert_from(key: &[u8], cert: &[u8], chain: Vec<&[u8]>) -> Result<Certs, Error> {
+    let key = parse_private_key(key)?;
+    let mut certs = parse_certs(cert)?;
+    if certs.is_empty() {
+        return Err(Error::InvalidCertificate(
+            "no certificate found".to_string(),
+        ));
+    }
+    let leaf = certs.remove(0);
+    for pem in chain {
+        certs.extend(parse_certs(pem)?);
+    }
+    check_key_matches(&leaf, &key)?;
+    let (not_before, not_after) = validity(&leaf)?;
+    Ok(Certs {
+        cert: leaf,
+        chain: certs,
+        key,
+        not_before,
+        not_after,
+    })
+}
+
+// validity returns the not_before and not_after times of cert.
+fn validity(cert: &Certificate) -> Result<(SystemTime, SystemTime), Error> {
+    let (_, cert) =
+        X509Certificate::from_der(&cert.0).map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+    let time = |t: x509_parser::time::ASN1Time| {
+        UNIX_EPOCH + Duration::from_secs(t.timestamp().try_into().unwrap_or_default())
+    };
+    Ok((
+        time(cert.validity().not_before),
+        time(cert.validity().not_after),
+    ))
+}
+
+// to_pem encodes cert as a PEM CERTIFICATE block.
+fn to_pem(cert: &Certificate) -> String {
+    let encoded = base64::encode(&cert.0);
+    let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ascii"));
+        pem.push('\n');
+    }
+    pem.push_str("-----END CERTIFICATE-----\n");
+    pem
+}
+
+fn parse_certs(pem: &[u8]) -> Result<Vec<Certificate>, Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(pem))
+        .map_err(|e| Error::InvalidCertificate(e.to_string()))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn parse_private_key(pem: &[u8]) -> Result<PrivateKey, Error> {
+    let mut reader = BufReader::new(pem);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| Error::InvalidPrivateKey(e.to_string()))?
+        {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(der)
+                | rustls_pemfile::Item::ECKey(der)
+                | rustls_pemfile::Item::RSAKey(der),
+            ) => return Ok(PrivateKey(der)),
+            Some(_) => continue,
+            None => return Err(Error::InvalidPrivateKey("no private key found".to_string())),
+        }
+    }
+}
+
+// Signature schemes a key can be checked with, and the matching algorithm to verify them.
+const KEY_CHECK_SCHEMES: &[rustls::SignatureScheme] = &[
+    rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
+    rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
+    rustls::SignatureScheme::RSA_PKCS1_SHA256,
+    rustls::SignatureScheme::ED25519,
+];
+
+fn verification_algorithm(scheme: rustls::SignatureScheme) -> &'static webpki::SignatureAlgorithm {
+    match scheme {
+        rustls::SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        rustls::SignatureScheme::RSA_PKCS1_SHA256 => &webpki::RSA_PKCS1_2048_8192_SHA256,
+        rustls::SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => &webpki::ECDSA_P256_SHA256,
+    }
+}
+
+// check_key_matches makes sure key is the private key of cert, by signing a message with the key
+// and verifying it with the public key of cert.
+fn check_key_matches(cert: &Certificate, key: &PrivateKey) -> Result<(), Error> {
+    let signing_key = rustls::sign::any_supported_type(key)
+        .map_err(|_| Error::InvalidPrivateKey("unsupported key type".to_string()))?;
+    let signer = signing_key
+        .choose_scheme(KEY_CHECK_SCHEMES)
+        .ok_or_else(|| Error::InvalidPrivateKey("unsupported key type".to_string()))?;
+    const MESSAGE: &[u8] = b"ztunnel key check";
+    let signature = signer.sign(MESSAGE)?;
+    let cert = webpki::EndEntityCert::try_from(cert.0.as_ref())
+        .map_err(|e| Error::InvalidCertificate(format!("{e:?}")))?;
+    cert.verify_signature(verification_algorithm(signer.scheme()), MESSAGE, &signature)
+        .map_err(|_| Error::KeyCertMismatch)
+}
+
+/// generate_csr generates a private key and a CSR for it, as specified by options.
+pub fn generate_csr(options: &CsrOptions) -> Result<CertSign, Error> {
+    let mut params = rcgen::CertificateParams::default();
+    params.alg = match options.key_type {
+        KeyType::Ec(EcCurve::P256) => &rcgen::PKCS_ECDSA_P256_SHA256,
+        KeyType::Ec(EcCurve::P384) => &rcgen::PKCS_ECDSA_P384_SHA384,
+        // ring cannot generate RSA keys.
+        KeyType::Rsa(_) => return Err(Error::Unsupported("RSA key generation")),
+    };
+    if options.signature_digest.is_some() {
+        return Err(Error::Unsupported("choosing the CSR signature digest"));
+    }
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    if let Some(o) = &options.organization {
+        params
+            .distinguished_name
+            .push(rcgen::DnType::OrganizationName, o.as_str());
+    }
+    if let Some(cn) = &options.common_name {
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, cn.as_str());
+    }
+    params.subject_alt_names = std::iter::once(rcgen::SanType::URI(options.san.clone()))
+        .chain(
+            options
+                .ip_sans
+                .iter()
+                .copied()
+                .map(rcgen::SanType::IpAddress),
+        )
+        .collect();
+    let cert = rcgen::Certificate::from_params(params)?;
+    Ok(CertSign {
+        csr: cert.serialize_request_pem()?.into_bytes(),
+        pkey: Zeroizing::new(cert.serialize_private_key_pem().into_bytes()),
+    })
+}
+
+/// extract_sans returns the SPIFFE identities in the URI SANs of cert.
+pub fn extract_sans(cert: &Certificate) -> Vec<Identity> {
+    uri_sans(cert)
+        .iter()
+        .filter_map(|uri| match Identity::from_str(uri) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                debug!("skipping URI SAN: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+// filter_sans maps the SANs of cert with f, skipping those it returns None for.
+fn filter_sans<T>(cert: &Certificate, f: impl FnMut(&GeneralName<'_>) -> Option<T>) -> Vec<T> {
+    let cert = match X509Certificate::from_der(&cert.0) {
+        Ok((_, cert)) => cert,
+        Err(_) => return Vec::new(),
+    };
+    match cert.subject_alternative_name() {
+        Ok(Some(sans)) => sans.value.general_names.iter().filter_map(f).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn uri_sans(cert: &Certificate) -> Vec<String> {
+    filter_sans(cert, |name| match name {
+        GeneralName::URI(uri) => Some(uri.to_string()),
+        _ => None,
+    })
+}
+
+fn dns_sans(cert: &Certificate) -> Vec<String> {
+    filter_sans(cert, |name| match name {
+        GeneralName::DNSName(name) => Some(name.to_string()),
+        _ => None,
+    })
+}
+
+fn ip_sans(cert: &Certificate) -> Vec<IpAddr> {
+    filter_sans(cert, |name| match name {
+        GeneralName::IPAddress(&[a, b, c, d]) => Some(IpAddr::from([a, b, c, d])),
+        GeneralName::IPAddress(ip) => <[u8; 16]>::try_from(*ip).ok().map(IpAddr::from),
+        _ => None,
+    })
+}
+
+pub trait SanChecker {
+    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError>;
+    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError>;
+}
+
+impl SanChecker for Certificate {
+    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError> {
+        let uri = identity.to_string();
+        if uri_sans(self).iter().any(|san| san == &uri) {
+            return Ok(());
+        }
+        // The SAN may still be an equivalent spelling of the identity, or absent.
+        let sans = extract_sans(self);
+        if sans.contains(identity) {
+            return Ok(());
+        }
+        Err(TlsError::SanError(identity.to_owned(), sans))
+    }
+
+    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError> {
+        let trust_domain = match identity {
+            Identity::Spiffe { trust_domain, .. } => trust_domain,
+        };
+        let sans = extract_sans(self);
+        if sans.iter().any(|id| match id {
+            Identity::Spiffe {
+                trust_domain: td, ..
+            } => td == trust_domain,
+        }) {
+            return Ok(());
+        }
+        Err(TlsError::SanTrustDomainError(
+            trust_domain.to_string(),
+            sans,
+        ))
+    }
+}
+
+impl SanChecker for Certs {
+    fn verify_san(&self, identity: &Identity) -> Result<(), TlsError> {
+        self.cert.verify_san(identity)
+    }
+
+    fn verify_san_trust_domain(&self, identity: &Identity) -> Result<(), TlsError> {
+        self.cert.verify_san_trust_domain(identity)
+    }
+}
+
+fn verify_ip_san(cert: &Certificate, ip: IpAddr) -> Result<(), TlsError> {
+    let ips = ip_sans(cert);
+    if ips.contains(&ip) {
+        Ok(())
+    } else {
+        Err(TlsError::IpSanError(ip, ips))
+    }
+}
+
+fn verify_dns_san(cert: &Certificate, name: &str) -> Result<(), TlsError> {
+    let names = dns_sans(cert);
+    if names.iter().any(|san| dns_name_matches(san, name)) {
+        Ok(())
+    } else {
+        Err(TlsError::DnsSanError(name.to_string(), names))
+    }
+}
+
+// verify_expected_peer checks cert is for the expected peer.
+fn verify_expected_peer(cert: &Certificate, dest: &ExpectedPeer) -> Result<(), TlsError> {
+    match dest {
+        ExpectedPeer::Identity(id) => cert.verify_san(id),
+        ExpectedPeer::IpSan(ip) => verify_ip_san(cert, *ip),
+        ExpectedPeer::DnsName(name) => verify_dns_san(cert, name),
+        ExpectedPeer::AnyOf(peers) => {
+            let mut errors = Vec::with_capacity(peers.len());
+            for peer in peers {
+                match verify_expected_peer(cert, peer) {
+                    Ok(()) => return Ok(()),
+                    Err(e) => errors.push(e),
+                }
+            }
+            Err(TlsError::NoExpectedPeer(errors))
+        }
+    }
+}
+
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+// Verifier checks that peers chain to one of roots, and that their certificate is for the
+// expected peer.
+enum Verifier {
+    None,
+    Peer(ExpectedPeer),
+    TrustDomain(Identity),
+}
+
+struct PeerVerifier {
+    roots: Vec<Certificate>,
+    verifier: Verifier,
+    require_client_cert: bool,
+}
+
+impl PeerVerifier {
+    fn verify(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+        usage: webpki::KeyUsage,
+    ) -> Result<(), rustls::Error> {
+        let cert = webpki::EndEntityCert::try_from(end_entity.0.as_ref()).map_err(pki_error)?;
+        let anchors = self
+            .roots
+            .iter()
+            .filter_map(|root| webpki::TrustAnchor::try_from_cert_der(&root.0).ok())
+            .collect::<Vec<_>>();
+        let intermediates = intermediates
+            .iter()
+            .map(|c| c.0.as_ref())
+            .collect::<Vec<_>>();
+        let time =
+            webpki::Time::try_from(now).map_err(|_| rustls::Error::FailedToGetCurrentTime)?;
+        cert.verify_for_usage(
+            SUPPORTED_SIG_ALGS,
+            &anchors,
+            &intermediates,
+            time,
+            usage,
+            &[],
+        )
+        .map_err(pki_error)?;
+        let res = match &self.verifier {
+            Verifier::None => Ok(()),
+            Verifier::Peer(dest) => verify_expected_peer(end_entity, dest),
+            Verifier::TrustDomain(id) => end_entity.verify_san_trust_domain(id),
+        };
+        res.map_err(|e| {
+            debug!("failed verifying TLS: {e}");
+            rustls::Error::InvalidCertificate(CertificateError::NotValidForName)
+        })
+    }
+}
+
+fn pki_error(e: webpki::Error) -> rustls::Error {
+    let e = match e {
+        webpki::Error::BadDer | webpki::Error::BadDerTime => CertificateError::BadEncoding,
+        webpki::Error::CertExpired => CertificateError::Expired,
+        webpki::Error::CertNotValidYet => CertificateError::NotValidYet,
+        webpki::Error::UnknownIssuer => CertificateError::UnknownIssuer,
+        webpki::Error::InvalidSignatureForPublicKey => CertificateError::BadSignature,
+        e => CertificateError::Other(Arc::new(PkiError(e))),
+    };
+    rustls::Error::InvalidCertificate(e)
+}
+
+#[derive(Debug)]
+struct PkiError(webpki::Error);
+
+impl fmt::Display for PkiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl std::error::Error for PkiError {}
+
+impl ServerCertVerifier for PeerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify(
+            end_entity,
+            intermediates,
+            now,
+            webpki::KeyUsage::server_auth(),
+        )?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for PeerVerifier {
+    fn client_auth_mandatory(&self) -> bool {
+        self.require_client_cert
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.verify(
+            end_entity,
+            intermediates,
+            now,
+            webpki::KeyUsage::client_auth(),
+        )?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+impl Alpn {
+    fn protocols(&self) -> Vec<Vec<u8>> {
+        match self {
+            Alpn::H2 => vec![b"h2".to_vec()],
+            Alpn::Http11 => vec![b"http/1.1".to_vec()],
+            Alpn::H2AndHttp11 => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        }
+    }
+}
+
+impl Certs {
+    // The certificates sent to peers: the leaf and intermediates. The root is expected to already
+    // be known to the peer.
+    fn presented_chain(&self) -> Vec<Certificate> {
+        std::iter::once(self.cert.clone())
+            .chain(
+                self.chain
+                    .iter()
+                    .take(self.chain.len().saturating_sub(1))
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    fn peer_verifier(&self, verifier: Verifier, require_client_cert: bool) -> Arc<PeerVerifier> {
+        Arc::new(PeerVerifier {
+            roots: self.chain.clone(),
+            verifier,
+            require_client_cert,
+        })
+    }
+
+    fn server_config(
+        &self,
+        verifier: Verifier,
+        require_client_cert: bool,
+    ) -> Result<Arc<rustls::ServerConfig>, Error> {
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(self.peer_verifier(verifier, require_client_cert))
+            .with_single_cert(self.presented_chain(), self.key.clone())?;
+        config.alpn_protocols = Alpn::H2.protocols();
+        Ok(Arc::new(config))
+    }
+
+    /// mtls_acceptor returns the configuration of a server requiring client certificates. If
+    /// dest_id is set, clients must share its trust domain.
+    pub fn mtls_acceptor(
+        &self,
+        dest_id: Option<&Identity>,
+    ) -> Result<Arc<rustls::ServerConfig>, Error> {
+        let verifier = match dest_id {
+            Some(id) => Verifier::TrustDomain(id.clone()),
+            None => Verifier::None,
+        };
+        self.server_config(verifier, true)
+    }
+
+    /// acceptor returns the configuration of a server that does not require client certificates.
+    pub fn acceptor(&self) -> Result<Arc<rustls::ServerConfig>, Error> {
+        self.server_config(Verifier::None, false)
+    }
+
+    /// connector returns the configuration of a client presenting these certificates to dest.
+    pub fn connector(
+        &self,
+        dest: impl Into<ExpectedPeer>,
+    ) -> Result<Arc<rustls::ClientConfig>, Error> {
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(self.peer_verifier(Verifier::Peer(dest.into()), true))
+            .with_client_auth_cert(self.presented_chain(), self.key.clone())?;
+        config.alpn_protocols = Alpn::H2.protocols();
+        Ok(Arc::new(config))
+    }
+
+    pub fn x509(&self) -> &Certificate {
+        &self.cert
+    }
+
+    /// identities returns the SPIFFE identities the certificate is for.
+    pub fn identities(&self) -> Vec<Identity> {
+        extract_sans(&self.cert)
+    }
+
+    /// chain returns the first certificate of the chain, usually the root, as PEM.
+    pub fn chain(&self) -> Result<Bytes, Error> {
+        let cert = self
+            .chain
+            .first()
+            .ok_or_else(|| Error::InvalidCertificate("no chain".to_string()))?;
+        Ok(to_pem(cert).into())
+    }
+
+    pub fn not_after(&self) -> SystemTime {
+        self.not_after
+    }
+
+    pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+        self.is_expired_at(clock.now())
+    }
+
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        now > self.not_after
+    }
+
+    /// lifetime returns how long the certificate is valid for, from not_before to not_after.
+    pub fn lifetime(&self) -> Duration {
+        self.not_after
+            .duration_since(self.not_before)
+            .unwrap_or(Duration::ZERO)
+    }
+
+    pub fn refresh_at(&self) -> SystemTime {
+        match self.not_after.duration_since(self.not_before) {
+            Ok(_) => self.not_before + self.lifetime() / 2,
+            Err(_) => self.not_after,
+        }
+    }
+
+    pub fn get_duration_until_refresh(&self, clock: &dyn Clock) -> Duration {
+        self.get_duration_until_refresh_at(clock.now())
+    }
+
+    pub fn get_duration_until_refresh_at(&self, now: SystemTime) -> Duration {
+        let halflife = self.lifetime() / 2;
+        // If now() is earlier than not_before, we need to refresh ASAP, so return 0.
+        let elapsed = now.duration_since(self.not_before).unwrap_or(halflife);
+        halflife
+            .checked_sub(elapsed)
+            .unwrap_or_else(|| Duration::from_secs(0))
+    }
+}
+
+/// peer_identities returns the SPIFFE identities of the certificate presented by the peer of a
+/// connection, client or server side.
+pub fn peer_identities(conn: &rustls::CommonState) -> Vec<Identity> {
+    conn.peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(extract_sans)
+        .unwrap_or_default()
+}
+
+/// LoopbackHandshake is the outcome of a handshake seen from both ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoopbackHandshake {
+    /// The identities of the server, as seen by the client.
+    pub server_identities: Vec<Identity>,
+    /// The identities of the client, as seen by the server.
+    pub client_identities: Vec<Identity>,
+    /// The protocol negotiated with ALPN, if any.
+    pub alpn: Option<Vec<u8>>,
+}
+
+/// loopback_handshake performs a mutual TLS handshake over the loopback interface between client,
+/// connecting to dest, and server. It is used to check a pair of certificates works together.
+pub async fn loopback_handshake(
+    client: &Certs,
+    server: &Certs,
+    dest: impl Into<ExpectedPeer>,
+) -> Result<LoopbackHandshake, TlsError> {
+    let listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))
+        .await
+        .map_err(TlsError::Handshake)?;
+    let addr = listener.local_addr().map_err(TlsError::Handshake)?;
+    let acceptor = tokio_rustls::TlsAcceptor::from(server.mtls_acceptor(None)?);
+    let server = tokio::spawn(async move {
+        let (conn, _) = listener.accept().await?;
+        acceptor.accept(conn).await
+    });
+    let connector = client.connector(dest)?;
+    let conn = TcpStream::connect(addr)
+        .await
+        .map_err(TlsError::Handshake)?;
+    let client = connect(connector, conn).await;
+    let server = server
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e)));
+    let client = client?;
+    let server = server.map_err(TlsError::Handshake)?;
+    let (_, client_conn) = client.get_ref();
+    let (_, server_conn) = server.get_ref();
+    Ok(LoopbackHandshake {
+        server_identities: peer_identities(client_conn),
+        client_identities: peer_identities(server_conn),
+        alpn: client_conn.alpn_protocol().map(<[u8]>::to_vec),
+    })
+}
+
+/// connect performs a TLS handshake as a client over stream. Peers are identified by their
+/// certificate rather than their name, so the server name is only used for SNI.
+pub async fn connect<S>(
+    config: Arc<rustls::ClientConfig>,
+    stream: S,
+) -> Result<tokio_rustls::client::TlsStream<S>, TlsError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Any valid name will do, since certificates are not verified against it.
+    let name = ServerName::try_from("ztunnel").expect("valid server name");
+    tokio_rustls::TlsConnector::from(config)
+        .connect(name, stream)
+        .await
+        .map_err(TlsError::Handshake)
+}
+
+#[async_trait::async_trait]
+pub trait CertProvider: Send + Sync {
+    async fn fetch_cert(&mut self, fd: &TcpStream) -> Result<Arc<rustls::ServerConfig>, TlsError>;
+}
+
+#[derive(Clone, Debug)]
+pub struct ControlPlaneCertProvider(pub Certs);
+
+#[async_trait::async_trait]
+impl CertProvider for ControlPlaneCertProvider {
+    async fn fetch_cert(&mut self, _: &TcpStream) -> Result<Arc<rustls::ServerConfig>, TlsError> {
+        Ok(self.0.acceptor()?)
+    }
+}
+
+#[derive(Clone)]
+pub struct RustlsTlsAcceptor<F: CertProvider> {
+    /// Acceptor is a function that determines the TLS configuration to use. As input, the FD of
+    /// the client connection is provided.
+    pub acceptor: F,
+}
+
+impl<F> tls_listener::AsyncTls<TcpStream> for RustlsTlsAcceptor<F>
+where
+    F: CertProvider + Clone + 'static,
+{
+    type Stream = tokio_rustls::server::TlsStream<TcpStream>;
+    type Error = TlsError;
+    type AcceptFuture = Pin<Box<dyn Future<Output = Result<Self::Stream, Self::Error>> + Send>>;
+
+    fn accept(&self, conn: TcpStream) -> Self::AcceptFuture {
+        let mut acceptor = self.acceptor.clone();
+        Box::pin(async move {
+            let config = acceptor.fetch_cert(&conn).await?;
+            tokio_rustls::TlsAcceptor::from(config)
+                .accept(conn)
+                .await
+                .map_err(TlsError::Handshake)
+        })
+    }
+}
+
+type BoxBody1 = HttpBody04ToHttpBody1<BoxBody>;
+
+/// RustlsGrpcChannel is a client TLS channel for gRPC requests to the control plane.
+#[derive(Clone)]
+pub struct RustlsGrpcChannel {
+    uri: Uri,
+    client: hyper_util::client::legacy::Client<RustlsConnector, BoxBody1>,
+}
+
+impl Debug for RustlsGrpcChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RustlsGrpcChannel")
+            .field("uri", &self.uri)
+            .finish()
+    }
+}
+
+/// grpc_connector provides a client TLS channel for gRPC requests. Unix domain sockets and HTTPS
+/// proxies are not supported by this backend.
+pub fn grpc_connector(
+    uri: String,
+    root_cert: RootCert,
+    cfg: ConnectorConfig,
+) -> Result<RustlsGrpcChannel, Error> {
+    if uri.starts_with("unix://") {
+        return Err(Error::Unsupported("unix domain socket control plane"));
+    }
+    let uri = Uri::try_from(uri)?;
+    if !matches!(cfg.proxy_for(&uri), Ok(None)) {
+        return Err(Error::Unsupported("HTTPS proxy"));
+    }
+    let mut roots = rustls::RootCertStore::empty();
+    let pem = match &root_cert {
+        RootCert::File(f) => std::fs::read(f)?,
+        RootCert::Static(b) => b.to_vec(),
+        RootCert::Directory(_) => return Err(Error::Unsupported("root certificate directory")),
+        RootCert::Default => return Err(Error::Unsupported("system root certificates")),
+    };
+    for root in parse_certs(&pem)? {
+        roots.add(&root)?;
+    }
+    if roots.is_empty() {
+        return Err(Error::NoRootCerts);
+    }
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = match cfg.alpn {
+        ControlPlaneAlpn::H2 => Alpn::H2.protocols(),
+        ControlPlaneAlpn::H2AndHttp11 => Alpn::H2AndHttp11.protocols(),
+        ControlPlaneAlpn::Disabled => Vec::new(),
+    };
+    // Follow Istio logic to allow localhost calls, verifying the certificate of istiod instead.
+    let server_name = match uri.host() {
+        Some("localhost") => Some("istiod.istio-system.svc".to_string()),
+        _ => None,
+    };
+    let connector = RustlsConnector {
+        http: cfg.http_connector(),
+        tls: tokio_rustls::TlsConnector::from(Arc::new(config)),
+        server_name,
+    };
+    Ok(RustlsGrpcChannel {
+        uri,
+        client: super::boring::grpc_client_builder(&cfg.limits).build(connector),
+    })
+}
+
+impl tower::Service<Request<BoxBody>> for RustlsGrpcChannel {
+    type Response = Response<HttpBody1ToHttpBody04<Incoming>>;
+    type Error = ChannelError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        let mut req = req.map(HttpBody04ToHttpBody1::new);
+        let (Some(scheme), Some(authority)) = (self.uri.scheme(), self.uri.authority()) else {
+            let err = ChannelError::InvalidUri(self.uri.to_string());
+            return Box::pin(async move { Err(err) });
+        };
+        let uri = Uri::builder()
+            .scheme(scheme.to_owned())
+            .authority(authority.to_owned())
+            .path_and_query(
+                req.uri()
+                    .path_and_query()
+                    .map(|pq| pq.as_str())
+                    .unwrap_or("/"),
+            )
+            .build();
+        match uri {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(e) => {
+                let err = ChannelError::InvalidUri(e.to_string());
+                return Box::pin(async move { Err(err) });
+            }
+        }
+        let future = self.client.request(req);
+        Box::pin(async move {
+            let res = future.await?;
+            Ok(res.map(HttpBody1ToHttpBody04::new))
+        })
+    }
+}
+
+#[derive(Clone)]
+struct RustlsConnector {
+    http: HttpConnector<CachingResolver>,
+    tls: tokio_rustls::TlsConnector,
+    // Name to verify the server certificate against, in place of the host of the request.
+    server_name: Option<String>,
+}
+
+impl tower::Service<Uri> for RustlsConnector {
+    type Response = RustlsStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        tower::Service::<Uri>::poll_ready(&mut self.http, cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = self
+            .server_name
+            .clone()
+            .or_else(|| uri.host().map(str::to_string))
+            .unwrap_or_default();
+        let connect = tower::Service::<Uri>::call(&mut self.http, uri);
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let tcp = connect.await?;
+            let name = ServerName::try_from(host.as_str())?;
+            Ok(RustlsStream(tls.connect(name, tcp).await?))
+        })
+    }
+}
+
+struct RustlsStream(tokio_rustls::client::TlsStream<TcpStream>);
+
+impl Connection for RustlsStream {
+    fn connected(&self) -> Connected {
+        let (_, session) = self.0.get_ref();
+        if session.alpn_protocol() == Some(b"h2") {
+            Connected::new().negotiated_h2()
+        } else {
+            Connected::new()
+        }
+    }
+}
+
+impl AsyncRead for RustlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RustlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::identity::Identity;
+    use crate::tls::{generate_test_certs, CsrOptions};
+
+    use super::{cert_from, generate_csr, Error, SanChecker};
+
+    const TEST_PKEY: &[u8] = include_bytes!("key.pem");
+
+    #[test]
+    fn san_checker() {
+        let id = Identity::default();
+        let certs = generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let leaf = certs.x509().to_pem().unwrap();
+        let certs = cert_from(TEST_PKEY, &leaf, vec![]).unwrap();
+        assert!(certs.verify_san(&id).is_ok());
+        assert!(certs.verify_san_trust_domain(&id).is_ok());
+        let other = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "default".to_string(),
+            service_account: "default".to_string(),
+        };
+        assert!(certs.verify_san(&other).is_err());
+        assert!(certs.verify_san_trust_domain(&other).is_ok());
+    }
+
+    #[test]
+    fn key_mismatch() {
+        let certs = generate_test_certs(
+            &Identity::default().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let other_key = generate_csr(&CsrOptions {
+            san: Identity::default().to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+        .pkey;
+        let leaf = certs.x509().to_pem().unwrap();
+        assert!(matches!(
+            cert_from(&other_key, &leaf, vec![]),
+            Err(Error::KeyCertMismatch)
+        ));
+    }
+
+    #[test]
+    fn csr_accepted_by_boring() {
+        let csr = generate_csr(&CsrOptions {
+            san: Identity::default().to_string(),
+            ip_sans: vec!["10.0.0.5".parse().unwrap()],
+            ..Default::default()
+        })
+        .unwrap();
+        // istiod parses CSRs with Go, so make sure another implementation accepts them.
+        let req = boring::x509::X509Req::from_pem(&csr.csr).unwrap();
+        let key = boring::pkey::PKey::private_key_from_pem(&csr.pkey).unwrap();
+        assert!(req.verify(&key).unwrap());
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Peer verification cases shared by the TLS backends, so that they accept and reject the same
+// peers.
+
+use std::net::IpAddr;
+
+use crate::identity::Identity;
+
+use super::{ExpectedPeer, TestIdentity, TestSan};
+
+pub struct SanCase {
+    pub name: &'static str,
+    /// The SANs of the certificate presented by the server.
+    pub server: TestIdentity,
+    /// What the client verifies the server certificate against.
+    pub expected: ExpectedPeer,
+    pub ok: bool,
+}
+
+fn spiffe(trust_domain: &str, namespace: &str, service_account: &str) -> Identity {
+    Identity::Spiffe {
+        trust_domain: trust_domain.to_string(),
+        namespace: namespace.to_string(),
+        service_account: service_account.to_string(),
+    }
+}
+
+pub fn san_cases() -> Vec<SanCase> {
+    let id = Identity::default();
+    let other_sa = spiffe("cluster.local", "istio-system", "other");
+    let other_td = spiffe("example.com", "istio-system", "ztunnel");
+    let longer = spiffe("cluster.local", "istio-system", "ztunnel-extra");
+    let ip: IpAddr = "10.0.0.5".parse().unwrap();
+    let other_ip: IpAddr = "10.0.0.6".parse().unwrap();
+    let waypoint = "waypoint.ns.svc.cluster.local";
+    let dns = |name: &str| TestSan::Dns(name.to_string());
+    let identity_or_waypoint = ExpectedPeer::AnyOf(vec![
+        id.clone().into(),
+        ExpectedPeer::DnsName(waypoint.to_string()),
+    ]);
+    vec![
+        SanCase {
+            name: "exact match",
+            server: id.clone().into(),
+            expected: id.clone().into(),
+            ok: true,
+        },
+        SanCase {
+            name: "other service account",
+            server: other_sa.clone().into(),
+            expected: id.clone().into(),
+            ok: false,
+        },
+        SanCase {
+            name: "other trust domain",
+            server: other_td.into(),
+            expected: id.clone().into(),
+            ok: false,
+        },
+        SanCase {
+            name: "identity prefix",
+            server: longer.into(),
+            expected: id.clone().into(),
+            ok: false,
+        },
+        SanCase {
+            name: "one of several URI SANs",
+            server: TestIdentity::Sans(vec![
+                TestSan::Uri(other_sa.clone()),
+                TestSan::Uri(id.clone()),
+            ]),
+            expected: id.clone().into(),
+            ok: true,
+        },
+        SanCase {
+            name: "identity in a DNS SAN",
+            server: TestIdentity::Sans(vec![TestSan::Dns(id.to_string())]),
+            expected: id.clone().into(),
+            ok: false,
+        },
+        SanCase {
+            name: "IP SAN",
+            server: TestIdentity::Sans(vec![TestSan::Uri(id.clone()), TestSan::Ip(ip)]),
+            expected: ip.into(),
+            ok: true,
+        },
+        SanCase {
+            name: "other IP SAN",
+            server: ip.into(),
+            expected: other_ip.into(),
+            ok: false,
+        },
+        SanCase {
+            name: "IP expected, identity presented",
+            server: id.clone().into(),
+            expected: ip.into(),
+            ok: false,
+        },
+        SanCase {
+            name: "DNS name",
+            server: TestIdentity::Sans(vec![TestSan::Uri(other_sa.clone()), dns(waypoint)]),
+            expected: ExpectedPeer::DnsName(waypoint.to_string()),
+            ok: true,
+        },
+        SanCase {
+            name: "DNS name in another case",
+            server: TestIdentity::Sans(vec![dns(waypoint)]),
+            expected: ExpectedPeer::DnsName(waypoint.to_uppercase()),
+            ok: true,
+        },
+        SanCase {
+            name: "other DNS name",
+            server: TestIdentity::Sans(vec![dns("gateway.ns.svc.cluster.local")]),
+            expected: ExpectedPeer::DnsName(waypoint.to_string()),
+            ok: false,
+        },
+        SanCase {
+            name: "wildcard DNS SAN",
+            server: TestIdentity::Sans(vec![dns("*.ns.svc.cluster.local")]),
+            expected: ExpectedPeer::DnsName(waypoint.to_string()),
+            ok: true,
+        },
+        SanCase {
+            name: "wildcard matches a single label",
+            server: TestIdentity::Sans(vec![dns("*.svc.cluster.local")]),
+            expected: ExpectedPeer::DnsName(waypoint.to_string()),
+            ok: false,
+        },
+        SanCase {
+            name: "partial wildcard",
+            server: TestIdentity::Sans(vec![dns("way*.ns.svc.cluster.local")]),
+            expected: ExpectedPeer::DnsName(waypoint.to_string()),
+            ok: false,
+        },
+        SanCase {
+            name: "identity or DNS name, DNS name presented",
+            server: TestIdentity::Sans(vec![TestSan::Uri(other_sa.clone()), dns(waypoint)]),
+            expected: identity_or_waypoint.clone(),
+            ok: true,
+        },
+        SanCase {
+            name: "identity or DNS name, identity presented",
+            server: id.into(),
+            expected: identity_or_waypoint.clone(),
+            ok: true,
+        },
+        SanCase {
+            name: "identity or DNS name, neither presented",
+            server: TestIdentity::Sans(vec![TestSan::Uri(other_sa), dns("other.ns.svc")]),
+            expected: identity_or_waypoint,
+            ok: false,
+        },
+    ]
+}
@@ -0,0 +1,213 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tracing::warn;
+
+// A fatal internal_error alert, in a record with the version clients accept before the handshake.
+const INTERNAL_ERROR_ALERT: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x50];
+
+/// LoadShedConfig sets when new TLS connections are refused, so that the process does not run
+/// out of file descriptors or memory in the middle of handshakes.
+#[derive(serde::Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoadShedConfig {
+    /// Most TLS streams open at once, handshakes in progress included. Unbounded if unset.
+    pub max_streams: Option<usize>,
+    /// Resident memory of the process, in bytes, above which new connections are refused.
+    /// Unbounded if unset.
+    pub max_rss_bytes: Option<u64>,
+    /// Whether refused clients are sent a TLS alert before the connection is closed, so that
+    /// they can tell the refusal from a network failure.
+    pub send_alert: bool,
+}
+
+/// ShedReason is why a connection was refused by a LoadShedPolicy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ShedReason {
+    /// As many TLS streams as allowed are open.
+    MaxStreams,
+    /// The resident memory of the process is over the threshold.
+    Memory,
+    /// An external signal reported resource pressure.
+    Pressure,
+}
+
+impl fmt::Display for ShedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ShedReason::MaxStreams => "max_streams",
+            ShedReason::Memory => "memory",
+            ShedReason::Pressure => "pressure",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// LoadShedPolicy decides whether new TLS connections are accepted. Deciding only reads atomics:
+/// memory is sampled in the background by watch_memory, and external pressure is reported with
+/// set_pressure. Clones share the state.
+#[derive(Clone, Debug)]
+pub struct LoadShedPolicy {
+    inner: Arc<ShedState>,
+}
+
+#[derive(Debug)]
+struct ShedState {
+    config: LoadShedConfig,
+    open: AtomicUsize,
+    rss: AtomicU64,
+    pressure: AtomicBool,
+}
+
+impl LoadShedPolicy {
+    pub fn new(config: LoadShedConfig) -> Self {
+        LoadShedPolicy {
+            inner: Arc::new(ShedState {
+                config,
+                open: AtomicUsize::new(0),
+                rss: AtomicU64::new(0),
+                pressure: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &LoadShedConfig {
+        &self.inner.config
+    }
+
+    /// admit decides whether a new connection is accepted. An accepted connection is counted as
+    /// an open stream until the returned guard is dropped.
+    pub fn admit(&self) -> Result<StreamGuard, ShedReason> {
+        let state = &self.inner;
+        if state.pressure.load(Ordering::Relaxed) {
+            return Err(ShedReason::Pressure);
+        }
+        if let Some(max) = state.config.max_rss_bytes {
+            if state.rss.load(Ordering::Relaxed) > max {
+                return Err(ShedReason::Memory);
+            }
+        }
+        let open = state.open.fetch_add(1, Ordering::AcqRel);
+        let guard = StreamGuard(state.clone());
+        match state.config.max_streams {
+            Some(max) if open >= max => Err(ShedReason::MaxStreams),
+            _ => Ok(guard),
+        }
+    }
+
+    /// open_streams returns the number of streams counted as open.
+    pub fn open_streams(&self) -> usize {
+        self.inner.open.load(Ordering::Acquire)
+    }
+
+    /// set_pressure is the hook for an external signal of resource pressure, such as a memory
+    /// pressure notification of the cgroup: while set, every new connection is refused.
+    pub fn set_pressure(&self, pressure: bool) {
+        self.inner.pressure.store(pressure, Ordering::Relaxed);
+    }
+
+    /// set_rss records the resident memory of the process, in bytes.
+    pub fn set_rss(&self, bytes: u64) {
+        self.inner.rss.store(bytes, Ordering::Relaxed);
+    }
+
+    /// watch_memory samples the resident memory of the process every interval, until the policy
+    /// is dropped. Nothing is sampled unless max_rss_bytes is set.
+    pub fn watch_memory(&self, interval: Duration) {
+        if self.inner.config.max_rss_bytes.is_none() {
+            return;
+        }
+        let policy = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            loop {
+                let Some(state) = policy.upgrade() else {
+                    return;
+                };
+                match resident_memory() {
+                    Ok(bytes) => state.rss.store(bytes, Ordering::Relaxed),
+                    Err(e) => {
+                        // It will not get readable later, such as on a system without procfs.
+                        warn!("failed to read resident memory, not shedding on memory: {e}");
+                        return;
+                    }
+                }
+                drop(state);
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    // refuse closes conn, sending an alert first if configured. The alert is only sent if the
+    // socket can take it right away, so that shedding never waits on a client.
+    pub(super) fn refuse(&self, conn: TcpStream) {
+        if self.inner.config.send_alert {
+            let _ = conn.try_write(&INTERNAL_ERROR_ALERT);
+        }
+    }
+}
+
+/// StreamGuard counts a stream as open against a LoadShedPolicy until it is dropped.
+#[derive(Debug)]
+pub struct StreamGuard(Arc<ShedState>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// resident_memory reads the resident memory of the process, in bytes.
+fn resident_memory() -> io::Result<u64> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|rss| rss.trim().strip_suffix("kB"))
+        .and_then(|kb| kb.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "VmRSS not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LoadShedConfig, LoadShedPolicy, ShedReason};
+
+    #[test]
+    fn admit() {
+        let policy = LoadShedPolicy::new(LoadShedConfig {
+            max_streams: Some(1),
+            max_rss_bytes: Some(1 << 30),
+            send_alert: false,
+        });
+        let guard = policy.admit().unwrap();
+        assert_eq!(policy.admit().unwrap_err(), ShedReason::MaxStreams);
+        drop(guard);
+        assert_eq!(policy.open_streams(), 0);
+
+        policy.set_rss(2 << 30);
+        assert_eq!(policy.admit().unwrap_err(), ShedReason::Memory);
+        policy.set_rss(0);
+        policy.set_pressure(true);
+        assert_eq!(policy.admit().unwrap_err(), ShedReason::Pressure);
+        policy.set_pressure(false);
+        assert!(policy.admit().is_ok());
+        assert!(super::resident_memory().unwrap() > 0);
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use boring::ssl;
+use tokio::net::TcpStream;
+
+// The ClientHello is read from the first record only, which is as large as records get.
+const MAX_HELLO_RECORD: usize = 5 + 16384;
+// How many times, and how long apart, a ClientHello split across TCP segments is peeked again.
+const PEEK_ATTEMPTS: usize = 10;
+const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME_EXTENSION: u16 = 0x0000;
+const HOST_NAME: u8 = 0x00;
+
+/// peek_sni returns the server name the client of conn asks for in its ClientHello, without
+/// consuming any data. The certificate has to be fetched before BoringSSL reads the ClientHello,
+/// as the certificate callbacks of BoringSSL cannot wait for a fetch. None is returned if the
+/// client sent no server name, or if it does not start with a ClientHello at all.
+pub async fn peek_sni(conn: &TcpStream) -> Option<String> {
+    let mut buf = vec![0; MAX_HELLO_RECORD];
+    for _ in 0..PEEK_ATTEMPTS {
+        let n = conn.peek(&mut buf).await.ok()?;
+        match parse_sni(&buf[..n]) {
+            Parsed::Done(sni) => return sni,
+            // Nothing more will arrive if the client closed.
+            Parsed::Incomplete if n == 0 => return None,
+            Parsed::Incomplete => tokio::time::sleep(PEEK_INTERVAL).await,
+        }
+    }
+    None
+}
+
+/// sni returns the server name the client of an accepted TLS stream asked for, if any.
+pub fn sni(ssl: &ssl::SslRef) -> Option<&str> {
+    ssl.servername(ssl::NameType::HOST_NAME)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Parsed {
+    Done(Option<String>),
+    Incomplete,
+}
+
+// parse_sni extracts the host name of the server_name extension of the ClientHello starting buf.
+fn parse_sni(buf: &[u8]) -> Parsed {
+    let mut record = Reader(buf);
+    let Some(header) = record.take(5) else {
+        return Parsed::Incomplete;
+    };
+    if header[0] != HANDSHAKE_RECORD {
+        return Parsed::Done(None);
+    }
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    let Some(body) = record.take(len) else {
+        return Parsed::Incomplete;
+    };
+    Parsed::Done(client_hello_sni(Reader(body)))
+}
+
+fn client_hello_sni(mut hello: Reader<'_>) -> Option<String> {
+    if hello.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    // A ClientHello larger than its record is not parsed, the caller falls back to no SNI.
+    let len = hello.u24()?;
+    let mut hello = Reader(hello.take(len)?);
+    // Version and random.
+    hello.take(2 + 32)?;
+    let session_id = hello.u8()? as usize;
+    hello.take(session_id)?;
+    let ciphers = hello.u16()? as usize;
+    hello.take(ciphers)?;
+    let compression = hello.u8()? as usize;
+    hello.take(compression)?;
+    let extensions = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(extensions)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let data = extensions.take(len)?;
+        if kind == SERVER_NAME_EXTENSION {
+            return server_name(Reader(data));
+        }
+    }
+    None
+}
+
+fn server_name(mut ext: Reader<'_>) -> Option<String> {
+    let len = ext.u16()? as usize;
+    let mut names = Reader(ext.take(len)?);
+    while !names.0.is_empty() {
+        let kind = names.u8()?;
+        let len = names.u16()? as usize;
+        let name = names.take(len)?;
+        if kind == HOST_NAME {
+            return std::str::from_utf8(name).ok().map(str::to_string);
+        }
+    }
+    None
+}
+
+// Reader reads big endian integers and slices off the front of a buffer.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::identity::Identity;
+    use crate::tls::{
+        generate_test_certs, Accept, AcceptMeta, BoringTlsAcceptor, CertProvider, Certs, ConnMeta,
+        MaybeTls, TlsError,
+    };
+
+    use super::{parse_sni, sni, Parsed};
+
+    // SniCertProvider serves the certificate of the SNI of the client, or a default one.
+    #[derive(Clone)]
+    struct SniCertProvider {
+        by_sni: HashMap<String, Certs>,
+        default: Certs,
+        seen: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl CertProvider for SniCertProvider {
+        async fn fetch_cert(&mut self, _: &TcpStream, _: &AcceptMeta) -> Result<Accept, TlsError> {
+            Ok(self.default.acceptor()?.into())
+        }
+
+        async fn fetch_cert_for(
+            &mut self,
+            fd: &TcpStream,
+            meta: &ConnMeta,
+        ) -> Result<Accept, TlsError> {
+            self.seen.lock().unwrap().push(meta.sni.clone());
+            match meta.sni.as_ref().and_then(|sni| self.by_sni.get(sni)) {
+                Some(certs) => Ok(certs.acceptor()?.into()),
+                None => self.fetch_cert(fd, &meta.addrs).await,
+            }
+        }
+    }
+
+    fn certs(id: &Identity) -> Certs {
+        generate_test_certs(
+            &id.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        )
+    }
+
+    #[tokio::test]
+    async fn routes_on_sni() {
+        let default = Identity::default();
+        let other = Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "other".to_string(),
+            service_account: "other".to_string(),
+        };
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let acceptor = BoringTlsAcceptor {
+            acceptor: SniCertProvider {
+                by_sni: HashMap::from([
+                    ("default.example".to_string(), certs(&default)),
+                    ("other.example".to_string(), certs(&other)),
+                ]),
+                default: certs(&default),
+                seen: seen.clone(),
+            },
+            metrics: None,
+            drain: Default::default(),
+            authorizer: None,
+            limiter: None,
+            socket: Default::default(),
+            shed: None,
+            sni_routing: false,
+        }
+        .with_sni_routing();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for (server_name, served) in [
+            (Some("default.example"), &default),
+            (Some("other.example"), &other),
+            (None, &default),
+        ] {
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let (conn, _) = listener.accept().await.unwrap();
+            let server = {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move { acceptor.accept_maybe_tls(conn, false).await })
+            };
+            // The client only accepts the certificate of the identity expected to be served.
+            let mut cfg = certs(&default)
+                .connector(served)
+                .unwrap()
+                .configure()
+                .unwrap();
+            cfg.set_verify_hostname(false);
+            cfg.set_use_server_name_indication(server_name.is_some());
+            tokio_boring::connect(cfg, server_name.unwrap_or(""), tcp)
+                .await
+                .unwrap();
+            let Ok(MaybeTls::Tls(server)) = server.await.unwrap() else {
+                panic!("expected a TLS stream");
+            };
+            assert_eq!(sni(server.ssl()), server_name);
+        }
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                Some("default.example".to_string()),
+                Some("other.example".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn parse() {
+        // A ClientHello for example.com, with only the server_name extension.
+        let mut hello = vec![0x03, 0x03];
+        hello.extend([0; 32]);
+        hello.extend([0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        let name = b"example.com";
+        let mut ext = vec![0x00, 0x00];
+        ext.extend(((name.len() + 5) as u16).to_be_bytes());
+        ext.extend(((name.len() + 3) as u16).to_be_bytes());
+        ext.push(0x00);
+        ext.extend((name.len() as u16).to_be_bytes());
+        ext.extend(name);
+        hello.extend((ext.len() as u16).to_be_bytes());
+        hello.extend(ext);
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend((hello.len() as u16).to_be_bytes());
+        handshake.extend(hello);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+
+        assert_eq!(
+            parse_sni(&record),
+            Parsed::Done(Some("example.com".to_string()))
+        );
+        assert_eq!(parse_sni(&record[..record.len() - 1]), Parsed::Incomplete);
+        assert_eq!(parse_sni(&record[..3]), Parsed::Incomplete);
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n"), Parsed::Done(None));
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use boring::x509::{self, X509StoreContextRef};
+
+use crate::identity::Identity;
+
+use super::{extract_sans, parse_root_certs, Error, TlsError};
+
+/// TrustBundle maps SPIFFE trust domains to the roots that are allowed to sign identities in that
+/// trust domain. It is used for federation, where a peer must not only chain to one of the trusted
+/// roots, but to a root of its own trust domain.
+#[derive(Clone, Debug, Default)]
+pub struct TrustBundle {
+    roots: HashMap<String, Vec<x509::X509>>,
+}
+
+impl TrustBundle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// from_files builds a TrustBundle from PEM root bundles, keyed by trust domain.
+    pub fn from_files(files: &HashMap<String, PathBuf>) -> Result<Self, Error> {
+        let mut bundle = Self::new();
+        for (trust_domain, path) in files {
+            let pem = std::fs::read(path).map_err(|e| Error::root_cert_io(path, e))?;
+            bundle.add(trust_domain, parse_root_certs(&pem)?);
+        }
+        Ok(bundle)
+    }
+
+    /// add trusts `roots` for identities in `trust_domain`, in addition to any existing roots.
+    pub fn add(&mut self, trust_domain: &str, roots: Vec<x509::X509>) {
+        self.roots
+            .entry(trust_domain.to_string())
+            .or_default()
+            .extend(roots);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// roots returns the union of all roots, across trust domains.
+    pub fn roots(&self) -> impl Iterator<Item = &x509::X509> {
+        self.roots.values().flatten()
+    }
+
+    pub fn roots_for(&self, trust_domain: &str) -> &[x509::X509] {
+        self.roots
+            .get(trust_domain)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// verify checks that the verified chain in ctx terminates at a root of the trust domain of
+    /// every SPIFFE SAN of the leaf, so that a CA of one trust domain cannot vouch for identities
+    /// of another. This must run after standard chain verification has succeeded.
+    pub fn verify(&self, ctx: &X509StoreContextRef) -> Result<(), TlsError> {
+        let chain = ctx.chain().ok_or(TlsError::PeerCertError)?;
+        let (Some(leaf), Some(root)) = (chain.iter().next(), chain.iter().last()) else {
+            return Err(TlsError::PeerCertError);
+        };
+        let root = root.to_der().map_err(Error::SslError)?;
+        let sans = extract_sans(leaf);
+        let trusted = !sans.is_empty()
+            && sans.iter().all(|id| {
+                let Identity::Spiffe { trust_domain, .. } = id;
+                self.roots_for(trust_domain)
+                    .iter()
+                    .any(|r| r.to_der().map(|r| r == root).unwrap_or(false))
+            });
+        if !trusted {
+            return Err(TlsError::TrustBundleError(sans));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::Uri;
+
+use crate::config::{Config, RootCert};
+use crate::identity::Identity;
+
+use super::boring::control_plane_connector;
+use super::{
+    generate_test_ca_with_key_type, generate_test_certs_with_key_type, is_fips, ConnectorConfig,
+    Endpoint, Error, KeyType, TlsRuntimeConfig, UDS_SCHEME_PREFIX,
+};
+
+/// ConfigError is a problem with the TLS configuration, found by validate_config.
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("FIPS mode is required, but the TLS library was not built with FIPS support")]
+    FipsUnavailable,
+    #[error("{service} address {address:?} has no scheme, expected https:// or unix://")]
+    MissingScheme {
+        service: &'static str,
+        address: String,
+    },
+    #[error("{service} address {address:?} is invalid: {source}")]
+    InvalidAddress {
+        service: &'static str,
+        address: String,
+        source: Error,
+    },
+    #[error("{service} root certificate {path:?} does not exist")]
+    MissingRootCert { service: &'static str, path: String },
+    #[error("{service} root certificate for {address:?} cannot be loaded: {source}")]
+    RootCert {
+        service: &'static str,
+        address: String,
+        source: Error,
+    },
+    #[error("failed to resolve {service} host {host:?}: {source}")]
+    Resolve {
+        service: &'static str,
+        host: String,
+        source: io::Error,
+    },
+    #[error("{service} connector for {address:?} cannot be built: {source}")]
+    Endpoint {
+        service: &'static str,
+        address: String,
+        source: Error,
+    },
+    #[error("invalid tls runtime config: {0}")]
+    RuntimeConfig(Error),
+    #[error("inbound TLS context cannot be built with the configured policy: {0}")]
+    Acceptor(Error),
+    #[error("outbound TLS context cannot be built with the configured policy: {0}")]
+    Connector(Error),
+}
+
+/// validate_config checks the TLS configuration up front, so that mistakes are reported at
+/// startup rather than as handshake failures later on. It loads the roots and builds the
+/// connector of every control plane endpoint, resolving but not dialing their hosts, and builds
+/// a throwaway acceptor and connector with the configured policy and a workload key of the
+/// configured type. All the problems found are returned, not only the first one.
+///
+/// Host resolution blocks, so this is to be called outside of the async runtime.
+pub fn validate_config(cfg: &Config) -> Result<(), Vec<ConfigError>> {
+    let mut errors = Vec::new();
+    if cfg.fips && !is_fips() {
+        errors.push(ConfigError::FipsUnavailable);
+    }
+    let connector_cfg = ConnectorConfig::from(cfg);
+    // With a fake CA, the CA endpoints are never used.
+    if !cfg.fake_ca {
+        for endpoint in cfg.ca_endpoints() {
+            validate_endpoint("CA", &endpoint, &connector_cfg, &mut errors);
+        }
+    }
+    for endpoint in cfg.xds_endpoints() {
+        validate_endpoint("XDS", &endpoint, &connector_cfg, &mut errors);
+    }
+    let runtime = match &cfg.tls_runtime_config {
+        Some(path) => TlsRuntimeConfig::read(path).unwrap_or_else(|e| {
+            errors.push(ConfigError::RuntimeConfig(e));
+            Default::default()
+        }),
+        None => Default::default(),
+    };
+    validate_policy(cfg, runtime, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn validate_endpoint(
+    service: &'static str,
+    endpoint: &Endpoint,
+    cfg: &ConnectorConfig,
+    errors: &mut Vec<ConfigError>,
+) {
+    let address = &endpoint.address;
+    if let Some(path) = address.strip_prefix(UDS_SCHEME_PREFIX) {
+        // Unix domain sockets are plaintext, see uds_connector.
+        if endpoint.root_cert != RootCert::Default {
+            errors.push(ConfigError::Endpoint {
+                service,
+                address: address.clone(),
+                source: Error::UdsRootCert(path.into()),
+            });
+        }
+        return;
+    }
+    let uri = match Uri::try_from(address.as_str()) {
+        Ok(uri) => uri,
+        Err(e) => {
+            errors.push(ConfigError::InvalidAddress {
+                service,
+                address: address.clone(),
+                source: e.into(),
+            });
+            return;
+        }
+    };
+    if uri.scheme().is_none() {
+        errors.push(ConfigError::MissingScheme {
+            service,
+            address: address.clone(),
+        });
+        return;
+    }
+
+    // A root that is neither a file nor a PEM bundle is most likely a file that does not exist,
+    // see parse_root_cert.
+    let root_missing = match &endpoint.root_cert {
+        RootCert::Static(b) => !b.windows(10).any(|w| w == b"-----BEGIN"),
+        _ => false,
+    };
+    if let (true, RootCert::Static(b)) = (root_missing, &endpoint.root_cert) {
+        errors.push(ConfigError::MissingRootCert {
+            service,
+            path: String::from_utf8_lossy(b).into_owned(),
+        });
+    } else if let Err(e) = control_plane_connector(&uri, &endpoint.root_cert, cfg) {
+        errors.push(match e {
+            Error::RootCertParse(_)
+            | Error::RootCertEmpty
+            | Error::RootCertDirectory(..)
+            | Error::RootCertIo(..) => ConfigError::RootCert {
+                service,
+                address: address.clone(),
+                source: e,
+            },
+            e => ConfigError::Endpoint {
+                service,
+                address: address.clone(),
+                source: e,
+            },
+        });
+    }
+
+    // Through a proxy, the host is resolved by the proxy.
+    if matches!(cfg.proxy_for(&uri), Ok(Some(_))) {
+        return;
+    }
+    let host = uri
+        .host()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("http") {
+            80
+        } else {
+            443
+        });
+    let resolved = (host, port).to_socket_addrs().and_then(|mut addrs| {
+        addrs
+            .next()
+            .map(drop)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses"))
+    });
+    if let Err(source) = resolved {
+        errors.push(ConfigError::Resolve {
+            service,
+            host: host.to_string(),
+            source,
+        });
+    }
+}
+
+// Builds an acceptor and a connector with the configured policy, for a certificate of the
+// configured workload key type. The certificate is issued by a throwaway root, as only the local
+// settings are checked.
+fn validate_policy(cfg: &Config, runtime: TlsRuntimeConfig, errors: &mut Vec<ConfigError>) {
+    let id = Identity::default();
+    let (ca_cert, ca_key) = generate_test_ca_with_key_type("validation", KeyType::default());
+    let certs = generate_test_certs_with_key_type(
+        &id.clone().into(),
+        Duration::ZERO,
+        Duration::from_secs(60),
+        cfg.workload_key_type,
+        &ca_cert,
+        &ca_key,
+    );
+    let runtime = Arc::new(runtime);
+    let builder = || {
+        let mut builder = certs
+            .builder()
+            .runtime_config(runtime.clone())
+            .security_level(cfg.openssl_security_level.unwrap_or(0))
+            .release_buffers(cfg.tls_records.release_buffers);
+        if let Some(sigalgs) = &cfg.tls_sigalgs {
+            builder = builder.sigalgs(sigalgs);
+        }
+        if let Some(max) = cfg.tls_records.max_send_fragment {
+            builder = builder.max_send_fragment(max);
+        }
+        if let Some(max) = cfg.tls_max_cert_list {
+            builder = builder.max_cert_list(max);
+        }
+        builder
+    };
+    if let Err(e) = builder().build_acceptor() {
+        errors.push(ConfigError::Acceptor(e));
+    }
+    if let Err(e) = builder().build_connector(&id) {
+        errors.push(ConfigError::Connector(e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::config::RootCert;
+    use crate::test_helpers::test_config;
+    use crate::tls::{generate_test_certs, is_fips, Endpoint, Error};
+
+    use super::{validate_config, ConfigError};
+
+    #[test]
+    fn valid() {
+        let mut cfg = test_config();
+        assert!(validate_config(&cfg).is_ok());
+
+        let certs = generate_test_certs(
+            &crate::identity::Identity::default().into(),
+            std::time::Duration::ZERO,
+            std::time::Duration::from_secs(100),
+        );
+        cfg.xds_address = Some("https://127.0.0.1:15012".to_string());
+        cfg.xds_root_cert = RootCert::Static(certs.chain().unwrap());
+        cfg.xds_failover_endpoints = vec![Endpoint {
+            address: "unix:///var/run/xds.sock".to_string(),
+            root_cert: RootCert::Default,
+        }];
+        assert!(validate_config(&cfg).is_ok());
+    }
+
+    #[test]
+    fn reports_all_problems() {
+        let mut cfg = test_config();
+        cfg.fake_ca = false;
+        cfg.ca_address = Some("https://127.0.0.1:15012".to_string());
+        cfg.ca_root_cert = RootCert::File("/nonexistent/root-cert.pem".into());
+        cfg.ca_failover_endpoints = vec![
+            Endpoint {
+                address: "https://ca.invalid:15012".to_string(),
+                root_cert: RootCert::Static("./var/run/secrets/missing.pem".into()),
+            },
+            Endpoint {
+                address: "unix:///var/run/ca.sock".to_string(),
+                root_cert: RootCert::File("/etc/ssl/root.pem".into()),
+            },
+        ];
+        cfg.xds_address = Some("istiod.istio-system.svc:15012".to_string());
+        cfg.xds_failover_endpoints = Vec::new();
+        cfg.fips = true;
+        cfg.tls_sigalgs = Some("NOT_A_SIGALG".to_string());
+        cfg.tls_runtime_config = Some(PathBuf::from("/nonexistent/tls-runtime.yaml"));
+
+        let errors = validate_config(&cfg).unwrap_err();
+        let has = |f: &dyn Fn(&ConfigError) -> bool| errors.iter().any(f);
+        assert!(
+            has(&|e| matches!(e, ConfigError::RootCert {
+                service: "CA",
+                source: Error::RootCertIo(p, _),
+                ..
+            } if p == &PathBuf::from("/nonexistent/root-cert.pem"))),
+            "{errors:?}"
+        );
+        assert!(
+            has(
+                &|e| matches!(e, ConfigError::MissingRootCert { service: "CA", path }
+                if path == "./var/run/secrets/missing.pem")
+            ),
+            "{errors:?}"
+        );
+        assert!(
+            has(
+                &|e| matches!(e, ConfigError::Resolve { service: "CA", host, .. }
+                if host == "ca.invalid")
+            ),
+            "{errors:?}"
+        );
+        assert!(
+            has(&|e| matches!(
+                e,
+                ConfigError::Endpoint {
+                    service: "CA",
+                    source: Error::UdsRootCert(_),
+                    ..
+                }
+            )),
+            "{errors:?}"
+        );
+        assert!(
+            has(
+                &|e| matches!(e, ConfigError::MissingScheme { service: "XDS", address }
+                if address == "istiod.istio-system.svc:15012")
+            ),
+            "{errors:?}"
+        );
+        assert_eq!(
+            has(&|e| matches!(e, ConfigError::FipsUnavailable)),
+            !is_fips(),
+            "{errors:?}"
+        );
+        assert!(
+            has(&|e| matches!(e, ConfigError::Acceptor(Error::InvalidSigalgs(..)))),
+            "{errors:?}"
+        );
+        assert!(
+            has(&|e| matches!(e, ConfigError::Connector(Error::InvalidSigalgs(..)))),
+            "{errors:?}"
+        );
+        assert!(
+            has(&|e| matches!(e, ConfigError::RuntimeConfig(Error::RuntimeConfig(..)))),
+            "{errors:?}"
+        );
+    }
+
+    #[test]
+    fn weak_workload_key() {
+        let mut cfg = test_config();
+        // P-256 provides 128 bits of security, level 5 requires 256.
+        cfg.openssl_security_level = Some(5);
+        let errors = validate_config(&cfg).unwrap_err();
+        assert!(
+            errors
+                .iter()
+                .any(|e| matches!(e, ConfigError::Acceptor(Error::SecurityLevel(128, 5, 256)))),
+            "{errors:?}"
+        );
+    }
+}
@@ -0,0 +1,315 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::identity::Identity;
+
+use super::{PeerCertInfo, San};
+
+/// XFCC_HEADER is the header carrying XfccElements, as Envoy names it.
+pub const XFCC_HEADER: &str = "x-forwarded-client-cert";
+
+// Characters left as is in the URL encoded Cert field, the unreserved characters of RFC 3986.
+const CERT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum XfccError {
+    #[error("xfcc field {0:?} is not a key=value pair")]
+    MalformedField(String),
+    #[error("xfcc value {0:?} has an unterminated quote")]
+    UnterminatedQuote(String),
+    #[error("xfcc Cert value is not valid URL encoded UTF-8")]
+    InvalidCert,
+}
+
+/// XfccElement describes a client certificate in an x-forwarded-client-cert header, as Envoy
+/// formats it: semicolon separated key=value fields, with values quoted if they contain a
+/// separator. Elements of a header are separated by commas.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct XfccElement {
+    /// The identity of the proxy the client certificate was presented to.
+    pub by: Option<String>,
+    /// Hex encoded SHA-256 digest of the DER client certificate.
+    pub hash: Option<String>,
+    /// The PEM client certificate, URL encoded in the header.
+    pub cert: Option<String>,
+    /// The subject of the client certificate. Always quoted in the header.
+    pub subject: Option<String>,
+    /// The URI SANs of the client certificate, one field each.
+    pub uri: Vec<String>,
+    /// The DNS SANs of the client certificate, one field each.
+    pub dns: Vec<String>,
+}
+
+impl fmt::Display for XfccElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(by) = &self.by {
+            fields.push(format!("By={}", quote_if_needed(by)));
+        }
+        if let Some(hash) = &self.hash {
+            fields.push(format!("Hash={}", quote_if_needed(hash)));
+        }
+        if let Some(cert) = &self.cert {
+            fields.push(format!("Cert={}", utf8_percent_encode(cert, CERT)));
+        }
+        if let Some(subject) = &self.subject {
+            fields.push(format!("Subject={}", quote(subject)));
+        }
+        for uri in &self.uri {
+            fields.push(format!("URI={}", quote_if_needed(uri)));
+        }
+        for dns in &self.dns {
+            fields.push(format!("DNS={}", quote_if_needed(dns)));
+        }
+        f.write_str(&fields.join(";"))
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn quote_if_needed(value: &str) -> String {
+    if value.contains([',', ';', '=', '"', '\\']) {
+        quote(value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// parse_xfcc parses the elements of an x-forwarded-client-cert header. Keys are case insensitive,
+/// and fields this struct does not describe, such as Chain, are skipped.
+pub fn parse_xfcc(header: &str) -> Result<Vec<XfccElement>, XfccError> {
+    split_unquoted(header, ',')?
+        .into_iter()
+        .map(parse_element)
+        .collect()
+}
+
+fn parse_element(element: &str) -> Result<XfccElement, XfccError> {
+    let mut parsed = XfccElement::default();
+    for field in split_unquoted(element, ';')? {
+        let field = field.trim();
+        if field.is_empty() {
+            continue;
+        }
+        let (key, value) = field
+            .split_once('=')
+            .ok_or_else(|| XfccError::MalformedField(field.to_string()))?;
+        let value = unquote(value)?;
+        match key.to_ascii_lowercase().as_str() {
+            "by" => parsed.by = Some(value),
+            "hash" => parsed.hash = Some(value),
+            "cert" => {
+                let cert = percent_decode_str(&value)
+                    .decode_utf8()
+                    .map_err(|_| XfccError::InvalidCert)?;
+                parsed.cert = Some(cert.into_owned());
+            }
+            "subject" => parsed.subject = Some(value),
+            "uri" => parsed.uri.push(value),
+            "dns" => parsed.dns.push(value),
+            _ => {}
+        }
+    }
+    Ok(parsed)
+}
+
+// Splits s on sep, except where sep is within a quoted value.
+fn split_unquoted(s: &str, sep: char) -> Result<Vec<&str>, XfccError> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(XfccError::UnterminatedQuote(s[start..].to_string()));
+    }
+    parts.push(&s[start..]);
+    Ok(parts)
+}
+
+fn unquote(value: &str) -> Result<String, XfccError> {
+    let Some(inner) = value.strip_prefix('"') else {
+        return Ok(value.to_string());
+    };
+    let inner = inner
+        .strip_suffix('"')
+        .ok_or_else(|| XfccError::UnterminatedQuote(value.to_string()))?;
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+    Ok(unquoted)
+}
+
+impl PeerCertInfo {
+    /// to_xfcc_element describes the certificate as an element of an x-forwarded-client-cert
+    /// header, as presented to by.
+    pub fn to_xfcc_element(&self, by: &Identity) -> String {
+        self.xfcc(by).to_string()
+    }
+
+    fn xfcc(&self, by: &Identity) -> XfccElement {
+        let mut element = XfccElement {
+            by: Some(by.to_string()),
+            hash: Some(self.sha256.clone()),
+            cert: Some(self.pem.clone()).filter(|pem| !pem.is_empty()),
+            subject: Some(self.subject.clone()),
+            ..Default::default()
+        };
+        for san in &self.typed_sans {
+            match san {
+                San::Uri(uri) => element.uri.push(uri.clone()),
+                San::Dns(name) => element.dns.push(name.clone()),
+                San::Ip(_) => {}
+            }
+        }
+        element
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use boring::hash::MessageDigest;
+
+    use crate::identity::Identity;
+    use crate::tls::{generate_test_certs, CertInfo, PeerCertInfo, San};
+
+    use super::{parse_xfcc, XfccElement, XfccError};
+
+    #[test]
+    fn envoy_examples() {
+        // From the Envoy documentation of x-forwarded-client-cert.
+        for header in [
+            "By=http://frontend.lyft.com;Hash=468ed33be74eee6556d90c0149c1309e9ba61d6425303443c0748a02dd8de688;Subject=\"/C=US/ST=CA/L=San Francisco/OU=Lyft/CN=Test Client\";URI=http://testclient.lyft.com",
+            "By=http://frontend.lyft.com;Hash=468ed33be74eee6556d90c0149c1309e9ba61d6425303443c0748a02dd8de688;URI=http://testclient.lyft.com,By=http://backend.lyft.com;Hash=9ba61d6425303443c0748a02dd8de688468ed33be74eee6556d90c0149c1309e;URI=http://frontend.lyft.com",
+            "By=http://frontend.lyft.com;Hash=468ed33be74eee6556d90c0149c1309e9ba61d6425303443c0748a02dd8de688;Subject=\"/C=US/ST=CA/L=San Francisco/OU=Lyft/CN=Test Client\";URI=http://testclient.lyft.com;DNS=lyft.com;DNS=www.lyft.com",
+        ] {
+            let elements = parse_xfcc(header).unwrap();
+            let formatted: Vec<_> = elements.iter().map(XfccElement::to_string).collect();
+            assert_eq!(formatted.join(","), header);
+        }
+    }
+
+    #[test]
+    fn escaping() {
+        let element = XfccElement {
+            by: Some("spiffe://cluster.local/ns/default/sa/default".to_string()),
+            cert: Some(
+                "-----BEGIN CERTIFICATE-----\nMIIB+/=\n-----END CERTIFICATE-----\n".to_string(),
+            ),
+            subject: Some("O=\"quoted\",CN=a\\b".to_string()),
+            uri: vec!["spiffe://td/ns/a;b/sa/c".to_string()],
+            ..Default::default()
+        };
+        let header = element.to_string();
+        assert_eq!(
+            header,
+            "By=spiffe://cluster.local/ns/default/sa/default;\
+             Cert=-----BEGIN%20CERTIFICATE-----%0AMIIB%2B%2F%3D%0A-----END%20CERTIFICATE-----%0A;\
+             Subject=\"O=\\\"quoted\\\",CN=a\\\\b\";\
+             URI=\"spiffe://td/ns/a;b/sa/c\""
+        );
+        assert_eq!(parse_xfcc(&header).unwrap(), vec![element]);
+
+        assert_eq!(
+            parse_xfcc("By=a;Subject=\"unterminated"),
+            Err(XfccError::UnterminatedQuote(
+                "By=a;Subject=\"unterminated".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_xfcc("By"),
+            Err(XfccError::MalformedField("By".to_string()))
+        );
+    }
+
+    #[test]
+    fn peer_cert_info() {
+        let by = Identity::default();
+        let info = PeerCertInfo {
+            subject: "O=cluster.local".to_string(),
+            sha256: "468ed33be74eee6556d90c0149c1309e9ba61d6425303443c0748a02dd8de688".to_string(),
+            cert: CertInfo {
+                serial_number: "1".to_string(),
+                not_before: "2023-01-01T00:00:00Z".to_string(),
+                not_after: "2023-01-02T00:00:00Z".to_string(),
+                sans: Vec::new(),
+                issuer: "O=cluster.local".to_string(),
+            },
+            typed_sans: vec![
+                San::Uri("spiffe://cluster.local/ns/client/sa/client".to_string()),
+                San::Dns("client.example".to_string()),
+                San::Ip("10.0.0.1".parse().unwrap()),
+            ],
+            pem: String::new(),
+        };
+        assert_eq!(
+            info.to_xfcc_element(&by),
+            "By=spiffe://cluster.local/ns/istio-system/sa/ztunnel;\
+             Hash=468ed33be74eee6556d90c0149c1309e9ba61d6425303443c0748a02dd8de688;\
+             Subject=\"O=cluster.local\";\
+             URI=spiffe://cluster.local/ns/client/sa/client;\
+             DNS=client.example"
+        );
+
+        // From a certificate, the hash is that of its DER.
+        let certs = generate_test_certs(
+            &by.clone().into(),
+            Duration::from_secs(0),
+            Duration::from_secs(100),
+        );
+        let info = PeerCertInfo::from_x509(certs.x509());
+        let element = parse_xfcc(&info.to_xfcc_element(&by)).unwrap().remove(0);
+        let digest = certs.x509().digest(MessageDigest::sha256()).unwrap();
+        assert_eq!(
+            element.hash.unwrap(),
+            digest
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<String>()
+        );
+        assert_eq!(element.uri, vec![by.to_string()]);
+        assert_eq!(
+            element.cert.unwrap().as_bytes(),
+            certs.x509().to_pem().unwrap()
+        );
+    }
+}
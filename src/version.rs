@@ -31,6 +31,7 @@ pub struct BuildInfo {
     build_status: String,
     git_tag: String,
     pub istio_version: String,
+    fips: bool,
 }
 
 impl BuildInfo {
@@ -42,13 +43,14 @@ impl BuildInfo {
             build_status: BUILD_STATUS.to_string(),
             git_tag: BUILD_TAG.to_string(),
             istio_version: env::var("ISTIO_VERSION").unwrap_or_else(|_| "unknown".to_string()),
+            fips: crate::tls::is_fips(),
         }
     }
 }
 
 impl Display for BuildInfo {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "version.BuildInfo{{Version:\"{}\", GitRevision:\"{}\", RustVersion:\"{}\", BuildStatus:\"{}\", GitTag:\"{}\", IstioVersion:\"{}\"}}",
-        self.version, self.git_revision, self.rust_version, self.build_status, self.git_tag, self.istio_version)
+        write!(f, "version.BuildInfo{{Version:\"{}\", GitRevision:\"{}\", RustVersion:\"{}\", BuildStatus:\"{}\", GitTag:\"{}\", IstioVersion:\"{}\", FIPS:{}}}",
+        self.version, self.git_revision, self.rust_version, self.build_status, self.git_tag, self.istio_version, self.fips)
     }
 }
@@ -39,7 +39,6 @@ use xds::istio::workload::Service as XdsService;
 use xds::istio::workload::Workload as XdsWorkload;
 
 use crate::config::{ConfigSource, ProxyMode};
-use crate::identity::Priority::Warmup;
 use crate::identity::{Identity, SecretManager};
 use crate::metrics::Metrics;
 use crate::rbac::{Authorization, RbacScope};
@@ -382,7 +381,9 @@ impl xds::Handler<XdsWorkload> for Arc<Mutex<WorkloadStore>> {
                 XdsUpdate::Update(w) => wli.insert_xds_workload(w.resource)?,
                 XdsUpdate::Remove(name) => {
                     info!("handling delete {}", name);
-                    wli.remove(name);
+                    if let Some(prev) = wli.remove(name) {
+                        wli.maybe_forget_certificate(&prev);
+                    }
                 }
             }
             Ok(())
@@ -399,7 +400,9 @@ impl xds::Handler<XdsAddress> for Arc<Mutex<WorkloadStore>> {
                 XdsUpdate::Update(w) => wli.insert_xds_address(w.resource)?,
                 XdsUpdate::Remove(name) => {
                     info!("handling delete {}", name);
-                    wli.remove(name);
+                    if let Some(prev) = wli.remove(name) {
+                        wli.maybe_forget_certificate(&prev);
+                    }
                 }
             }
             Ok(())
@@ -428,6 +431,13 @@ impl xds::Handler<XdsAuthorization> for Arc<Mutex<WorkloadStore>> {
     }
 }
 
+// Requests to the SecretManager, made as local workloads come and go.
+#[derive(Debug)]
+enum CertRequest {
+    Prefetch(Identity),
+    Forget(Identity),
+}
+
 impl WorkloadManager {
     pub async fn new(
         config: config::Config,
@@ -435,19 +445,28 @@ impl WorkloadManager {
         awaiting_ready: readiness::BlockReady,
         cert_manager: Arc<SecretManager>,
     ) -> anyhow::Result<WorkloadManager> {
-        let (tx, mut rx) = mpsc::channel::<Identity>(256);
+        let (tx, mut rx) = mpsc::channel::<CertRequest>(256);
         tokio::spawn(async move {
-            while let Some(workload_identity) = rx.recv().await {
-                match cert_manager
-                    .fetch_certificate_pri(&workload_identity, Warmup)
-                    .await
-                {
-                    Ok(_) => debug!("prefetched cert for {:?}", workload_identity.to_string()),
-                    Err(e) => error!(
-                        "unable to prefetch cert for {:?}, skipping, {:?}",
-                        workload_identity.to_string(),
-                        e
-                    ),
+            while let Some(req) = rx.recv().await {
+                match req {
+                    // The SecretManager rate limits prefetches, so there is no need to wait for
+                    // the certificate before handling the next request.
+                    CertRequest::Prefetch(workload_identity) => {
+                        match cert_manager.prefetch_certificate(&workload_identity).await {
+                            Ok(_) => {
+                                debug!("prefetching cert for {:?}", workload_identity.to_string())
+                            }
+                            Err(e) => error!(
+                                "unable to prefetch cert for {:?}, skipping, {:?}",
+                                workload_identity.to_string(),
+                                e
+                            ),
+                        }
+                    }
+                    CertRequest::Forget(workload_identity) => {
+                        debug!("forgetting cert for {:?}", workload_identity.to_string());
+                        cert_manager.forget_certificate(&workload_identity).await;
+                    }
                 }
             }
         });
@@ -932,7 +951,7 @@ pub struct WorkloadStore {
     policies_by_namespace: HashMap<String, HashSet<String>>,
 
     #[serde(skip_serializing, default)]
-    cert_tx: Option<mpsc::Sender<Identity>>,
+    cert_tx: Option<mpsc::Sender<CertRequest>>,
 
     // needed to determine whether or not to prefetch certs
     proxy_mode: ProxyMode,
@@ -983,11 +1002,19 @@ impl WorkloadStore {
 
     fn insert_xds_workload(&mut self, w: XdsWorkload) -> anyhow::Result<()> {
         let workload = Workload::try_from(&w)?;
+        let prev = self
+            .workloads
+            .get(&network_addr(&workload.network, workload.workload_ip))
+            .cloned();
         self.insert_workload(workload.clone(), w.virtual_ips)?;
+        if let Some(prev) = prev {
+            // The workload may have changed identity.
+            self.maybe_forget_certificate(&prev);
+        }
 
         if self.should_prefetch_certificate(&workload) {
             if let Some(tx) = self.cert_tx.as_mut() {
-                if let Err(e) = tx.try_send(workload.identity()) {
+                if let Err(e) = tx.try_send(CertRequest::Prefetch(workload.identity())) {
                     info!("couldn't prefetch: {:?}", e)
                 }
             }
@@ -1006,6 +1033,27 @@ impl WorkloadStore {
             (w.native_tunnel || w.protocol == Protocol::HBONE)
     }
 
+    // Stops managing the certificate of a removed workload, unless another local workload shares
+    // its identity.
+    fn maybe_forget_certificate(&self, removed: &Workload) {
+        if !self.should_prefetch_certificate(removed) {
+            return;
+        }
+        let id = removed.identity();
+        if self
+            .workloads
+            .values()
+            .any(|w| self.should_prefetch_certificate(w) && w.identity() == id)
+        {
+            return;
+        }
+        if let Some(tx) = self.cert_tx.as_ref() {
+            if let Err(e) = tx.try_send(CertRequest::Forget(id)) {
+                info!("couldn't forget certificate: {:?}", e)
+            }
+        }
+    }
+
     fn insert_xds_authorization(&mut self, r: XdsAuthorization) -> anyhow::Result<()> {
         let rbac = rbac::Authorization::try_from(&r)?;
         trace!("insert policy {}", serde_json::to_string(&rbac)?);
@@ -1147,11 +1195,12 @@ impl WorkloadStore {
             .insert(namespaced_hostname.to_owned(), svc_arc);
     }
 
-    fn remove(&mut self, xds_name: String) {
+    // Removes the workload or service, returning the removed workload if any.
+    fn remove(&mut self, xds_name: String) -> Option<Workload> {
         let parts = xds_name.split_once('/');
         if parts.is_none() {
             error!("received invalid resource removal {}, ignoring", xds_name);
-            return;
+            return None;
         }
 
         // we received either network/IP for workload or namespace/hostname for service
@@ -1173,19 +1222,18 @@ impl WorkloadStore {
         };
 
         if let Some(ip) = maybe_ip {
-            self.remove_workload(network_or_namespace, ip);
+            self.remove_workload(network_or_namespace, ip)
         } else {
             self.remove_service(network_or_namespace, ip_or_hostname);
+            None
         }
     }
 
-    fn remove_workload(&mut self, network: &str, ip: IpAddr) {
-        let Some(prev) = self.workloads.remove(&network_addr(network, ip)) else {
-            return;
-        };
+    fn remove_workload(&mut self, network: &str, ip: IpAddr) -> Option<Workload> {
+        let prev = self.workloads.remove(&network_addr(network, ip))?;
         let prev_addr = &network_addr(&prev.network, prev.workload_ip);
         let Some(prev_vips) = self.workload_to_vips.remove(prev_addr) else {
-            return;
+            return Some(prev);
         };
         for vip in prev_vips.iter() {
             self.staged_vips
@@ -1199,6 +1247,7 @@ impl WorkloadStore {
                 wls.write().unwrap().endpoints.remove(prev_addr);
             }
         }
+        Some(prev)
     }
 
     fn remove_service(&mut self, namespace: &str, hostname: &str) {
@@ -1235,18 +1284,25 @@ impl WorkloadStore {
         if let Some(svc) = self.services_by_ip.get(&network_addr(network, addr.ip())) {
             let svc = svc.read().unwrap().clone();
             let Some(target_port) = svc.ports.get(&addr.port()) else {
-                debug!("found VIP {}, but port {} was unknown", addr.ip(), addr.port());
-                return None
+                debug!(
+                    "found VIP {}, but port {} was unknown",
+                    addr.ip(),
+                    addr.port()
+                );
+                return None;
             };
             // Randomly pick an upstream
             // TODO: do this more efficiently, and not just randomly
             let Some((_, ep)) = svc.endpoints.iter().choose(&mut rand::thread_rng()) else {
                 debug!("VIP {} has no healthy endpoints", addr);
-                return None
+                return None;
             };
-            let Some(wl) = self.workloads.get(&network_addr(&ep.address.network, ep.address.address)) else {
+            let Some(wl) = self
+                .workloads
+                .get(&network_addr(&ep.address.network, ep.address.address))
+            else {
                 debug!("failed to fetch workload for {}", ep.address);
-                return None
+                return None;
             };
             // If endpoint overrides the target port, use that instead
             let target_port = ep.port.get(&addr.port()).unwrap_or(target_port);
@@ -1309,6 +1365,7 @@ mod tests {
     use std::str::FromStr;
 
     use bytes::Bytes;
+    use matches::assert_matches;
 
     use crate::test_helpers;
     use crate::test_helpers::helpers::initialize_telemetry;
@@ -1673,6 +1730,54 @@ mod tests {
         assert_eq!((wi.services_by_hostname.len()), 0);
     }
 
+    #[test]
+    fn forget_certificate_on_removal() {
+        initialize_telemetry();
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut wi = WorkloadStore {
+            cert_tx: Some(tx),
+            proxy_mode: ProxyMode::Shared,
+            local_node: Some("local".to_string()),
+            ..Default::default()
+        };
+        let workload = |ip: u8, sa: &str| XdsWorkload {
+            address: Bytes::copy_from_slice(&[127, 0, 0, ip]),
+            name: format!("wl{ip}"),
+            namespace: "ns".to_string(),
+            service_account: sa.to_string(),
+            node: "local".to_string(),
+            tunnel_protocol: xds::istio::workload::TunnelProtocol::Hbone as i32,
+            ..Default::default()
+        };
+        let identity = |sa: &str| Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "ns".to_string(),
+            service_account: sa.to_string(),
+        };
+        let remove = |wi: &mut WorkloadStore, name: &str| {
+            if let Some(prev) = wi.remove(name.to_string()) {
+                wi.maybe_forget_certificate(&prev);
+            }
+        };
+        for (ip, sa) in [(1, "a"), (2, "a"), (3, "b")] {
+            wi.insert_xds_workload(workload(ip, sa)).unwrap();
+            assert_matches!(rx.try_recv(), Ok(CertRequest::Prefetch(id)) if id == identity(sa));
+        }
+
+        // Another workload still uses the identity.
+        remove(&mut wi, "/127.0.0.1");
+        assert!(rx.try_recv().is_err());
+
+        remove(&mut wi, "/127.0.0.2");
+        assert_matches!(rx.try_recv(), Ok(CertRequest::Forget(id)) if id == identity("a"));
+
+        // Changing the identity of a workload forgets the previous one.
+        wi.insert_xds_workload(workload(3, "c")).unwrap();
+        assert_matches!(rx.try_recv(), Ok(CertRequest::Forget(id)) if id == identity("b"));
+        assert_matches!(rx.try_recv(), Ok(CertRequest::Prefetch(id)) if id == identity("c"));
+        assert!(rx.try_recv().is_err());
+    }
+
     #[test]
     fn staged_vips_cleanup() {
         initialize_telemetry();
@@ -26,7 +26,6 @@ use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use crate::config::RootCert;
 use crate::metrics::xds::*;
 use crate::metrics::{IncrementRecorder, Metrics};
 use crate::xds::istio::security::Authorization;
@@ -114,9 +113,10 @@ pub fn handle_single_resource<T: prost::Message, F: FnMut(XdsUpdate<T>) -> anyho
 }
 
 pub struct Config {
-    address: String,
-    root_cert: RootCert,
-    auth: identity::AuthSource,
+    // XDS servers, in the order they are used.
+    endpoints: Vec<tls::Endpoint>,
+    connector: tls::ConnectorConfig,
+    auth: identity::TokenProvider,
     proxy_metadata: HashMap<String, String>,
 
     address_handler: Box<dyn Handler<Address>>,
@@ -128,9 +128,9 @@ pub struct Config {
 impl Config {
     pub fn new(config: crate::config::Config) -> Config {
         Config {
-            address: config.xds_address.clone().unwrap(),
-            root_cert: config.xds_root_cert.clone(),
-            auth: config.auth,
+            endpoints: config.xds_endpoints(),
+            connector: tls::ConnectorConfig::from(&config),
+            auth: identity::TokenProvider::new(config.auth, config.auth_token_refresh_window),
             address_handler: Box::new(NopHandler {}),
             authorization_handler: Box::new(NopHandler {}),
             initial_watches: Vec::new(),
@@ -165,6 +165,7 @@ impl Config {
             metrics,
             block_ready: Some(block_ready),
             connection_id: 0,
+            channel: None,
         }
     }
 }
@@ -183,6 +184,8 @@ pub struct AdsClient {
     block_ready: Option<readiness::BlockReady>,
 
     connection_id: u32,
+    // Channel of the last connection, whose failover state is kept across reconnections.
+    channel: Option<tls::FailoverChannel>,
 }
 
 /// Demanded allows awaiting for an on-demand XDS resource
@@ -365,8 +368,15 @@ impl AdsClient {
     }
 
     async fn run_internal(&mut self) -> Result<(), Error> {
-        let address = self.config.address.clone();
-        let svc = tls::grpc_connector(address, self.config.root_cert.clone()).unwrap();
+        let svc = match &self.channel {
+            Some(channel) => channel.rebuild(),
+            None => tls::grpc_failover_connector(
+                self.config.endpoints.clone(),
+                self.config.connector.clone(),
+            ),
+        }
+        .unwrap();
+        self.channel = Some(svc.clone());
         let mut client =
             AggregatedDiscoveryServiceClient::with_interceptor(svc, self.config.auth.clone());
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel::<DeltaDiscoveryRequest>(100);
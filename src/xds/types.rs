@@ -22,6 +22,23 @@ pub mod service {
             tonic::include_proto!("envoy.service.discovery.v3");
         }
     }
+    pub mod secret {
+        pub mod v3 {
+            tonic::include_proto!("envoy.service.secret.v3");
+        }
+    }
+}
+
+#[allow(warnings)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub mod extensions {
+    pub mod transport_sockets {
+        pub mod tls {
+            pub mod v3 {
+                tonic::include_proto!("envoy.extensions.transport_sockets.tls.v3");
+            }
+        }
+    }
 }
 
 #[allow(warnings)]
@@ -43,3 +60,5 @@ pub const SERVICE_TYPE: &str = "type.googleapis.com/istio.workload.Service";
 pub const GATEWAY_ADDRESS_TYPE: &str = "type.googleapis.com/istio.workload.GatewayAddress";
 pub const ADDRESS_TYPE: &str = "type.googleapis.com/istio.workload.Address";
 pub const AUTHORIZATION_TYPE: &str = "type.googleapis.com/istio.security.Authorization";
+pub const SECRET_TYPE: &str =
+    "type.googleapis.com/envoy.extensions.transport_sockets.tls.v3.Secret";